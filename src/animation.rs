@@ -162,7 +162,7 @@ impl Rig {
         })
     }
 
-    fn mat4_transpose(trf: russimp::Matrix4x4) -> Mat4 {
+    pub(crate) fn mat4_transpose(trf: russimp::Matrix4x4) -> Mat4 {
         Mat4::from([
             [trf.a1, trf.b1, trf.c1, trf.d1],
             [trf.a2, trf.b2, trf.c2, trf.d2],
@@ -291,12 +291,12 @@ impl Channel {
         &self.name
     }
     pub fn sample(&self, t: f32, trf: Similarity3) -> Similarity3 {
-        let (p1, p2, pr) = Self::sample_keys(&self.position_keys, trf.translation, t);
+        let (p1, p2, pr) = sample_keys(&self.position_keys, trf.translation, t);
         //dbg!(p1,p2,pr);
         let p = p1.lerp(p2, pr);
-        let (r1, r2, rr) = Self::sample_keys(&self.rotation_keys, trf.rotation, t);
+        let (r1, r2, rr) = sample_keys(&self.rotation_keys, trf.rotation, t);
         let r = r1.lerp(r2, rr).normalized();
-        let (s1, s2, sr) = Self::sample_keys(
+        let (s1, s2, sr) = sample_keys(
             &self.scale_keys,
             Vec3::new(trf.scale, trf.scale, trf.scale),
             t,
@@ -304,17 +304,20 @@ impl Channel {
         let s = s1.lerp(s2, sr).x;
         Similarity3::new(p, r, s)
     }
-    fn sample_keys<KT: Copy>(keys: &[(f32, KT)], default: KT, t: f32) -> (KT, KT, f32) {
-        keys.windows(2)
-            // find a pair of keyframes so t1 <= t and t2 > t
-            .find(|ks| ks[0].0 <= t && ks[1].0 > t)
-            // extract k1, k2, and r (t-t1)/(t2-t1)
-            .map(|ks| (ks[0].1, ks[1].1, (t - ks[0].0) / (ks[1].0 - ks[0].0)))
-            // if no such pair, use the last keyframe only and a ratio of 1.0
-            .or_else(|| keys.last().map(|k| (k.1, k.1, 1.0)))
-            // if there were no keyframes at all, use the default transform
-            .unwrap_or((default, default, 1.0))
-    }
+}
+
+/// The keyframes either side of `t` and how far `t` is between them, for
+/// anything keyed by time (also used by camera tracks in `cutscene`).
+pub(crate) fn sample_keys<KT: Copy>(keys: &[(f32, KT)], default: KT, t: f32) -> (KT, KT, f32) {
+    keys.windows(2)
+        // find a pair of keyframes so t1 <= t and t2 > t
+        .find(|ks| ks[0].0 <= t && ks[1].0 > t)
+        // extract k1, k2, and r (t-t1)/(t2-t1)
+        .map(|ks| (ks[0].1, ks[1].1, (t - ks[0].0) / (ks[1].0 - ks[0].0)))
+        // if no such pair, use the last keyframe only and a ratio of 1.0
+        .or_else(|| keys.last().map(|k| (k.1, k.1, 1.0)))
+        // if there were no keyframes at all, use the default transform
+        .unwrap_or((default, default, 1.0))
 }
 
 impl Animation {
@@ -1,7 +1,8 @@
 use crate::animation;
-use crate::color_eyre::eyre::{ensure, eyre};
+use crate::color_eyre::eyre::{ensure, eyre, WrapErr};
 use crate::image::Image;
-use crate::renderer::{flat, skinned, textured};
+use crate::renderer::culling::{self, Bounds};
+use crate::renderer::{flat, lod, pbr, skinned, textured};
 use crate::types::*;
 use crate::vulkan::Vulkan;
 use crate::Result;
@@ -10,64 +11,262 @@ use std::marker::PhantomData;
 use std::rc::Rc;
 use std::sync::Arc;
 use thunderdome::{Arena, Index};
+use vulkano::buffer::ImmutableBuffer;
 use vulkano::image::immutable::ImmutableImage;
 use vulkano::sync::GpuFuture; 
 
 pub struct Texture {
     pub image: Image,
-    pub texture: Arc<ImmutableImage>,
+    // false for data that isn't a color, like normal maps
+    pub srgb: bool,
+    // None until `Assets::upload`
+    pub(crate) texture: Option<Arc<ImmutableImage>>,
 }
+impl Texture {
+    /// The texture's copy on the GPU.  Panics if it hasn't been uploaded.
+    pub fn gpu_image(&self) -> Arc<ImmutableImage> {
+        self.texture
+            .clone()
+            .expect("texture used for drawing before Assets::upload")
+    }
+}
+
+/// A mesh's vertices and indices once they've been copied to the GPU.
+pub struct MeshBuffers<V: bytemuck::Pod + Send + Sync + 'static> {
+    pub verts: Arc<ImmutableBuffer<[V]>>,
+    pub idx: Arc<ImmutableBuffer<[u32]>>,
+}
+impl<V: bytemuck::Pod + Send + Sync + 'static> MeshBuffers<V> {
+    fn upload(verts: &[V], idx: &[u32], vulkan: &mut Vulkan) -> Result<Self> {
+        let (vb, vb_fut) = ImmutableBuffer::from_iter(
+            verts.iter().copied(),
+            vulkano::buffer::BufferUsage::vertex_buffer(),
+            vulkan.queue.clone(),
+        )?;
+        let (ib, ib_fut) = ImmutableBuffer::from_iter(
+            idx.iter().copied(),
+            vulkano::buffer::BufferUsage::index_buffer(),
+            vulkan.queue.clone(),
+        )?;
+        let load_fut = vb_fut.join(ib_fut);
+        vulkan.wait_for(Box::new(load_fut));
+        Ok(Self { verts: vb, idx: ib })
+    }
+}
+
+/// How to import a flat or textured model file.
+#[derive(Clone, Copy, Debug)]
+pub struct ImportSettings {
+    // bake each node's transform (and its ancestors') into its meshes, so
+    // the model is laid out the way it was in the modeling tool; otherwise
+    // every mesh stays in its own space, all on top of each other
+    pub node_transforms: bool,
+}
+impl Default for ImportSettings {
+    fn default() -> Self {
+        Self {
+            node_transforms: true,
+        }
+    }
+}
+
+/// One node of a model file's hierarchy.  A model's nodes are listed
+/// parents first, so `parent` is always an earlier node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelNode {
+    pub name: String,
+    pub parent: Option<usize>,
+    // relative to the parent
+    pub local: Mat4,
+    // relative to the model, i.e. the parent's transform * local
+    pub transform: Mat4,
+    // indices into the model's meshes
+    pub meshes: Vec<usize>,
+}
+
+/// The meshes of the first node called `name` and everything under it, or
+/// `None` if there's no such node.
+pub fn node_meshes(nodes: &[ModelNode], name: &str) -> Option<Vec<usize>> {
+    let top = nodes.iter().position(|n| n.name == name)?;
+    let mut inside = vec![false; nodes.len()];
+    inside[top] = true;
+    let mut meshes = nodes[top].meshes.clone();
+    for (i, n) in nodes.iter().enumerate().skip(top + 1) {
+        if n.parent.map_or(false, |p| inside[p]) {
+            inside[i] = true;
+            meshes.extend_from_slice(&n.meshes);
+        }
+    }
+    Some(meshes)
+}
+
+// stand-ins for material slots a model leaves empty
+#[derive(Clone, Copy)]
+struct BlankTextures {
+    // leaves the material's factors as they are
+    white: TextureRef,
+    // pointing straight out of the surface
+    normal: TextureRef,
+}
+
+/// Everything the game has loaded.  Loading only parses files into CPU-side
+/// data (meshes, rigs, animations, images), so worlds can be updated without
+/// a GPU; `upload` copies whatever's new over to the GPU before drawing.
 pub struct Assets {
     skinned_meshes: Arena<skinned::Mesh>,
     textured_meshes: Arena<textured::Mesh>,
     animations: Arena<animation::Animation>,
     textures: Arena<Texture>,
+    blank: BlankTextures,
     materials: Arena<flat::Material>,
+    textured_materials: Arena<textured::Material>,
+    environment: pbr::Environment,
     materials_by_name: HashMap<String, MaterialRef<flat::Material>>,
     flat_meshes: Arena<flat::Mesh>,
+    // something's been loaded since the last upload
+    needs_upload: bool,
 }
 impl Assets {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
+        let mut textures = Arena::new();
+        let mut add = |rgba: [u8; 4], srgb: bool| {
+            TextureRef(textures.insert(Texture {
+                image: Image::from_rgba8(1, 1, &rgba),
+                srgb,
+                texture: None,
+            }))
+        };
+        let blank = BlankTextures {
+            white: add([255, 255, 255, 255], true),
+            normal: add([128, 128, 255, 255], false),
+        };
         Self {
             skinned_meshes: Arena::new(),
             textured_meshes: Arena::new(),
             animations: Arena::new(),
-            textures: Arena::new(),
+            textures,
+            blank,
             flat_meshes: Arena::new(),
             materials: Arena::new(),
+            textured_materials: Arena::new(),
+            // plain white ambient light, like before there were environments
+            environment: pbr::Environment::uniform(Color(255, 255, 255, 255)),
             materials_by_name: HashMap::new(),
+            needs_upload: true,
         }
     }
-    pub fn load_texture(
-        &mut self,
-        path: &std::path::Path,
-        vulkan: &mut Vulkan,
-    ) -> Result<TextureRef> {
+    /// Copy everything loaded since the last call over to the GPU.
+    pub fn upload(&mut self, vulkan: &mut Vulkan) -> Result<()> {
+        if !self.needs_upload {
+            return Ok(());
+        }
+        for (_, tex) in self.textures.iter_mut().filter(|(_, t)| t.texture.is_none()) {
+            let (vulk_img, fut) = ImmutableImage::from_iter(
+                tex.image.as_slice().iter().copied(),
+                vulkano::image::ImageDimensions::Dim2d {
+                    width: tex.image.sz.x,
+                    height: tex.image.sz.y,
+                    array_layers: 1,
+                },
+                vulkano::image::MipmapsCount::One,
+                if tex.srgb {
+                    vulkano::format::Format::R8G8B8A8_SRGB
+                } else {
+                    vulkano::format::Format::R8G8B8A8_UNORM
+                },
+                vulkan.queue.clone(),
+            )?;
+            vulkan.wait_for(Box::new(fut));
+            tex.texture = Some(vulk_img);
+        }
+        for (_, mat) in self.materials.iter_mut().filter(|(_, m)| m.buffer.is_none()) {
+            let (buffer, fut) = ImmutableBuffer::from_data(
+                pbr::FactorData::from(mat.factors()),
+                vulkano::buffer::BufferUsage::uniform_buffer(),
+                vulkan.queue.clone(),
+            )?;
+            vulkan.wait_for(Box::new(fut));
+            mat.buffer = Some(buffer);
+        }
+        for (_, mat) in self
+            .textured_materials
+            .iter_mut()
+            .filter(|(_, m)| m.buffer.is_none())
+        {
+            let (buffer, fut) = ImmutableBuffer::from_data(
+                pbr::FactorData::from(&mat.factors),
+                vulkano::buffer::BufferUsage::uniform_buffer(),
+                vulkan.queue.clone(),
+            )?;
+            vulkan.wait_for(Box::new(fut));
+            mat.buffer = Some(buffer);
+        }
+        self.environment.upload(vulkan)?;
+        for (_, mesh) in self.skinned_meshes.iter_mut().filter(|(_, m)| m.gpu.is_none()) {
+            mesh.gpu = Some(MeshBuffers::upload(&mesh.verts, &mesh.idx, vulkan)?);
+        }
+        for (_, mesh) in self.textured_meshes.iter_mut().filter(|(_, m)| m.gpu.is_none()) {
+            mesh.gpu = Some(MeshBuffers::upload(&mesh.verts, &mesh.idx, vulkan)?);
+        }
+        for (_, mesh) in self.flat_meshes.iter_mut().filter(|(_, m)| m.gpu.is_none()) {
+            mesh.gpu = Some(MeshBuffers::upload(&mesh.verts, &mesh.idx, vulkan)?);
+        }
+        self.needs_upload = false;
+        Ok(())
+    }
+    pub fn load_texture(&mut self, path: &std::path::Path) -> Result<TextureRef> {
         let img = Image::from_file(path)?;
-        let (vulk_img, fut) = ImmutableImage::from_iter(
-            img.as_slice().iter().copied(),
-            vulkano::image::ImageDimensions::Dim2d {
-                width: img.sz.x,
-                height: img.sz.y,
-                array_layers: 1,
-            },
-            vulkano::image::MipmapsCount::One,
-            vulkano::format::Format::R8G8B8A8_SRGB,
-            vulkan.queue.clone(),
-        )?;
-        vulkan.wait_for(Box::new(fut));
+        Ok(self.add_texture(img, true))
+    }
+    /// Add an image to be used as a texture.  `srgb` should be false for
+    /// anything but colors, e.g. normal maps.
+    pub fn add_texture(&mut self, image: Image, srgb: bool) -> TextureRef {
         let tid = self.textures.insert(Texture {
-            image: img,
-            texture: vulk_img,
+            image,
+            srgb,
+            texture: None,
         });
-        Ok(TextureRef(tid))
+        self.needs_upload = true;
+        TextureRef(tid)
+    }
+    /// A material with only a diffuse texture.
+    pub fn diffuse_material(&mut self, diffuse: TextureRef) -> MaterialRef<textured::Material> {
+        self.add_textured_material(textured::Material {
+            name: String::new(),
+            diffuse,
+            normal: self.blank.normal,
+            metallic_roughness: self.blank.white,
+            emissive: self.blank.white,
+            occlusion: self.blank.white,
+            factors: pbr::Factors::default(),
+            buffer: None,
+        })
+    }
+    pub fn add_textured_material(
+        &mut self,
+        material: textured::Material,
+    ) -> MaterialRef<textured::Material> {
+        self.needs_upload = true;
+        MaterialRef(self.textured_materials.insert(material), PhantomData)
+    }
+    /// A copy of `base` with a different diffuse texture.
+    pub fn with_diffuse(
+        &mut self,
+        base: MaterialRef<textured::Material>,
+        diffuse: TextureRef,
+    ) -> MaterialRef<textured::Material> {
+        let material = textured::Material {
+            diffuse,
+            buffer: None,
+            ..self.textured_material(base).clone()
+        };
+        self.add_textured_material(material)
     }
     pub fn load_skinned(
         &mut self,
         path: &std::path::Path,
         node_root: &[&str],
-        vulkan: &mut Vulkan,
     ) -> Result<Vec<MeshRef<skinned::Mesh>>> {
         use russimp::scene::{PostProcess, Scene};
         let scene = Scene::from_file(
@@ -76,6 +275,7 @@ impl Assets {
             vec![
                 PostProcess::GenerateUVCoords,
                 PostProcess::Triangulate,
+                PostProcess::GenerateNormals,
                 PostProcess::JoinIdenticalVertices,
                 PostProcess::FlipUVs,
                 PostProcess::LimitBoneWeights,
@@ -110,6 +310,11 @@ impl Assets {
                     "Mesh face has too many indices: {:?}",
                     mesh.faces[0]
                 );
+                ensure!(
+                    mesh.normals.len() == verts.len(),
+                    "Mesh has no normals: {:?}",
+                    path
+                );
                 let mut bone_weights: Vec<[f32; 4]> = vec![[1.0, 0.0, 0.0, 0.0]; verts.len()];
                 let mut bone_usage: Vec<[u8; 4]> = vec![[255, 255, 255, 255]; verts.len()];
                 for bone in mesh.bones.iter() {
@@ -125,64 +330,64 @@ impl Assets {
                     }
                 }
                 //dbg!(&bone_weights, &bone_usage);
-                // This is safe to allow because we need an ExactSizeIterator of faces
-                #[allow(clippy::needless_collect)]
                 let faces: Vec<u32> = mesh
                     .faces
                     .iter()
                     .flat_map(|v| v.0.iter().copied())
                     .collect();
-                let (vb, vb_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    verts
-                        .iter()
-                        .zip(uvs.into_iter())
-                        .zip(bone_weights.iter())
-                        .zip(bone_usage.iter())
-                        .map(|(((pos, uv), weights), usage)| skinned::Vertex {
-                            position: [pos.x, pos.y, pos.z],
-                            uv: [uv.x, uv.y],
-                            bone_weights: {
-                                let w: f32 = weights.iter().sum();
-                                [
-                                    weights[0] / w,
-                                    weights[1] / w,
-                                    weights[2] / w,
-                                    weights[3] / w,
-                                ]
-                            },
-                            bone_ids: ((usage[0] as u32) << 24)
-                                | ((usage[1] as u32) << 16)
-                                | ((usage[2] as u32) << 8)
-                                | (usage[3] as u32),
-                        }),
-                    vulkano::buffer::BufferUsage::vertex_buffer(),
-                    vulkan.queue.clone(),
-                )?;
-                let (ib, ib_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    faces.into_iter(),
-                    vulkano::buffer::BufferUsage::index_buffer(),
-                    vulkan.queue.clone(),
-                )?;
-
-                let load_fut = vb_fut.join(ib_fut);
-                vulkan.wait_for(Box::new(load_fut));
-
+                let vertices: Vec<skinned::Vertex> = verts
+                    .iter()
+                    .zip(uvs.into_iter())
+                    .zip(bone_weights.iter())
+                    .zip(bone_usage.iter())
+                    .zip(mesh.normals.iter())
+                    .map(|((((pos, uv), weights), usage), n)| skinned::Vertex {
+                        position: [pos.x, pos.y, pos.z],
+                        uv: [uv.x, uv.y],
+                        normal: [n.x, n.y, n.z],
+                        bone_weights: {
+                            let w: f32 = weights.iter().sum();
+                            [
+                                weights[0] / w,
+                                weights[1] / w,
+                                weights[2] / w,
+                                weights[3] / w,
+                            ]
+                        },
+                        bone_ids: ((usage[0] as u32) << 24)
+                            | ((usage[1] as u32) << 16)
+                            | ((usage[2] as u32) << 8)
+                            | (usage[3] as u32),
+                    })
+                    .collect();
+                let bounds = mesh_bounds(vertices.iter().map(|v| v.position));
                 let mid = self.skinned_meshes.insert(skinned::Mesh {
                     mesh,
                     rig,
-                    verts: vb,
-                    idx: ib,
+                    verts: vertices,
+                    idx: faces,
+                    bounds: bounds.padded(bounds.radius * culling::SKINNED_PADDING),
+                    gpu: None,
                 });
+                self.needs_upload = true;
                 Ok(MeshRef(mid, PhantomData))
             })
             .collect();
         meshes
     }
-    pub fn load_textured(
+    /// Load every mesh in a model file along with its materials' textures,
+    /// whether they're separate files or embedded (as in a .glb).  Meshes
+    /// are moved to where the file's node hierarchy puts them, and the
+    /// hierarchy is kept in the model's `nodes`.
+    pub fn load_textured(&mut self, path: &std::path::Path) -> Result<Rc<textured::Model>> {
+        self.load_textured_with(path, ImportSettings::default())
+    }
+    /// `load_textured`, but imported with `settings` instead of the defaults.
+    pub fn load_textured_with(
         &mut self,
         path: &std::path::Path,
-        vulkan: &mut Vulkan,
-    ) -> Result<Vec<MeshRef<textured::Mesh>>> {
+        settings: ImportSettings,
+    ) -> Result<Rc<textured::Model>> {
         use russimp::scene::{PostProcess, Scene};
         let scene = Scene::from_file(
             path.to_str()
@@ -190,14 +395,25 @@ impl Assets {
             vec![
                 PostProcess::GenerateUVCoords,
                 PostProcess::Triangulate,
+                PostProcess::GenerateNormals,
+                PostProcess::CalcTangentSpace,
                 PostProcess::JoinIdenticalVertices,
                 PostProcess::FlipUVs,
             ],
         )?;
-        let meshes: Result<Vec<_>, _> = scene
-            .meshes
-            .into_iter()
-            .map(|mesh| {
+        // texture paths are relative to the model file
+        let dir = path.parent().unwrap_or_else(|| std::path::Path::new(""));
+        let mats = scene
+            .materials
+            .iter()
+            .map(|mat| self.load_textured_material(mat, dir))
+            .collect::<Result<Vec<_>>>()?;
+        let mut meshes = vec![];
+        let mut nodes = vec![];
+        for (mut node, file_meshes) in file_nodes(&scene) {
+            let bake = Bake::new(settings, &node);
+            for mi in file_meshes {
+                let mesh = &scene.meshes[mi as usize];
                 let verts = &mesh.vertices;
                 let uvs = mesh
                     .texture_coords
@@ -218,43 +434,142 @@ impl Assets {
                     "Mesh face has too many indices: {:?}",
                     mesh.faces[0]
                 );
-                // This is safe to allow because we need an ExactSizeIterator of faces
-                #[allow(clippy::needless_collect)]
-                let faces: Vec<u32> = mesh
+                ensure!(
+                    mesh.normals.len() == verts.len(),
+                    "Mesh has no normals: {:?}",
+                    path
+                );
+                let mut faces: Vec<u32> = mesh
                     .faces
                     .iter()
                     .flat_map(|v| v.0.iter().copied())
                     .collect();
-                let (vb, vb_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    verts.iter().zip(uvs.into_iter()).map(|(pos, uv)| {
-                        crate::renderer::textured::Vertex {
-                            position: [pos.x, pos.y, pos.z],
+                bake.faces(&mut faces);
+                let vertices: Vec<textured::Vertex> = verts
+                    .iter()
+                    .zip(uvs.into_iter())
+                    .zip(mesh.normals.iter())
+                    .enumerate()
+                    .map(|(i, ((pos, uv), n))| {
+                        let n = Vec3::new(n.x, n.y, n.z);
+                        textured::Vertex {
+                            position: bake.position([pos.x, pos.y, pos.z]),
                             uv: [uv.x, uv.y],
+                            normal: bake.normal(n.into()),
+                            tangent: bake.tangent(tangent(mesh, i, n)),
                         }
-                    }),
-                    vulkano::buffer::BufferUsage::vertex_buffer(),
-                    vulkan.queue.clone(),
-                )?;
-                let (ib, ib_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    faces.into_iter(),
-                    vulkano::buffer::BufferUsage::index_buffer(),
-                    vulkan.queue.clone(),
-                )?;
-
-                let load_fut = vb_fut.join(ib_fut);
-                vulkan.wait_for(Box::new(load_fut));
-
-                let mid = self
-                    .textured_meshes
-                    .insert(crate::renderer::textured::Mesh {
+                    })
+                    .collect();
+                let mat = mats[mesh.material_index as usize];
+                let mid = self.textured_meshes.insert(textured::Mesh {
+                    mesh: rebuilt_source(
                         mesh,
-                        verts: vb,
-                        idx: ib,
-                    });
-                Ok(MeshRef(mid, PhantomData))
-            })
-            .collect();
-        meshes
+                        vertices.iter().map(|v| (v.position, v.normal)),
+                        &faces,
+                    ),
+                    bounds: mesh_bounds(vertices.iter().map(|v| v.position)),
+                    verts: vertices,
+                    idx: faces,
+                    gpu: None,
+                });
+                self.needs_upload = true;
+                node.meshes.push(meshes.len());
+                meshes.push((MeshRef(mid, PhantomData), mat));
+            }
+            nodes.push(node);
+        }
+        Ok(Rc::new(
+            textured::Model::new(
+                meshes.iter().map(|(m, _)| m).copied().collect(),
+                meshes.iter().map(|(_, m)| m).copied().collect(),
+            )
+            .with_nodes(nodes),
+        ))
+    }
+    fn load_textured_material(
+        &mut self,
+        mat: &russimp::material::Material,
+        dir: &std::path::Path,
+    ) -> Result<MaterialRef<textured::Material>> {
+        use russimp::material::TextureType;
+        let diffuse = self.load_material_texture(
+            mat,
+            &[TextureType::BaseColor, TextureType::Diffuse],
+            dir,
+            true,
+        )?;
+        // Blender writes normal maps into OBJ files as bump maps
+        let normal = self.load_material_texture(
+            mat,
+            &[TextureType::Normals, TextureType::NormalCamera, TextureType::Height],
+            dir,
+            false,
+        )?;
+        // glTF's combined metallic-roughness image, which assimp files under
+        // one or both of these
+        let metallic_roughness = self.load_material_texture(
+            mat,
+            &[TextureType::Unknown, TextureType::Metalness],
+            dir,
+            false,
+        )?;
+        let emissive = self.load_material_texture(
+            mat,
+            &[TextureType::EmissionColor, TextureType::Emissive],
+            dir,
+            true,
+        )?;
+        // glTF puts ambient occlusion in a lightmap slot
+        let occlusion = self.load_material_texture(mat, &[TextureType::LightMap], dir, false)?;
+        let mut factors = pbr_factors(mat);
+        // OBJ files can name an emissive map with a black emissive color
+        if emissive.is_some() && factors.emissive == Vec3::zero() {
+            factors.emissive = Vec3::one();
+        }
+        Ok(self.add_textured_material(textured::Material {
+            name: material_name(mat).unwrap_or_default(),
+            diffuse: diffuse.unwrap_or(self.blank.white),
+            normal: normal.unwrap_or(self.blank.normal),
+            metallic_roughness: metallic_roughness.unwrap_or(self.blank.white),
+            emissive: emissive.unwrap_or(self.blank.white),
+            occlusion: occlusion.unwrap_or(self.blank.white),
+            factors,
+            buffer: None,
+        }))
+    }
+    // the first texture of the first of `types` that `mat` has
+    fn load_material_texture(
+        &mut self,
+        mat: &russimp::material::Material,
+        types: &[russimp::material::TextureType],
+        dir: &std::path::Path,
+        srgb: bool,
+    ) -> Result<Option<TextureRef>> {
+        use russimp::material::DataContent;
+        let tex = match types
+            .iter()
+            .find_map(|t| mat.textures.get(t).and_then(|ts| ts.first()))
+        {
+            Some(tex) => tex,
+            None => return Ok(None),
+        };
+        let image = match &tex.data {
+            // embedded in the model file
+            Some(DataContent::Bytes(bytes)) => Image::from_encoded(bytes)?,
+            Some(DataContent::Texel(texels)) => {
+                let bytes: Vec<u8> = texels.iter().flat_map(|t| [t.r, t.g, t.b, t.a]).collect();
+                Image::from_rgba8(tex.width, tex.height, &bytes)
+            }
+            None => {
+                let path = dir.join(&tex.filename);
+                Image::from_file_raw(&path)
+                    .wrap_err_with(|| format!("Couldn't load material texture {:?}", path))?
+            }
+        };
+        // colors get premultiplied like any other texture, but data maps
+        // (normals, metallic-roughness, occlusion) have to stay as they are
+        let image = if srgb { image.premultiplied() } else { image };
+        Ok(Some(self.add_texture(image, srgb)))
     }
     pub fn load_anim(
         &mut self,
@@ -283,10 +598,16 @@ impl Assets {
         let aid = self.animations.insert(anim);
         Ok(AnimRef(aid))
     }
-    pub fn load_flat(
+    /// Load every mesh in a model file along with its materials, laid out
+    /// like `load_textured`'s.
+    pub fn load_flat(&mut self, path: &std::path::Path) -> Result<Rc<flat::Model>> {
+        self.load_flat_with(path, ImportSettings::default())
+    }
+    /// `load_flat`, but imported with `settings` instead of the defaults.
+    pub fn load_flat_with(
         &mut self,
         path: &std::path::Path,
-        vulkan: &mut Vulkan,
+        settings: ImportSettings,
     ) -> Result<Rc<flat::Model>> {
         use russimp::scene::{PostProcess, Scene};
         let scene = Scene::from_file(
@@ -294,58 +615,29 @@ impl Assets {
                 .ok_or_else(|| eyre!("Mesh path can't be converted to string: {:?}", path))?,
             vec![
                 PostProcess::Triangulate,
+                PostProcess::GenerateNormals,
                 PostProcess::JoinIdenticalVertices,
                 PostProcess::LimitBoneWeights,
             ],
         )?;
         let mats: Vec<MaterialRef<flat::Material>> = scene
             .materials
-            .into_iter()
+            .iter()
             .map(|mat| {
-                let color = mat
-                    .properties
-                    .iter()
-                    .find(|p| p.key == "$clr.base")
-                    .and_then(|p| {
-                        if let russimp::material::PropertyTypeInfo::FloatArray(fs) = &p.data {
-                            Some(Vec4::new(fs[0], fs[1], fs[2], fs[3]))
-                        } else {
-                            None
-                        }
-                    })
-                    .unwrap_or(Vec4::new(1., 1., 1., 1.));
-                let name = mat
-                    .properties
-                    .iter()
-                    .find(|p| p.key == "?mat.name")
-                    .and_then(|p| {
-                        if let russimp::material::PropertyTypeInfo::String(n) = &p.data {
-                            Some(n.clone())
-                        } else {
-                            None
-                        }
-                    })
-                    .unwrap_or_else(|| "BLANK".to_string());
+                let factors = pbr_factors(mat);
+                let name = material_name(mat).unwrap_or_else(|| "BLANK".to_string());
                 match self.materials_by_name.entry(name.clone()) {
                     std::collections::hash_map::Entry::Occupied(e) => {
                         println!(
                             "Skip material {:?}, already found {:?}",
-                            (color, name),
+                            (factors, name),
                             self.materials[e.get().0]
                         );
                         *e.get()
                     }
                     std::collections::hash_map::Entry::Vacant(e) => {
-                        let (buffer, fut) = vulkano::buffer::ImmutableBuffer::from_data(
-                            color.into(),
-                            vulkano::buffer::BufferUsage::uniform_buffer(),
-                            vulkan.queue.clone(),
-                        )
-                        .unwrap();
-                        vulkan.wait_for(Box::new(fut));
-                        let mat_ref = self
-                            .materials
-                            .insert(flat::Material::new(color, name, buffer));
+                        let mat_ref = self.materials.insert(flat::Material::new(factors, name));
+                        self.needs_upload = true;
                         let mat_ref = MaterialRef(mat_ref, PhantomData);
                         e.insert(mat_ref);
                         mat_ref
@@ -353,53 +645,118 @@ impl Assets {
                 }
             })
             .collect();
-        let meshes: Result<Vec<_>, _> = scene
-            .meshes
-            .into_iter()
-            .map(|mesh| {
+        let mut meshes = vec![];
+        let mut nodes = vec![];
+        for (mut node, file_meshes) in file_nodes(&scene) {
+            let bake = Bake::new(settings, &node);
+            for mi in file_meshes {
+                let mesh = &scene.meshes[mi as usize];
                 let verts = &mesh.vertices;
                 ensure!(
                     mesh.faces[0].0.len() == 3,
                     "Mesh face has too many indices: {:?}",
                     mesh.faces[0]
                 );
-                // This is safe to allow because we need an ExactSizeIterator of faces
-                #[allow(clippy::needless_collect)]
-                let faces: Vec<u32> = mesh
+                ensure!(
+                    mesh.normals.len() == verts.len(),
+                    "Mesh has no normals: {:?}",
+                    path
+                );
+                let mut faces: Vec<u32> = mesh
                     .faces
                     .iter()
                     .flat_map(|v| v.0.iter().copied())
                     .collect();
-                let (vb, vb_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    verts.iter().map(|pos| flat::Vertex {
-                        position: [pos.x, pos.y, pos.z],
-                    }),
-                    vulkano::buffer::BufferUsage::vertex_buffer(),
-                    vulkan.queue.clone(),
-                )?;
-                let (ib, ib_fut) = vulkano::buffer::ImmutableBuffer::from_iter(
-                    faces.into_iter(),
-                    vulkano::buffer::BufferUsage::index_buffer(),
-                    vulkan.queue.clone(),
-                )?;
-
-                let load_fut = vb_fut.join(ib_fut);
-                vulkan.wait_for(Box::new(load_fut));
-
+                bake.faces(&mut faces);
+                let vertices: Vec<flat::Vertex> = verts
+                    .iter()
+                    .zip(mesh.normals.iter())
+                    .map(|(pos, n)| flat::Vertex {
+                        position: bake.position([pos.x, pos.y, pos.z]),
+                        normal: bake.normal([n.x, n.y, n.z]),
+                    })
+                    .collect();
                 let mat = mats[mesh.material_index as usize];
                 let mid = self.flat_meshes.insert(flat::Mesh {
-                    mesh,
-                    verts: vb,
-                    idx: ib,
+                    mesh: rebuilt_source(
+                        mesh,
+                        vertices.iter().map(|v| (v.position, v.normal)),
+                        &faces,
+                    ),
+                    bounds: mesh_bounds(vertices.iter().map(|v| v.position)),
+                    verts: vertices,
+                    idx: faces,
+                    gpu: None,
                 });
-                Ok((MeshRef(mid, PhantomData), mat))
+                self.needs_upload = true;
+                node.meshes.push(meshes.len());
+                meshes.push((MeshRef(mid, PhantomData), mat));
+            }
+            nodes.push(node);
+        }
+        Ok(Rc::new(
+            flat::Model::new(
+                meshes.iter().map(|(m, _)| m).copied().collect(),
+                meshes.iter().map(|(_, m)| m).copied().collect(),
+            )
+            .with_nodes(nodes),
+        ))
+    }
+    /// A simplified copy of `model` with about `ratio` of its vertices,
+    /// drawn with the same materials, for use as one of its levels of
+    /// detail.
+    pub fn decimate_flat(&mut self, model: &flat::Model, ratio: f32) -> flat::Model {
+        let meshes = model
+            .meshes()
+            .iter()
+            .map(|m| {
+                let src = &self.flat_meshes[m.0];
+                let (verts, idx) =
+                    lod::decimate(&src.verts, &src.idx, ratio, |v| Vec3::from(v.position));
+                let mesh = flat::Mesh {
+                    mesh: rebuilt_source(
+                        &src.mesh,
+                        verts.iter().map(|v| (v.position, v.normal)),
+                        &idx,
+                    ),
+                    bounds: mesh_bounds(verts.iter().map(|v| v.position)),
+                    verts,
+                    idx,
+                    gpu: None,
+                };
+                MeshRef(self.flat_meshes.insert(mesh), PhantomData)
             })
             .collect();
-        let meshes = meshes?;
-        Ok(Rc::new(flat::Model::new(
-            meshes.iter().map(|(m, _)| m).copied().collect(),
-            meshes.iter().map(|(_, m)| m).copied().collect(),
-        )))
+        self.needs_upload = true;
+        flat::Model::new(meshes, model.materials().to_vec()).with_nodes(model.nodes().to_vec())
+    }
+    /// A simplified copy of `model` with about `ratio` of its vertices,
+    /// drawn with the same materials, for use as one of its levels of
+    /// detail.
+    pub fn decimate_textured(&mut self, model: &textured::Model, ratio: f32) -> textured::Model {
+        let meshes = model
+            .meshes()
+            .iter()
+            .map(|m| {
+                let src = &self.textured_meshes[m.0];
+                let (verts, idx) =
+                    lod::decimate(&src.verts, &src.idx, ratio, |v| Vec3::from(v.position));
+                let mesh = textured::Mesh {
+                    mesh: rebuilt_source(
+                        &src.mesh,
+                        verts.iter().map(|v| (v.position, v.normal)),
+                        &idx,
+                    ),
+                    bounds: mesh_bounds(verts.iter().map(|v| v.position)),
+                    verts,
+                    idx,
+                    gpu: None,
+                };
+                MeshRef(self.textured_meshes.insert(mesh), PhantomData)
+            })
+            .collect();
+        self.needs_upload = true;
+        textured::Model::new(meshes, model.materials().to_vec()).with_nodes(model.nodes().to_vec())
     }
     pub fn skinned_mesh(&self, m: MeshRef<skinned::Mesh>) -> &skinned::Mesh {
         &self.skinned_meshes[m.0]
@@ -413,6 +770,26 @@ impl Assets {
     pub fn material(&self, m: MaterialRef<flat::Material>) -> &flat::Material {
         &self.materials[m.0]
     }
+    pub fn textured_material(&self, m: MaterialRef<textured::Material>) -> &textured::Material {
+        &self.textured_materials[m.0]
+    }
+    pub fn environment(&self) -> &pbr::Environment {
+        &self.environment
+    }
+    /// Light every lit surface with `environment` from now on.
+    pub fn set_environment(&mut self, environment: pbr::Environment) {
+        self.environment = environment;
+        self.needs_upload = true;
+    }
+    /// Load an environment from six images, in +x, -x, +y, -y, +z, -z order.
+    pub fn load_environment(&mut self, faces: &[&std::path::Path]) -> Result<()> {
+        let faces = faces
+            .iter()
+            .map(|p| Image::from_file(p))
+            .collect::<Result<Vec<_>>>()?;
+        self.set_environment(pbr::Environment::new(faces)?);
+        Ok(())
+    }
     pub fn texture(&self, m: TextureRef) -> &Texture {
         &self.textures[m.0]
     }
@@ -421,6 +798,206 @@ impl Assets {
     }
 }
 
+fn mesh_bounds(positions: impl Iterator<Item = [f32; 3]>) -> Bounds {
+    Bounds::from_points(&positions.map(Vec3::from).collect::<Vec<_>>())
+}
+
+// a model file's nodes, parents first, each with the indices of the file's
+// meshes it holds.  The returned nodes' `meshes` are left empty.
+fn file_nodes(scene: &russimp::scene::Scene) -> Vec<(ModelNode, Vec<u32>)> {
+    let root = match &scene.root {
+        Some(root) => root.clone(),
+        None => {
+            return vec![(
+                ModelNode {
+                    name: String::new(),
+                    parent: None,
+                    local: Mat4::identity(),
+                    transform: Mat4::identity(),
+                    meshes: vec![],
+                },
+                (0..scene.meshes.len() as u32).collect(),
+            )]
+        }
+    };
+    let mut nodes: Vec<(ModelNode, Vec<u32>)> = vec![];
+    let mut queue = std::collections::VecDeque::from([(root, None)]);
+    while let Some((next, parent)) = queue.pop_front() {
+        let next = next.borrow();
+        let local = animation::Rig::mat4_transpose(next.transformation);
+        let transform = match parent {
+            Some(p) => nodes[p].0.transform * local,
+            None => local,
+        };
+        let idx = nodes.len();
+        queue.extend(next.children.iter().map(|c| (c.clone(), Some(idx))));
+        nodes.push((
+            ModelNode {
+                name: next.name.clone(),
+                parent,
+                local,
+                transform,
+                meshes: vec![],
+            },
+            next.meshes.clone(),
+        ));
+    }
+    nodes
+}
+
+// moves one node's vertices into the model's space
+struct Bake {
+    transform: Mat4,
+    // for normals and tangents, which don't scale along with positions
+    normal_transform: Mat3,
+    // an odd number of axes flipped, so triangles' winding has to be too
+    mirrored: bool,
+}
+impl Bake {
+    fn new(settings: ImportSettings, node: &ModelNode) -> Self {
+        let transform = if settings.node_transforms {
+            node.transform
+        } else {
+            Mat4::identity()
+        };
+        let linear = transform.truncate();
+        Self {
+            transform,
+            normal_transform: linear.inversed().transposed(),
+            mirrored: linear.determinant() < 0.0,
+        }
+    }
+    fn position(&self, p: [f32; 3]) -> [f32; 3] {
+        self.transform.transform_point3(Vec3::from(p)).into()
+    }
+    fn normal(&self, n: [f32; 3]) -> [f32; 3] {
+        (self.normal_transform * Vec3::from(n)).normalized().into()
+    }
+    fn tangent(&self, t: [f32; 4]) -> [f32; 4] {
+        let dir = (self.transform.truncate() * Vec3::new(t[0], t[1], t[2])).normalized();
+        let w = if self.mirrored { -t[3] } else { t[3] };
+        [dir.x, dir.y, dir.z, w]
+    }
+    fn faces(&self, idx: &mut [u32]) {
+        if self.mirrored {
+            for tri in idx.chunks_exact_mut(3) {
+                tri.swap(1, 2);
+            }
+        }
+    }
+}
+
+// an imported mesh with `verts` and `idx` in place of `src`'s, so anything
+// reading `Mesh::mesh` (like collision) sees the same triangles we draw, be
+// they baked into their node's space or decimated
+fn rebuilt_source(
+    src: &russimp::mesh::Mesh,
+    verts: impl Iterator<Item = ([f32; 3], [f32; 3])>,
+    idx: &[u32],
+) -> russimp::mesh::Mesh {
+    let (vertices, normals) = verts
+        .map(|([x, y, z], [nx, ny, nz])| {
+            (
+                russimp::Vector3D { x, y, z },
+                russimp::Vector3D {
+                    x: nx,
+                    y: ny,
+                    z: nz,
+                },
+            )
+        })
+        .unzip();
+    russimp::mesh::Mesh {
+        name: src.name.clone(),
+        vertices,
+        normals,
+        faces: idx
+            .chunks_exact(3)
+            .map(|f| russimp::face::Face(f.to_vec()))
+            .collect(),
+        material_index: src.material_index,
+        ..Default::default()
+    }
+}
+
+fn material_name(mat: &russimp::material::Material) -> Option<String> {
+    mat.properties
+        .iter()
+        .find(|p| p.key == "?mat.name")
+        .and_then(|p| {
+            if let russimp::material::PropertyTypeInfo::String(n) = &p.data {
+                Some(n.clone())
+            } else {
+                None
+            }
+        })
+}
+
+// glTF's PBR factors, under the keys various versions of assimp have put
+// them, or defaults for anything the material doesn't say
+fn pbr_factors(mat: &russimp::material::Material) -> pbr::Factors {
+    use russimp::material::PropertyTypeInfo;
+    let floats = |keys: &[&str]| {
+        mat.properties
+            .iter()
+            .find(|p| keys.contains(&p.key.as_str()))
+            .and_then(|p| match &p.data {
+                PropertyTypeInfo::FloatArray(fs) if !fs.is_empty() => Some(fs.clone()),
+                _ => None,
+            })
+    };
+    let mut f = pbr::Factors::default();
+    if let Some(c) = floats(&["$clr.base"]).filter(|c| c.len() >= 3) {
+        f.base_color = Vec4::new(c[0], c[1], c[2], c.get(3).copied().unwrap_or(1.0));
+    }
+    if let Some(m) = floats(&[
+        "$mat.metallicFactor",
+        "$mat.gltf.pbrMetallicRoughness.metallicFactor",
+    ]) {
+        f.metallic = m[0];
+    }
+    if let Some(r) = floats(&[
+        "$mat.roughnessFactor",
+        "$mat.gltf.pbrMetallicRoughness.roughnessFactor",
+    ]) {
+        f.roughness = r[0];
+    }
+    if let Some(e) = floats(&["$clr.emissive"]).filter(|e| e.len() >= 3) {
+        f.emissive = Vec3::new(e[0], e[1], e[2]);
+    }
+    // OBJ's d, and glTF's alpha mode
+    let opacity = floats(&["$mat.opacity"]).map_or(1.0, |o| o[0]);
+    f.base_color.w = f.base_color.w.min(opacity);
+    let blend = mat.properties.iter().any(|p| {
+        p.key == "$mat.gltf.alphaMode"
+            && matches!(&p.data, PropertyTypeInfo::String(m) if m == "BLEND")
+    });
+    f.transparent = blend || opacity < 1.0;
+    f
+}
+
+// `mesh`'s tangent at vertex `i` with the bitangent's handedness in w, or
+// any tangent at all if it has none (e.g. without texture coordinates)
+fn tangent(mesh: &russimp::mesh::Mesh, i: usize, n: Vec3) -> [f32; 4] {
+    match (mesh.tangents.get(i), mesh.bitangents.get(i)) {
+        (Some(t), Some(b)) => {
+            let t = Vec3::new(t.x, t.y, t.z);
+            let b = Vec3::new(b.x, b.y, b.z);
+            let w = if n.cross(t).dot(b) < 0.0 { -1.0 } else { 1.0 };
+            [t.x, t.y, t.z, w]
+        }
+        _ => {
+            let other = if n.x.abs() < 0.9 {
+                Vec3::unit_x()
+            } else {
+                Vec3::unit_y()
+            };
+            let t = (other - n * n.dot(other)).normalized();
+            [t.x, t.y, t.z, 1.0]
+        }
+    }
+}
+
 pub struct MeshRef<M>(Index, PhantomData<M>);
 impl<M> Clone for MeshRef<M> {
     fn clone(&self) -> Self {
@@ -469,3 +1046,35 @@ impl<M> std::hash::Hash for MaterialRef<M> {
 pub struct TextureRef(Index);
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct AnimRef(Index);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, parent: Option<usize>, meshes: &[usize]) -> ModelNode {
+        ModelNode {
+            name: name.to_string(),
+            parent,
+            local: Mat4::identity(),
+            transform: Mat4::identity(),
+            meshes: meshes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn node_meshes_takes_the_subtree() {
+        let nodes = [
+            node("Root", None, &[0]),
+            node("Table", Some(0), &[1, 2]),
+            node("Leg", Some(1), &[3]),
+            node("Door", Some(0), &[4]),
+            // only the first node with a name counts
+            node("Table", Some(3), &[5]),
+        ];
+        assert_eq!(node_meshes(&nodes, "Root"), Some(vec![0, 1, 2, 3, 4, 5]));
+        assert_eq!(node_meshes(&nodes, "Table"), Some(vec![1, 2, 3]));
+        assert_eq!(node_meshes(&nodes, "Leg"), Some(vec![3]));
+        assert_eq!(node_meshes(&nodes, "Door"), Some(vec![4, 5]));
+        assert_eq!(node_meshes(&nodes, "Chair"), None);
+    }
+}
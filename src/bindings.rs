@@ -0,0 +1,563 @@
+//! Named actions on top of `Input`.
+//!
+//! Game code asks whether an `Action` is down/pressed/released, or how far an
+//! `Axis` moved this frame, and `Bindings` works out which keys, mouse buttons,
+//! gamepad buttons or axes that means.  Bindings load from RON files like
+//! `content/setup/bindings.ron`, and any action a file leaves out keeps its
+//! default binding.
+use crate::gamepad::{PadAxis, PadButton};
+use crate::input::Input;
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use winit::event::{MouseButton, VirtualKeyCode};
+
+pub const DEFAULT_BINDINGS: &str = "content/setup/bindings.ron";
+// degrees of turn per pixel of mouse movement
+pub const DEFAULT_MOUSE_SENSITIVITY: f32 = 0.5;
+// how far a stick or trigger has to go before it counts as a button press
+pub const PAD_AXIS_PRESS: f32 = 0.5;
+// degrees of turn per tick with the right stick all the way over
+const PAD_LOOK_SPEED: f32 = 3.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    Sprint,
+    Crouch,
+    Jump,
+    Photograph,
+    Pause,
+    Unpause,
+    ResetPlayer,
+    ResetPosition,
+    ResetView,
+    QuickSave,
+    QuickLoad,
+    SkipCutscene,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Axis {
+    // positive turns right
+    LookX,
+    // positive looks down
+    LookY,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    Pad(PadButton),
+    // a stick or trigger pushed past PAD_AXIS_PRESS one way or the other
+    PadAxisPositive(PadAxis),
+    PadAxisNegative(PadAxis),
+}
+impl Binding {
+    fn axis_past(&self, v: f32) -> bool {
+        match self {
+            Binding::PadAxisPositive(_) => v >= PAD_AXIS_PRESS,
+            Binding::PadAxisNegative(_) => v <= -PAD_AXIS_PRESS,
+            _ => false,
+        }
+    }
+    fn is_down(&self, input: &Input) -> bool {
+        match self {
+            Binding::Key(kc) => input.is_key_down(*kc),
+            Binding::Mouse(mb) => input.is_mouse_button_down(*mb),
+            Binding::Pad(b) => input.is_pad_button_down(*b),
+            Binding::PadAxisPositive(a) | Binding::PadAxisNegative(a) => {
+                self.axis_past(input.get_pad_axis(*a))
+            }
+        }
+    }
+    // how far the binding is held, from 0 to 1: all or nothing for keys
+    // and buttons, but as far as a stick or trigger is past its dead zone
+    fn value(&self, input: &Input) -> f32 {
+        match self {
+            Binding::PadAxisPositive(a) => input.get_pad_axis(*a).max(0.0),
+            Binding::PadAxisNegative(a) => (-input.get_pad_axis(*a)).max(0.0),
+            _ => self.is_down(input) as i32 as f32,
+        }
+    }
+    fn is_pressed(&self, input: &Input) -> bool {
+        match self {
+            Binding::Key(kc) => input.is_key_pressed(*kc),
+            Binding::Mouse(mb) => input.is_mouse_button_pressed(*mb),
+            Binding::Pad(b) => input.is_pad_button_pressed(*b),
+            Binding::PadAxisPositive(a) | Binding::PadAxisNegative(a) => {
+                self.axis_past(input.get_pad_axis(*a))
+                    && !self.axis_past(input.get_prev_pad_axis(*a))
+            }
+        }
+    }
+    fn is_released(&self, input: &Input) -> bool {
+        match self {
+            Binding::Key(kc) => input.is_key_released(*kc),
+            Binding::Mouse(mb) => input.is_mouse_button_released(*mb),
+            Binding::Pad(b) => input.is_pad_button_released(*b),
+            Binding::PadAxisPositive(a) | Binding::PadAxisNegative(a) => {
+                !self.axis_past(input.get_pad_axis(*a))
+                    && self.axis_past(input.get_prev_pad_axis(*a))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AxisBinding {
+    // mouse movement this frame, in pixels, scaled by the mouse sensitivity
+    MouseX,
+    MouseY,
+    // lines scrolled this frame
+    ScrollX,
+    ScrollY,
+    // a stick or trigger past its dead zone, times `scale`
+    Pad { axis: PadAxis, scale: f32 },
+    // -1 while `negative` is held, 1 while `positive` is, 0 for both or neither
+    Keys {
+        negative: VirtualKeyCode,
+        positive: VirtualKeyCode,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Bindings {
+    #[serde(default = "default_sensitivity")]
+    pub mouse_sensitivity: f32,
+    #[serde(default)]
+    pub invert_y: bool,
+    #[serde(default)]
+    pub actions: BTreeMap<Action, Vec<Binding>>,
+    #[serde(default)]
+    pub axes: BTreeMap<Axis, Vec<AxisBinding>>,
+}
+fn default_sensitivity() -> f32 {
+    DEFAULT_MOUSE_SENSITIVITY
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        use Binding::*;
+        use PadAxis::*;
+        use VirtualKeyCode as K;
+        let actions = BTreeMap::from([
+            (
+                Action::MoveForward,
+                vec![Key(K::W), Key(K::Up), PadAxisPositive(LeftStickY)],
+            ),
+            (
+                Action::MoveBackward,
+                vec![Key(K::S), Key(K::Down), PadAxisNegative(LeftStickY)],
+            ),
+            (
+                Action::MoveLeft,
+                vec![Key(K::A), Key(K::Left), PadAxisNegative(LeftStickX)],
+            ),
+            (
+                Action::MoveRight,
+                vec![Key(K::D), Key(K::Right), PadAxisPositive(LeftStickX)],
+            ),
+            (Action::Sprint, vec![Key(K::LShift), Pad(PadButton::LeftStick)]),
+            (
+                Action::Crouch,
+                vec![Key(K::LControl), Key(K::C), Pad(PadButton::East)],
+            ),
+            (Action::Jump, vec![Key(K::Space), Pad(PadButton::South)]),
+            (
+                Action::Photograph,
+                vec![Mouse(MouseButton::Left), PadAxisPositive(RightTrigger)],
+            ),
+            (Action::Pause, vec![Key(K::Key1)]),
+            (Action::Unpause, vec![Key(K::Key0)]),
+            (Action::ResetPlayer, vec![Key(K::Q), Pad(PadButton::Select)]),
+            (Action::ResetPosition, vec![Key(K::R)]),
+            (Action::ResetView, vec![Key(K::E)]),
+            (Action::QuickSave, vec![Key(K::F5)]),
+            (Action::QuickLoad, vec![Key(K::F9)]),
+            (
+                Action::SkipCutscene,
+                vec![Key(K::Return), Pad(PadButton::Start)],
+            ),
+        ]);
+        let axes = BTreeMap::from([
+            (
+                Axis::LookX,
+                vec![
+                    AxisBinding::MouseX,
+                    AxisBinding::Pad {
+                        axis: RightStickX,
+                        scale: PAD_LOOK_SPEED,
+                    },
+                ],
+            ),
+            (
+                Axis::LookY,
+                vec![
+                    AxisBinding::MouseY,
+                    // stick up is positive, but looking up is negative
+                    AxisBinding::Pad {
+                        axis: RightStickY,
+                        scale: -PAD_LOOK_SPEED,
+                    },
+                ],
+            ),
+        ]);
+        Self {
+            mouse_sensitivity: DEFAULT_MOUSE_SENSITIVITY,
+            invert_y: false,
+            actions,
+            axes,
+        }
+    }
+}
+
+impl Bindings {
+    /// Read bindings from `path`, falling back to the defaults for anything
+    /// the file doesn't mention.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read bindings {:?}", path))?;
+        let loaded: Bindings = ron::from_str(&text)
+            .wrap_err_with(|| format!("Couldn't parse bindings {:?}", path))?;
+        let mut bindings = Self::default();
+        bindings.mouse_sensitivity = loaded.mouse_sensitivity;
+        bindings.invert_y = loaded.invert_y;
+        bindings.actions.extend(loaded.actions);
+        bindings.axes.extend(loaded.axes);
+        Ok(bindings)
+    }
+    /// Like `load`, but a missing file just means the defaults.
+    pub fn load_or_default(path: &Path) -> Result<Self> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::new())?;
+        std::fs::write(path, text).wrap_err_with(|| format!("Couldn't write bindings {:?}", path))
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.actions.get(&action).map(|b| b.as_slice()).unwrap_or(&[])
+    }
+    /// Add another way to trigger `action`.
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        let bs = self.actions.entry(action).or_default();
+        if !bs.contains(&binding) {
+            bs.push(binding);
+        }
+    }
+    pub fn unbind(&mut self, action: Action, binding: Binding) {
+        if let Some(bs) = self.actions.get_mut(&action) {
+            bs.retain(|b| *b != binding);
+        }
+    }
+    /// Replace every binding for `action` with `binding`.
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        self.actions.insert(action, vec![binding]);
+    }
+    pub fn bind_axis(&mut self, axis: Axis, bindings: Vec<AxisBinding>) {
+        self.axes.insert(axis, bindings);
+    }
+    pub fn set_mouse_sensitivity(&mut self, sensitivity: f32) {
+        self.mouse_sensitivity = sensitivity;
+    }
+
+    pub fn is_down(&self, input: &Input, action: Action) -> bool {
+        self.bindings(action).iter().any(|b| b.is_down(input))
+    }
+    /// How far `action` is held, from 0 to 1, over whichever of its bindings
+    /// is held furthest.  A key counts as all the way, but a stick only
+    /// counts as far as it's pushed (past its dead zone), so moving can be
+    /// slower than full speed.
+    pub fn value(&self, input: &Input, action: Action) -> f32 {
+        self.bindings(action)
+            .iter()
+            .map(|b| b.value(input))
+            .fold(0.0, f32::max)
+    }
+    /// True on the frame the action starts, so long as no other binding for
+    /// it was already held.
+    pub fn is_pressed(&self, input: &Input, action: Action) -> bool {
+        let bs = self.bindings(action);
+        bs.iter().any(|b| b.is_pressed(input))
+            && !bs.iter().any(|b| b.is_down(input) && !b.is_pressed(input))
+    }
+    /// True on the frame the last binding holding the action is let go.
+    pub fn is_released(&self, input: &Input, action: Action) -> bool {
+        let bs = self.bindings(action);
+        bs.iter().any(|b| b.is_released(input)) && !bs.iter().any(|b| b.is_down(input))
+    }
+    /// How far `axis` moved this frame, summed over all its bindings.
+    pub fn axis(&self, input: &Input, axis: Axis) -> f32 {
+        let delta = input.get_mouse_delta();
+        let v: f32 = self
+            .axes
+            .get(&axis)
+            .map(|bs| bs.as_slice())
+            .unwrap_or(&[])
+            .iter()
+            .map(|b| match b {
+                AxisBinding::MouseX => delta.x as f32 * self.mouse_sensitivity,
+                AxisBinding::MouseY => delta.y as f32 * self.mouse_sensitivity,
+                AxisBinding::ScrollX => input.get_scroll_delta().x,
+                AxisBinding::ScrollY => input.get_scroll_delta().y,
+                AxisBinding::Pad { axis, scale } => input.get_pad_axis(*axis) * scale,
+                AxisBinding::Keys { negative, positive } => {
+                    (input.is_key_down(*positive) as i32 - input.is_key_down(*negative) as i32)
+                        as f32
+                }
+            })
+            .sum();
+        if axis == Axis::LookY && self.invert_y {
+            -v
+        } else {
+            v
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gamepad::{PadDevice, SyntheticPads, STICK_DEAD_ZONE};
+
+    use VirtualKeyCode as K;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("bindings-test-{}-{}.ron", name, std::process::id()))
+    }
+
+    #[test]
+    fn load_keeps_defaults_for_the_rest() {
+        let path = temp_path("partial");
+        std::fs::write(
+            &path,
+            "(mouse_sensitivity: 2.0, actions: { Jump: [Key(J)] })",
+        )
+        .unwrap();
+        let loaded = Bindings::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        let defaults = Bindings::default();
+        assert_eq!(loaded.mouse_sensitivity, 2.0);
+        assert!(!loaded.invert_y);
+        assert_eq!(loaded.bindings(Action::Jump), &[Binding::Key(K::J)]);
+        assert_eq!(
+            loaded.bindings(Action::MoveForward),
+            defaults.bindings(Action::MoveForward)
+        );
+        assert_eq!(loaded.axes, defaults.axes);
+    }
+
+    #[test]
+    fn load_or_default_only_forgives_missing_files() {
+        let path = temp_path("missing");
+        assert_eq!(
+            Bindings::load_or_default(&path).unwrap(),
+            Bindings::default()
+        );
+        std::fs::write(&path, "(actions: { Jump: [Key(").unwrap();
+        let corrupt = Bindings::load_or_default(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(corrupt.is_err());
+    }
+
+    #[test]
+    fn save_then_load() {
+        let path = temp_path("round-trip");
+        let mut bindings = Bindings::default();
+        bindings.rebind(Action::Photograph, Binding::Key(K::P));
+        bindings.set_mouse_sensitivity(0.25);
+        bindings.invert_y = true;
+        bindings.save(&path).unwrap();
+        let loaded = Bindings::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), bindings);
+    }
+
+    #[test]
+    fn bind_unbind_rebind() {
+        let mut bindings = Bindings::default();
+        let before = bindings.bindings(Action::Jump).len();
+        bindings.bind(Action::Jump, Binding::Key(K::J));
+        // binding the same thing twice doesn't add it twice
+        bindings.bind(Action::Jump, Binding::Key(K::J));
+        assert_eq!(bindings.bindings(Action::Jump).len(), before + 1);
+        assert_eq!(
+            bindings.bindings(Action::Jump).last(),
+            Some(&Binding::Key(K::J))
+        );
+        bindings.unbind(Action::Jump, Binding::Key(K::Space));
+        assert!(!bindings
+            .bindings(Action::Jump)
+            .contains(&Binding::Key(K::Space)));
+        assert!(bindings
+            .bindings(Action::Jump)
+            .contains(&Binding::Key(K::J)));
+        bindings.rebind(Action::Jump, Binding::Mouse(MouseButton::Right));
+        assert_eq!(
+            bindings.bindings(Action::Jump),
+            &[Binding::Mouse(MouseButton::Right)]
+        );
+        // unbinding everything leaves the action with nothing to trigger it
+        bindings.unbind(Action::Jump, Binding::Mouse(MouseButton::Right));
+        let mut input = Input::new();
+        input.set_mouse_button(MouseButton::Right, true);
+        assert!(!bindings.is_down(&input, Action::Jump));
+    }
+
+    #[test]
+    fn several_bindings_for_one_action() {
+        let mut bindings = Bindings::default();
+        bindings.rebind(Action::Jump, Binding::Key(K::Space));
+        bindings.bind(Action::Jump, Binding::Key(K::J));
+        let mut input = Input::new();
+        input.set_key(K::Space, true);
+        assert!(bindings.is_pressed(&input, Action::Jump));
+        input.next_frame();
+        // pressing the other one while the first is held isn't a new press
+        input.set_key(K::J, true);
+        assert!(!bindings.is_pressed(&input, Action::Jump));
+        input.next_frame();
+        // and letting go of one isn't a release while the other's held
+        input.set_key(K::Space, false);
+        assert!(!bindings.is_released(&input, Action::Jump));
+        assert!(bindings.is_down(&input, Action::Jump));
+        input.next_frame();
+        input.set_key(K::J, false);
+        assert!(bindings.is_released(&input, Action::Jump));
+        assert!(!bindings.is_down(&input, Action::Jump));
+    }
+
+    #[test]
+    fn axis_sums_its_bindings() {
+        let mut bindings = Bindings::default();
+        bindings.bind_axis(
+            Axis::LookX,
+            vec![
+                AxisBinding::MouseX,
+                AxisBinding::Keys {
+                    negative: K::Left,
+                    positive: K::Right,
+                },
+            ],
+        );
+        let mut input = Input::new();
+        input.handle_cursor_motion((4.0, 2.0));
+        input.set_key(K::Right, true);
+        let x = bindings.axis(&input, Axis::LookX);
+        assert_eq!(x, 4.0 * DEFAULT_MOUSE_SENSITIVITY + 1.0);
+        // both keys cancel out
+        input.set_key(K::Left, true);
+        assert_eq!(
+            bindings.axis(&input, Axis::LookX),
+            4.0 * DEFAULT_MOUSE_SENSITIVITY
+        );
+        assert_eq!(
+            bindings.axis(&input, Axis::LookY),
+            2.0 * DEFAULT_MOUSE_SENSITIVITY
+        );
+        bindings.invert_y = true;
+        assert_eq!(
+            bindings.axis(&input, Axis::LookY),
+            -2.0 * DEFAULT_MOUSE_SENSITIVITY
+        );
+    }
+
+    fn poll(input: &mut Input, pads: &mut SyntheticPads) {
+        input.next_frame();
+        input.handle_pads(pads.poll());
+    }
+
+    #[test]
+    fn stick_dead_zone() {
+        let bindings = Bindings::default();
+        let mut input = Input::new();
+        let mut pads = SyntheticPads::new();
+        pads.pad_mut(0).set_axis(PadAxis::LeftStickY, 0.1);
+        poll(&mut input, &mut pads);
+        assert_eq!(bindings.value(&input, Action::MoveForward), 0.0);
+        assert!(!bindings.is_down(&input, Action::MoveForward));
+        // past the dead zone but not far enough to count as a press
+        pads.pad_mut(0).set_axis(PadAxis::LeftStickY, 0.4);
+        poll(&mut input, &mut pads);
+        let v = bindings.value(&input, Action::MoveForward);
+        assert!((v - (0.4 - STICK_DEAD_ZONE) / (1.0 - STICK_DEAD_ZONE)).abs() < 1e-5);
+        assert!(!bindings.is_down(&input, Action::MoveForward));
+        assert_eq!(bindings.value(&input, Action::MoveBackward), 0.0);
+        pads.pad_mut(0).set_axis(PadAxis::LeftStickY, -1.0);
+        poll(&mut input, &mut pads);
+        assert_eq!(bindings.value(&input, Action::MoveBackward), 1.0);
+        assert!(bindings.is_pressed(&input, Action::MoveBackward));
+        assert_eq!(bindings.value(&input, Action::MoveForward), 0.0);
+    }
+
+    #[test]
+    fn keys_count_all_the_way() {
+        let bindings = Bindings::default();
+        let mut input = Input::new();
+        let mut pads = SyntheticPads::new();
+        pads.pad_mut(0).set_axis(PadAxis::LeftStickX, 0.5);
+        poll(&mut input, &mut pads);
+        assert!(bindings.value(&input, Action::MoveRight) < 1.0);
+        input.set_key(VirtualKeyCode::D, true);
+        assert_eq!(bindings.value(&input, Action::MoveRight), 1.0);
+    }
+
+    #[test]
+    fn unplugging_releases_everything() {
+        let bindings = Bindings::default();
+        let mut input = Input::new();
+        let mut pads = SyntheticPads::new();
+        pads.pad_mut(1).set_button(PadButton::South, true);
+        pads.pad_mut(1).set_axis(PadAxis::LeftStickY, 1.0);
+        poll(&mut input, &mut pads);
+        assert_eq!(input.pads_connected().collect::<Vec<_>>(), vec![1]);
+        assert!(bindings.is_pressed(&input, Action::Jump));
+        assert!(bindings.is_down(&input, Action::MoveForward));
+        pads.disconnect(1);
+        poll(&mut input, &mut pads);
+        assert_eq!(input.pads_disconnected().collect::<Vec<_>>(), vec![1]);
+        assert!(!input.is_pad_connected(1));
+        assert!(!bindings.is_down(&input, Action::Jump));
+        assert!(bindings.is_released(&input, Action::Jump));
+        assert!(bindings.is_released(&input, Action::MoveForward));
+        assert_eq!(bindings.value(&input, Action::MoveForward), 0.0);
+    }
+
+    #[test]
+    fn right_stick_looks() {
+        let mut bindings = Bindings::default();
+        let mut input = Input::new();
+        let mut pads = SyntheticPads::new();
+        pads.pad_mut(0).set_axis(PadAxis::RightStickX, 1.0);
+        poll(&mut input, &mut pads);
+        assert_eq!(bindings.axis(&input, Axis::LookX), PAD_LOOK_SPEED);
+        assert_eq!(bindings.axis(&input, Axis::LookY), 0.0);
+        // stick up looks up
+        pads.pad_mut(0).set_axis(PadAxis::RightStickX, 0.0);
+        pads.pad_mut(0).set_axis(PadAxis::RightStickY, 1.0);
+        poll(&mut input, &mut pads);
+        assert_eq!(bindings.axis(&input, Axis::LookX), 0.0);
+        assert_eq!(bindings.axis(&input, Axis::LookY), -PAD_LOOK_SPEED);
+        bindings.invert_y = true;
+        assert_eq!(bindings.axis(&input, Axis::LookY), PAD_LOOK_SPEED);
+        // and a stick resting in its dead zone doesn't drift
+        pads.pad_mut(0)
+            .set_axis(PadAxis::RightStickY, STICK_DEAD_ZONE * 0.5);
+        poll(&mut input, &mut pads);
+        assert_eq!(bindings.axis(&input, Axis::LookY), 0.0);
+    }
+}
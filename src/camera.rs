@@ -1,48 +1,166 @@
+use crate::collision::Ray;
 use crate::types::*;
+
+pub mod controller;
+
+pub const DEFAULT_FOV: f32 = PI / 2.0;
+pub const DEFAULT_NEAR: f32 = 0.1;
+
 #[derive(Clone, Copy, Debug)]
 pub struct Camera {
+    // world to view
     pub transform: Similarity3,
+    // vertical, in radians
     pub fov: f32,
     pub ratio: f32,
+    // distance to the near clipping plane; there's no far plane
+    pub near: f32,
 }
 impl Camera {
     pub fn look_at(eye: Vec3, at: Vec3, up: Vec3) -> Camera {
         let iso = Mat4::look_at(eye, at, up).into_isometry();
         Self::from_transform(Similarity3::new(iso.translation, iso.rotation, 1.0))
     }
-    pub fn look_at_degrees(eye: Vec3, up: Vec3, deg: (f32,f32)) -> Camera {
-        // for changes in the x axis rotation, we change y and z values
-        // for changes in the y axis rotation, we change x and z values
-        let theta = deg.0;
-        let rho = deg.1 + 90.0;
-        let x = eye.x + rho.to_radians().sin() * theta.to_radians().sin();
-        let y = eye.y + rho.to_radians().cos();
-        let z = eye.z + rho.to_radians().sin() * theta.to_radians().cos();
-        let at = Vec3 { x, y, z };
-        Self::look_at(eye, at, up)
+    /// A camera at `eye` turned by `rotation`, which takes the camera's own
+    /// axes (looking down -z with +y up) to the world's.
+    pub fn from_rotation(eye: Vec3, rotation: Rotor3) -> Camera {
+        let view = rotation.reversed();
+        Self::from_transform(Similarity3::new(-(view * eye), view, 1.0))
     }
     pub fn from_transform(s: Similarity3) -> Self {
         Self {
             transform: s,
-            fov: PI / 2.0,
+            fov: DEFAULT_FOV,
             ratio: 4.0 / 3.0,
+            near: DEFAULT_NEAR,
         }
     }
     pub fn set_ratio(&mut self, r: f32) {
         self.ratio = r;
     }
+    pub fn set_fov(&mut self, fov: f32) {
+        self.fov = fov;
+    }
+    pub fn set_near(&mut self, near: f32) {
+        self.near = near;
+    }
     pub fn as_matrix(&self) -> Mat4 {
         // projection * view
         let proj = ultraviolet::projection::rh_yup::perspective_reversed_infinite_z_vk(
-            self.fov, self.ratio, 0.1,
+            self.fov, self.ratio, self.near,
         );
         proj * self.transform.into_homogeneous_matrix()
     }
+    /// Where the camera is in the world.
+    pub fn eye(&self) -> Vec3 {
+        self.transform.inversed().translation
+    }
+    /// A ray from the eye through `screen`, which runs from (0,0) at the
+    /// top left of the view to (1,1) at the bottom right.
+    pub fn ray_through(&self, screen: Vec2) -> Ray {
+        let inv = self.transform.inversed();
+        let half_h = (self.fov / 2.0).tan();
+        let half_w = half_h * self.ratio;
+        let dir = Vec3::new(
+            (screen.x * 2.0 - 1.0) * half_w,
+            (1.0 - screen.y * 2.0) * half_h,
+            -1.0,
+        );
+        Ray::new(inv.translation, inv.rotation * dir)
+    }
+    /// The point `dist` units from the eye along the ray through `screen`.
+    pub fn screen_to_world(&self, screen: Vec2, dist: f32) -> Vec3 {
+        let ray = self.ray_through(screen);
+        ray.origin + ray.dir * dist
+    }
+    /// Where `p` shows up on screen, with the same coordinates as
+    /// `ray_through`, or `None` if it's behind the camera.  Points off to the
+    /// side land outside 0..1.
+    pub fn world_to_screen(&self, p: Vec3) -> Option<Vec2> {
+        let clip = self.as_matrix() * Vec4::new(p.x, p.y, p.z, 1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        // vulkan's clip space has y pointing down already
+        Some(Vec2::new(
+            (clip.x / clip.w + 1.0) / 2.0,
+            (clip.y / clip.w + 1.0) / 2.0,
+        ))
+    }
+    /// A ray from the eye straight through the middle of the view.
+    pub fn center_ray(&self) -> Ray {
+        self.ray_through(Vec2::new(0.5, 0.5))
+    }
+    /// Whether `p` lies in front of the camera and inside the view.
+    pub fn can_see(&self, p: Vec3) -> bool {
+        let clip = self.as_matrix() * Vec4::new(p.x, p.y, p.z, 1.0);
+        clip.w > 0.0 && (clip.x / clip.w).abs() <= 1.0 && (clip.y / clip.w).abs() <= 1.0
+    }
     pub fn interpolate(&self, other: &Self, r: f32) -> Self {
         Self {
             transform: self.transform.lerp(&other.transform, r),
             fov: self.fov.lerp(other.fov, r),
             ratio: self.ratio.lerp(other.ratio, r),
+            near: self.near.lerp(other.near, r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera::look_at(
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(4.0, 2.0, 8.0),
+            Vec3::unit_y(),
+        )
+    }
+
+    #[test]
+    fn screen_to_world_and_back() {
+        let cam = camera();
+        for screen in [
+            Vec2::new(0.5, 0.5),
+            Vec2::new(0.2, 0.7),
+            Vec2::new(0.9, 0.1),
+            Vec2::new(0.05, 0.95),
+        ] {
+            let p = cam.screen_to_world(screen, 5.0);
+            assert!(((p - cam.eye()).mag() - 5.0).abs() < 1e-4);
+            let back = cam.world_to_screen(p).unwrap();
+            assert!(
+                (back - screen).mag() < 1e-4,
+                "{:?} came back as {:?}",
+                screen,
+                back
+            );
+            assert!(cam.can_see(p));
         }
     }
+
+    #[test]
+    fn center_ray_looks_ahead() {
+        let cam = camera();
+        let ray = cam.center_ray();
+        let ahead = (Vec3::new(4.0, 2.0, 8.0) - Vec3::new(1.0, 2.0, 3.0)).normalized();
+        assert!((ray.origin - Vec3::new(1.0, 2.0, 3.0)).mag() < 1e-4);
+        assert!((ray.dir - ahead).mag() < 1e-4, "{:?}", ray.dir);
+        // the top of the screen is up
+        let up = cam.ray_through(Vec2::new(0.5, 0.0));
+        assert!(up.dir.y > 0.0);
+    }
+
+    #[test]
+    fn cant_see_behind_or_beside() {
+        let cam = camera();
+        let behind = Vec3::new(1.0, 2.0, 3.0) - Vec3::new(3.0, 0.0, 5.0);
+        assert!(!cam.can_see(behind));
+        assert_eq!(cam.world_to_screen(behind), None);
+        // far enough off to the side to be past the edge of the view
+        let beside = cam.screen_to_world(Vec2::new(1.5, 0.5), 5.0);
+        assert!(!cam.can_see(beside));
+        assert!(cam.world_to_screen(beside).unwrap().x > 1.0);
+    }
 }
@@ -0,0 +1,235 @@
+//! Turning look and move input into a `Camera`.
+//!
+//! A `Controller` keeps yaw and pitch in degrees, the same way `Player` and
+//! `SaveData` always have: yaw 0 faces +z and turning left increases it, and
+//! positive pitch looks down.  The camera's orientation is built from them as
+//! a `Rotor3`, so there's no roll and no look-at target to work out.
+use super::{Camera, DEFAULT_FOV, DEFAULT_NEAR};
+use crate::types::*;
+
+// looking any closer to straight up or down flips the view over
+pub const MAX_PITCH: f32 = 89.0;
+// units per second, for `FreeFly`
+pub const DEFAULT_FLY_SPEED: f32 = 10.0;
+// how close and far `Orbit` can zoom
+pub const MIN_ORBIT_DISTANCE: f32 = 0.5;
+pub const MAX_ORBIT_DISTANCE: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Looking out from `eye`, which whoever owns the controller moves along
+    /// with the thing whose eyes these are.
+    FirstPerson,
+    /// Circling `target` from `distance` away, always looking at it.
+    Orbit { target: Vec3, distance: f32 },
+    /// Flying around freely with `fly`.
+    FreeFly,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Controller {
+    pub mode: Mode,
+    // where the camera is, except in `Orbit` mode where it's worked out
+    pub eye: Vec3,
+    // where look input has turned us to, in degrees
+    yaw: f32,
+    pitch: f32,
+    // where the camera actually points, which trails yaw and pitch when smoothing
+    shown_yaw: f32,
+    shown_pitch: f32,
+    // seconds for the view to turn most (about 63%) of the way to where it's
+    // been told to look; 0 turns instantly
+    pub smoothing: f32,
+    pub fov: f32,
+    pub near: f32,
+    pub fly_speed: f32,
+}
+
+impl Controller {
+    pub fn new(mode: Mode, eye: Vec3, deg: (f32, f32)) -> Self {
+        let pitch = deg.1.clamp(-MAX_PITCH, MAX_PITCH);
+        Self {
+            mode,
+            eye,
+            yaw: deg.0,
+            pitch,
+            shown_yaw: deg.0,
+            shown_pitch: pitch,
+            smoothing: 0.0,
+            fov: DEFAULT_FOV,
+            near: DEFAULT_NEAR,
+            fly_speed: DEFAULT_FLY_SPEED,
+        }
+    }
+    pub fn first_person(eye: Vec3, deg: (f32, f32)) -> Self {
+        Self::new(Mode::FirstPerson, eye, deg)
+    }
+    pub fn orbit(target: Vec3, distance: f32, deg: (f32, f32)) -> Self {
+        let distance = distance.clamp(MIN_ORBIT_DISTANCE, MAX_ORBIT_DISTANCE);
+        Self::new(Mode::Orbit { target, distance }, target, deg)
+    }
+    pub fn free_fly(eye: Vec3, deg: (f32, f32)) -> Self {
+        Self::new(Mode::FreeFly, eye, deg)
+    }
+
+    /// Yaw and pitch in degrees, as last set or looked towards.
+    pub fn degrees(&self) -> (f32, f32) {
+        (self.yaw, self.pitch)
+    }
+    /// Point straight at `deg`, skipping any smoothing.
+    pub fn set_degrees(&mut self, deg: (f32, f32)) {
+        self.yaw = deg.0;
+        self.pitch = deg.1.clamp(-MAX_PITCH, MAX_PITCH);
+        self.shown_yaw = self.yaw;
+        self.shown_pitch = self.pitch;
+    }
+    /// Turn by `yaw` degrees to the left and `pitch` degrees down.
+    pub fn look(&mut self, yaw: f32, pitch: f32) {
+        self.yaw += yaw;
+        self.pitch = (self.pitch + pitch).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+    /// Let the view catch up with look input after `dt` seconds.
+    pub fn update(&mut self, dt: f32) {
+        if self.smoothing <= 0.0 {
+            self.shown_yaw = self.yaw;
+            self.shown_pitch = self.pitch;
+            return;
+        }
+        let k = 1.0 - (-dt / self.smoothing).exp();
+        self.shown_yaw += (self.yaw - self.shown_yaw) * k;
+        self.shown_pitch += (self.pitch - self.shown_pitch) * k;
+    }
+    /// Move by `dir` (x right, y up, z forward, relative to where we're
+    /// looking) at `fly_speed` for `dt` seconds.
+    pub fn fly(&mut self, dir: Vec3, dt: f32) {
+        let rot = self.rotation();
+        // the camera looks down its own -z, and its right is +x
+        let world = rot * Vec3::new(dir.x, dir.y, -dir.z);
+        self.eye += world * self.fly_speed * dt;
+    }
+    /// Move closer to (negative) or further from the orbit target.
+    pub fn zoom(&mut self, by: f32) {
+        if let Mode::Orbit { distance, .. } = &mut self.mode {
+            *distance = (*distance + by).clamp(MIN_ORBIT_DISTANCE, MAX_ORBIT_DISTANCE);
+        }
+    }
+
+    /// Camera-to-world rotation for the view as it's shown.
+    pub fn rotation(&self) -> Rotor3 {
+        rotation_from_degrees((self.shown_yaw, self.shown_pitch))
+    }
+    /// The direction the view is facing.
+    pub fn forward(&self) -> Vec3 {
+        self.rotation() * -Vec3::unit_z()
+    }
+    /// Where the view is from.
+    pub fn eye_position(&self) -> Vec3 {
+        match self.mode {
+            Mode::Orbit { target, distance } => target - self.forward() * distance,
+            Mode::FirstPerson | Mode::FreeFly => self.eye,
+        }
+    }
+    pub fn camera(&self) -> Camera {
+        let mut cam = Camera::from_rotation(self.eye_position(), self.rotation());
+        cam.set_fov(self.fov);
+        cam.set_near(self.near);
+        cam
+    }
+}
+
+/// Camera-to-world rotation for a view turned `deg` (yaw, pitch) degrees.
+pub fn rotation_from_degrees(deg: (f32, f32)) -> Rotor3 {
+    Rotor3::from_rotation_xz(-deg.0.to_radians())
+        * Rotor3::from_rotation_yz(deg.1.to_radians())
+        // the camera looks down -z, but yaw 0 faces +z
+        * Rotor3::from_rotation_xz(PI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).mag() < 1e-4
+    }
+
+    #[test]
+    fn yaw_zero_faces_z() {
+        let c = Controller::first_person(Vec3::zero(), (0.0, 0.0));
+        assert!(close(c.forward(), Vec3::unit_z()), "{:?}", c.forward());
+        // turning left from +z faces +x
+        let c = Controller::first_person(Vec3::zero(), (90.0, 0.0));
+        assert!(close(c.forward(), Vec3::unit_x()), "{:?}", c.forward());
+        let c = Controller::first_person(Vec3::zero(), (0.0, 200.0));
+        assert_eq!(c.degrees().1, MAX_PITCH);
+        assert!(c.forward().y < 0.0);
+    }
+
+    #[test]
+    fn orbit_keeps_its_distance() {
+        let target = Vec3::new(1.0, 2.0, 3.0);
+        let mut c = Controller::orbit(target, 1000.0, (30.0, 20.0));
+        assert_eq!(
+            c.mode,
+            Mode::Orbit {
+                target,
+                distance: MAX_ORBIT_DISTANCE
+            }
+        );
+        c.zoom(-1000.0);
+        assert_eq!(
+            c.mode,
+            Mode::Orbit {
+                target,
+                distance: MIN_ORBIT_DISTANCE
+            }
+        );
+        c.zoom(4.5);
+        let eye = c.eye_position();
+        assert!(((eye - target).mag() - 5.0).abs() < 1e-4);
+        // and looks straight at the target
+        assert!(close((target - eye).normalized(), c.forward()));
+        assert!(close(c.camera().eye(), eye));
+    }
+
+    #[test]
+    fn free_fly_moves_where_it_looks() {
+        let mut c = Controller::free_fly(Vec3::zero(), (0.0, 0.0));
+        c.fly(Vec3::new(0.0, 0.0, 1.0), 0.5);
+        assert!(close(c.eye, Vec3::new(0.0, 0.0, 0.5 * DEFAULT_FLY_SPEED)));
+        c.eye = Vec3::zero();
+        // facing +z, right is -x
+        c.fly(Vec3::new(1.0, 1.0, 0.0), 0.1);
+        assert!(close(c.eye, Vec3::new(-1.0, 1.0, 0.0)), "{:?}", c.eye);
+        c.eye = Vec3::zero();
+        c.look(90.0, 0.0);
+        c.update(0.1);
+        c.fly(Vec3::new(0.0, 0.0, 1.0), 0.1);
+        assert!(close(c.eye, Vec3::unit_x()), "{:?}", c.eye);
+    }
+
+    #[test]
+    fn smoothing_catches_up() {
+        let mut c = Controller::first_person(Vec3::zero(), (0.0, 0.0));
+        c.smoothing = 0.1;
+        c.look(90.0, 30.0);
+        // where it's told to look changes straight away...
+        assert_eq!(c.degrees(), (90.0, 30.0));
+        // ...but the view only gets most of the way there in `smoothing`
+        c.update(0.1);
+        let most = 1.0 - (-1.0f32).exp();
+        assert!((c.shown_yaw - 90.0 * most).abs() < 1e-3, "{}", c.shown_yaw);
+        assert!(
+            (c.shown_pitch - 30.0 * most).abs() < 1e-3,
+            "{}",
+            c.shown_pitch
+        );
+        for _ in 0..100 {
+            c.update(1.0 / 60.0);
+        }
+        assert!((c.shown_yaw - 90.0).abs() < 1e-3 && (c.shown_pitch - 30.0).abs() < 1e-3);
+        // and set_degrees skips the smoothing
+        c.set_degrees((10.0, 0.0));
+        assert_eq!((c.shown_yaw, c.shown_pitch), (10.0, 0.0));
+    }
+}
@@ -0,0 +1,519 @@
+use crate::assets::Assets;
+use crate::renderer::flat;
+use crate::types::*;
+
+// how many times we'll push a capsule out of geometry per movement step
+const MAX_RESOLVE_ITERS: usize = 4;
+// extra distance to leave between a resolved capsule and the surface it hit
+const SKIN: f32 = 0.001;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Triangle {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub c: Vec3,
+}
+impl Triangle {
+    pub fn normal(&self) -> Vec3 {
+        (self.b - self.a).cross(self.c - self.a).normalized()
+    }
+    fn is_degenerate(&self) -> bool {
+        (self.b - self.a).cross(self.c - self.a).mag_sq() < 1e-12
+    }
+    /// The point on the triangle closest to `p`
+    /// (Ericson, Real-Time Collision Detection, 5.1.5).
+    pub fn closest_point(&self, p: Vec3) -> Vec3 {
+        let (a, b, c) = (self.a, self.b, self.c);
+        let ab = b - a;
+        let ac = c - a;
+        let ap = p - a;
+        let d1 = ab.dot(ap);
+        let d2 = ac.dot(ap);
+        if d1 <= 0.0 && d2 <= 0.0 {
+            return a;
+        }
+        let bp = p - b;
+        let d3 = ab.dot(bp);
+        let d4 = ac.dot(bp);
+        if d3 >= 0.0 && d4 <= d3 {
+            return b;
+        }
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+            return a + ab * (d1 / (d1 - d3));
+        }
+        let cp = p - c;
+        let d5 = ab.dot(cp);
+        let d6 = ac.dot(cp);
+        if d6 >= 0.0 && d5 <= d6 {
+            return c;
+        }
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+            return a + ac * (d2 / (d2 - d6));
+        }
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+            return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+        }
+        let denom = 1.0 / (va + vb + vc);
+        a + ab * (vb * denom) + ac * (vc * denom)
+    }
+    pub fn bounds(&self) -> RPrism {
+        RPrism::from_points([self.a, self.b, self.c].into_iter()).unwrap()
+    }
+    /// Distance along `ray` to where it crosses this triangle, from either side
+    /// (Moller-Trumbore).
+    pub fn raycast(&self, ray: &Ray) -> Option<f32> {
+        let e1 = self.b - self.a;
+        let e2 = self.c - self.a;
+        let p = ray.dir.cross(e2);
+        let det = e1.dot(p);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = ray.origin - self.a;
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(e1);
+        let v = ray.dir.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = e2.dot(q) * inv_det;
+        if t >= 0.0 {
+            Some(t)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: Vec3,
+    // always unit length, so hit distances are in world units
+    pub dir: Vec3,
+}
+impl Ray {
+    pub fn new(origin: Vec3, dir: Vec3) -> Self {
+        Self {
+            origin,
+            dir: dir.normalized(),
+        }
+    }
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.dir * t
+    }
+    /// Distance along the ray to where it enters `rp` (0 if it starts inside),
+    /// and the normal of the face it entered through.
+    pub fn cast_prism(&self, rp: RPrism) -> Option<(f32, Vec3)> {
+        let br = rp.max();
+        let mut t_enter = 0.0_f32;
+        let mut t_exit = f32::INFINITY;
+        let mut normal = Vec3::zero();
+        for axis in 0..3 {
+            let (o, d) = (self.origin[axis], self.dir[axis]);
+            if d.abs() < 1e-8 {
+                if o < rp.pos[axis] || o > br[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let (near, far, n) = if d > 0.0 {
+                ((rp.pos[axis] - o) / d, (br[axis] - o) / d, -1.0)
+            } else {
+                ((br[axis] - o) / d, (rp.pos[axis] - o) / d, 1.0)
+            };
+            if near > t_enter {
+                t_enter = near;
+                normal = Vec3::zero();
+                normal[axis] = n;
+            }
+            t_exit = t_exit.min(far);
+            if t_enter > t_exit {
+                return None;
+            }
+        }
+        Some((t_enter, normal))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    pub dist: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+/// Static triangle soup, e.g. the walls and furniture of a room.
+pub struct TriMesh {
+    tris: Vec<Triangle>,
+    bounds: RPrism,
+}
+impl TriMesh {
+    pub fn new(tris: Vec<Triangle>) -> Self {
+        let bounds = RPrism::from_points(tris.iter().flat_map(|t| [t.a, t.b, t.c]))
+            .unwrap_or(RPrism {
+                pos: Vec3::zero(),
+                sz: Vec3::zero(),
+            });
+        Self { tris, bounds }
+    }
+    /// Collect the (triangulated) faces of a flat mesh, placed in the world by `trf`.
+    pub fn from_flat_mesh(mesh: &flat::Mesh, trf: Similarity3) -> Self {
+        Self::new(Self::flat_mesh_tris(mesh, trf).collect())
+    }
+    /// Collect the faces of every mesh of a flat model into one collider.
+    pub fn from_flat_model(model: &flat::Model, assets: &Assets, trf: Similarity3) -> Self {
+        Self::new(
+            model
+                .meshes()
+                .iter()
+                .flat_map(|m| Self::flat_mesh_tris(assets.flat_mesh(*m), trf))
+                .collect(),
+        )
+    }
+    fn flat_mesh_tris(mesh: &flat::Mesh, trf: Similarity3) -> impl Iterator<Item = Triangle> + '_ {
+        let verts = &mesh.mesh.vertices;
+        let pt = move |i: u32| {
+            let v = &verts[i as usize];
+            trf.transform_vec(Vec3::new(v.x, v.y, v.z))
+        };
+        mesh.mesh
+            .faces
+            .iter()
+            .filter(|f| f.0.len() == 3)
+            .map(move |f| Triangle {
+                a: pt(f.0[0]),
+                b: pt(f.0[1]),
+                c: pt(f.0[2]),
+            })
+            .filter(|t| !t.is_degenerate())
+    }
+    pub fn bounds(&self) -> RPrism {
+        self.bounds
+    }
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.tris
+    }
+}
+
+/// An upright capsule: `base` is the lowest point (the feet), and the
+/// capsule extends `height` units up from there.
+#[derive(Clone, Copy, Debug)]
+pub struct Capsule {
+    pub base: Vec3,
+    pub height: f32,
+    pub radius: f32,
+}
+impl Capsule {
+    // endpoints of the capsule's core segment
+    fn segment(&self) -> (Vec3, Vec3) {
+        let up = Vec3::unit_y();
+        (
+            self.base + up * self.radius,
+            self.base + up * (self.height - self.radius).max(self.radius),
+        )
+    }
+    fn closest_on_segment(&self, p: Vec3) -> Vec3 {
+        let (a, b) = self.segment();
+        let ab = b - a;
+        let len_sq = ab.mag_sq();
+        if len_sq < 1e-12 {
+            return a;
+        }
+        a + ab * ((p - a).dot(ab) / len_sq).clamp(0.0, 1.0)
+    }
+    pub fn bounds(&self) -> RPrism {
+        RPrism {
+            pos: self.base - Vec3::new(self.radius, 0.0, self.radius),
+            sz: Vec3::new(self.radius * 2.0, self.height, self.radius * 2.0),
+        }
+    }
+    pub fn moved_to(&self, base: Vec3) -> Self {
+        Self { base, ..*self }
+    }
+    /// How far (and which way) the capsule needs to move to stop intersecting `tri`.
+    pub fn contact_triangle(&self, tri: &Triangle) -> Option<Contact> {
+        let (a, b) = self.segment();
+        // find the point on the triangle nearest the segment: start from where the
+        // segment's line crosses the triangle's plane (or the segment's start if it
+        // runs parallel), clamped onto the triangle.
+        let n = tri.normal();
+        let dir = b - a;
+        let denom = n.dot(dir);
+        let reference = if denom.abs() > 1e-6 {
+            let t = (n.dot(tri.a - a) / denom).clamp(0.0, 1.0);
+            tri.closest_point(a + dir * t)
+        } else {
+            tri.closest_point(a)
+        };
+        let center = self.closest_on_segment(reference);
+        Self::sphere_contact(center, self.radius, tri.closest_point(center), n)
+    }
+    /// How far (and which way) the capsule needs to move to stop intersecting `other`.
+    pub fn contact_prism(&self, other: RPrism) -> Option<Contact> {
+        let clamp = |p: Vec3| p.clamped(other.pos, other.max());
+        let reference = clamp(self.closest_on_segment(other.center()));
+        let center = self.closest_on_segment(reference);
+        let closest = clamp(center);
+        if closest != center {
+            return Self::sphere_contact(center, self.radius, closest, Vec3::unit_y());
+        }
+        // the core segment is inside the box: leave through the nearest face
+        let lo = center - other.pos;
+        let hi = other.max() - center;
+        let mut best = (f32::INFINITY, Vec3::zero());
+        for axis in 0..3 {
+            let mut n = Vec3::zero();
+            if lo[axis] < best.0 {
+                n[axis] = -1.0;
+                best = (lo[axis], n);
+            }
+            if hi[axis] < best.0 {
+                n[axis] = 1.0;
+                best = (hi[axis], n);
+            }
+        }
+        Some(Contact {
+            normal: best.1,
+            depth: best.0 + self.radius,
+        })
+    }
+    fn sphere_contact(center: Vec3, radius: f32, closest: Vec3, fallback: Vec3) -> Option<Contact> {
+        let d = center - closest;
+        let dist_sq = d.mag_sq();
+        if dist_sq >= radius * radius {
+            return None;
+        }
+        let dist = dist_sq.sqrt();
+        let normal = if dist > 1e-6 { d / dist } else { fallback };
+        Some(Contact {
+            normal,
+            depth: radius - dist,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+    // direction to push the moving shape out along
+    pub normal: Vec3,
+    pub depth: f32,
+}
+
+/// All the static geometry things can bump into.
+#[derive(Default)]
+pub struct Colliders {
+    boxes: Vec<RPrism>,
+    meshes: Vec<TriMesh>,
+}
+impl Colliders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_box(&mut self, b: RPrism) {
+        self.boxes.push(b);
+    }
+    pub fn add_mesh(&mut self, m: TriMesh) {
+        self.meshes.push(m);
+    }
+    pub fn boxes(&self) -> &[RPrism] {
+        &self.boxes
+    }
+    pub fn meshes(&self) -> &[TriMesh] {
+        &self.meshes
+    }
+    /// Every contact between `cap` and the world.
+    pub fn contacts(&self, cap: &Capsule) -> Vec<Contact> {
+        let bounds = cap.bounds();
+        let mut out: Vec<Contact> = self
+            .boxes
+            .iter()
+            .filter(|b| b.overlaps(bounds))
+            .filter_map(|b| cap.contact_prism(*b))
+            .collect();
+        for m in self.meshes.iter().filter(|m| m.bounds.overlaps(bounds)) {
+            out.extend(
+                m.tris
+                    .iter()
+                    .filter(|t| t.bounds().overlaps(bounds))
+                    .filter_map(|t| cap.contact_triangle(t)),
+            );
+        }
+        out
+    }
+    /// The closest thing `ray` hits within `max_dist`.
+    pub fn raycast(&self, ray: &Ray, max_dist: f32) -> Option<RayHit> {
+        let mut best: Option<RayHit> = None;
+        let mut consider = |dist: f32, normal: Vec3| {
+            if dist <= max_dist && best.map(|b| dist < b.dist).unwrap_or(true) {
+                best = Some(RayHit {
+                    dist,
+                    point: ray.at(dist),
+                    normal,
+                });
+            }
+        };
+        for b in self.boxes.iter() {
+            if let Some((dist, normal)) = ray.cast_prism(*b) {
+                consider(dist, normal);
+            }
+        }
+        for m in self.meshes.iter() {
+            if ray.cast_prism(m.bounds).is_none() {
+                continue;
+            }
+            for t in m.tris.iter() {
+                if let Some(dist) = t.raycast(ray) {
+                    // face the normal back towards the ray
+                    let n = t.normal();
+                    consider(dist, if n.dot(ray.dir) > 0.0 { -n } else { n });
+                }
+            }
+        }
+        best
+    }
+    /// The highest surface under the base of `cap`, no more than `reach` below it,
+    /// whose normal is at least `min_normal_y` upwards.  Rays are cast down from
+    /// the middle and the rim of the capsule's base, so we still find a ledge
+    /// when only the edge of the capsule is over it.
+    pub fn ground(&self, cap: &Capsule, reach: f32, min_normal_y: f32) -> Option<RayHit> {
+        // start the rays a little above the base so we find the floor we're standing on
+        let lift = 0.01;
+        let r = cap.radius * 0.9;
+        let d = r * std::f32::consts::FRAC_1_SQRT_2;
+        [
+            Vec3::zero(),
+            Vec3::new(r, 0.0, 0.0),
+            Vec3::new(-r, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, r),
+            Vec3::new(0.0, 0.0, -r),
+            Vec3::new(d, 0.0, d),
+            Vec3::new(-d, 0.0, d),
+            Vec3::new(d, 0.0, -d),
+            Vec3::new(-d, 0.0, -d),
+        ]
+        .into_iter()
+        .filter_map(|off| {
+            let ray = Ray::new(cap.base + off + Vec3::unit_y() * lift, -Vec3::unit_y());
+            self.raycast(&ray, reach + lift)
+        })
+        .filter(|hit| hit.normal.y >= min_normal_y)
+        .max_by(|h1, h2| {
+            h1.point
+                .y
+                .partial_cmp(&h2.point.y)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+    }
+    /// Push `cap` out of anything it's intersecting, returning the new base
+    /// position and the normals of the surfaces it was pushed away from.
+    pub fn resolve(&self, cap: Capsule) -> (Vec3, Vec<Vec3>) {
+        let mut cap = cap;
+        let mut normals = vec![];
+        for _ in 0..MAX_RESOLVE_ITERS {
+            let deepest = self
+                .contacts(&cap)
+                .into_iter()
+                .max_by(|c1, c2| {
+                    c1.depth
+                        .partial_cmp(&c2.depth)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+            match deepest {
+                Some(c) => {
+                    cap.base += c.normal * (c.depth + SKIN);
+                    normals.push(c.normal);
+                }
+                None => break,
+            }
+        }
+        (cap.base, normals)
+    }
+    /// Move `cap` by `delta`, sliding along any walls in the way rather than
+    /// stopping dead or passing through them.  Returns the new base position.
+    pub fn slide(&self, cap: Capsule, delta: Vec3) -> Vec3 {
+        // step in increments shorter than the radius so we can't tunnel through thin walls
+        let max_step = cap.radius * 0.5;
+        let steps = (delta.mag() / max_step).ceil().max(1.0) as usize;
+        let mut step = delta / steps as f32;
+        let mut cap = cap;
+        for _ in 0..steps {
+            let (base, normals) = self.resolve(cap.moved_to(cap.base + step));
+            cap.base = base;
+            // take out the part of the motion that goes into what we hit
+            for n in normals {
+                let into = step.dot(n);
+                if into < 0.0 {
+                    step -= n * into;
+                }
+            }
+        }
+        cap.base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capsule(x: f32, z: f32) -> Capsule {
+        Capsule {
+            base: Vec3::new(x, 0.0, z),
+            height: 2.0,
+            radius: 0.4,
+        }
+    }
+    // a wall along the z axis whose near face is at x = 1
+    fn wall() -> Colliders {
+        let mut c = Colliders::new();
+        c.add_box(RPrism {
+            pos: Vec3::new(1.0, -1.0, -10.0),
+            sz: Vec3::new(1.0, 5.0, 20.0),
+        });
+        c
+    }
+
+    #[test]
+    fn slide_stops_at_wall() {
+        let end = wall().slide(capsule(0.0, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        assert!(end.x <= 1.0 - 0.4 + 1e-3, "{:?}", end);
+        assert!(end.x > 1.0 - 0.4 - 0.01, "{:?}", end);
+        assert!(end.z.abs() < 1e-4, "{:?}", end);
+    }
+
+    #[test]
+    fn slide_keeps_motion_along_wall() {
+        let end = wall().slide(capsule(0.0, 0.0), Vec3::new(2.0, 0.0, 2.0));
+        assert!(end.x <= 1.0 - 0.4 + 1e-3, "{:?}", end);
+        // only the part going into the wall is lost
+        assert!(end.z > 1.9, "{:?}", end);
+    }
+
+    #[test]
+    fn slide_is_free_in_the_open() {
+        let end = wall().slide(capsule(0.0, 0.0), Vec3::new(-2.0, 0.0, 1.0));
+        assert!((end - Vec3::new(-2.0, 0.0, 1.0)).mag() < 1e-4, "{:?}", end);
+    }
+
+    #[test]
+    fn resolve_pushes_out_of_triangles() {
+        let mut c = Colliders::new();
+        c.add_mesh(TriMesh::new(vec![Triangle {
+            a: Vec3::new(-5.0, 0.0, -5.0),
+            b: Vec3::new(-5.0, 0.0, 5.0),
+            c: Vec3::new(5.0, 0.0, -5.0),
+        }]));
+        let mut cap = capsule(0.0, 0.0);
+        cap.base.y = -0.1;
+        let (base, normals) = c.resolve(cap);
+        assert!(base.y >= 0.0, "{:?}", base);
+        assert_eq!(normals.len(), 1);
+        assert!(normals[0].y > 0.99);
+    }
+}
@@ -0,0 +1,302 @@
+//! Scripted camera moves, like the intro pan of a level or a close-up of a
+//! clue once it's photographed.
+//!
+//! A `CameraTrack` keys the camera's position, rotation and field of view
+//! over time, just like an animation `Channel` keys a joint, and is loaded
+//! from RON files in `content/cutscenes`.  Every key says how to ease towards
+//! the next one.  While a `Cutscene` is playing it decides what the camera
+//! sees, and the player can't move.
+use crate::animation::sample_keys;
+use crate::camera::{controller, Camera};
+use crate::types::*;
+use color_eyre::eyre::{ensure, Result, WrapErr};
+use serde::Deserialize;
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Easing {
+    #[default]
+    Linear,
+    // start slow
+    In,
+    // end slow
+    Out,
+    // start and end slow
+    InOut,
+    // stay put until the next key, then jump
+    Hold,
+}
+impl Easing {
+    /// Reshape `r`, how far we are between two keys, from 0 to 1.
+    pub fn apply(self, r: f32) -> f32 {
+        match self {
+            Easing::Linear => r,
+            Easing::In => r * r,
+            Easing::Out => 1.0 - (1.0 - r) * (1.0 - r),
+            Easing::InOut => r * r * (3.0 - 2.0 * r),
+            Easing::Hold => 0.0,
+        }
+    }
+}
+
+/// A value at a time (in seconds from the start of the track), and how to
+/// get from here to the next key.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Key<T> {
+    pub t: f32,
+    pub value: T,
+    #[serde(default)]
+    pub ease: Easing,
+}
+
+/// A camera track as written in a cutscene file.  Anything without keys is
+/// left as the camera had it when the cutscene started.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TrackDesc {
+    // where the eye is
+    #[serde(default)]
+    pub position: Vec<Key<(f32, f32, f32)>>,
+    // (yaw, pitch) in degrees, as for `camera::controller`
+    #[serde(default)]
+    pub rotation: Vec<Key<(f32, f32)>>,
+    // vertical field of view in degrees
+    #[serde(default)]
+    pub fov: Vec<Key<f32>>,
+    #[serde(default = "default_skippable")]
+    pub skippable: bool,
+}
+fn default_skippable() -> bool {
+    true
+}
+
+pub struct CameraTrack {
+    position_keys: Vec<(f32, (Vec3, Easing))>,
+    rotation_keys: Vec<(f32, (Rotor3, Easing))>,
+    fov_keys: Vec<(f32, (f32, Easing))>,
+    duration: f32,
+    skippable: bool,
+}
+
+impl CameraTrack {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read cutscene {:?}", path))?;
+        let desc: TrackDesc = ron::from_str(&text)
+            .wrap_err_with(|| format!("Couldn't parse cutscene {:?}", path))?;
+        Self::from_desc(&desc).wrap_err_with(|| format!("Invalid cutscene {:?}", path))
+    }
+    pub fn from_desc(desc: &TrackDesc) -> Result<Self> {
+        fn check<T>(what: &str, keys: &[Key<T>]) -> Result<()> {
+            if let Some(first) = keys.first() {
+                ensure!(first.t == 0.0, "{} keys have to start at t: 0.0", what);
+            }
+            ensure!(
+                keys.windows(2).all(|ks| ks[0].t < ks[1].t),
+                "{} keys have to be in order, with no two at the same time",
+                what
+            );
+            Ok(())
+        }
+        check("position", &desc.position)?;
+        check("rotation", &desc.rotation)?;
+        check("fov", &desc.fov)?;
+        let duration = [
+            desc.position.last().map(|k| k.t),
+            desc.rotation.last().map(|k| k.t),
+            desc.fov.last().map(|k| k.t),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(0.0, f32::max);
+        Ok(Self {
+            position_keys: desc
+                .position
+                .iter()
+                .map(|k| (k.t, (k.value.into(), k.ease)))
+                .collect(),
+            rotation_keys: desc
+                .rotation
+                .iter()
+                .map(|k| (k.t, (controller::rotation_from_degrees(k.value), k.ease)))
+                .collect(),
+            fov_keys: desc
+                .fov
+                .iter()
+                .map(|k| (k.t, (k.value.to_radians(), k.ease)))
+                .collect(),
+            duration,
+            skippable: desc.skippable,
+        })
+    }
+    /// How long the track runs, in seconds.
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+    /// The camera `t` seconds in; `from` fills in anything the track doesn't
+    /// key, as well as the aspect ratio and near plane.
+    pub fn sample(&self, t: f32, from: &Camera) -> Camera {
+        let inv = from.transform.inversed();
+        let ((p1, ease), (p2, _), r) =
+            sample_keys(&self.position_keys, (inv.translation, Easing::Linear), t);
+        let eye = p1.lerp(p2, ease.apply(r));
+        let ((r1, ease), (r2, _), r) =
+            sample_keys(&self.rotation_keys, (inv.rotation, Easing::Linear), t);
+        // the same turn can be written two ways; go the short way round
+        let r2 = if r1.dot(r2) < 0.0 { r2 * -1.0 } else { r2 };
+        let rot = r1.lerp(r2, ease.apply(r)).normalized();
+        let ((f1, ease), (f2, _), r) = sample_keys(&self.fov_keys, (from.fov, Easing::Linear), t);
+        let mut cam = Camera::from_rotation(eye, rot);
+        cam.set_fov(f1.lerp(f2, ease.apply(r)));
+        cam.set_ratio(from.ratio);
+        cam.set_near(from.near);
+        cam
+    }
+}
+
+/// A camera track being played.
+pub struct Cutscene {
+    track: Rc<CameraTrack>,
+    t: f32,
+}
+impl Cutscene {
+    pub fn new(track: Rc<CameraTrack>) -> Self {
+        Self { track, t: 0.0 }
+    }
+    pub fn tick(&mut self, dt: f64) {
+        self.t += dt as f32;
+    }
+    pub fn is_done(&self) -> bool {
+        self.t >= self.track.duration()
+    }
+    pub fn is_skippable(&self) -> bool {
+        self.track.skippable
+    }
+    /// What the camera sees now, filling in from `from` as for `sample`.
+    pub fn camera(&self, from: &Camera) -> Camera {
+        self.track.sample(self.t, from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(ron: &str) -> Result<CameraTrack> {
+        CameraTrack::from_desc(&ron::from_str(ron)?)
+    }
+
+    fn forward(cam: &Camera) -> Vec3 {
+        cam.center_ray().dir
+    }
+
+    #[test]
+    fn easing() {
+        for ease in [Easing::Linear, Easing::In, Easing::Out, Easing::InOut] {
+            assert_eq!(ease.apply(0.0), 0.0, "{:?}", ease);
+            assert_eq!(ease.apply(1.0), 1.0, "{:?}", ease);
+        }
+        assert_eq!(Easing::Linear.apply(0.5), 0.5);
+        assert_eq!(Easing::In.apply(0.5), 0.25);
+        assert_eq!(Easing::Out.apply(0.5), 0.75);
+        assert_eq!(Easing::InOut.apply(0.5), 0.5);
+        assert!(Easing::InOut.apply(0.25) < 0.25);
+        assert!(Easing::InOut.apply(0.75) > 0.75);
+        assert_eq!(Easing::Hold.apply(0.99), 0.0);
+    }
+
+    #[test]
+    fn sample_between_keys() {
+        let track = parse(
+            "(
+                position: [
+                    (t: 0.0, value: (0.0, 0.0, 0.0)),
+                    (t: 2.0, value: (4.0, 0.0, 0.0), ease: In),
+                    (t: 3.0, value: (4.0, 2.0, 0.0)),
+                ],
+                fov: [(t: 0.0, value: 60.0), (t: 1.0, value: 90.0)],
+            )",
+        )
+        .unwrap();
+        assert_eq!(track.duration(), 3.0);
+        let from = Camera::look_at(Vec3::zero(), Vec3::unit_z(), Vec3::unit_y());
+        let cam = track.sample(1.0, &from);
+        assert!((cam.eye() - Vec3::new(2.0, 0.0, 0.0)).mag() < 1e-4);
+        assert!((cam.fov - 90f32.to_radians()).abs() < 1e-4);
+        let cam = track.sample(0.5, &from);
+        assert!((cam.fov - 75f32.to_radians()).abs() < 1e-4);
+        // the second key eases in
+        let cam = track.sample(2.5, &from);
+        assert!((cam.eye() - Vec3::new(4.0, 0.5, 0.0)).mag() < 1e-4);
+        // past the end it stays on the last key
+        let cam = track.sample(10.0, &from);
+        assert!((cam.eye() - Vec3::new(4.0, 2.0, 0.0)).mag() < 1e-4);
+    }
+
+    #[test]
+    fn turns_the_short_way() {
+        let track =
+            parse("(rotation: [(t: 0.0, value: (170.0, 0.0)), (t: 1.0, value: (-170.0, 0.0))])")
+                .unwrap();
+        let from = Camera::look_at(Vec3::zero(), Vec3::unit_z(), Vec3::unit_y());
+        // yaw 180 faces -z; the long way round would pass yaw 0, facing +z
+        let mid = forward(&track.sample(0.5, &from));
+        assert!((mid - -Vec3::unit_z()).mag() < 1e-4, "{:?}", mid);
+        let end = Camera::from_rotation(
+            Vec3::zero(),
+            controller::rotation_from_degrees((-170.0, 0.0)),
+        );
+        assert!((forward(&track.sample(1.0, &from)) - forward(&end)).mag() < 1e-4);
+    }
+
+    #[test]
+    fn fills_gaps_from_the_camera() {
+        let track = parse("(fov: [(t: 0.0, value: 60.0), (t: 1.0, value: 40.0)])").unwrap();
+        let mut from = Camera::look_at(
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(4.0, 2.0, 8.0),
+            Vec3::unit_y(),
+        );
+        from.set_ratio(16.0 / 9.0);
+        from.set_near(0.5);
+        let cam = track.sample(0.5, &from);
+        assert!((cam.eye() - from.eye()).mag() < 1e-4);
+        assert!((forward(&cam) - forward(&from)).mag() < 1e-4);
+        assert!((cam.fov - 50f32.to_radians()).abs() < 1e-4);
+        assert_eq!(cam.ratio, from.ratio);
+        assert_eq!(cam.near, from.near);
+        // and with no keys at all the camera stays as it was
+        let cam = parse("()").unwrap().sample(0.5, &from);
+        assert_eq!(cam.fov, from.fov);
+        assert!((cam.eye() - from.eye()).mag() < 1e-4);
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        // has to start at 0
+        assert!(parse("(fov: [(t: 0.5, value: 60.0)])").is_err());
+        // has to be in order
+        assert!(parse(
+            "(position: [
+                (t: 0.0, value: (0.0, 0.0, 0.0)),
+                (t: 2.0, value: (1.0, 0.0, 0.0)),
+                (t: 1.0, value: (2.0, 0.0, 0.0)),
+            ])"
+        )
+        .is_err());
+        // with no two at once
+        assert!(
+            parse("(rotation: [(t: 0.0, value: (0.0, 0.0)), (t: 0.0, value: (90.0, 0.0))])")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn shipped_cutscenes_load() {
+        for entry in std::fs::read_dir("content/cutscenes").unwrap() {
+            let path = entry.unwrap().path();
+            let track = CameraTrack::load(&path).unwrap();
+            assert!(track.duration() > 0.0, "{:?}", path);
+        }
+    }
+}
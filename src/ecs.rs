@@ -0,0 +1,247 @@
+//! Entities and their components: everything in a level the game keeps track
+//! of, apart from the player's movement (`object::Player`) and the scenery.
+//!
+//! An `Entity` is a generational id, so one kept after its entity was
+//! despawned never finds whatever took its slot.  Each kind of component has
+//! its own `Storage` in the `World`; an entity has a component if it's in
+//! that storage.  Storages iterate in slot order, so queries come out in the
+//! same order every run, which replays rely on.  Systems are plain functions
+//! over the world (like `place_renderables` and `rooms::RoomTracker::update`)
+//! that the game runs every tick.
+use crate::scene::{NodeRef, Scene};
+use crate::types::*;
+use thunderdome::{Arena, Index};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Entity(Index);
+
+/// Where an entity is.  For a room or a clue, that's the minimum corner of
+/// its volume; for the player, their feet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub pos: Vec3,
+}
+/// The space an entity takes up, relative to its `Transform`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Volume(pub RPrism);
+/// A scene node that goes wherever the entity does, `offset` from it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Renderable {
+    pub node: NodeRef,
+    pub offset: Similarity3,
+}
+/// Something the player is looking for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Clue {
+    pub found: bool,
+}
+/// The innermost room an entity is in, or for a room, the room it sits in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Container(pub Option<Entity>);
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Room {
+    // the player is in it, or in a room inside it
+    pub occupied: bool,
+}
+/// The id the level file gave an entity.  Unlike `Entity`s these are the
+/// same every time a level is built, so saves use them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Id(pub usize);
+/// Marks the entity standing in for the player.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Player;
+
+/// One kind of component, for any number of entities.
+pub struct Storage<T> {
+    // indexed by the entity's slot
+    items: Vec<Option<(Entity, T)>>,
+}
+impl<T> Default for Storage<T> {
+    fn default() -> Self {
+        Self { items: vec![] }
+    }
+}
+impl<T> Storage<T> {
+    /// Give `entity` the component `c`, returning the one it had before.
+    pub fn insert(&mut self, entity: Entity, c: T) -> Option<T> {
+        let slot = entity.0.slot() as usize;
+        if self.items.len() <= slot {
+            self.items.resize_with(slot + 1, || None);
+        }
+        match self.items[slot].replace((entity, c)) {
+            Some((old, c)) if old == entity => Some(c),
+            _ => None,
+        }
+    }
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        let item = self.items.get_mut(entity.0.slot() as usize)?;
+        match item {
+            Some((e, _)) if *e == entity => item.take().map(|(_, c)| c),
+            _ => None,
+        }
+    }
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        match self.items.get(entity.0.slot() as usize)? {
+            Some((e, c)) if *e == entity => Some(c),
+            _ => None,
+        }
+    }
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        match self.items.get_mut(entity.0.slot() as usize)? {
+            Some((e, c)) if *e == entity => Some(c),
+            _ => None,
+        }
+    }
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.get(entity).is_some()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> {
+        self.items.iter().flatten().map(|(e, c)| (*e, c))
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> {
+        self.items.iter_mut().flatten().map(|(e, c)| (*e, c))
+    }
+    /// Every entity with both this component and one in `other`.
+    pub fn join<'a, U>(
+        &'a self,
+        other: &'a Storage<U>,
+    ) -> impl Iterator<Item = (Entity, &'a T, &'a U)> {
+        self.iter()
+            .filter_map(move |(e, c)| other.get(e).map(|o| (e, c, o)))
+    }
+}
+
+#[derive(Default)]
+pub struct World {
+    entities: Arena<()>,
+    pub transforms: Storage<Transform>,
+    pub volumes: Storage<Volume>,
+    pub renderables: Storage<Renderable>,
+    pub clues: Storage<Clue>,
+    pub containers: Storage<Container>,
+    pub rooms: Storage<Room>,
+    pub ids: Storage<Id>,
+    pub players: Storage<Player>,
+}
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// A new entity with no components.
+    pub fn spawn(&mut self) -> Entity {
+        Entity(self.entities.insert(()))
+    }
+    /// Remove `entity` and all its components.
+    pub fn despawn(&mut self, entity: Entity) {
+        if self.entities.remove(entity.0).is_none() {
+            return;
+        }
+        self.transforms.remove(entity);
+        self.volumes.remove(entity);
+        self.renderables.remove(entity);
+        self.clues.remove(entity);
+        self.containers.remove(entity);
+        self.rooms.remove(entity);
+        self.ids.remove(entity);
+        self.players.remove(entity);
+    }
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.entities.contains(entity.0)
+    }
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities.iter().map(|(i, _)| Entity(i))
+    }
+    /// The space `entity` takes up in the world, if it has a volume.
+    pub fn volume(&self, entity: Entity) -> Option<RPrism> {
+        let Volume(vol) = self.volumes.get(entity)?;
+        let pos = self.transforms.get(entity).map_or(Vec3::zero(), |t| t.pos);
+        Some(RPrism {
+            pos: pos + vol.pos,
+            sz: vol.sz,
+        })
+    }
+    /// The entity the level gave `id`.
+    pub fn find(&self, id: usize) -> Option<Entity> {
+        self.ids.iter().find(|(_, i)| i.0 == id).map(|(e, _)| e)
+    }
+    pub fn id(&self, entity: Entity) -> Option<usize> {
+        self.ids.get(entity).map(|i| i.0)
+    }
+    /// The innermost room `entity` is in.
+    pub fn container(&self, entity: Entity) -> Option<Entity> {
+        self.containers.get(entity).and_then(|c| c.0)
+    }
+    pub fn player(&self) -> Option<Entity> {
+        self.players.iter().next().map(|(e, _)| e)
+    }
+}
+
+/// Move every renderable entity's scene node to where the entity is.
+pub fn place_renderables(world: &World, scene: &mut Scene) {
+    for (_, r, t) in world.renderables.join(&world.transforms) {
+        if scene.contains(r.node) {
+            let at = Similarity3::new(t.pos, Rotor3::identity(), 1.0);
+            scene.set_world(r.node, at * r.offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_entities_find_nothing() {
+        let mut world = World::new();
+        let old = world.spawn();
+        world.clues.insert(old, Clue::default());
+        world.ids.insert(old, Id(7));
+        world.despawn(old);
+        // the new entity takes the old one's slot, but not its components
+        let new = world.spawn();
+        assert_eq!(new.0.slot(), old.0.slot());
+        assert!(!world.contains(old) && world.contains(new));
+        assert!(world.clues.get(new).is_none());
+        world.clues.insert(new, Clue { found: true });
+        assert!(world.clues.get(old).is_none());
+        assert!(world.clues.get_mut(old).is_none());
+        assert!(world.clues.remove(old).is_none());
+        assert!(!world.clues.contains(old));
+        assert_eq!(world.clues.get(new), Some(&Clue { found: true }));
+    }
+
+    #[test]
+    fn despawn_removes_every_component() {
+        let mut world = World::new();
+        let e = world.spawn();
+        let other = world.spawn();
+        world.transforms.insert(e, Transform { pos: Vec3::one() });
+        world.volumes.insert(
+            e,
+            Volume(RPrism {
+                pos: Vec3::zero(),
+                sz: Vec3::one(),
+            }),
+        );
+        world.clues.insert(e, Clue::default());
+        world.containers.insert(e, Container(Some(other)));
+        world.rooms.insert(e, Room::default());
+        world.ids.insert(e, Id(3));
+        world.players.insert(e, Player);
+        world.ids.insert(other, Id(4));
+        world.despawn(e);
+        assert!(!world.contains(e));
+        assert_eq!(world.entities().collect::<Vec<_>>(), vec![other]);
+        assert_eq!(world.volume(e), None);
+        assert_eq!(world.find(3), None);
+        assert_eq!(world.player(), None);
+        assert_eq!(world.container(e), None);
+        assert!(world.transforms.iter().next().is_none());
+        assert!(world.clues.iter().next().is_none());
+        assert!(world.rooms.iter().next().is_none());
+        assert_eq!(world.find(4), Some(other));
+        // despawning twice is harmless
+        world.despawn(e);
+        assert!(world.contains(other));
+    }
+}
@@ -4,7 +4,6 @@ use crate::animation;
 use crate::assets::{self, Assets};
 use crate::renderer;
 use crate::vulkan::Vulkan;
-use winit::event::MouseButton;
 use color_eyre::eyre::Result;
 use std::rc::Rc;
 use winit::event::{Event, WindowEvent, VirtualKeyCode};
@@ -12,6 +11,8 @@ use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 use crate::input;
 use crate::camera::Camera;
+use crate::gamepad::{self, PadDevice};
+use crate::image::Image;
 
 
 pub trait GameThing:'static {}
@@ -19,6 +20,10 @@ pub trait GameThing:'static {}
 pub trait World {
     fn update(&mut self, inp: &input::Input, assets: &mut assets::Assets);
     fn render(&mut self, assets: &mut assets::Assets, render_state: &mut renderer::RenderState);
+    /// Called once the window has closed, just before the program exits.
+    /// `play_world` never returns or drops the world, so anything that
+    /// should outlive the game (like a recording) has to be written here.
+    fn quit(&mut self) {}
 }
 
 pub struct WindowSettings {
@@ -65,6 +70,7 @@ pub struct Engine {
     event_loop: Option<EventLoop<()>>,
     vulkan: Vulkan,
     input: input::Input,
+    pad_device: Option<Box<dyn PadDevice>>,
     // 1 is new, 0 is old
     render_states: [crate::renderer::RenderState; 2],
     interpolated_state: crate::renderer::RenderState,
@@ -79,15 +85,29 @@ pub struct Engine {
 
 impl Engine {
     pub fn new(ws: WindowSettings, dt: f64) -> Self {
-        use crate::types::Vec3;
         let event_loop = EventLoop::new();
         let wb = WindowBuilder::new()
             .with_inner_size(winit::dpi::LogicalSize::new(ws.w as f32, ws.h as f32))
             .with_title(ws.title);
+        let vulkan = Vulkan::new(wb, &event_loop);
+        let mut engine = Self::with_vulkan(vulkan, Some(event_loop), dt);
+        if let Some(pads) = gamepad::GilrsDevice::new() {
+            engine.set_pad_device(Box::new(pads));
+        }
+        engine
+    }
+    /// An engine with no window which renders into an offscreen image.
+    /// It can't `play`; drive it with `step_world` and `render_world` instead.
+    /// It has no gamepads unless given some with `set_pad_device`.  Fails if
+    /// there's no Vulkan device to render with.
+    pub fn headless(w: u32, h: u32, dt: f64) -> Result<Self> {
+        Ok(Self::with_vulkan(Vulkan::headless(w, h)?, None, dt))
+    }
+    fn with_vulkan(mut vulkan: Vulkan, event_loop: Option<EventLoop<()>>, dt: f64) -> Self {
+        use crate::types::Vec3;
         let input = input::Input::new();
         let default_cam =
             Camera::look_at(Vec3::new(0., 0., 0.), Vec3::new(0., 0., 1.), Vec3::unit_y());
-        let mut vulkan = Vulkan::new(wb, &event_loop);
         Self {
             assets: Assets::new(),
             skinned_renderer: crate::renderer::skinned::Renderer::new(&mut vulkan),
@@ -101,12 +121,22 @@ impl Engine {
             ],
             interpolated_state: crate::renderer::RenderState::new(default_cam),
             dt,
-            event_loop: Some(event_loop),
+            event_loop,
             input,
+            pad_device: None,
             acc: 0.0,
             last_frame: std::time::Instant::now(),
         }
     }
+    /// Read gamepads from `device` from now on.
+    pub fn set_pad_device(&mut self, device: Box<dyn PadDevice>) {
+        self.pad_device = Some(device);
+    }
+    fn poll_pads(&mut self) {
+        if let Some(device) = self.pad_device.as_mut() {
+            self.input.handle_pads(device.poll());
+        }
+    }
     pub fn set_camera(&mut self, cam:Camera) {
         self.render_states = [
             crate::renderer::RenderState::new(cam),
@@ -114,7 +144,10 @@ impl Engine {
         ]
     }
     pub fn play(mut self, f:impl Fn(&mut Self) + 'static) -> Result<()> {
-        let ev = self.event_loop.take().unwrap();
+        let ev = self
+            .event_loop
+            .take()
+            .expect("A headless engine has no event loop to play");
         ev.run(move |event, _, control_flow| {
             match event {
                 // Nested match patterns are pretty useful---see if you can figure out what's going on in this match.
@@ -145,12 +178,30 @@ impl Engine {
                 Event::WindowEvent {
                     event: WindowEvent::MouseInput {
                         state: button_state,
-                        button: MouseButton::Left,
+                        button,
                         ..
                     }, 
                     ..
                 } => {
-                    self.input.handle_left_mouse_event(button_state)
+                    self.input.handle_mouse_event(button, button_state)
+                }
+                Event::WindowEvent {
+                    event: WindowEvent::MouseWheel { delta, .. },
+                    ..
+                } => {
+                    self.input.handle_scroll_event(delta)
+                }
+                Event::WindowEvent {
+                    event: WindowEvent::ModifiersChanged(modifiers),
+                    ..
+                } => {
+                    self.input.handle_modifiers_event(modifiers)
+                }
+                Event::WindowEvent {
+                    event: WindowEvent::ReceivedCharacter(c),
+                    ..
+                } => {
+                    self.input.handle_char_event(c)
                 }
                 Event::DeviceEvent {
                     event: winit::event::DeviceEvent::MouseMotion { delta }
@@ -170,6 +221,7 @@ impl Engine {
                 Event::MainEventsCleared => {
                     // track DT, accumulator, ...
                     {
+                        self.poll_pads();
                         f(&mut self);
                         self.input.next_frame();
                     }
@@ -180,7 +232,10 @@ impl Engine {
         });
     }
     pub fn play_world(mut self, mut w: impl World + 'static) -> Result<()> {
-        let ev = self.event_loop.take().unwrap();
+        let ev = self
+            .event_loop
+            .take()
+            .expect("A headless engine has no event loop to play");
         self.last_frame = std::time::Instant::now();
         ev.run(move |event, _, control_flow| {
             match event {
@@ -191,6 +246,7 @@ impl Engine {
                 } => {
                     *control_flow = ControlFlow::Exit;
                 }
+                Event::LoopDestroyed => w.quit(),
                 Event::WindowEvent {
                     event: WindowEvent::Resized(_),
                     ..
@@ -212,12 +268,30 @@ impl Engine {
                 Event::WindowEvent {
                     event: WindowEvent::MouseInput {
                         state: button_state,
-                        button: MouseButton::Left,
+                        button,
                         ..
                     }, 
                     ..
                 } => {
-                    self.input.handle_left_mouse_event(button_state)
+                    self.input.handle_mouse_event(button, button_state)
+                }
+                Event::WindowEvent {
+                    event: WindowEvent::MouseWheel { delta, .. },
+                    ..
+                } => {
+                    self.input.handle_scroll_event(delta)
+                }
+                Event::WindowEvent {
+                    event: WindowEvent::ModifiersChanged(modifiers),
+                    ..
+                } => {
+                    self.input.handle_modifiers_event(modifiers)
+                }
+                Event::WindowEvent {
+                    event: WindowEvent::ReceivedCharacter(c),
+                    ..
+                } => {
+                    self.input.handle_char_event(c)
                 }
                 Event::DeviceEvent {
                     event: winit::event::DeviceEvent::MouseMotion { delta }
@@ -242,6 +316,7 @@ impl Engine {
                 Event::MainEventsCleared => {
                     // track DT, accumulator, ...
                     {
+                        self.poll_pads();
                         self.acc += self.last_frame.elapsed().as_secs_f64();
                         self.last_frame = std::time::Instant::now();
                         while self.acc >= self.dt {
@@ -266,6 +341,8 @@ impl Engine {
             AutoCommandBufferBuilder, CommandBufferUsage, SubpassContents,
         };
 
+        // worlds can load things during update
+        self.assets.upload(&mut self.vulkan).unwrap();
         let vulkan = &mut self.vulkan;
         vulkan.recreate_swapchain_if_necessary();
         let image_num = vulkan.get_next_image();
@@ -310,6 +387,25 @@ impl Engine {
             &self.interpolated_state.camera,
         );
 
+        // shadow maps first, so the main pass can sample them.  Unused maps
+        // are still cleared so there's never anything stale in them.
+        let shadow_views = self
+            .interpolated_state
+            .lights
+            .shadow_views(&self.interpolated_state.camera);
+        for (i, fb) in vulkan.shadows.framebuffers.iter().enumerate() {
+            builder
+                .begin_render_pass(fb.clone(), SubpassContents::Inline, vec![(1.0).into()])
+                .unwrap()
+                .set_viewport(0, [crate::vulkan::ShadowMaps::viewport()]);
+            if let Some(view) = shadow_views.get(i) {
+                self.skinned_renderer.draw_shadows(&mut builder, *view);
+                self.flat_renderer.draw_shadows(&mut builder, *view);
+                self.textured_renderer.draw_shadows(&mut builder, *view);
+            }
+            builder.end_render_pass().unwrap();
+        }
+
         builder
             .begin_render_pass(
                 vulkan.framebuffers[image_num].clone(),
@@ -324,26 +420,64 @@ impl Engine {
         self.flat_renderer.draw(&mut builder);
         self.textured_renderer.draw(&mut builder);
 
+        // then everything see-through, farthest from the camera first
+        // whichever renderer it's in, so nearer things blend over farther
+        use renderer::TransparentPass;
+        let mut transparent: Vec<(f32, TransparentPass, usize)> = self
+            .flat_renderer
+            .transparent_draws()
+            .map(|(d, i)| (d, TransparentPass::Flat, i))
+            .chain(
+                self.textured_renderer
+                    .transparent_draws()
+                    .map(|(d, i)| (d, TransparentPass::Textured, i)),
+            )
+            .chain(
+                self.sprites_renderer
+                    .transparent_draws()
+                    .map(|(d, i)| (d, TransparentPass::Sprites, i)),
+            )
+            .collect();
+        transparent.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        for (_, pass, i) in transparent {
+            match pass {
+                TransparentPass::Flat => self.flat_renderer.draw_transparent(&mut builder, i),
+                TransparentPass::Textured => {
+                    self.textured_renderer.draw_transparent(&mut builder, i)
+                }
+                TransparentPass::Sprites => self.sprites_renderer.draw_transparent(&mut builder, i),
+            }
+        }
+        self.sprites_renderer.finish_frame();
+        self.flat_renderer.finish_frame();
+        self.textured_renderer.finish_frame();
+
         builder.end_render_pass().unwrap();
 
         let command_buffer = builder.build().unwrap();
         vulkan.execute_commands(command_buffer, image_num);
     }
     pub fn load_texture(&mut self, path: &std::path::Path) -> Result<assets::TextureRef> {
-        self.assets.load_texture(path, &mut self.vulkan)
+        let tex = self.assets.load_texture(path)?;
+        self.assets.upload(&mut self.vulkan)?;
+        Ok(tex)
     }
     pub fn load_skinned(
         &mut self,
         path: &std::path::Path,
         node_root: &[&str],
     ) -> Result<Vec<assets::MeshRef<renderer::skinned::Mesh>>> {
-        self.assets.load_skinned(path, node_root, &mut self.vulkan)
+        let meshes = self.assets.load_skinned(path, node_root)?;
+        self.assets.upload(&mut self.vulkan)?;
+        Ok(meshes)
     }
     pub fn load_textured(
         &mut self,
         path: &std::path::Path,
-    ) -> Result<Vec<assets::MeshRef<renderer::textured::Mesh>>> {
-        self.assets.load_textured(path, &mut self.vulkan)
+    ) -> Result<Rc<renderer::textured::Model>> {
+        let model = self.assets.load_textured(path)?;
+        self.assets.upload(&mut self.vulkan)?;
+        Ok(model)
     }
     pub fn load_anim(
         &mut self,
@@ -362,20 +496,70 @@ impl Engine {
         assert_eq!(meshes.len(), textures.len());
         Rc::new(renderer::skinned::Model::new(meshes, textures))
     }
-    pub fn create_textured_model(
-        &self,
-        meshes: Vec<assets::MeshRef<renderer::textured::Mesh>>,
+    /// A copy of `model` with each mesh's diffuse texture replaced.
+    pub fn retexture_model(
+        &mut self,
+        model: &renderer::textured::Model,
         textures: Vec<assets::TextureRef>,
     ) -> Rc<renderer::textured::Model> {
-        assert_eq!(meshes.len(), textures.len());
-        Rc::new(renderer::textured::Model::new(meshes, textures))
+        assert_eq!(model.meshes().len(), textures.len());
+        let materials = model
+            .materials()
+            .iter()
+            .zip(textures)
+            .map(|(m, t)| self.assets.with_diffuse(*m, t))
+            .collect();
+        Rc::new(
+            renderer::textured::Model::new(model.meshes().to_vec(), materials)
+                .with_nodes(model.nodes().to_vec()),
+        )
     }
     pub fn load_flat(&mut self, path: &std::path::Path) -> Result<Rc<renderer::flat::Model>> {
-        self.assets.load_flat(path, &mut self.vulkan)
+        let model = self.assets.load_flat(path)?;
+        self.assets.upload(&mut self.vulkan)?;
+        Ok(model)
     }
     pub fn get_inputs(&self) -> Input {
         self.input.clone()
     }
+    pub fn assets(&self) -> &Assets {
+        &self.assets
+    }
+    /// How many instances the last frame drew, drew only into shadow maps,
+    /// or skipped for being out of view, across every renderer.
+    pub fn cull_stats(&self) -> renderer::culling::CullStats {
+        self.flat_renderer.cull_stats()
+            + self.textured_renderer.cull_stats()
+            + self.skinned_renderer.cull_stats()
+            + self.sprites_renderer.cull_stats()
+    }
+    /// For loading straight into the engine's assets, e.g. with `Level::build`.
+    /// Anything loaded this way is uploaded before the next frame is drawn.
+    pub fn assets_mut(&mut self) -> &mut Assets {
+        &mut self.assets
+    }
+    /// Run one fixed-timestep update of `w` with the current input.
+    pub fn step_world(&mut self, w: &mut impl World) {
+        self.poll_pads();
+        w.update(&self.input, &mut self.assets);
+        self.input.next_frame();
+    }
+    /// Run `w` through every frame of `rec`.
+    pub fn replay_world(&mut self, w: &mut impl World, rec: &crate::replay::Recording) {
+        crate::replay::replay(rec, w, &mut self.assets);
+    }
+    /// Render `w` once, without interpolation, and read the frame back.
+    /// Only available on a `headless` engine.
+    pub fn render_world(&mut self, w: &mut impl World) -> Result<Image> {
+        for rs in self.render_states.iter_mut() {
+            rs.clear();
+            w.render(&mut self.assets, rs);
+        }
+        self.interpolated_state.clear();
+        self.acc = 0.0;
+        self.render3d();
+        self.vulkan.read_back()
+    }
 }
 
 /*
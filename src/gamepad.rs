@@ -0,0 +1,203 @@
+//! Gamepads.
+//!
+//! A `PadDevice` reports the state of every connected pad once per frame, and
+//! `Input` takes it from there, so game code asks `Input` (or `Bindings`)
+//! about pads just like keys.  `GilrsDevice` talks to real controllers;
+//! `SyntheticPads` lets tests and tools plug in pads and press their buttons
+//! without any hardware.
+use serde::{Deserialize, Serialize};
+
+// sticks closer to the middle than this read as centered
+pub const STICK_DEAD_ZONE: f32 = 0.15;
+// triggers pulled less than this read as released
+pub const TRIGGER_DEAD_ZONE: f32 = 0.05;
+
+pub type PadId = usize;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    Select,
+    Start,
+    LeftStick,
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+const PAD_BUTTONS: usize = PadButton::DPadRight as usize + 1;
+
+/// Sticks run from -1 to 1 with positive y up; triggers from 0 to 1.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger,
+}
+const PAD_AXES: usize = PadAxis::RightTrigger as usize + 1;
+
+/// Everything about one pad at one moment, before dead zones are applied.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PadState {
+    buttons: [bool; PAD_BUTTONS],
+    axes: [f32; PAD_AXES],
+}
+impl PadState {
+    pub fn is_down(&self, b: PadButton) -> bool {
+        self.buttons[b as usize]
+    }
+    pub fn set_button(&mut self, b: PadButton, down: bool) {
+        self.buttons[b as usize] = down;
+    }
+    pub fn raw_axis(&self, a: PadAxis) -> f32 {
+        self.axes[a as usize]
+    }
+    pub fn set_axis(&mut self, a: PadAxis, v: f32) {
+        self.axes[a as usize] = match a {
+            PadAxis::LeftTrigger | PadAxis::RightTrigger => v.clamp(0.0, 1.0),
+            _ => v.clamp(-1.0, 1.0),
+        };
+    }
+    /// `a` with dead zones applied and rescaled so it still reaches 1 at full
+    /// tilt.  Sticks use a round dead zone, so pushing one diagonally doesn't
+    /// snap to an axis.
+    pub fn axis(&self, a: PadAxis) -> f32 {
+        let (x, y) = match a {
+            PadAxis::LeftStickX | PadAxis::LeftStickY => (PadAxis::LeftStickX, PadAxis::LeftStickY),
+            PadAxis::RightStickX | PadAxis::RightStickY => {
+                (PadAxis::RightStickX, PadAxis::RightStickY)
+            }
+            PadAxis::LeftTrigger | PadAxis::RightTrigger => {
+                return rescale(self.raw_axis(a), TRIGGER_DEAD_ZONE);
+            }
+        };
+        let (x, y) = (self.raw_axis(x), self.raw_axis(y));
+        let mag = (x * x + y * y).sqrt();
+        if mag <= STICK_DEAD_ZONE {
+            return 0.0;
+        }
+        let scale = rescale(mag.min(1.0), STICK_DEAD_ZONE) / mag;
+        self.raw_axis(a) * scale
+    }
+}
+fn rescale(v: f32, dead: f32) -> f32 {
+    if v <= dead {
+        0.0
+    } else {
+        ((v - dead) / (1.0 - dead)).min(1.0)
+    }
+}
+
+/// Somewhere pad state comes from.
+pub trait PadDevice {
+    /// Catch up on whatever happened since the last poll and report every pad
+    /// that's connected now.  A pad that stops showing up has been unplugged.
+    fn poll(&mut self) -> Vec<(PadId, PadState)>;
+}
+
+/// Pads driven by hand.
+#[derive(Clone, Debug, Default)]
+pub struct SyntheticPads {
+    pads: std::collections::BTreeMap<PadId, PadState>,
+}
+impl SyntheticPads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn connect(&mut self, id: PadId) {
+        self.pads.entry(id).or_default();
+    }
+    pub fn disconnect(&mut self, id: PadId) {
+        self.pads.remove(&id);
+    }
+    /// The state of pad `id`, plugging it in first if need be.
+    pub fn pad_mut(&mut self, id: PadId) -> &mut PadState {
+        self.pads.entry(id).or_default()
+    }
+}
+impl PadDevice for SyntheticPads {
+    fn poll(&mut self) -> Vec<(PadId, PadState)> {
+        self.pads.iter().map(|(id, p)| (*id, *p)).collect()
+    }
+}
+// so a test can keep a handle on the pads it gave the engine
+impl PadDevice for std::rc::Rc<std::cell::RefCell<SyntheticPads>> {
+    fn poll(&mut self) -> Vec<(PadId, PadState)> {
+        self.borrow_mut().poll()
+    }
+}
+
+pub struct GilrsDevice {
+    gilrs: gilrs::Gilrs,
+}
+impl GilrsDevice {
+    /// Real controllers, or `None` if this platform can't do gamepads.
+    pub fn new() -> Option<Self> {
+        match gilrs::Gilrs::new() {
+            Ok(gilrs) => Some(Self { gilrs }),
+            Err(gilrs::Error::NotImplemented(_)) => None,
+            Err(e) => {
+                println!("Gamepads unavailable: {}", e);
+                None
+            }
+        }
+    }
+}
+impl PadDevice for GilrsDevice {
+    fn poll(&mut self) -> Vec<(PadId, PadState)> {
+        use gilrs::{Axis, Button};
+        // gilrs updates its pad states (and connections) as events are read
+        while self.gilrs.next_event().is_some() {}
+        const BUTTONS: [(Button, PadButton); PAD_BUTTONS] = [
+            (Button::South, PadButton::South),
+            (Button::East, PadButton::East),
+            (Button::North, PadButton::North),
+            (Button::West, PadButton::West),
+            (Button::LeftTrigger, PadButton::LeftBumper),
+            (Button::RightTrigger, PadButton::RightBumper),
+            (Button::Select, PadButton::Select),
+            (Button::Start, PadButton::Start),
+            (Button::LeftThumb, PadButton::LeftStick),
+            (Button::RightThumb, PadButton::RightStick),
+            (Button::DPadUp, PadButton::DPadUp),
+            (Button::DPadDown, PadButton::DPadDown),
+            (Button::DPadLeft, PadButton::DPadLeft),
+            (Button::DPadRight, PadButton::DPadRight),
+        ];
+        const STICKS: [(Axis, PadAxis); 4] = [
+            (Axis::LeftStickX, PadAxis::LeftStickX),
+            (Axis::LeftStickY, PadAxis::LeftStickY),
+            (Axis::RightStickX, PadAxis::RightStickX),
+            (Axis::RightStickY, PadAxis::RightStickY),
+        ];
+        const TRIGGERS: [(Button, PadAxis); 2] = [
+            (Button::LeftTrigger2, PadAxis::LeftTrigger),
+            (Button::RightTrigger2, PadAxis::RightTrigger),
+        ];
+        self.gilrs
+            .gamepads()
+            .map(|(id, pad)| {
+                let mut state = PadState::default();
+                for (gb, b) in BUTTONS {
+                    state.set_button(b, pad.is_pressed(gb));
+                }
+                for (ga, a) in STICKS {
+                    state.set_axis(a, pad.value(ga));
+                }
+                for (gb, a) in TRIGGERS {
+                    state.set_axis(a, pad.button_data(gb).map(|d| d.value()).unwrap_or(0.0));
+                }
+                (id.into(), state)
+            })
+            .collect()
+    }
+}
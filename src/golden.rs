@@ -0,0 +1,380 @@
+//! Golden-image regression checks for the four renderers.
+//!
+//! `cargo run -- --golden` renders every scene below on a headless engine and
+//! compares the result against `content/golden/<scene>.png`.  Failing scenes
+//! get `<scene>.actual.png` and `<scene>.diff.png` written to `target/golden`.
+//! `cargo run -- --golden --bless` (re)writes the reference images instead.
+use crate::animation;
+use crate::assets;
+use crate::camera::Camera;
+use crate::engine::{Engine, World};
+use crate::image::Image;
+use crate::input;
+use crate::renderer::{self, RenderState};
+use crate::types::*;
+use color_eyre::eyre::{ensure, eyre, Result};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+pub const GOLDEN_DIR: &str = "content/golden";
+pub const OUTPUT_DIR: &str = "target/golden";
+const W: u32 = 320;
+const H: u32 = 240;
+// per-channel difference allowed before a pixel counts as changed;
+// leaves room for rasterization differences between drivers.
+pub const DEFAULT_TOLERANCE: u8 = 8;
+
+pub struct Comparison {
+    pub mismatched: usize,
+    pub max_delta: u8,
+    pub diff: Image,
+}
+impl Comparison {
+    pub fn passed(&self) -> bool {
+        self.mismatched == 0
+    }
+}
+
+/// Compare two images of the same size pixel by pixel.  The diff image shows
+/// matching pixels as dimmed grayscale and mismatched pixels in red.
+pub fn compare(actual: &Image, expected: &Image, tolerance: u8) -> Result<Comparison> {
+    ensure!(
+        actual.sz.x == expected.sz.x && actual.sz.y == expected.sz.y,
+        "Image sizes differ: {}x{} vs {}x{}",
+        actual.sz.x,
+        actual.sz.y,
+        expected.sz.x,
+        expected.sz.y
+    );
+    let mut diff = Image::new(actual.sz.x, actual.sz.y);
+    let mut mismatched = 0;
+    let mut max_delta = 0;
+    for ((a, e), d) in actual
+        .as_slice()
+        .iter()
+        .zip(expected.as_slice().iter())
+        .zip(diff.as_mut_slice().iter_mut())
+    {
+        let delta = [
+            a.0.abs_diff(e.0),
+            a.1.abs_diff(e.1),
+            a.2.abs_diff(e.2),
+            a.3.abs_diff(e.3),
+        ]
+        .into_iter()
+        .max()
+        .unwrap();
+        max_delta = max_delta.max(delta);
+        *d = if delta > tolerance {
+            mismatched += 1;
+            Color(255, 0, 0, 255)
+        } else {
+            let lum = ((a.0 as u16 + a.1 as u16 + a.2 as u16) / 12) as u8;
+            Color(lum, lum, lum, 255)
+        };
+    }
+    Ok(Comparison {
+        mismatched,
+        max_delta,
+        diff,
+    })
+}
+
+fn load_reference(p: &Path) -> Result<Image> {
+    // not Image::from_file: references are stored exactly as read back, so
+    // they must not be premultiplied a second time.
+    let img = image_reading::open(p)?.into_rgba8();
+    Ok(Image::from_rgba8(img.width(), img.height(), img.as_raw()))
+}
+
+/// Check `actual` against the reference image for `name`, writing
+/// the actual and diff images to `OUTPUT_DIR` if they don't match.
+pub fn check(name: &str, actual: &Image, tolerance: u8, bless: bool) -> Result<()> {
+    let reference = Path::new(GOLDEN_DIR).join(format!("{}.png", name));
+    if bless {
+        std::fs::create_dir_all(GOLDEN_DIR)?;
+        actual.save(&reference)?;
+        println!("Blessed {:?}", reference);
+        return Ok(());
+    }
+    ensure!(
+        reference.exists(),
+        "No reference image {:?}; run with --golden --bless to create it",
+        reference
+    );
+    let expected = load_reference(&reference)?;
+    let cmp = compare(actual, &expected, tolerance)?;
+    if cmp.passed() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(OUTPUT_DIR)?;
+    let out = PathBuf::from(OUTPUT_DIR);
+    actual.save(&out.join(format!("{}.actual.png", name)))?;
+    cmp.diff.save(&out.join(format!("{}.diff.png", name)))?;
+    Err(eyre!(
+        "{}: {} pixels differ by more than {} (max {}); see {:?}",
+        name,
+        cmp.mismatched,
+        tolerance,
+        cmp.max_delta,
+        out.join(format!("{}.diff.png", name))
+    ))
+}
+
+struct Skinned {
+    model: Rc<renderer::skinned::Model>,
+    animation: assets::AnimRef,
+    state: animation::AnimationState,
+    trf: Similarity3,
+}
+struct Sprite {
+    tex: assets::TextureRef,
+    cel: Rect,
+    trf: Isometry3,
+    size: Vec2,
+}
+
+/// A fixed camera and a handful of things to draw with it.
+struct Scene {
+    camera: Camera,
+    flats: Vec<(Rc<renderer::flat::Model>, Similarity3)>,
+    textured: Vec<(Rc<renderer::textured::Model>, Similarity3)>,
+    skinned: Vec<Skinned>,
+    sprites: Vec<Sprite>,
+}
+impl Scene {
+    fn new(camera: Camera) -> Self {
+        Self {
+            camera,
+            flats: vec![],
+            textured: vec![],
+            skinned: vec![],
+            sprites: vec![],
+        }
+    }
+}
+impl World for Scene {
+    fn update(&mut self, _inp: &input::Input, _assets: &mut assets::Assets) {}
+    fn render(&mut self, _assets: &mut assets::Assets, rs: &mut RenderState) {
+        rs.set_camera(self.camera);
+        for (m_i, (m, trf)) in self.flats.iter().enumerate() {
+            rs.render_flat(m.clone(), *trf, m_i);
+        }
+        for (t_i, (t, trf)) in self.textured.iter().enumerate() {
+            rs.render_textured(t.clone(), *trf, t_i);
+        }
+        for (s_i, s) in self.skinned.iter().enumerate() {
+            rs.render_skinned(s.model.clone(), s.animation, s.state, s.trf, s_i);
+        }
+        for (s_i, s) in self.sprites.iter().enumerate() {
+            rs.render_sprite(s.tex, s.cel, s.trf, s.size, s_i);
+        }
+    }
+}
+
+fn default_camera() -> Camera {
+    Camera::look_at(Vec3::new(0., 2., -6.), Vec3::zero(), Vec3::unit_y())
+}
+
+fn flat_cube(engine: &mut Engine) -> Result<Scene> {
+    let mut scene = Scene::new(default_camera());
+    let model = engine.load_flat(Path::new("content/cube.obj"))?;
+    scene.flats.push((
+        model,
+        Similarity3::new(Vec3::zero(), Rotor3::from_rotation_xz(0.5), 1.0),
+    ));
+    Ok(scene)
+}
+
+fn flat_livingroom(engine: &mut Engine) -> Result<Scene> {
+    let mut scene = Scene::new(Camera::look_at(
+        Vec3::new(0., 2., 0.),
+        Vec3::new(0., 1., 10.),
+        Vec3::unit_y(),
+    ));
+    // placed like the level places it
+    let model = engine.load_flat(Path::new("content/livingroom.glb"))?;
+    scene.flats.push((
+        model,
+        Similarity3::new(Vec3::new(0.0, 0.0, 32.43), Rotor3::identity(), 15.0),
+    ));
+    Ok(scene)
+}
+
+fn textured_cube(engine: &mut Engine) -> Result<Scene> {
+    let mut scene = Scene::new(default_camera());
+    let tex = engine.load_texture(Path::new("content/cube-diffuse.jpg"))?;
+    let model = engine.load_textured(Path::new("content/cube.obj"))?;
+    let textures = vec![tex; model.meshes().len()];
+    let model = engine.retexture_model(&model, textures);
+    scene.textured.push((
+        model,
+        Similarity3::new(Vec3::zero(), Rotor3::from_rotation_xz(0.5), 1.0),
+    ));
+    Ok(scene)
+}
+
+fn skinned(
+    engine: &mut Engine,
+    path: &str,
+    node_root: &[&str],
+    texture: &str,
+    anim: &str,
+    trf: Similarity3,
+) -> Result<Scene> {
+    let mut scene = Scene::new(default_camera());
+    let path = Path::new(path);
+    let tex = engine.load_texture(Path::new(texture))?;
+    let meshes = engine.load_skinned(path, node_root)?;
+    let animation = engine.load_anim(
+        path,
+        meshes[0],
+        animation::AnimationSettings { looping: true },
+        anim,
+    )?;
+    let textures = vec![tex; meshes.len()];
+    let model = engine.create_skinned_model(meshes, textures);
+    scene.skinned.push(Skinned {
+        model,
+        animation,
+        // sample a fixed, non-rest pose
+        state: animation::AnimationState { t: 0.5 },
+        trf,
+    });
+    Ok(scene)
+}
+
+fn skinned_simpleskin(engine: &mut Engine) -> Result<Scene> {
+    skinned(
+        engine,
+        "content/khronos/SimpleSkin/glTF-Embedded/SimpleSkin.gltf",
+        &["ROOT", "nodes_1"],
+        "content/blue.png",
+        "",
+        Similarity3::new(Vec3::new(-0.5, -1.0, 0.0), Rotor3::identity(), 1.0),
+    )
+}
+
+fn skinned_cesiumman(engine: &mut Engine) -> Result<Scene> {
+    skinned(
+        engine,
+        "content/khronos/CesiumMan/glTF/CesiumMan.gltf",
+        &["Z_UP", "Armature", "Skeleton_torso_joint_1"],
+        "content/khronos/CesiumMan/glTF/CesiumMan_img0.jpg",
+        "",
+        Similarity3::new(Vec3::new(0.0, -1.0, 0.0), Rotor3::identity(), 2.0),
+    )
+}
+
+fn skinned_fox(engine: &mut Engine) -> Result<Scene> {
+    skinned(
+        engine,
+        "content/khronos/Fox/glTF/Fox.gltf",
+        &["root", "_rootJoint"],
+        "content/khronos/Fox/glTF/Texture.png",
+        "Walk",
+        Similarity3::new(Vec3::new(0.0, -1.0, 0.0), Rotor3::identity(), 0.03),
+    )
+}
+
+fn sprites_king(engine: &mut Engine) -> Result<Scene> {
+    let mut scene = Scene::new(default_camera());
+    let tex = engine.load_texture(Path::new("content/king.png"))?;
+    scene.sprites.push(Sprite {
+        tex,
+        cel: Rect {
+            pos: Vec2::new(0.0, 0.0),
+            sz: Vec2::new(1.0, 1.0),
+        },
+        trf: Isometry3::new(Vec3::zero(), Rotor3::from_rotation_xz(PI)),
+        size: Vec2::new(3.0, 3.0),
+    });
+    Ok(scene)
+}
+
+type SceneFn = fn(&mut Engine) -> Result<Scene>;
+const SCENES: &[(&str, SceneFn)] = &[
+    ("flat_cube", flat_cube),
+    ("flat_livingroom", flat_livingroom),
+    ("textured_cube", textured_cube),
+    ("skinned_simpleskin", skinned_simpleskin),
+    ("skinned_cesiumman", skinned_cesiumman),
+    ("skinned_fox", skinned_fox),
+    ("sprites_king", sprites_king),
+];
+
+/// Render and check every scene, each on a fresh headless engine so
+/// assets and batches can't leak from one scene into the next.
+pub fn run(dt: f64, bless: bool) -> Result<()> {
+    let mut failures = vec![];
+    for (name, build) in SCENES {
+        let mut engine = Engine::headless(W, H, dt)?;
+        let result = build(&mut engine)
+            .and_then(|mut scene| engine.render_world(&mut scene))
+            .and_then(|img| check(name, &img, DEFAULT_TOLERANCE, bless));
+        match result {
+            Ok(()) => println!("golden {}: ok", name),
+            Err(e) => {
+                println!("golden {}: FAILED\n{:?}", name, e);
+                failures.push(*name);
+            }
+        }
+    }
+    ensure!(failures.is_empty(), "Golden image checks failed: {:?}", failures);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(w: u32, h: u32, c: Color) -> Image {
+        let mut img = Image::new(w, h);
+        img.as_mut_slice().fill(c);
+        img
+    }
+
+    #[test]
+    fn compare_within_tolerance_passes() {
+        let a = solid(4, 3, Color(100, 100, 100, 255));
+        let b = solid(4, 3, Color(104, 96, 100, 255));
+        let cmp = compare(&a, &b, 4).unwrap();
+        assert!(cmp.passed());
+        assert_eq!(cmp.max_delta, 4);
+    }
+
+    #[test]
+    fn compare_over_tolerance_fails_and_marks_diff() {
+        let a = solid(4, 3, Color(120, 60, 30, 255));
+        let mut b = solid(4, 3, Color(120, 60, 30, 255));
+        b.as_mut_slice()[5] = Color(120, 60, 90, 255);
+        let cmp = compare(&a, &b, DEFAULT_TOLERANCE).unwrap();
+        assert!(!cmp.passed());
+        assert_eq!(cmp.mismatched, 1);
+        assert_eq!(cmp.max_delta, 60);
+        // the mismatch is red, everything else dimmed grayscale
+        let lum = ((120 + 60 + 30) / 12) as u8;
+        for (i, px) in cmp.diff.as_slice().iter().enumerate() {
+            if i == 5 {
+                assert_eq!(*px, Color(255, 0, 0, 255));
+            } else {
+                assert_eq!(*px, Color(lum, lum, lum, 255));
+            }
+        }
+    }
+
+    #[test]
+    fn compare_rejects_size_mismatch() {
+        let a = solid(4, 3, Color(0, 0, 0, 255));
+        let b = solid(3, 4, Color(0, 0, 0, 255));
+        assert!(compare(&a, &b, DEFAULT_TOLERANCE).is_err());
+    }
+
+    // needs a Vulkan device (a software one like lavapipe will do), so run
+    // it with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn golden_images() {
+        run(crate::DT, false).unwrap();
+    }
+}
@@ -19,25 +19,59 @@ impl Image {
     pub fn as_slice(&self) -> &[Color] {
         &self.buffer
     }
-    pub fn from_file(p: &std::path::Path) -> Result<Self> {
-        let img = image_reading::open(p)?.into_rgba8();
-        let sz = Vec2i {
-            x: img.width(),
-            y: img.height(),
-        };
-        let img = img.into_vec();
-        Ok(Self {
-            buffer: img
+    pub fn as_mut_slice(&mut self) -> &mut [Color] {
+        &mut self.buffer
+    }
+    /// Build an image from tightly packed RGBA8 bytes, taken as-is.
+    pub fn from_rgba8(w: u32, h: u32, bytes: &[u8]) -> Self {
+        assert_eq!(bytes.len(), (w * h * 4) as usize);
+        Self {
+            buffer: bytes
                 .chunks_exact(4)
-                .map(|px| {
-                    let a = px[3] as f32 / 255.0;
-                    let r = (px[0] as f32 * a) as u8;
-                    let g = (px[1] as f32 * a) as u8;
-                    let b = (px[2] as f32 * a) as u8;
-                    Color(r, g, b, (a * 255.0) as u8)
-                })
+                .map(|px| Color(px[0], px[1], px[2], px[3]))
                 .collect(),
-            sz,
-        })
+            sz: Vec2i { x: w, y: h },
+        }
+    }
+    pub fn save(&self, p: &std::path::Path) -> Result<()> {
+        let bytes: &[u8] = bytemuck::cast_slice(self.as_slice());
+        image_reading::save_buffer(
+            p,
+            bytes,
+            self.sz.x,
+            self.sz.y,
+            image_reading::ColorType::Rgba8,
+        )?;
+        Ok(())
+    }
+    /// Load an image file with its colors premultiplied by alpha, like
+    /// every texture that gets drawn blended.
+    pub fn from_file(p: &std::path::Path) -> Result<Self> {
+        Ok(Self::from_file_raw(p)?.premultiplied())
+    }
+    /// Load an image file as-is, for textures that hold data rather than
+    /// colors, like normal maps.
+    pub fn from_file_raw(p: &std::path::Path) -> Result<Self> {
+        Ok(Self::from_dynamic(image_reading::open(p)?))
+    }
+    /// Decode an image file that's already in memory, like a PNG embedded
+    /// in a .glb, taken as-is.
+    pub fn from_encoded(bytes: &[u8]) -> Result<Self> {
+        Ok(Self::from_dynamic(image_reading::load_from_memory(bytes)?))
+    }
+    fn from_dynamic(img: image_reading::DynamicImage) -> Self {
+        let img = img.into_rgba8();
+        Self::from_rgba8(img.width(), img.height(), img.as_raw())
+    }
+    /// Multiply every pixel's color by its alpha.
+    pub fn premultiplied(mut self) -> Self {
+        for px in self.buffer.iter_mut() {
+            let a = px.3 as f32 / 255.0;
+            let r = (px.0 as f32 * a) as u8;
+            let g = (px.1 as f32 * a) as u8;
+            let b = (px.2 as f32 * a) as u8;
+            *px = Color(r, g, b, px.3);
+        }
+        self
     }
 }
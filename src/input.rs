@@ -1,28 +1,59 @@
+use crate::gamepad::{PadAxis, PadButton, PadId, PadState};
 use crate::image::Vec2i;
+use crate::types::Vec2;
+use serde::{Deserialize, Serialize};
 use winit::event::ElementState;
+use winit::event::ModifiersState;
+use winit::event::MouseButton;
+use winit::event::MouseScrollDelta;
 use winit::event::VirtualKeyCode;
-const MOUSE_MOVE_SCALE: f32 = 2.;
+use std::collections::BTreeMap;
+// room for every VirtualKeyCode
+const KEY_COUNT: usize = 255;
+// left, right, middle, and a few extra buttons
+const MOUSE_BUTTONS: usize = 16;
+// trackpads scroll in pixels, wheels in lines; count this many pixels as a line
+const PIXELS_PER_LINE: f32 = 20.0;
 
-#[derive(Debug, Clone)]
+// Serializable so recordings can store a snapshot per tick.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Input {
+    #[serde(serialize_with = "held::serialize")]
+    #[serde(deserialize_with = "held::deserialize::<_, KEY_COUNT>")]
     now_keys: Box<[bool]>,
+    #[serde(serialize_with = "held::serialize")]
+    #[serde(deserialize_with = "held::deserialize::<_, KEY_COUNT>")]
     prev_keys: Box<[bool]>,
-    mouse_down: bool,
-    prev_mouse_down: bool,
+    #[serde(serialize_with = "held::serialize")]
+    #[serde(deserialize_with = "held::deserialize::<_, MOUSE_BUTTONS>")]
+    now_buttons: Box<[bool]>,
+    #[serde(serialize_with = "held::serialize")]
+    #[serde(deserialize_with = "held::deserialize::<_, MOUSE_BUTTONS>")]
+    prev_buttons: Box<[bool]>,
     mouse_position: winit::dpi::PhysicalPosition<f64>,
     prev_mouse_position: winit::dpi::PhysicalPosition<f64>,
-    mouse_delta: (f64,f64)
+    mouse_delta: (f64,f64),
+    scroll_delta: Vec2,
+    modifiers: ModifiersState,
+    chars: String,
+    pads: BTreeMap<PadId, PadState>,
+    prev_pads: BTreeMap<PadId, PadState>,
 }
 impl Input {
     pub(crate) fn new() -> Self {
         Self {
-            now_keys: vec![false; 255].into_boxed_slice(),
-            prev_keys: vec![false; 255].into_boxed_slice(),
-            mouse_down: false,
-            prev_mouse_down: false,
+            now_keys: vec![false; KEY_COUNT].into_boxed_slice(),
+            prev_keys: vec![false; KEY_COUNT].into_boxed_slice(),
+            now_buttons: vec![false; MOUSE_BUTTONS].into_boxed_slice(),
+            prev_buttons: vec![false; MOUSE_BUTTONS].into_boxed_slice(),
             mouse_position: winit::dpi::PhysicalPosition { x: 0.0, y: 0.0 },
             prev_mouse_position: winit::dpi::PhysicalPosition { x: 0.0, y: 0.0 },
-            mouse_delta: (0.,0.)
+            mouse_delta: (0.,0.),
+            scroll_delta: Vec2::zero(),
+            modifiers: ModifiersState::empty(),
+            chars: String::new(),
+            pads: BTreeMap::new(),
+            prev_pads: BTreeMap::new(),
         }
     }
     pub fn is_key_down(&self, kc: VirtualKeyCode) -> bool {
@@ -37,11 +68,112 @@ impl Input {
     pub fn is_key_released(&self, kc: VirtualKeyCode) -> bool {
         !self.now_keys[kc as usize] && self.prev_keys[kc as usize]
     }
+    pub fn is_mouse_button_down(&self, mb: MouseButton) -> bool {
+        button_index(mb).map(|i| self.now_buttons[i]).unwrap_or(false)
+    }
+    pub fn is_mouse_button_up(&self, mb: MouseButton) -> bool {
+        !self.is_mouse_button_down(mb)
+    }
+    pub fn is_mouse_button_pressed(&self, mb: MouseButton) -> bool {
+        button_index(mb)
+            .map(|i| self.now_buttons[i] && !self.prev_buttons[i])
+            .unwrap_or(false)
+    }
+    pub fn is_mouse_button_released(&self, mb: MouseButton) -> bool {
+        button_index(mb)
+            .map(|i| !self.now_buttons[i] && self.prev_buttons[i])
+            .unwrap_or(false)
+    }
+    // shorthands for the left button
+    pub fn is_mouse_down(&self) -> bool {
+        self.is_mouse_button_down(MouseButton::Left)
+    }
+    pub fn is_mouse_pressed(&self) -> bool {
+        self.is_mouse_button_pressed(MouseButton::Left)
+    }
+    pub fn is_mouse_released(&self) -> bool {
+        self.is_mouse_button_released(MouseButton::Left)
+    }
+    /// How far the wheel scrolled this frame, in lines; positive y is away
+    /// from the user.
+    pub fn get_scroll_delta(&self) -> Vec2 {
+        self.scroll_delta
+    }
+    pub fn get_modifiers(&self) -> ModifiersState {
+        self.modifiers
+    }
+    pub fn is_shift_down(&self) -> bool {
+        self.modifiers.shift()
+    }
+    pub fn is_ctrl_down(&self) -> bool {
+        self.modifiers.ctrl()
+    }
+    pub fn is_alt_down(&self) -> bool {
+        self.modifiers.alt()
+    }
+    pub fn is_logo_down(&self) -> bool {
+        self.modifiers.logo()
+    }
+    /// Text typed this frame, in order, with control characters left out.
+    pub fn get_chars(&self) -> &str {
+        &self.chars
+    }
+    pub fn pad_ids(&self) -> impl Iterator<Item = PadId> + '_ {
+        self.pads.keys().copied()
+    }
+    pub fn is_pad_connected(&self, id: PadId) -> bool {
+        self.pads.contains_key(&id)
+    }
+    /// Pads plugged in since the last frame.
+    pub fn pads_connected(&self) -> impl Iterator<Item = PadId> + '_ {
+        self.pad_ids().filter(|id| !self.prev_pads.contains_key(id))
+    }
+    /// Pads unplugged since the last frame.
+    pub fn pads_disconnected(&self) -> impl Iterator<Item = PadId> + '_ {
+        self.prev_pads
+            .keys()
+            .copied()
+            .filter(|id| !self.pads.contains_key(id))
+    }
+    pub fn get_pad(&self, id: PadId) -> Option<&PadState> {
+        self.pads.get(&id)
+    }
+    // the pad queries below look at every connected pad
+    pub fn is_pad_button_down(&self, b: PadButton) -> bool {
+        self.pads.values().any(|p| p.is_down(b))
+    }
+    pub fn is_pad_button_pressed(&self, b: PadButton) -> bool {
+        self.pads.iter().any(|(id, p)| {
+            p.is_down(b) && !self.prev_pads.get(id).map(|pp| pp.is_down(b)).unwrap_or(false)
+        })
+    }
+    pub fn is_pad_button_released(&self, b: PadButton) -> bool {
+        self.prev_pads.iter().any(|(id, pp)| {
+            pp.is_down(b) && !self.pads.get(id).map(|p| p.is_down(b)).unwrap_or(false)
+        })
+    }
+    /// `a`, past the dead zone, on whichever pad has it pushed furthest.
+    pub fn get_pad_axis(&self, a: PadAxis) -> f32 {
+        self.pads
+            .values()
+            .map(|p| p.axis(a))
+            .fold(0.0, |best, v| if v.abs() > best.abs() { v } else { best })
+    }
+    /// `get_pad_axis` as of the last frame.
+    pub fn get_prev_pad_axis(&self, a: PadAxis) -> f32 {
+        self.prev_pads
+            .values()
+            .map(|p| p.axis(a))
+            .fold(0.0, |best, v| if v.abs() > best.abs() { v } else { best })
+    }
     pub(crate) fn next_frame(&mut self) {
         self.prev_keys.copy_from_slice(&self.now_keys);
-        self.prev_mouse_down = self.mouse_down;
+        self.prev_buttons.copy_from_slice(&self.now_buttons);
         self.prev_mouse_position = self.mouse_position;
-        self.mouse_delta = (0.,0.)
+        self.mouse_delta = (0.,0.);
+        self.scroll_delta = Vec2::zero();
+        self.chars.clear();
+        self.prev_pads.clone_from(&self.pads);
     }
     pub(crate) fn handle_key_event(&mut self, ke: winit::event::KeyboardInput) {
         if let winit::event::KeyboardInput {
@@ -50,25 +182,48 @@ impl Input {
             ..
         } = ke
         {
-            match state {
-                winit::event::ElementState::Pressed => {
-                    self.now_keys[keycode as usize] = true;
-                }
-                winit::event::ElementState::Released => {
-                    self.now_keys[keycode as usize] = false;
-                }
-            }
+            self.set_key(keycode, state == ElementState::Pressed);
+        }
+    }
+    pub(crate) fn handle_mouse_event(&mut self, mb: MouseButton, ms: ElementState) {
+        self.set_mouse_button(mb, ms == ElementState::Pressed);
+    }
+    /// Hold or let go of `kc` without a window, e.g. in a `Simulation`.
+    pub fn set_key(&mut self, kc: VirtualKeyCode, down: bool) {
+        self.now_keys[kc as usize] = down;
+    }
+    pub fn set_mouse_button(&mut self, mb: MouseButton, down: bool) {
+        if let Some(i) = button_index(mb) {
+            self.now_buttons[i] = down;
         }
     }
-    pub(crate) fn handle_left_mouse_event(&mut self, ms: ElementState) {
-        self.mouse_down = ms == ElementState::Pressed;
+    pub(crate) fn handle_scroll_event(&mut self, delta: MouseScrollDelta) {
+        self.scroll_delta += match delta {
+            MouseScrollDelta::LineDelta(x, y) => Vec2::new(x, y),
+            MouseScrollDelta::PixelDelta(p) => {
+                Vec2::new(p.x as f32, p.y as f32) / PIXELS_PER_LINE
+            }
+        };
+    }
+    pub(crate) fn handle_modifiers_event(&mut self, modifiers: ModifiersState) {
+        self.modifiers = modifiers;
+    }
+    pub(crate) fn handle_pads(&mut self, pads: Vec<(PadId, PadState)>) {
+        self.pads = pads.into_iter().collect();
+    }
+    pub(crate) fn handle_char_event(&mut self, c: char) {
+        if !c.is_control() {
+            self.chars.push(c);
+        }
     }
     pub(crate) fn handle_cursor_moved_event(&mut self, cp: winit::dpi::PhysicalPosition<f64>) {
         self.mouse_position = cp;
     }
 
     pub(crate) fn handle_cursor_motion(&mut self, delta: (f64,f64)) {
-        self.mouse_delta = delta;
+        // there can be several motion events per frame
+        self.mouse_delta.0 += delta.0;
+        self.mouse_delta.1 += delta.1;
     }
 
     pub fn get_mouse_position(&self) -> winit::dpi::PhysicalPosition<f64> {
@@ -83,8 +238,144 @@ impl Input {
         winit::dpi::PhysicalPosition { x: self.mouse_delta.0, y: self.mouse_delta.1 }
     }
 
-    pub(crate) fn get_mouse_move_scale() -> f32 {
-        MOUSE_MOVE_SCALE
+}
+
+fn button_index(mb: MouseButton) -> Option<usize> {
+    let i = match mb {
+        MouseButton::Left => 0,
+        MouseButton::Right => 1,
+        MouseButton::Middle => 2,
+        MouseButton::Other(n) => 3 + n as usize,
+    };
+    (i < MOUSE_BUTTONS).then(|| i)
+}
+
+// Key and button arrays are stored as the indices of whatever's held,
+// which is almost always nothing, and read back at their full length `LEN`.
+mod held {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    pub fn serialize<S: Serializer>(v: &[bool], s: S) -> Result<S::Ok, S::Error> {
+        let held: Vec<u16> = v
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| **d)
+            .map(|(i, _)| i as u16)
+            .collect();
+        held.serialize(s)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>, const LEN: usize>(
+        d: D,
+    ) -> Result<Box<[bool]>, D::Error> {
+        let held = Vec::<u16>::deserialize(d)?;
+        let mut v = vec![false; LEN];
+        for i in held {
+            *v.get_mut(i as usize)
+                .ok_or_else(|| D::Error::custom(format!("{} is out of range", i)))? = true;
+        }
+        Ok(v.into_boxed_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &Input) -> Input {
+        ron::from_str(&ron::to_string(input).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn buttons_press_hold_and_release() {
+        let mut input = Input::new();
+        input.set_mouse_button(MouseButton::Left, true);
+        input.set_mouse_button(MouseButton::Middle, true);
+        assert!(input.is_mouse_pressed() && input.is_mouse_down());
+        assert!(input.is_mouse_button_pressed(MouseButton::Middle));
+        assert!(input.is_mouse_button_up(MouseButton::Right));
+        input.next_frame();
+        // still held, but no longer just pressed
+        assert!(input.is_mouse_down() && !input.is_mouse_pressed());
+        input.set_mouse_button(MouseButton::Left, false);
+        assert!(input.is_mouse_released());
+        assert!(!input.is_mouse_button_released(MouseButton::Middle));
+        assert!(input.is_mouse_button_down(MouseButton::Middle));
+        input.next_frame();
+        assert!(!input.is_mouse_released() && input.is_mouse_button_up(MouseButton::Left));
+        // buttons past the last one are never down
+        input.set_mouse_button(MouseButton::Other(200), true);
+        assert!(!input.is_mouse_button_down(MouseButton::Other(200)));
+    }
+
+    #[test]
+    fn scroll_adds_up_in_lines() {
+        let mut input = Input::new();
+        input.handle_scroll_event(MouseScrollDelta::LineDelta(0.0, 1.0));
+        input.handle_scroll_event(MouseScrollDelta::PixelDelta(
+            winit::dpi::PhysicalPosition::new(f64::from(PIXELS_PER_LINE), -10.0),
+        ));
+        assert_eq!(input.get_scroll_delta(), Vec2::new(1.0, 0.5));
+        input.next_frame();
+        assert_eq!(input.get_scroll_delta(), Vec2::zero());
+    }
+
+    #[test]
+    fn modifiers_stay_until_changed() {
+        let mut input = Input::new();
+        input.handle_modifiers_event(ModifiersState::SHIFT | ModifiersState::CTRL);
+        input.next_frame();
+        assert!(input.is_shift_down() && input.is_ctrl_down());
+        assert!(!input.is_alt_down() && !input.is_logo_down());
+        input.handle_modifiers_event(ModifiersState::ALT);
+        assert_eq!(input.get_modifiers(), ModifiersState::ALT);
+        assert!(!input.is_shift_down() && input.is_alt_down());
+    }
+
+    #[test]
+    fn chars_leave_out_control_characters() {
+        let mut input = Input::new();
+        for c in ['h', '\u{8}', 'é', '\r', '!'] {
+            input.handle_char_event(c);
+        }
+        assert_eq!(input.get_chars(), "hé!");
+        input.next_frame();
+        assert_eq!(input.get_chars(), "");
     }
 
+    #[test]
+    fn serde_round_trip() {
+        let mut input = Input::new();
+        input.set_key(VirtualKeyCode::W, true);
+        input.set_mouse_button(MouseButton::Right, true);
+        input.next_frame();
+        input.handle_scroll_event(MouseScrollDelta::LineDelta(0.0, -2.0));
+        input.handle_modifiers_event(ModifiersState::SHIFT);
+        input.handle_char_event('q');
+        input.set_key(VirtualKeyCode::W, false);
+        input.set_mouse_button(MouseButton::Other(4), true);
+        let back = round_trip(&input);
+        assert_eq!(back.now_keys.len(), KEY_COUNT);
+        assert_eq!(back.prev_keys.len(), KEY_COUNT);
+        assert_eq!(back.now_buttons.len(), MOUSE_BUTTONS);
+        assert_eq!(back.prev_buttons.len(), MOUSE_BUTTONS);
+        assert!(back.is_key_released(VirtualKeyCode::W));
+        assert!(back.is_mouse_button_down(MouseButton::Right));
+        assert!(back.is_mouse_button_pressed(MouseButton::Other(4)));
+        assert_eq!(back.get_scroll_delta(), Vec2::new(0.0, -2.0));
+        assert!(back.is_shift_down());
+        assert_eq!(back.get_chars(), "q");
+        // a frame later it behaves just like the original
+        let mut input = back;
+        input.next_frame();
+        assert!(!input.is_key_released(VirtualKeyCode::W));
+        assert!(!input.is_mouse_button_pressed(MouseButton::Other(4)));
+    }
+
+    #[test]
+    fn rejects_buttons_out_of_range() {
+        let text = ron::to_string(&Input::new()).unwrap();
+        let bad = text.replacen("now_buttons:[]", "now_buttons:[16]", 1);
+        assert_ne!(bad, text);
+        assert!(ron::from_str::<Input>(&bad).is_err());
+    }
 }
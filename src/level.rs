@@ -0,0 +1,892 @@
+//! Levels are described by RON files in `content/levels`.  A level lists the
+//! models it uses by name, the rooms (and which room each one sits inside),
+//! the clues and not-clues to find, any scenery, and where the player starts.
+//! See `content/levels/livingroom.ron` for an example.
+use crate::animation;
+use crate::collision;
+use crate::cutscene;
+use crate::assets::{Assets, ImportSettings};
+use crate::ecs;
+use crate::renderer;
+use crate::types::*;
+use crate::scene::{self, Drawable};
+use crate::{GameObject, GameState, GameStuff, START_ROOM};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+
+// the player is always object 0
+pub const PLAYER_ID: usize = 0;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Volume {
+    // minimum corner
+    pub pos: (f32, f32, f32),
+    pub size: (f32, f32, f32),
+}
+impl Volume {
+    pub fn prism(&self) -> RPrism {
+        RPrism {
+            pos: self.pos.into(),
+            sz: self.size.into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum ModelDesc {
+    // `node_transforms: false` leaves every mesh where it is in its own
+    // space, ignoring where the file's nodes put it
+    Flat {
+        path: String,
+        #[serde(default)]
+        lods: Vec<LodDesc>,
+        #[serde(default = "default_node_transforms")]
+        node_transforms: bool,
+    },
+    // diffuse textures to use instead of the file's: one per mesh, or a
+    // single texture shared by every mesh
+    Textured {
+        path: String,
+        #[serde(default)]
+        textures: Vec<String>,
+        #[serde(default)]
+        lods: Vec<LodDesc>,
+        #[serde(default = "default_node_transforms")]
+        node_transforms: bool,
+    },
+    Skinned {
+        path: String,
+        node_root: Vec<String>,
+        textures: Vec<String>,
+        // empty for the file's first animation
+        #[serde(default)]
+        animation: String,
+        #[serde(default = "default_looping")]
+        looping: bool,
+    },
+}
+fn default_looping() -> bool {
+    true
+}
+fn default_node_transforms() -> bool {
+    true
+}
+impl ModelDesc {
+    fn paths(&self) -> impl Iterator<Item = &String> {
+        let (path, textures, lods): (&String, &[String], &[LodDesc]) = match self {
+            ModelDesc::Flat { path, lods, .. } => (path, &[], lods),
+            ModelDesc::Textured {
+                path,
+                textures,
+                lods,
+                ..
+            } => (path, textures, lods),
+            ModelDesc::Skinned { path, textures, .. } => (path, textures, &[]),
+        };
+        std::iter::once(path)
+            .chain(textures.iter())
+            .chain(lods.iter().filter_map(|l| match &l.source {
+                LodSource::File(p) => Some(p),
+                LodSource::Decimate(_) => None,
+            }))
+    }
+    fn lods(&self) -> &[LodDesc] {
+        match self {
+            ModelDesc::Flat { lods, .. } | ModelDesc::Textured { lods, .. } => lods,
+            ModelDesc::Skinned { .. } => &[],
+        }
+    }
+}
+
+/// A coarser version of a model, drawn in its place past `threshold`.  List
+/// them from finest to coarsest.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LodDesc {
+    pub threshold: renderer::lod::Threshold,
+    pub source: LodSource,
+}
+#[derive(Deserialize, Debug, Clone)]
+pub enum LodSource {
+    // a model file of the same kind, with its own materials
+    File(String),
+    // the full detail model simplified to about this fraction of its
+    // vertices
+    Decimate(f32),
+}
+
+/// An instance of a named model.  `rot` is in degrees about the x, y and z
+/// axes, applied in that order.  A placement with a `parent` is positioned
+/// relative to the earlier placement with that `name`, and moves with it.
+/// A placement with a `node` draws only that node of the model file and the
+/// nodes under it, where they'd be in the whole model.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Placement {
+    pub model: String,
+    #[serde(default)]
+    pub node: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub pos: (f32, f32, f32),
+    #[serde(default)]
+    pub rot: (f32, f32, f32),
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    // only flat models without a parent can be collided with
+    #[serde(default)]
+    pub collide: bool,
+}
+fn default_scale() -> f32 {
+    1.0
+}
+impl Placement {
+    pub fn transform(&self) -> Similarity3 {
+        let (x, y, z) = self.rot;
+        let rot = Rotor3::from_rotation_xy(z.to_radians())
+            * Rotor3::from_rotation_xz(y.to_radians())
+            * Rotor3::from_rotation_yz(x.to_radians());
+        Similarity3::new(self.pos.into(), rot, self.scale)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoomDesc {
+    pub id: usize,
+    #[serde(default)]
+    pub container: Option<usize>,
+    pub volume: Volume,
+}
+
+/// A clue or not-clue: something the player can photograph.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ThingDesc {
+    pub id: usize,
+    pub volume: Volume,
+    #[serde(default)]
+    pub model: Option<Placement>,
+    // a cutscene file to play when this is photographed (clues only)
+    #[serde(default)]
+    pub reveal: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Spawn {
+    // where the player's feet start
+    pub pos: (f32, f32, f32),
+    #[serde(default)]
+    pub deg: (f32, f32),
+}
+
+/// A light in the level.  Colors are RGB and can go above 1 for brighter
+/// lights.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum LightDesc {
+    // shining along `dir` everywhere, like the sun
+    Directional {
+        dir: (f32, f32, f32),
+        color: (f32, f32, f32),
+        #[serde(default)]
+        shadow: bool,
+    },
+    // shining out from `pos`, fading to nothing `range` units away
+    Point {
+        pos: (f32, f32, f32),
+        color: (f32, f32, f32),
+        range: f32,
+    },
+    // a point light shining within `angle` degrees of `dir`
+    Spot {
+        pos: (f32, f32, f32),
+        dir: (f32, f32, f32),
+        color: (f32, f32, f32),
+        range: f32,
+        angle: f32,
+        #[serde(default)]
+        shadow: bool,
+    },
+}
+impl LightDesc {
+    pub fn light(&self) -> renderer::lights::Light {
+        match *self {
+            LightDesc::Directional { dir, color, shadow } => {
+                renderer::lights::Light::Directional {
+                    dir: dir.into(),
+                    color: color.into(),
+                    shadow,
+                }
+            }
+            LightDesc::Point { pos, color, range } => renderer::lights::Light::Point {
+                pos: pos.into(),
+                color: color.into(),
+                range,
+            },
+            LightDesc::Spot {
+                pos,
+                dir,
+                color,
+                range,
+                angle,
+                shadow,
+            } => renderer::lights::Light::Spot {
+                pos: pos.into(),
+                dir: dir.into(),
+                color: color.into(),
+                range,
+                angle: angle.to_radians(),
+                shadow,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Level {
+    pub goal_clues: usize,
+    pub spawn: Spawn,
+    #[serde(default)]
+    pub models: BTreeMap<String, ModelDesc>,
+    #[serde(default)]
+    pub rooms: Vec<RoomDesc>,
+    #[serde(default)]
+    pub clues: Vec<ThingDesc>,
+    #[serde(default)]
+    pub not_clues: Vec<ThingDesc>,
+    #[serde(default)]
+    pub scenery: Vec<Placement>,
+    // a cutscene file to play when the level starts
+    #[serde(default)]
+    pub intro: Option<String>,
+    // white ambient and no lights leaves everything unlit
+    #[serde(default = "default_ambient")]
+    pub ambient: (f32, f32, f32),
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    // cubemap faces (+x, -x, +y, -y, +z, -z) that ambient light and
+    // reflections come from, tinted by `ambient`; none for plain white
+    #[serde(default)]
+    pub environment: Vec<String>,
+}
+fn default_ambient() -> (f32, f32, f32) {
+    (1.0, 1.0, 1.0)
+}
+
+enum Loaded {
+    Flat(Rc<crate::renderer::flat::Model>),
+    Textured(Rc<crate::renderer::textured::Model>),
+    Skinned(Rc<crate::renderer::skinned::Model>, crate::assets::AnimRef),
+}
+
+impl Level {
+    /// Read and validate a level file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read level {:?}", path))?;
+        let level: Level =
+            ron::from_str(&text).wrap_err_with(|| format!("Couldn't parse level {:?}", path))?;
+        level
+            .validate()
+            .wrap_err_with(|| format!("Invalid level {:?}", path))?;
+        Ok(level)
+    }
+
+    /// Check everything that can be checked without loading any assets,
+    /// reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        for (name, desc) in self.models.iter() {
+            for p in desc.paths() {
+                if !Path::new(p).exists() {
+                    problems.push(format!("model {:?}: unknown asset path {:?}", name, p));
+                }
+            }
+            let no_textures = match desc {
+                ModelDesc::Flat { .. } | ModelDesc::Textured { .. } => false,
+                ModelDesc::Skinned { textures, .. } => textures.is_empty(),
+            };
+            if no_textures {
+                problems.push(format!("model {:?}: needs at least one texture", name));
+            }
+            for lod in desc.lods() {
+                if let LodSource::Decimate(ratio) = lod.source {
+                    if !(ratio > 0.0 && ratio <= 1.0) {
+                        problems.push(format!(
+                            "model {:?}: decimation ratio {} isn't in (0, 1]",
+                            name, ratio
+                        ));
+                    }
+                }
+            }
+        }
+        let placements = self.scenery.iter().chain(
+            self.clues
+                .iter()
+                .chain(self.not_clues.iter())
+                .filter_map(|t| t.model.as_ref()),
+        );
+        // earlier placements' names, and the ones in the scene graph that
+        // can be parents
+        let mut names = HashSet::new();
+        let mut parents = HashSet::new();
+        for p in placements {
+            match self.models.get(&p.model) {
+                None => problems.push(format!("unknown model {:?}", p.model)),
+                Some(ModelDesc::Flat { .. }) => {}
+                Some(_) if p.collide => problems.push(format!(
+                    "model {:?}: only flat models can collide",
+                    p.model
+                )),
+                Some(_) => {}
+            }
+            if p.collide && p.parent.is_some() {
+                // its collider would stay put when its parent moved
+                problems.push(format!(
+                    "model {:?}: placements with a parent can't collide",
+                    p.model
+                ));
+            }
+            let in_scene = !matches!(self.models.get(&p.model), Some(ModelDesc::Skinned { .. }));
+            if !in_scene && p.node.is_some() {
+                problems.push(format!(
+                    "model {:?}: skinned models can't be split into nodes",
+                    p.model
+                ));
+            }
+            if let Some(parent) = p.parent.as_ref() {
+                if !in_scene {
+                    problems.push(format!(
+                        "model {:?}: skinned models can't have parents",
+                        p.model
+                    ));
+                } else if !parents.contains(parent.as_str()) {
+                    problems.push(format!(
+                        "model {:?}: parent {:?} isn't an earlier flat or textured placement",
+                        p.model, parent
+                    ));
+                }
+            }
+            if let Some(name) = p.name.as_ref() {
+                if !names.insert(name.as_str()) {
+                    problems.push(format!("duplicate placement name {:?}", name));
+                }
+                if in_scene {
+                    parents.insert(name.as_str());
+                }
+            }
+        }
+        let mut ids = HashSet::from([PLAYER_ID]);
+        let all_ids = self
+            .rooms
+            .iter()
+            .map(|r| r.id)
+            .chain(self.clues.iter().map(|c| c.id))
+            .chain(self.not_clues.iter().map(|c| c.id));
+        for id in all_ids {
+            if id == PLAYER_ID {
+                problems.push(format!("id {} is reserved for the player", id));
+            } else if !ids.insert(id) {
+                problems.push(format!("duplicate id {}", id));
+            }
+        }
+        for r in self.rooms.iter() {
+            match r.container.map(|c| self.rooms.iter().find(|o| o.id == c)) {
+                None => {}
+                Some(None) => problems.push(format!(
+                    "room {}: container {} is not a room",
+                    r.id,
+                    r.container.unwrap()
+                )),
+                Some(Some(c)) if !c.volume.prism().contains(r.volume.prism()) => problems.push(
+                    format!("room {}: doesn't fit inside its container {}", r.id, c.id),
+                ),
+                Some(Some(_)) => {}
+            }
+        }
+        for (kind, things) in [("clue", &self.clues), ("not-clue", &self.not_clues)] {
+            for t in things.iter() {
+                if self.innermost_room(t.volume.prism()).is_none() {
+                    problems.push(format!("{} {}: outside every room", kind, t.id));
+                }
+            }
+        }
+        for t in self.not_clues.iter().filter(|t| t.reveal.is_some()) {
+            problems.push(format!("not-clue {}: only clues can have a reveal", t.id));
+        }
+        let cutscenes = self
+            .clues
+            .iter()
+            .filter_map(|t| t.reveal.as_ref())
+            .chain(self.intro.iter());
+        for p in cutscenes {
+            if !Path::new(p).exists() {
+                problems.push(format!("unknown cutscene path {:?}", p));
+            }
+        }
+        if !matches!(self.environment.len(), 0 | 6) {
+            problems.push(format!(
+                "environment has {} faces instead of 6",
+                self.environment.len()
+            ));
+        }
+        for p in self.environment.iter() {
+            if !Path::new(p).exists() {
+                problems.push(format!("unknown environment path {:?}", p));
+            }
+        }
+        if self.lights.len() > renderer::lights::MAX_LIGHTS {
+            problems.push(format!(
+                "{} lights, but only {} can be used",
+                self.lights.len(),
+                renderer::lights::MAX_LIGHTS
+            ));
+        }
+        for (i, l) in self.lights.iter().enumerate() {
+            match *l {
+                LightDesc::Point { range, .. } | LightDesc::Spot { range, .. } if range <= 0.0 => {
+                    problems.push(format!("light {}: range has to be positive", i))
+                }
+                LightDesc::Directional { dir: (0.0, 0.0, 0.0), .. }
+                | LightDesc::Spot { dir: (0.0, 0.0, 0.0), .. } => {
+                    problems.push(format!("light {}: dir can't be zero", i))
+                }
+                LightDesc::Spot { angle, .. } if !(angle > 0.0 && angle < 90.0) => problems.push(
+                    format!("light {}: angle has to be between 0 and 90 degrees", i),
+                ),
+                _ => {}
+            }
+        }
+        let shadows = self
+            .lights
+            .iter()
+            .filter(|l| {
+                matches!(
+                    l,
+                    LightDesc::Directional { shadow: true, .. } | LightDesc::Spot { shadow: true, .. }
+                )
+            })
+            .count();
+        if shadows > renderer::lights::MAX_SHADOWS {
+            problems.push(format!(
+                "{} lights cast shadows, but only {} can",
+                shadows,
+                renderer::lights::MAX_SHADOWS
+            ));
+        }
+        if !self.rooms.is_empty() && self.spawn_room().is_none() {
+            problems.push("spawn point is outside every room".to_string());
+        }
+        if self.goal_clues > self.clues.len() {
+            problems.push(format!(
+                "goal_clues is {} but there are only {} clues",
+                self.goal_clues,
+                self.clues.len()
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!(
+                "{} problem(s):\n  {}",
+                problems.len(),
+                problems.join("\n  ")
+            ))
+        }
+    }
+
+    /// The smallest room that entirely contains `vol`.
+    pub fn innermost_room(&self, vol: RPrism) -> Option<usize> {
+        self.smallest_room(|r| r.contains(vol))
+    }
+    fn spawn_room(&self) -> Option<usize> {
+        let spawn = Vec3::from(self.spawn.pos);
+        self.smallest_room(|r| r.contains_point(spawn))
+    }
+    fn smallest_room(&self, pred: impl Fn(&RPrism) -> bool) -> Option<usize> {
+        self.rooms
+            .iter()
+            .map(|r| (r.id, r.volume.prism()))
+            .filter(|(_, r)| pred(r))
+            .map(|(id, r)| (id, r.sz.x * r.sz.y * r.sz.z))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(id, _)| id)
+    }
+
+    /// Load the level's assets and set up a game in it.  This doesn't need a
+    /// GPU; give it `Engine::assets_mut` to play the level, or a fresh
+    /// `Assets` to simulate it headlessly.
+    pub fn build(&self, assets: &mut Assets) -> Result<GameState> {
+        if !self.environment.is_empty() {
+            let faces: Vec<&Path> = self.environment.iter().map(Path::new).collect();
+            assets.load_environment(&faces).wrap_err("environment")?;
+        }
+        let mut models = HashMap::new();
+        for (name, desc) in self.models.iter() {
+            let loaded =
+                Self::load_model(assets, desc).wrap_err_with(|| format!("model {:?}", name))?;
+            models.insert(name.as_str(), loaded);
+        }
+        let mut stuff = GameStuff {
+            world: ecs::World::new(),
+            scene: scene::Scene::new(),
+            things: vec![],
+            colliders: collision::Colliders::new(),
+        };
+        // and the id of the clue or not-clue each one belongs to
+        let placements = self.scenery.iter().map(|p| (None, p)).chain(
+            self.clues
+                .iter()
+                .chain(self.not_clues.iter())
+                .filter_map(|t| Some((Some(t.id), t.model.as_ref()?))),
+        );
+        let mut nodes = HashMap::new();
+        let mut thing_nodes = HashMap::new();
+        for (thing, p) in placements {
+            let trf = p.transform();
+            let parent = p.parent.as_ref().map(|name| nodes[name.as_str()]);
+            let no_node = |n: &String| eyre!("model {:?} has no node {:?}", p.model, n);
+            let node = match &models[p.model.as_str()] {
+                Loaded::Flat(model) => {
+                    let model = match p.node.as_ref() {
+                        Some(n) => Rc::new(model.part(n).ok_or_else(|| no_node(n))?),
+                        None => model.clone(),
+                    };
+                    let node = stuff.scene.add(Drawable::Flat(model.clone()), trf, parent);
+                    if p.collide {
+                        // where it starts out; colliders don't follow the
+                        // scene graph
+                        stuff
+                            .colliders
+                            .add_mesh(collision::TriMesh::from_flat_model(
+                                &model,
+                                assets,
+                                stuff.scene.world(node),
+                            ));
+                    }
+                    node
+                }
+                Loaded::Textured(model) => {
+                    let model = match p.node.as_ref() {
+                        Some(n) => Rc::new(model.part(n).ok_or_else(|| no_node(n))?),
+                        None => model.clone(),
+                    };
+                    stuff.scene.add(Drawable::Textured(model), trf, parent)
+                }
+                Loaded::Skinned(model, animation) => {
+                    stuff.things.push(GameObject {
+                        trf,
+                        model: model.clone(),
+                        animation: *animation,
+                        state: animation::AnimationState { t: 0.0 },
+                    });
+                    continue;
+                }
+            };
+            if let Some(name) = p.name.as_ref() {
+                nodes.insert(name.as_str(), node);
+            }
+            if let Some(id) = thing {
+                thing_nodes.insert(id, node);
+            }
+        }
+        let world = &mut stuff.world;
+        let mut rooms = HashMap::new();
+        for r in self.rooms.iter() {
+            let e = Self::spawn(world, r.id, r.volume.prism());
+            world.rooms.insert(e, ecs::Room::default());
+            rooms.insert(r.id, e);
+        }
+        for r in self.rooms.iter() {
+            let container = r.container.and_then(|c| rooms.get(&c).copied());
+            world
+                .containers
+                .insert(rooms[&r.id], ecs::Container(container));
+        }
+        let things = self.clues.iter().map(|t| (t, true));
+        let things = things.chain(self.not_clues.iter().map(|t| (t, false)));
+        for (t, is_clue) in things {
+            let vol = t.volume.prism();
+            let e = Self::spawn(world, t.id, vol);
+            let room = self
+                .innermost_room(vol)
+                .and_then(|r| rooms.get(&r).copied());
+            world.containers.insert(e, ecs::Container(room));
+            if is_clue {
+                world.clues.insert(e, ecs::Clue::default());
+            }
+            if let Some(&node) = thing_nodes.get(&t.id) {
+                // keep the model where the level put it relative to the
+                // volume, wherever the entity goes
+                let at = Similarity3::new(vol.pos, Rotor3::identity(), 1.0);
+                let offset = at.inversed() * stuff.scene.world(node);
+                world
+                    .renderables
+                    .insert(e, ecs::Renderable { node, offset });
+            }
+        }
+        let current_room = self.spawn_room().unwrap_or(START_ROOM);
+        let mut game = GameState::new(
+            stuff,
+            current_room,
+            self.goal_clues,
+            self.spawn.pos.into(),
+            self.spawn.deg,
+        );
+        // clues sharing a cutscene file share the track
+        let mut tracks: HashMap<String, Rc<cutscene::CameraTrack>> = HashMap::new();
+        let mut track = |path: &str| -> Result<Rc<cutscene::CameraTrack>> {
+            if let Some(t) = tracks.get(path) {
+                return Ok(t.clone());
+            }
+            let t = Rc::new(cutscene::CameraTrack::load(Path::new(path))?);
+            tracks.insert(path.to_string(), t.clone());
+            Ok(t)
+        };
+        for c in self.clues.iter() {
+            if let Some(path) = c.reveal.as_ref() {
+                game.reveals.insert(c.id, track(path)?);
+            }
+        }
+        if let Some(path) = self.intro.as_ref() {
+            game.play_cutscene(track(path)?);
+        }
+        game.lights = renderer::lights::Lights {
+            ambient: self.ambient.into(),
+            lights: self.lights.iter().map(LightDesc::light).collect(),
+        };
+        Ok(game)
+    }
+
+    // an entity with the level's `id`, taking up `vol`
+    fn spawn(world: &mut ecs::World, id: usize, vol: RPrism) -> ecs::Entity {
+        let e = world.spawn();
+        world.ids.insert(e, ecs::Id(id));
+        world.transforms.insert(e, ecs::Transform { pos: vol.pos });
+        world.volumes.insert(
+            e,
+            ecs::Volume(RPrism {
+                pos: Vec3::zero(),
+                sz: vol.sz,
+            }),
+        );
+        e
+    }
+
+    fn load_model(assets: &mut Assets, desc: &ModelDesc) -> Result<Loaded> {
+        match desc {
+            ModelDesc::Flat {
+                path,
+                lods,
+                node_transforms,
+            } => {
+                let settings = ImportSettings {
+                    node_transforms: *node_transforms,
+                };
+                let mut model = (*assets.load_flat_with(Path::new(path), settings)?).clone();
+                for lod in lods {
+                    let level = match &lod.source {
+                        LodSource::File(p) => {
+                            (*assets.load_flat_with(Path::new(p), settings)?).clone()
+                        }
+                        LodSource::Decimate(ratio) => assets.decimate_flat(&model, *ratio),
+                    };
+                    model = model.with_lod(lod.threshold, level);
+                }
+                Ok(Loaded::Flat(Rc::new(model)))
+            }
+            ModelDesc::Textured {
+                path,
+                textures,
+                lods,
+                node_transforms,
+            } => {
+                let settings = ImportSettings {
+                    node_transforms: *node_transforms,
+                };
+                let loaded = assets.load_textured_with(Path::new(path), settings)?;
+                let mut model = if textures.is_empty() {
+                    (*loaded).clone()
+                } else {
+                    let textures = Self::load_textures(assets, textures, loaded.meshes().len())?;
+                    let materials = loaded
+                        .materials()
+                        .iter()
+                        .zip(textures)
+                        .map(|(m, t)| assets.with_diffuse(*m, t))
+                        .collect();
+                    renderer::textured::Model::new(loaded.meshes().to_vec(), materials)
+                        .with_nodes(loaded.nodes().to_vec())
+                };
+                for lod in lods {
+                    let level = match &lod.source {
+                        LodSource::File(p) => {
+                            (*assets.load_textured_with(Path::new(p), settings)?).clone()
+                        }
+                        LodSource::Decimate(ratio) => assets.decimate_textured(&model, *ratio),
+                    };
+                    model = model.with_lod(lod.threshold, level);
+                }
+                Ok(Loaded::Textured(Rc::new(model)))
+            }
+            ModelDesc::Skinned {
+                path,
+                node_root,
+                textures,
+                animation,
+                looping,
+            } => {
+                let node_root: Vec<&str> = node_root.iter().map(|s| s.as_str()).collect();
+                let meshes = assets.load_skinned(Path::new(path), &node_root)?;
+                let anim = assets.load_anim(
+                    Path::new(path),
+                    meshes[0],
+                    animation::AnimationSettings { looping: *looping },
+                    animation,
+                )?;
+                let textures = Self::load_textures(assets, textures, meshes.len())?;
+                Ok(Loaded::Skinned(
+                    Rc::new(renderer::skinned::Model::new(meshes, textures)),
+                    anim,
+                ))
+            }
+        }
+    }
+
+    fn load_textures(
+        assets: &mut Assets,
+        paths: &[String],
+        mesh_count: usize,
+    ) -> Result<Vec<crate::assets::TextureRef>> {
+        let textures = paths
+            .iter()
+            .map(|p| assets.load_texture(Path::new(p)))
+            .collect::<Result<Vec<_>>>()?;
+        match textures.len() {
+            1 => Ok(vec![textures[0]; mesh_count]),
+            n if n == mesh_count => Ok(textures),
+            n => Err(eyre!(
+                "{} textures given for {} meshes; give one per mesh or one for all",
+                n,
+                mesh_count
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one room with one clue in it, and `extra` added to the level
+    fn level(extra: &str) -> Level {
+        ron::from_str(&format!(
+            r#"(
+                goal_clues: 1,
+                spawn: (pos: (1.0, 0.0, 1.0)),
+                models: {{ "cube": Flat(path: "content/cube.obj") }},
+                rooms: [(id: 1, volume: (pos: (0.0, 0.0, 0.0), size: (10.0, 5.0, 10.0)))],
+                clues: [(id: 2, volume: (pos: (4.0, 0.0, 4.0), size: (1.0, 1.0, 1.0)))],
+                {}
+            )"#,
+            extra
+        ))
+        .unwrap()
+    }
+    fn not_clue(id: usize, x: f32) -> String {
+        format!(
+            "not_clues: [(id: {}, volume: (pos: ({:?}, 0.0, 2.0), size: (1.0, 1.0, 1.0)))],",
+            id, x
+        )
+    }
+    fn problems(level: &Level) -> String {
+        level.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn valid_level_passes() {
+        level("").validate().unwrap();
+        Level::load(Path::new("content/levels/livingroom.ron")).unwrap();
+    }
+
+    #[test]
+    fn unknown_asset_path() {
+        let mut l = level("");
+        l.models.insert(
+            "missing".to_string(),
+            ModelDesc::Flat {
+                path: "content/missing.obj".to_string(),
+                lods: vec![],
+                node_transforms: true,
+            },
+        );
+        let p = problems(&l);
+        assert!(p.starts_with("1 problem(s)"), "{}", p);
+        assert!(
+            p.contains(r#"model "missing": unknown asset path "content/missing.obj""#),
+            "{}",
+            p
+        );
+    }
+
+    #[test]
+    fn duplicate_id() {
+        let l = level(&not_clue(1, 2.0));
+        let p = problems(&l);
+        assert!(p.contains("duplicate id 1"), "{}", p);
+        let l = level(&not_clue(0, 2.0));
+        let p = problems(&l);
+        assert!(p.contains("id 0 is reserved for the player"), "{}", p);
+    }
+
+    #[test]
+    fn clue_outside_every_room() {
+        let mut l = level("");
+        l.clues[0].volume.pos = (9.5, 0.0, 4.0);
+        let p = problems(&l);
+        assert!(p.contains("clue 2: outside every room"), "{}", p);
+    }
+
+    #[test]
+    fn parented_placements_cant_collide() {
+        let scenery = |collide: bool| {
+            format!(
+                r#"scenery: [
+                    (model: "cube", name: Some("table"), collide: true),
+                    (model: "cube", parent: Some("table"), collide: {}),
+                ],"#,
+                collide
+            )
+        };
+        level(&scenery(false)).validate().unwrap();
+        let p = problems(&level(&scenery(true)));
+        assert!(p.starts_with("1 problem(s)"), "{}", p);
+        assert!(
+            p.contains(r#"model "cube": placements with a parent can't collide"#),
+            "{}",
+            p
+        );
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut l = level(&not_clue(2, 20.0));
+        l.scenery.push(Placement {
+            model: "nothing".to_string(),
+            node: None,
+            name: None,
+            parent: None,
+            pos: (0.0, 0.0, 0.0),
+            rot: (0.0, 0.0, 0.0),
+            scale: 1.0,
+            collide: false,
+        });
+        let p = problems(&l);
+        assert!(p.starts_with("3 problem(s)"), "{}", p);
+        assert!(p.contains(r#"unknown model "nothing""#), "{}", p);
+        assert!(p.contains("duplicate id 2"), "{}", p);
+        assert!(p.contains("not-clue 2: outside every room"), "{}", p);
+    }
+}
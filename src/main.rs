@@ -11,12 +11,25 @@ use crate::camera::Camera;
 
 mod animation;
 mod assets;
+mod bindings;
 mod camera;
+mod collision;
+mod cutscene;
+mod ecs;
 mod engine;
+mod gamepad;
+mod golden;
 mod image;
 mod input;
+mod level;
 mod object;
+mod picking;
 mod renderer;
+mod replay;
+mod rooms;
+mod save;
+mod scene;
+mod simulation;
 mod types;
 mod vulkan;
 use object::*;
@@ -24,9 +37,11 @@ use std::rc::Rc;
 use types::*;
 
 const DT: f64 = 1.0 / 60.0;
-const GOAL_CLUES: usize = 10;
 const START_ROOM: usize = 0;
-const PLAYER_MOVE_SPD: f32 = 0.25;
+// units per second
+const PLAYER_MOVE_SPD: f32 = 15.0;
+const QUICKSAVE_SLOT: usize = 0;
+const LEVEL: &str = "content/levels/livingroom.ron";
 
 
 #[derive(Debug)]
@@ -40,8 +55,8 @@ struct GameObject {
     state: animation::AnimationState,
 }
 impl GameObject {
-    fn tick_animation(&mut self) {
-        self.state.tick(DT);
+    fn tick_animation(&mut self, dt: f64) {
+        self.state.tick(dt);
     }
 }
 struct Sprite {
@@ -50,39 +65,19 @@ struct Sprite {
     cel: Rect,
     size: Vec2,
 }
-struct Flat {
-    trf: Similarity3,
-    model: Rc<renderer::flat::Model>,
-}
-struct Textured {
-    trf: Similarity3,
-    model: Rc<renderer::textured::Model>,
-}
 
 pub struct GameStuff {
-    rooms: Vec<object::Room>,
-    objects: HashMap<usize, Box<dyn object::Object>>, //key usize is the object id; to get room call contiainer
-    //osborn has work arounds??? ^^^
-    textured: Vec<Textured>,
-    flats: Vec<Flat>,
-}
-impl GameStuff {
-    // fn new(rooms: Vec<object::Room>, objects: HashMap<usize, Box<dyn object::Object>>) -> GameStuff {
-    //     GameStuff { rooms, objects }
-    // }
-
-    // fn found_clue(&mut self, obj_key: usize) -> () {
-    //     if let Some(some_obj) = self.objects.get_mut(&obj_key) {
-    //         if let object::ObjType::Clue = some_obj.otype {
-    //             some_obj.found = true;
-    //         }
-    //     }
-    // }
+    // rooms, clues, not-clues and the player
+    world: ecs::World,
+    // every flat and textured model
+    scene: scene::Scene,
+    things: Vec<GameObject>,
+    colliders: collision::Colliders,
 }
 
-pub fn player_vol() -> types::RPrism {
+pub fn player_vol(pos: Vec3) -> types::RPrism {
     types::RPrism {
-        pos: Vec3::new(0.0, 0.0, 0.0),
+        pos,
         sz: Vec3::new(2.0, 2.0, 2.0),
     }
 }
@@ -97,73 +92,319 @@ pub struct GameState {
     film_used: usize,
     clues_found: Vec<usize>,
     goal_clues: usize,
+    room_tracker: rooms::RoomTracker,
+    // what the camera is doing instead of following the player, if anything
+    cutscene: Option<cutscene::Cutscene>,
+    // played when the clue with that id is photographed
+    reveals: HashMap<usize, Rc<cutscene::CameraTrack>>,
+    lights: renderer::lights::Lights,
+    // saves by slot, if they're kept in memory instead of in the save files
+    memory_saves: Option<HashMap<usize, save::SaveData>>,
+    // seconds per update
+    dt: f64,
 }
 
 impl GameState {
-    fn new(stuff: GameStuff, current_room: usize, goal_clues: usize) -> GameState {
-        GameState {
+    fn new(
+        mut stuff: GameStuff,
+        current_room: usize,
+        goal_clues: usize,
+        spawn: Vec3,
+        spawn_deg: (f32, f32),
+    ) -> GameState {
+        let player = object::Player::new(player_vol(spawn), spawn_deg, PLAYER_MOVE_SPD);
+        let world = &mut stuff.world;
+        let e = world.spawn();
+        world.players.insert(e, ecs::Player);
+        world.ids.insert(e, ecs::Id(level::PLAYER_ID));
+        let room = world.find(current_room);
+        world.containers.insert(e, ecs::Container(room));
+        let mut game = GameState {
             stuff,
             current_room,
-            player: object::Player::new(
-                0,
-                Some(current_room),
-                player_vol(),
-                (0., 0.),
-                PLAYER_MOVE_SPD,
-            ), //this is temp CHANGE
+            player,
             film_used: 0,
             clues_found: Vec::new(),
-            goal_clues
+            goal_clues,
+            room_tracker: rooms::RoomTracker::new(),
+            cutscene: None,
+            reveals: HashMap::new(),
+            lights: renderer::lights::Lights::default(),
+            memory_saves: None,
+            dt: DT,
+        };
+        game.sync_player_entity();
+        game
+    }
+}
+
+impl GameState {
+    /// Update as if `dt` seconds pass each tick, to match an engine or a
+    /// recording that doesn't use `DT`.
+    pub fn set_timestep(&mut self, dt: f64) {
+        self.dt = dt;
+    }
+    /// Put the player's entity wherever `player` is, so systems see them
+    /// there.
+    fn sync_player_entity(&mut self) {
+        let world = &mut self.stuff.world;
+        if let Some(e) = world.player() {
+            let pos = self.player.get_pos();
+            let body = self.player.get_volume();
+            world.transforms.insert(e, ecs::Transform { pos });
+            world.volumes.insert(
+                e,
+                ecs::Volume(RPrism {
+                    pos: body.pos - pos,
+                    sz: body.sz,
+                }),
+            );
+        }
+    }
+    /// Take the camera away from the player until `track` is over.
+    fn play_cutscene(&mut self, track: Rc<cutscene::CameraTrack>) {
+        self.cutscene = Some(cutscene::Cutscene::new(track));
+    }
+    /// The entity under the middle of the screen, if any.
+    fn looking_at(&self) -> Option<picking::Pick> {
+        picking::pick(
+            &self.player.get_camera().center_ray(),
+            &self.stuff.world,
+            &self.stuff.colliders,
+            picking::MAX_PICK_DIST,
+        )
+    }
+    /// Use up a frame of film and mark every clue in the shot as found.
+    /// Returns the ids of the clues found by this photo.
+    fn take_photo(&mut self) -> Vec<usize> {
+        if self.film_used >= self.player.film_capacity {
+            return vec![];
+        }
+        self.film_used += 1;
+        let camera = self.player.get_camera();
+        let world = &self.stuff.world;
+        let colliders = &self.stuff.colliders;
+        let mut found: Vec<(usize, ecs::Entity)> = world
+            .clues
+            .join(&world.ids)
+            .filter(|(_, clue, _)| !clue.found)
+            .filter(|(e, _, _)| picking::can_photograph(&camera, *e, world, colliders))
+            .map(|(e, _, id)| (id.0, e))
+            .collect();
+        // in the order the level numbered them, rather than wherever the
+        // entities happen to be stored
+        found.sort_unstable_by_key(|(id, _)| *id);
+        for (id, e) in found.iter() {
+            if let Some(clue) = self.stuff.world.clues.get_mut(*e) {
+                clue.found = true;
+            }
+            self.clues_found.push(*id);
+        }
+        found.into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn save_data(&self) -> save::SaveData {
+        let world = &self.stuff.world;
+        let mut found: Vec<usize> = world
+            .clues
+            .join(&world.ids)
+            .filter(|(_, clue, _)| clue.found)
+            .map(|(_, _, id)| id.0)
+            .collect();
+        found.sort_unstable();
+        let pos = self.player.get_pos();
+        save::SaveData {
+            version: save::SAVE_VERSION,
+            current_room: self.current_room,
+            player_pos: (pos.x, pos.y, pos.z),
+            perspective_deg: self.player.get_deg(),
+            film_used: self.film_used,
+            clues_found: self.clues_found.clone(),
+            found,
         }
     }
+    fn apply_save_data(&mut self, data: &save::SaveData) {
+        self.current_room = data.current_room;
+        self.player.set_pos(data.player_pos.into());
+        self.player.set_deg(data.perspective_deg);
+        self.player.vel_xz = Vec2::zero();
+        self.player.vel_y = 0.0;
+        self.player.grounded = false;
+        self.sync_player_entity();
+        self.film_used = data.film_used;
+        self.clues_found = data.clues_found.clone();
+        // ids that aren't in this level any more are ignored
+        let world = &mut self.stuff.world;
+        for (e, clue) in world.clues.iter_mut() {
+            clue.found = world
+                .ids
+                .get(e)
+                .map_or(false, |id| data.found.contains(&id.0));
+        }
+    }
+    pub fn save(&self, path: &std::path::Path) -> Result<()> {
+        self.save_data().write(path)
+    }
+    /// Restore progress saved with `save`.  The level itself isn't saved, so
+    /// this should be called on a game built from the same level file.
+    pub fn load(&mut self, path: &std::path::Path) -> Result<()> {
+        let data = save::SaveData::read(path)?;
+        self.apply_save_data(&data);
+        Ok(())
+    }
+    /// Keep saves in memory from now on, leaving the save files alone.
+    /// Replays do this so they neither overwrite the player's saves nor
+    /// depend on what's in them.
+    pub fn keep_saves_in_memory(&mut self) {
+        self.memory_saves.get_or_insert_with(HashMap::new);
+    }
+    pub fn save_slot(&mut self, slot: usize) -> Result<()> {
+        let path = save::slot_path(slot)?;
+        let data = self.save_data();
+        match self.memory_saves.as_mut() {
+            Some(saves) => {
+                saves.insert(slot, data);
+                Ok(())
+            }
+            None => data.write(&path),
+        }
+    }
+    pub fn load_slot(&mut self, slot: usize) -> Result<()> {
+        let path = save::slot_path(slot)?;
+        let data = match self.memory_saves.as_ref() {
+            Some(saves) => saves
+                .get(&slot)
+                .cloned()
+                .ok_or_else(|| color_eyre::eyre::eyre!("Nothing saved in slot {}", slot))?,
+            None => save::SaveData::read(&path)?,
+        };
+        self.apply_save_data(&data);
+        Ok(())
+    }
 }
 
 impl engine::World for GameState {
     fn update(&mut self, input: &input::Input, _assets: &mut assets::Assets) {
-        let player = &mut self.player;
-        player.move_with_input(input);
+        let dt = self.dt;
+        for thing in self.stuff.things.iter_mut() {
+            thing.tick_animation(dt);
+        }
+        // the player waits until it's over, but the rest of the game goes on
+        let in_cutscene = self.cutscene.is_some();
+        if let Some(cs) = self.cutscene.as_mut() {
+            cs.tick(dt);
+            let skip = cs.is_skippable()
+                && self
+                    .player
+                    .bindings
+                    .is_pressed(input, bindings::Action::SkipCutscene);
+            if skip || cs.is_done() {
+                self.cutscene = None;
+            }
+        }
+        if !in_cutscene {
+            let player = &mut self.player;
+            player.move_with_input(input, &self.stuff.colliders, dt as f32);
+        }
+        // systems
+        self.sync_player_entity();
+        let world = &mut self.stuff.world;
+        self.room_tracker.update(world);
+        ecs::place_renderables(world, &mut self.stuff.scene);
+        let room = world.player().and_then(|e| world.container(e));
+        if let Some(room) = room.and_then(|r| world.id(r)) {
+            self.current_room = room;
+        }
+        let bindings = &self.player.bindings;
+        // the player can't see what they'd be photographing
+        let photo = !in_cutscene && bindings.is_pressed(input, bindings::Action::Photograph);
+        let quicksave = bindings.is_pressed(input, bindings::Action::QuickSave);
+        let quickload = bindings.is_pressed(input, bindings::Action::QuickLoad);
+        if photo {
+            let found = self.take_photo();
+            let reveal = found.iter().find_map(|id| self.reveals.get(id).cloned());
+            if let Some(track) = reveal {
+                self.play_cutscene(track);
+            }
+        }
+        if quicksave {
+            if let Err(e) = self.save_slot(QUICKSAVE_SLOT) {
+                println!("Quicksave failed: {:?}", e);
+            }
+        } else if quickload {
+            if let Err(e) = self.load_slot(QUICKSAVE_SLOT) {
+                println!("Quickload failed: {:?}", e);
+            }
+        }
     }
     fn render(&mut self, _a: &mut assets::Assets, rs: &mut renderer::RenderState) {
         let camera = self.player.get_camera();
-        rs.set_camera(camera);
+        match self.cutscene.as_ref() {
+            Some(cs) => rs.set_camera(cs.camera(&camera)),
+            None => rs.set_camera(camera),
+        }
+        rs.set_ambient(self.lights.ambient);
+        for light in self.lights.lights.iter() {
+            rs.add_light(*light);
+        }
 
-        // for (obj_i, obj) in self.things.iter_mut().enumerate() {
-        //     rs.render_skinned(obj.model.clone(), obj.animation, obj.state, obj.trf, obj_i);
-        // }
+        for (obj_i, obj) in self.stuff.things.iter_mut().enumerate() {
+            rs.render_skinned(obj.model.clone(), obj.animation, obj.state, obj.trf, obj_i);
+        }
         // for (s_i, s) in self.sprites.iter_mut().enumerate() {
         //     rs.render_sprite(s.tex, s.cel, s.trf, s.size, s_i);
         // }
-        for (m_i, m) in self.stuff.flats.iter_mut().enumerate() {
-            rs.render_flat(m.model.clone(), m.trf, m_i);
-        }
-        for (t_i, t) in self.stuff.textured.iter_mut().enumerate() {
-            rs.render_textured(t.model.clone(), t.trf, t_i);
-        }
+        self.stuff.scene.render(rs);
     }
 
 }
 
+// the argument after `flag`, if `flag` was given
+fn arg_value(flag: &str) -> Option<String> {
+    let mut args = std::env::args();
+    args.find(|a| a == flag)?;
+    args.next()
+}
+
+/// Play a recording made with `--record` without a window or GPU and print
+/// where the game ended up.
+fn replay_headless(path: &std::path::Path) -> Result<()> {
+    let rec = replay::Recording::load(path)?;
+    let mut assets = assets::Assets::new();
+    let level = level::Level::load(std::path::Path::new(LEVEL))?;
+    let mut game_state = level.build(&mut assets)?;
+    if let Some(bindings) = rec.bindings.clone() {
+        game_state.player.bindings = bindings;
+    }
+    game_state.keep_saves_in_memory();
+    game_state.set_timestep(rec.dt);
+    let mut sim = simulation::Simulation::new(game_state, assets, rec.dt);
+    sim.replay(&rec);
+    println!(
+        "After {} frames:\n{}",
+        sim.ticks(),
+        sim.world().save_data().to_ron()?
+    );
+    Ok(())
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
+    if std::env::args().any(|a| a == "--golden") {
+        return golden::run(DT, std::env::args().any(|a| a == "--bless"));
+    }
+    if let Some(path) = arg_value("--replay") {
+        return replay_headless(std::path::Path::new(&path));
+    }
 
     let mut engine: Engine = Engine::new(WindowSettings::default(), DT);
 
     let camera = camera::Camera::look_at(Vec3::new(0., -2., -10.), Vec3::zero(), Vec3::unit_y());
     engine.set_camera(camera);
-    let mut stuff = GameStuff {
-        rooms: vec![],
-        objects: HashMap::from([]),
-        textured: vec![],
-        flats: vec![],
-    };
-
-
-
-    let flat_model = engine.load_flat(std::path::Path::new("content/livingroom.glb"))?;
-    stuff.flats.push(Flat {
-        trf: Similarity3::new(Vec3::new(0.0, 0.0, 10.0), Rotor3::from_rotation_yz(90.0f32.to_radians()), 1.0),
-        model: flat_model,
-    });
+    let level = level::Level::load(std::path::Path::new(LEVEL))?;
+    let mut game_state = level.build(engine.assets_mut())?;
+    game_state.player.bindings =
+        bindings::Bindings::load_or_default(std::path::Path::new(bindings::DEFAULT_BINDINGS))?;
 
     // let tex = engine.load_texture(std::path::Path::new("content/skins/robot3.png"))?;
     // let meshes = engine.load_textured(
@@ -175,6 +416,107 @@ fn main() -> Result<()> {
     //     trf: Similarity3::new(Vec3::new(0.0, 0.0, -10.0), Rotor3::identity(), 5.0),
     //     model: robot,
     // });
-    let game_state = GameState::new(stuff, START_ROOM, GOAL_CLUES);
+    if let Some(path) = arg_value("--record") {
+        let mut rec = replay::Recording::new(DT);
+        rec.bindings = Some(game_state.player.bindings.clone());
+        return engine.play_world(replay::Recorder::new(game_state, rec, path.into()));
+    }
     engine.play_world(game_state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A game with two clues in one room, standing on a floor at y = 0,
+    /// that needs no asset files.
+    pub(crate) fn new_game() -> GameState {
+        let level: level::Level = ron::from_str(
+            r#"(
+                goal_clues: 2,
+                spawn: (pos: (5.0, 0.0, 5.0)),
+                rooms: [(id: 1, volume: (pos: (0.0, 0.0, 0.0), size: (10.0, 5.0, 10.0)))],
+                clues: [
+                    (id: 2, volume: (pos: (1.0, 0.0, 1.0), size: (1.0, 1.0, 1.0))),
+                    (id: 3, volume: (pos: (8.0, 0.0, 8.0), size: (1.0, 1.0, 1.0))),
+                ],
+            )"#,
+        )
+        .unwrap();
+        let mut game = level.build(&mut assets::Assets::new()).unwrap();
+        game.stuff.colliders.add_box(RPrism {
+            pos: Vec3::new(-10.0, -1.0, -10.0),
+            sz: Vec3::new(30.0, 1.0, 30.0),
+        });
+        game
+    }
+    fn found(game: &GameState) -> Vec<usize> {
+        let world = &game.stuff.world;
+        let mut found: Vec<usize> = world
+            .clues
+            .join(&world.ids)
+            .filter(|(_, clue, _)| clue.found)
+            .map(|(_, _, id)| id.0)
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    #[test]
+    fn save_data_restores_progress() {
+        let mut game = new_game();
+        let clue = game.stuff.world.find(3).unwrap();
+        game.stuff.world.clues.get_mut(clue).unwrap().found = true;
+        game.clues_found.push(3);
+        game.film_used = 1;
+        game.player.set_pos(Vec3::new(2.0, 0.0, 7.0));
+        let data = game.save_data();
+        assert_eq!(data.found, vec![3]);
+        assert_eq!(data.player_pos, (2.0, 0.0, 7.0));
+
+        let mut fresh = new_game();
+        assert_eq!(found(&fresh), Vec::<usize>::new());
+        let text = data.to_ron().unwrap();
+        fresh.apply_save_data(&save::SaveData::from_ron(&text).unwrap());
+        assert_eq!(found(&fresh), vec![3]);
+        assert_eq!(fresh.save_data(), data);
+        // the player's entity moves along before the next update
+        let world = &fresh.stuff.world;
+        let e = world.player().unwrap();
+        assert_eq!(world.transforms.get(e).unwrap().pos, data.player_pos.into());
+        assert_eq!(world.volume(e), Some(fresh.player.get_volume()));
+    }
+
+    #[test]
+    fn cutscenes_only_hold_the_player() {
+        use winit::event::VirtualKeyCode as K;
+        let mut game = new_game();
+        game.keep_saves_in_memory();
+        // a half second track, two ticks long at this timestep
+        game.set_timestep(0.25);
+        let desc: cutscene::TrackDesc =
+            ron::from_str("(fov: [(t: 0.0, value: 60.0), (t: 0.5, value: 60.0)])").unwrap();
+        game.play_cutscene(Rc::new(cutscene::CameraTrack::from_desc(&desc).unwrap()));
+        let room = game.stuff.world.find(1).unwrap();
+        let start = game.player.get_pos();
+        let mut sim = simulation::Simulation::new(game, assets::Assets::new(), 0.25);
+        sim.input_mut().set_key(K::W, true);
+        sim.input_mut().set_key(K::F5, true);
+        sim.world_mut().player.set_pos(Vec3::new(20.0, 0.0, 20.0));
+        sim.step();
+        // the player can't walk, but the game still saves and sees them leave
+        let game = sim.world();
+        assert!(game.cutscene.is_some());
+        assert_eq!(game.player.get_pos(), Vec3::new(20.0, 0.0, 20.0));
+        let saves = game.memory_saves.as_ref().unwrap();
+        assert!(saves.contains_key(&QUICKSAVE_SLOT));
+        assert!(!game.stuff.world.rooms.get(room).unwrap().occupied);
+        sim.world_mut().player.set_pos(start);
+        sim.step();
+        assert!(sim.world().cutscene.is_none());
+        assert_eq!(sim.world().player.get_pos(), start);
+        sim.step();
+        assert!(sim.world().player.get_pos().z > start.z);
+        assert!(sim.world().stuff.world.rooms.get(room).unwrap().occupied);
+    }
+}
@@ -5,184 +5,95 @@ pub use ultraviolet::mat::Mat4;
 pub use ultraviolet::rotor::Rotor3;
 pub use ultraviolet::transform::Isometry3;
 pub use ultraviolet::vec::{Vec2, Vec3};
+use crate::bindings::{Action, Axis, Bindings};
 use crate::camera;
+use crate::collision;
 use crate::input;
-use winit::event::VirtualKeyCode;
 
 const PLAYER_HEIGHT: f32 = 2.;
-const PAUSE: VirtualKeyCode = VirtualKeyCode::Key1;
-const UNPAUSE: VirtualKeyCode = VirtualKeyCode::Key0;
-const RESET_PLAYER: VirtualKeyCode = VirtualKeyCode::Q;
-const RESET_POS: VirtualKeyCode = VirtualKeyCode::W;
-const RESET_DEG: VirtualKeyCode = VirtualKeyCode::E;
+const PLAYER_RADIUS: f32 = 0.4;
+// camera height above the player's feet
+const EYE_HEIGHT: f32 = 1.8;
+// units per second per second
+const GRAVITY: f32 = 20.0;
+// units per second, straight up
+const JUMP_SPD: f32 = 7.0;
+// tallest ledge we'll walk up without jumping
+const STEP_HEIGHT: f32 = 0.35;
+// how far down we'll stick to the floor when walking off a slope or stair
+const GROUND_SNAP: f32 = 0.2;
+// floors steeper than this (cosine of the slope angle) don't count as ground
+const MIN_GROUND_NORMAL_Y: f32 = 0.7;
+// respawn if we somehow fall out of the world
+const KILL_Y: f32 = -50.0;
+// fractions of `move_spd` for sprinting and crouching
+const SPRINT_MULT: f32 = 1.6;
+const CROUCH_MULT: f32 = 0.4;
+// units per second per second when speeding up and slowing down on the ground
+const WALK_ACCEL: f32 = 120.0;
+const WALK_DECEL: f32 = 150.0;
+// how much of that we get while in the air
+const AIR_CONTROL: f32 = 0.3;
 
-#[derive(Copy, Clone)]
-pub enum ObjType {
-    Room,
-    Clue,
-    NotClue,
-    Player,
-}
-
-pub trait Object {
-    
-    fn get_id(&self) -> usize;
-    fn get_type(&self) -> ObjType;
-    fn get_container(&self)-> Option<usize>;
-    fn get_volume(&self)-> RPrism;
-    fn get_pos(&self) -> Vec3;
-    fn set_pos(&mut self,pos:Vec3);
-}
-
-pub struct Room{
-    pub id: usize,
-    pub otype: ObjType,
-    pub container: Option<usize>,
-    pub volume: RPrism,
-    pub is_occupied: bool,
-}
-impl Room{
-fn new(id: usize, container: Option<usize>, volume: RPrism) -> Room{
-        Room{
-            id, otype: ObjType::Room, container, volume, is_occupied: false
-        }
-    }
-}
-impl Object for Room{
-    fn get_id(&self) -> usize
-    { 
-        self.id
-    }
-    fn get_type(&self) -> ObjType{
-        self.otype
-    }
-    fn get_container(&self)-> Option<usize>
-    {
-        self.container
-    }
-    fn get_volume(&self)-> RPrism
-    {
-        self.volume
-    }
-    fn get_pos(&self) -> Vec3{
-        self.volume.pos
-    }
-    fn set_pos(&mut self, pos: Vec3) {
-        self.volume.pos = pos;
-    }
-}
-
-pub struct Clue{
-    pub id: usize,
-    pub otype: ObjType,
-    pub container: Option<usize>,
-    pub volume: RPrism,
-    pub found: bool,
-}
-impl Clue{
-     fn new(id: usize, container: Option<usize>, volume: RPrism) -> Clue{
-        Clue{
-            id, otype: ObjType::Clue, container, volume, found: false
-        }
-    }
-}
-impl Object for Clue{
-    fn get_id(&self) -> usize
-    { 
-        self.id
-    }
-    fn get_type(&self) -> ObjType{
-        self.otype
-    }
-    fn get_container(&self)-> Option<usize>
-    {
-        self.container
-    }
-    fn get_volume(&self)-> RPrism
-    {
-        self.volume
-    }
-    fn get_pos(&self) -> Vec3{
-        self.volume.pos
-    }
-    fn set_pos(&mut self, pos: Vec3) {
-        self.volume.pos = pos
-    }
-}
-
-pub struct NotClue{
-    pub id: usize,
-    pub otype: ObjType,
-    pub container: Option<usize>,
-    pub volume: RPrism,
-}
-impl NotClue{
-    fn new(id: usize, container: Option<usize>, volume: RPrism) -> NotClue{
-        NotClue{
-            id, otype: ObjType::NotClue, container, volume
-        }
-    }
-}
-impl Object for NotClue{
-    fn get_id(&self) -> usize
-    { 
-        self.id
-    }
-    fn get_type(&self) -> ObjType{
-        self.otype
-    }
-    fn get_container(&self)-> Option<usize>
-    {
-        self.container
-    }
-    fn get_volume(&self)-> RPrism
-    {
-        self.volume
-    }
-    fn get_pos(&self) -> Vec3{
-        self.volume.pos
-    }
-    fn set_pos(&mut self, pos: Vec3) {
-        self.volume.pos = pos
-    }
-}
+/// The player's body and view.  Everything else about the player (like which
+/// room they're in) is on their entity in the `ecs::World`.
 pub struct Player{
-    pub id: usize,
-    pub otype: ObjType,
-    pub container: Option<usize>,
     pub volume: RPrism,
     pub film_capacity: usize,
-    pub perspective_deg: (f32, f32),
+    // first-person view from the player's eyes
+    pub view: camera::controller::Controller,
+    // top walking speed, in units per second
     pub move_spd: f32,
     pub pause_rot: bool,
+    // horizontal velocity (x and z), in units per second
+    pub vel_xz: Vec2,
+    pub vel_y: f32,
+    pub grounded: bool,
+    pub bindings: Bindings,
     default_pos: Vec3,
     default_deg: (f32, f32)
 }
 impl Player{
-    pub fn new(id: usize, container: Option<usize>, volume: RPrism, perspective_deg: (f32, f32), move_spd: f32) -> Player{
+    pub fn new(volume: RPrism, perspective_deg: (f32, f32), move_spd: f32) -> Player{
         Player{
-            id, otype: ObjType::Room, container, volume,
-            film_capacity: 10, perspective_deg, move_spd, 
-            pause_rot: false, default_pos: volume.pos, default_deg: perspective_deg
+            volume,
+            film_capacity: 10, move_spd,
+            view: camera::controller::Controller::first_person(
+                volume.pos + Vec3::new(0.0, EYE_HEIGHT, 0.0),
+                perspective_deg,
+            ),
+            pause_rot: false, vel_xz: Vec2::zero(), vel_y: 0.0, grounded: false,
+            bindings: Bindings::default(),
+            default_pos: volume.pos, default_deg: perspective_deg
         }
     }
     pub fn get_camera(&self) -> camera::Camera {
-        let player_at = self.get_pos();
-        let at = Vec3::new(player_at.x, PLAYER_HEIGHT, player_at.z);
-        camera::Camera::look_at_degrees(at, Vec3::unit_y(), self.get_deg())
+        let mut view = self.view;
+        view.eye = self.get_pos() + Vec3::new(0.0, EYE_HEIGHT, 0.0);
+        view.camera()
     }
 
     pub fn get_default_camera(&self) -> camera::Camera {
-        let player_at = self.default_pos;
-        let at = Vec3::new(player_at.x, PLAYER_HEIGHT, player_at.z);
-        camera::Camera::look_at_degrees(at, Vec3::unit_y(), self.default_deg)
+        let mut view = self.view;
+        view.eye = self.default_pos + Vec3::new(0.0, EYE_HEIGHT, 0.0);
+        view.set_degrees(self.default_deg);
+        view.camera()
     }
    
+    /// The player's collision shape, standing on `volume.pos`.
+    pub fn capsule(&self) -> collision::Capsule {
+        collision::Capsule {
+            base: self.get_pos(),
+            height: PLAYER_HEIGHT,
+            radius: PLAYER_RADIUS,
+        }
+    }
+
     pub fn get_deg(&self) -> (f32,f32) {
-        self.perspective_deg
+        self.view.degrees()
     }
     pub fn set_deg(&mut self, deg: (f32,f32)) {
-        self.perspective_deg = deg
+        self.view.set_degrees(deg)
     }
 
     pub fn pause_rotation(&mut self){
@@ -193,106 +104,325 @@ impl Player{
         self.pause_rot = false;
     }
 
-    pub fn move_with_input(&mut self, input: &input::Input) {
-        let delta = input.get_mouse_delta();
-    
-        let mut is_moving = false;
-        let mut directions: Vec<Direction> = Vec::new();
-
-        if input.is_key_pressed(PAUSE){
+    /// Walk, look and fall for one tick `dt` seconds long.
+    pub fn move_with_input(
+        &mut self,
+        input: &input::Input,
+        colliders: &collision::Colliders,
+        dt: f32,
+    ) {
+        if self.bindings.is_pressed(input, Action::Pause){
             self.pause_rotation();
         }
-        else if input.is_key_pressed(UNPAUSE){
+        else if self.bindings.is_pressed(input, Action::Unpause){
             self.unpause_rotation();
         }
-    
-        if input.is_key_down(VirtualKeyCode::Up) || input.is_key_down(VirtualKeyCode::W) {
-            directions.push(Direction::Forward);
-            is_moving = true;
-        }
-        else if input.is_key_down(VirtualKeyCode::Down) || input.is_key_down(VirtualKeyCode::S) {
-            directions.push(Direction::Backward);
-            is_moving = true;
-        }
-        else if input.is_key_down(VirtualKeyCode::Right) || input.is_key_down(VirtualKeyCode::D) {
-            directions.push(Direction::Left);
-            is_moving = true;
+        let bindings = &self.bindings;
+        let held = |a: Action| bindings.value(input, a);
+        // x is to the right, y is forward; opposite directions cancel out.
+        // Keys held together are no faster than one, but a stick only
+        // partway over walks rather than runs.
+        let mut wish = Vec2::new(
+            held(Action::MoveRight) - held(Action::MoveLeft),
+            held(Action::MoveForward) - held(Action::MoveBackward),
+        );
+        if wish.mag_sq() > 1.0 {
+            wish.normalize();
         }
-        else if input.is_key_down(VirtualKeyCode::Left) || input.is_key_down(VirtualKeyCode::A) {
-            directions.push(Direction::Right);
-            is_moving = true;
-        }
-    
-        let (mut cam_degrees_x, mut cam_degrees_y) = self.get_deg();
-    
+        let speed = if bindings.is_down(input, Action::Crouch) {
+            self.move_spd * CROUCH_MULT
+        } else if bindings.is_down(input, Action::Sprint) {
+            self.move_spd * SPRINT_MULT
+        } else {
+            self.move_spd
+        };
+
         if !self.pause_rot {
-            cam_degrees_x -= delta.x as f32 / input::Input::get_mouse_move_scale();
-            cam_degrees_y += delta.y as f32 / input::Input::get_mouse_move_scale();
-        }
-        
-        if cam_degrees_y > 89.0 {
-            cam_degrees_y = 89.0
+            self.view.look(
+                -bindings.axis(input, Axis::LookX),
+                bindings.axis(input, Axis::LookY),
+            );
         }
-        if cam_degrees_y < -89.0 {
-            cam_degrees_y = -89.0
-        }
-    
-        let mut theta = cam_degrees_x;
-        directions.iter().for_each(|direction| {
-            match direction {
-                Direction::Left => theta -= 90.0,
-                Direction::Right => theta += 90.0,
-                Direction::Backward => theta += 180.0,
-                _ => ()
+
+        // turning left increases theta, so right is a quarter turn the other way
+        let theta = self.view.degrees().0.to_radians();
+        let forward = Vec2::new(theta.sin(), theta.cos());
+        let right = Vec2::new(-theta.cos(), theta.sin());
+        let target = (right * wish.x + forward * wish.y) * speed;
+        let jump = bindings.is_pressed(input, Action::Jump);
+        let reset_player = bindings.is_pressed(input, Action::ResetPlayer);
+        let reset_pos = bindings.is_pressed(input, Action::ResetPosition);
+        let reset_deg = bindings.is_pressed(input, Action::ResetView);
+        let step = self.accelerate(target, dt);
+        let start = self.get_pos();
+        let pos = self.walk(step, colliders);
+        // don't keep pushing into whatever stopped us (a tick that takes no
+        // time, like a paused one, can't tell)
+        if dt > 0.0 {
+            let moved = Vec2::new(pos.x - start.x, pos.z - start.z) / dt;
+            if moved.mag_sq() < self.vel_xz.mag_sq() {
+                self.vel_xz = moved;
             }
-        });
-    
-        let distance = if is_moving { self.move_spd } else { 0. };
-    
-        let mut pos = self.get_pos();
-        pos.z += (distance * theta.to_radians().cos()) as f32;
-        pos.x += (distance * theta.to_radians().sin()) as f32;
-        
-        if input.is_key_pressed(RESET_PLAYER){
-            self.set_pos(self.default_pos);
+        }
+        let pos = self.fall(pos, jump, colliders, dt);
+
+        if reset_player {
+            self.respawn();
             self.set_deg(self.default_deg);
         }
-        else if input.is_key_pressed(RESET_POS){
-            self.set_pos(self.default_pos);
-            self.set_deg((cam_degrees_x, cam_degrees_y));
+        else if reset_pos || pos.y < KILL_Y {
+            self.respawn();
         }
-        else if input.is_key_pressed(RESET_DEG){
+        else if reset_deg {
             self.set_pos(pos);
             self.set_deg(self.default_deg);
         }
         else {
             self.set_pos(pos);
-            self.set_deg((cam_degrees_x, cam_degrees_y));
         }
+        self.view.update(dt);
     }
 }
-impl Object for Player{
-    
-    fn get_id(&self) -> usize
-    { 
-        self.id
+impl Player{
+    fn respawn(&mut self) {
+        self.set_pos(self.default_pos);
+        self.vel_xz = Vec2::zero();
+        self.vel_y = 0.0;
+        self.grounded = false;
     }
-    fn get_type(&self) -> ObjType{
-        self.otype
+
+    // Speed up towards `target` velocity (or slow down if it's zero), and
+    // return how far that takes us this tick.
+    fn accelerate(&mut self, target: Vec2, dt: f32) -> Vec3 {
+        let rate = if target.mag_sq() > 0.0 { WALK_ACCEL } else { WALK_DECEL };
+        let rate = if self.grounded { rate } else { rate * AIR_CONTROL };
+        let diff = target - self.vel_xz;
+        let max_change = rate * dt;
+        if diff.mag() <= max_change {
+            self.vel_xz = target;
+        } else {
+            self.vel_xz += diff.normalized() * max_change;
+        }
+        Vec3::new(self.vel_xz.x * dt, 0.0, self.vel_xz.y * dt)
     }
-    fn get_container(&self)-> Option<usize>
-    {
-        self.container
+
+    // Horizontal movement: slide along walls, and if something low is in the
+    // way while we're on the ground, try stepping up onto it.
+    fn walk(&self, step: Vec3, colliders: &collision::Colliders) -> Vec3 {
+        let cap = self.capsule();
+        let start = cap.base;
+        let flat = colliders.slide(cap, step);
+        if !self.grounded || step.mag_sq() == 0.0 {
+            return flat;
+        }
+        let progress = |p: Vec3| Vec3::new(p.x - start.x, 0.0, p.z - start.z).mag_sq();
+        if progress(flat) >= step.mag_sq() * 0.99 {
+            return flat;
+        }
+        let up = colliders.slide(cap, Vec3::new(0.0, STEP_HEIGHT, 0.0));
+        let over = cap.moved_to(colliders.slide(cap.moved_to(up), step));
+        // come back down onto whatever we stepped up on
+        let ground = colliders.ground(&over, up.y - start.y, MIN_GROUND_NORMAL_Y);
+        match ground {
+            Some(hit) if hit.point.y > start.y => {
+                let landed = Vec3::new(over.base.x, hit.point.y, over.base.z);
+                let (landed, _) = colliders.resolve(cap.moved_to(landed));
+                if progress(landed) > progress(flat) {
+                    landed
+                } else {
+                    flat
+                }
+            }
+            _ => flat,
+        }
+    }
+
+    // Vertical movement: gravity, jumping, landing and sticking to the ground.
+    fn fall(&mut self, pos: Vec3, jump: bool, colliders: &collision::Colliders, dt: f32) -> Vec3 {
+        let cap = self.capsule().moved_to(pos);
+        if jump && self.grounded {
+            self.vel_y = JUMP_SPD;
+            self.grounded = false;
+        }
+        let was_grounded = self.grounded;
+        self.vel_y -= GRAVITY * dt;
+        let dy = self.vel_y * dt;
+        let mut pos = colliders.slide(cap, Vec3::new(0.0, dy, 0.0));
+        let moved = pos.y - cap.base.y;
+        if dy > 0.0 && moved < dy * 0.5 {
+            // bumped our head
+            self.vel_y = 0.0;
+        }
+        self.grounded = false;
+        if self.vel_y <= 0.0 {
+            // look for ground just below our feet; snap further if we were on the
+            // ground last tick so walking down stairs doesn't turn into falling
+            let snap = if was_grounded { GROUND_SNAP } else { 0.0 };
+            let reach = snap + (-dy).max(0.0) + 0.01;
+            if let Some(hit) = colliders.ground(&cap.moved_to(pos), reach, MIN_GROUND_NORMAL_Y) {
+                pos.y = hit.point.y;
+                self.vel_y = 0.0;
+                self.grounded = true;
+            }
+        }
+        pos
     }
-    fn get_volume(&self)-> RPrism
+}
+impl Player{
+    // the space the player's capsule takes up, rather than `volume`,
+    // whose `pos` is where the player's feet are
+    pub fn get_volume(&self)-> RPrism
     {
-        self.volume
+        self.capsule().bounds()
     }
-    fn get_pos(&self) -> Vec3{
+    pub fn get_pos(&self) -> Vec3{
         self.volume.pos
     }
-    fn set_pos(&mut self, pos: Vec3) {
+    pub fn set_pos(&mut self, pos: Vec3) {
         self.volume.pos = pos;
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a floor whose top is at y = 0, with a ledge `ledge` high starting at x = 0.45
+    fn level(ledge: f32) -> collision::Colliders {
+        let mut c = collision::Colliders::new();
+        c.add_box(RPrism {
+            pos: Vec3::new(-10.0, -1.0, -10.0),
+            sz: Vec3::new(20.0, 1.0, 20.0),
+        });
+        c.add_box(RPrism {
+            pos: Vec3::new(0.45, 0.0, -10.0),
+            sz: Vec3::new(5.0, ledge, 20.0),
+        });
+        c
+    }
+    fn player_at(pos: Vec3, grounded: bool) -> Player {
+        let mut p = Player::new(
+            RPrism {
+                pos,
+                sz: Vec3::new(PLAYER_RADIUS * 2.0, PLAYER_HEIGHT, PLAYER_RADIUS * 2.0),
+            },
+            (0.0, 0.0),
+            10.0,
+        );
+        p.grounded = grounded;
+        p
+    }
+    const DT: f32 = 1.0 / 60.0;
+
+    #[test]
+    fn gravity_uses_dt() {
+        let mut p = player_at(Vec3::new(-5.0, 5.0, 0.0), false);
+        p.move_with_input(&input::Input::new(), &level(0.0), 0.1);
+        assert!((p.vel_y + GRAVITY * 0.1).abs() < 1e-5);
+        assert!((p.get_pos().y - (5.0 - GRAVITY * 0.1 * 0.1)).abs() < 1e-4);
+        assert!(!p.grounded);
+    }
+
+    #[test]
+    fn falls_until_grounded() {
+        let mut p = player_at(Vec3::new(-5.0, 3.0, 0.0), false);
+        let c = level(0.0);
+        for _ in 0..120 {
+            p.move_with_input(&input::Input::new(), &c, DT);
+        }
+        assert!(p.grounded);
+        assert_eq!(p.vel_y, 0.0);
+        assert!(p.get_pos().y.abs() < 1e-3, "{:?}", p.get_pos());
+    }
+
+    #[test]
+    fn snaps_down_only_when_grounded() {
+        let c = level(0.0);
+        let start = Vec3::new(-5.0, GROUND_SNAP * 0.5, 0.0);
+        // walking off a small drop sticks to the floor...
+        let mut p = player_at(start, true);
+        let pos = p.fall(start, false, &c, DT);
+        assert!(p.grounded);
+        assert!(pos.y.abs() < 1e-3, "{:?}", pos);
+        // ...but jumping down it doesn't
+        let mut p = player_at(start, false);
+        let pos = p.fall(start, false, &c, DT);
+        assert!(!p.grounded);
+        assert!(pos.y > 0.05, "{:?}", pos);
+        // and neither does a drop deeper than the snap distance
+        let start = Vec3::new(-5.0, GROUND_SNAP * 2.0, 0.0);
+        let mut p = player_at(start, true);
+        let pos = p.fall(start, false, &c, DT);
+        assert!(!p.grounded);
+        assert!(pos.y > GROUND_SNAP, "{:?}", pos);
+    }
+
+    #[test]
+    fn steps_up_low_ledges() {
+        let ledge = STEP_HEIGHT - 0.05;
+        let p = player_at(Vec3::zero(), true);
+        let pos = p.walk(Vec3::new(0.2, 0.0, 0.0), &level(ledge));
+        assert!((pos.y - ledge).abs() < 1e-2, "{:?}", pos);
+        assert!(pos.x > 0.15, "{:?}", pos);
+    }
+
+    #[test]
+    fn blocked_by_high_ledges() {
+        let p = player_at(Vec3::zero(), true);
+        let pos = p.walk(Vec3::new(0.2, 0.0, 0.0), &level(STEP_HEIGHT + 0.15));
+        assert!(pos.y.abs() < 1e-3, "{:?}", pos);
+        assert!(pos.x < 0.06, "{:?}", pos);
+    }
+
+    #[test]
+    fn zero_dt_stands_still() {
+        use winit::event::VirtualKeyCode as K;
+        let mut input = input::Input::new();
+        input.set_key(K::W, true);
+        let start = Vec3::new(-5.0, 0.0, 0.0);
+        let mut p = player_at(start, true);
+        p.vel_xz = Vec2::new(0.0, 3.0);
+        p.move_with_input(&input, &level(0.0), 0.0);
+        assert_eq!(p.get_pos(), start);
+        assert_eq!(p.vel_xz, Vec2::new(0.0, 3.0));
+        assert!(p.vel_y.is_finite());
+    }
+
+    // how fast the player is going after walking with `input` for a while
+    fn speed_with(input: &input::Input) -> f32 {
+        let mut p = player_at(Vec3::new(-5.0, 0.0, 0.0), true);
+        let c = level(0.0);
+        for _ in 0..20 {
+            p.move_with_input(input, &c, DT);
+        }
+        p.vel_xz.mag()
+    }
+
+    #[test]
+    fn diagonals_are_no_faster() {
+        use winit::event::VirtualKeyCode as K;
+        let mut input = input::Input::new();
+        input.set_key(K::W, true);
+        let forward = speed_with(&input);
+        assert!((forward - 10.0).abs() < 1e-3, "{}", forward);
+        input.set_key(K::D, true);
+        let diagonal = speed_with(&input);
+        assert!((diagonal - forward).abs() < 1e-3, "{}", diagonal);
+        // opposite keys cancel out
+        input.set_key(K::A, true);
+        assert!((speed_with(&input) - forward).abs() < 1e-3);
+    }
+
+    #[test]
+    fn sticks_walk_as_far_as_theyre_pushed() {
+        use crate::gamepad::{PadAxis, PadDevice, SyntheticPads};
+        let mut pads = SyntheticPads::new();
+        pads.pad_mut(0).set_axis(PadAxis::LeftStickY, 0.6);
+        let tilt = pads.pad_mut(0).axis(PadAxis::LeftStickY);
+        assert!(tilt > 0.3 && tilt < 0.7, "{}", tilt);
+        let mut input = input::Input::new();
+        input.handle_pads(pads.poll());
+        let speed = speed_with(&input);
+        assert!((speed - 10.0 * tilt).abs() < 1e-3, "{}", speed);
+    }
+}
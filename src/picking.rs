@@ -0,0 +1,148 @@
+//! Working out what the player is looking at.
+use crate::camera::Camera;
+use crate::collision::{Colliders, Ray};
+use crate::ecs::{Entity, World};
+
+// how far away something can be and still be picked or photographed
+pub const MAX_PICK_DIST: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pick {
+    pub entity: Entity,
+    pub dist: f32,
+}
+
+/// The nearest entity whose volume `ray` hits within `max_dist`, unless level
+/// geometry in `colliders` gets in the way first.  Rooms and the player are
+/// never picked: the camera is usually inside both.
+pub fn pick(ray: &Ray, world: &World, colliders: &Colliders, max_dist: f32) -> Option<Pick> {
+    let nearest = world
+        .volumes
+        .iter()
+        .filter(|(e, _)| !world.rooms.contains(*e) && !world.players.contains(*e))
+        .filter_map(|(e, _)| {
+            ray.cast_prism(world.volume(e)?)
+                .map(|(dist, _)| Pick { entity: e, dist })
+        })
+        .filter(|p| p.dist <= max_dist)
+        .min_by(|a, b| {
+            a.dist
+                .partial_cmp(&b.dist)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?;
+    match colliders.raycast(ray, nearest.dist) {
+        Some(_) => None,
+        None => Some(nearest),
+    }
+}
+
+/// Whether `target` could show up in a photo taken with `camera`: the middle
+/// of its volume must be in view, and the first thing a ray from the eye to it
+/// hits must be `target` itself.
+pub fn can_photograph(
+    camera: &Camera,
+    target: Entity,
+    world: &World,
+    colliders: &Colliders,
+) -> bool {
+    let center = match world.volume(target) {
+        Some(vol) => vol.center(),
+        None => return false,
+    };
+    if !camera.can_see(center) {
+        return false;
+    }
+    let eye = camera.eye();
+    if (center - eye).mag() > MAX_PICK_DIST {
+        return false;
+    }
+    let ray = Ray::new(eye, center - eye);
+    pick(&ray, world, colliders, MAX_PICK_DIST)
+        .map(|p| p.entity == target)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::{Room, Transform, Volume};
+    use crate::types::*;
+
+    fn unit_at(world: &mut World, pos: Vec3) -> Entity {
+        let e = world.spawn();
+        world.transforms.insert(e, Transform { pos });
+        world.volumes.insert(
+            e,
+            Volume(RPrism {
+                pos: Vec3::zero(),
+                sz: Vec3::one(),
+            }),
+        );
+        e
+    }
+    // a room around the origin with a far and a near box straight ahead
+    fn world() -> (World, Entity, Entity) {
+        let mut world = World::new();
+        let room = unit_at(&mut world, Vec3::new(-20.0, -20.0, -20.0));
+        world.volumes.insert(
+            room,
+            Volume(RPrism {
+                pos: Vec3::zero(),
+                sz: Vec3::new(40.0, 40.0, 40.0),
+            }),
+        );
+        world.rooms.insert(room, Room::default());
+        let far = unit_at(&mut world, Vec3::new(-0.5, -0.5, 10.0));
+        let near = unit_at(&mut world, Vec3::new(-0.5, -0.5, 5.0));
+        (world, near, far)
+    }
+    fn wall_at(z: f32) -> Colliders {
+        let mut c = Colliders::new();
+        c.add_box(RPrism {
+            pos: Vec3::new(-5.0, -5.0, z),
+            sz: Vec3::new(10.0, 10.0, 0.1),
+        });
+        c
+    }
+
+    #[test]
+    fn pick_finds_nearest() {
+        let (world, near, far) = world();
+        let forward = Ray::new(Vec3::zero(), Vec3::unit_z());
+        let p = pick(&forward, &world, &Colliders::new(), MAX_PICK_DIST).unwrap();
+        assert_eq!(p.entity, near);
+        assert_eq!(p.dist, 5.0);
+        // from behind, the far one is nearer
+        let back = Ray::new(Vec3::new(0.0, 0.0, 15.0), -Vec3::unit_z());
+        let p = pick(&back, &world, &Colliders::new(), MAX_PICK_DIST).unwrap();
+        assert_eq!(p.entity, far);
+        assert_eq!(p.dist, 4.0);
+        // rooms aren't picked, even though the ray starts inside one
+        assert!(pick(&forward, &world, &Colliders::new(), 4.0).is_none());
+        let up = Ray::new(Vec3::zero(), Vec3::unit_y());
+        assert!(pick(&up, &world, &Colliders::new(), MAX_PICK_DIST).is_none());
+    }
+
+    #[test]
+    fn pick_blocked_by_geometry() {
+        let (world, _, _) = world();
+        let forward = Ray::new(Vec3::zero(), Vec3::unit_z());
+        assert!(pick(&forward, &world, &wall_at(2.0), MAX_PICK_DIST).is_none());
+        // geometry behind the nearest entity doesn't matter
+        assert!(pick(&forward, &world, &wall_at(7.0), MAX_PICK_DIST).is_some());
+    }
+
+    #[test]
+    fn photograph_needs_clear_view() {
+        let (world, near, far) = world();
+        let camera = Camera::look_at(Vec3::zero(), Vec3::unit_z(), Vec3::unit_y());
+        assert!(can_photograph(&camera, near, &world, &Colliders::new()));
+        // hidden behind the near one
+        assert!(!can_photograph(&camera, far, &world, &Colliders::new()));
+        // hidden behind a wall
+        assert!(!can_photograph(&camera, near, &world, &wall_at(2.0)));
+        // behind the camera
+        let away = Camera::look_at(Vec3::zero(), -Vec3::unit_z(), Vec3::unit_y());
+        assert!(!can_photograph(&away, near, &world, &Colliders::new()));
+    }
+}
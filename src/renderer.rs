@@ -1,4 +1,8 @@
+pub mod culling;
 pub mod flat;
+pub mod lights;
+pub mod lod;
+pub mod pbr;
 pub mod skinned;
 pub mod sprites;
 pub mod textured;
@@ -11,12 +15,37 @@ use std::rc::Rc;
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct RenderKey(usize);
 
+/// Which renderer a transparent instance belongs to.  After the opaque
+/// batches, the engine gathers every renderer's transparent instances and
+/// draws them farthest from the camera first, whichever renderer they're in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum TransparentPass {
+    Flat,
+    Textured,
+    Sprites,
+}
+
+/// Blending for premultiplied alpha, which is what `Image` holds and what
+/// every transparent pipeline writes.
+pub(crate) fn premultiplied_blend() -> vulkano::pipeline::graphics::color_blend::ColorBlendState {
+    use vulkano::pipeline::graphics::color_blend::*;
+    ColorBlendState::new(1).blend(AttachmentBlend {
+        color_op: BlendOp::Add,
+        color_source: BlendFactor::One,
+        color_destination: BlendFactor::OneMinusSrcAlpha,
+        alpha_op: BlendOp::Add,
+        alpha_source: BlendFactor::One,
+        alpha_destination: BlendFactor::OneMinusSrcAlpha,
+    })
+}
+
 pub struct RenderState {
     skinned: HashMap<RenderKey, skinned::SingleRenderState>,
     sprites: HashMap<RenderKey, sprites::SingleRenderState>,
     flats: HashMap<RenderKey, flat::SingleRenderState>,
     textured: HashMap<RenderKey, textured::SingleRenderState>,
     pub(crate) camera: Camera,
+    pub(crate) lights: lights::Lights,
 }
 impl RenderState {
     pub fn new(cam: Camera) -> Self {
@@ -26,6 +55,7 @@ impl RenderState {
             flats: HashMap::new(),
             textured: HashMap::new(),
             camera: cam,
+            lights: lights::Lights::default(),
         }
     }
     pub fn camera_mut(&mut self) -> &mut Camera {
@@ -39,6 +69,7 @@ impl RenderState {
         self.sprites.clear();
         self.flats.clear();
         self.textured.clear();
+        self.lights = lights::Lights::default();
     }
     pub fn interpolate_from(&mut self, rs1: &Self, rs2: &Self, r: f32) {
         for (k, v1) in rs2.skinned.iter() {
@@ -58,6 +89,18 @@ impl RenderState {
             self.textured.insert(*k, v0.interpolate(v1, r));
         }
         self.camera = rs1.camera.interpolate(&rs2.camera, r);
+        self.lights = rs2.lights.clone();
+    }
+    pub fn lights(&self) -> &lights::Lights {
+        &self.lights
+    }
+    /// Light every surface by `color`, whatever the other lights are doing.
+    /// Defaults to white, which leaves everything unlit.
+    pub fn set_ambient(&mut self, color: Vec3) {
+        self.lights.ambient = color;
+    }
+    pub fn add_light(&mut self, light: lights::Light) {
+        self.lights.lights.push(light);
     }
 
     pub fn render_skinned(
@@ -102,7 +145,25 @@ impl RenderState {
             .sprites
             .insert(
                 RenderKey(key),
-                sprites::SingleRenderState::new(tex, region, transform, size),
+                sprites::SingleRenderState::new(tex, region, transform, size, false),
+            )
+            .is_none());
+    }
+    /// Like `render_sprite`, but blended over whatever's behind it instead
+    /// of cut out where it's mostly transparent.
+    pub fn render_transparent_sprite(
+        &mut self,
+        tex: assets::TextureRef,
+        region: Rect,
+        transform: Isometry3,
+        size: Vec2,
+        key: usize,
+    ) {
+        assert!(self
+            .sprites
+            .insert(
+                RenderKey(key),
+                sprites::SingleRenderState::new(tex, region, transform, size, true),
             )
             .is_none());
     }
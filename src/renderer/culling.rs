@@ -0,0 +1,325 @@
+//! Bounding volumes for meshes, and skipping instances nobody can see.
+//!
+//! Every mesh gets `Bounds` when it's imported.  Each renderer's `prepare`
+//! checks its instances against the camera's frustum: anything outside it
+//! isn't drawn in the main pass, and unless it's inside a shadow caster's
+//! frustum too (where it could still cast a shadow into view) it isn't drawn
+//! at all.
+use super::lights::Lights;
+use crate::camera::Camera;
+use crate::types::*;
+
+// how much bigger skinned meshes' bounds are than their bind pose, as a
+// fraction of its radius, since animations can reach past it
+pub const SKINNED_PADDING: f32 = 0.5;
+
+/// A mesh's extent in its own space: an axis-aligned box, and a sphere
+/// around the box's center.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub center: Vec3,
+    pub radius: f32,
+}
+impl Bounds {
+    pub fn from_points(points: &[Vec3]) -> Self {
+        if points.is_empty() {
+            return Self {
+                min: Vec3::zero(),
+                max: Vec3::zero(),
+                center: Vec3::zero(),
+                radius: 0.0,
+            };
+        }
+        let (min, max) = points.iter().fold((points[0], points[0]), |(lo, hi), p| {
+            (lo.min_by_component(*p), hi.max_by_component(*p))
+        });
+        let center = (min + max) / 2.0;
+        let radius = points
+            .iter()
+            .map(|p| (*p - center).mag())
+            .fold(0.0, f32::max);
+        Self {
+            min,
+            max,
+            center,
+            radius,
+        }
+    }
+    /// Grown by `amount` in every direction.
+    pub fn padded(&self, amount: f32) -> Self {
+        Self {
+            min: self.min - Vec3::broadcast(amount),
+            max: self.max + Vec3::broadcast(amount),
+            center: self.center,
+            radius: self.radius + amount,
+        }
+    }
+    /// World-space bounds of an instance drawn with `trf`: the box is the
+    /// smallest axis-aligned one around the rotated box.
+    pub fn transformed(&self, trf: &Similarity3) -> Self {
+        let center = trf.transform_vec(self.center);
+        let half = (self.max - self.min) / 2.0 * trf.scale;
+        let rot = trf.rotation.into_matrix();
+        let reach = |row: usize| {
+            rot.cols[0][row].abs() * half.x
+                + rot.cols[1][row].abs() * half.y
+                + rot.cols[2][row].abs() * half.z
+        };
+        let extent = Vec3::new(reach(0), reach(1), reach(2));
+        let box_center = trf.transform_vec((self.min + self.max) / 2.0);
+        Self {
+            min: box_center - extent,
+            max: box_center + extent,
+            center,
+            radius: self.radius * trf.scale,
+        }
+    }
+}
+
+/// The planes around everything a view-projection matrix can see, with
+/// their normals pointing in.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+impl Frustum {
+    pub fn new(viewproj: Mat4) -> Self {
+        let c = viewproj.cols;
+        let row = |i: usize| Vec4::new(c[0][i], c[1][i], c[2][i], c[3][i]);
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        // left, right, bottom, top, and depth 0 and 1.  An infinite
+        // projection has no far plane, so that one comes out empty and
+        // everything's on its inside.
+        let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2].map(|p| {
+            let len = p.xyz().mag();
+            if len < 1e-6 {
+                Vec4::new(0.0, 0.0, 0.0, 1.0)
+            } else {
+                p / len
+            }
+        });
+        Self { planes }
+    }
+    /// Whether anything within world-space `bounds` might be visible.
+    pub fn intersects(&self, bounds: &Bounds) -> bool {
+        self.planes.iter().all(|p| {
+            let n = p.xyz();
+            if n.dot(bounds.center) + p.w < -bounds.radius {
+                return false;
+            }
+            // the box's corner farthest along the normal
+            let corner = Vec3::new(
+                if n.x >= 0.0 {
+                    bounds.max.x
+                } else {
+                    bounds.min.x
+                },
+                if n.y >= 0.0 {
+                    bounds.max.y
+                } else {
+                    bounds.min.y
+                },
+                if n.z >= 0.0 {
+                    bounds.max.z
+                } else {
+                    bounds.min.z
+                },
+            );
+            n.dot(corner) + p.w >= 0.0
+        })
+    }
+}
+
+/// What to do with one instance this frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Visibility {
+    Visible,
+    // off screen, but it might cast a shadow that isn't
+    ShadowOnly,
+    Culled,
+}
+
+/// The camera's and shadow casters' frusta, made once a frame.
+pub(crate) struct Culler {
+    camera: Frustum,
+    shadows: Vec<Frustum>,
+}
+impl Culler {
+    pub(crate) fn new(camera: &Camera, lights: &Lights) -> Self {
+        Self {
+            camera: Frustum::new(camera.as_matrix()),
+            shadows: lights
+                .shadow_views(camera)
+                .into_iter()
+                .map(Frustum::new)
+                .collect(),
+        }
+    }
+    /// Where an instance of a mesh with `bounds` drawn with `trf` is needed.
+    pub(crate) fn classify(&self, bounds: &Bounds, trf: &Similarity3) -> Visibility {
+        let world = bounds.transformed(trf);
+        if self.camera.intersects(&world) {
+            Visibility::Visible
+        } else if self.shadows.iter().any(|f| f.intersects(&world)) {
+            Visibility::ShadowOnly
+        } else {
+            Visibility::Culled
+        }
+    }
+}
+
+/// How many instances a renderer handled last frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub drawn: usize,
+    // only drawn into shadow maps
+    pub shadow_only: usize,
+    pub culled: usize,
+}
+impl CullStats {
+    pub(crate) fn count(&mut self, vis: Visibility) {
+        match vis {
+            Visibility::Visible => self.drawn += 1,
+            Visibility::ShadowOnly => self.shadow_only += 1,
+            Visibility::Culled => self.culled += 1,
+        }
+    }
+}
+impl std::ops::Add for CullStats {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            drawn: self.drawn + other.drawn,
+            shadow_only: self.shadow_only + other.shadow_only,
+            culled: self.culled + other.culled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::lights::Light;
+
+    // looking down -z from the origin
+    fn camera() -> Camera {
+        Camera::look_at(Vec3::zero(), -Vec3::unit_z(), Vec3::unit_y())
+    }
+    fn cube(center: Vec3, half: f32) -> Bounds {
+        Bounds::from_points(&[
+            center - Vec3::broadcast(half),
+            center + Vec3::broadcast(half),
+        ])
+    }
+    fn at(center: Vec3) -> Similarity3 {
+        Similarity3::new(center, Rotor3::identity(), 1.0)
+    }
+
+    #[test]
+    fn frustum_planes() {
+        let f = Frustum::new(camera().as_matrix());
+        // 90 degrees tall and 4/3 as wide, so 10 units out the edges are 10
+        // units up and down and 13.33 to the sides
+        assert!(f.intersects(&cube(Vec3::new(0.0, 0.0, -10.0), 1.0)));
+        for (outside, straddling) in [
+            (Vec3::new(-16.0, 0.0, -10.0), Vec3::new(-13.3, 0.0, -10.0)),
+            (Vec3::new(16.0, 0.0, -10.0), Vec3::new(13.3, 0.0, -10.0)),
+            (Vec3::new(0.0, -13.0, -10.0), Vec3::new(0.0, -10.0, -10.0)),
+            (Vec3::new(0.0, 13.0, -10.0), Vec3::new(0.0, 10.0, -10.0)),
+            (Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 0.0)),
+        ] {
+            assert!(!f.intersects(&cube(outside, 1.0)), "{:?}", outside);
+            assert!(f.intersects(&cube(straddling, 1.0)), "{:?}", straddling);
+        }
+        // there's no far plane
+        assert!(f.intersects(&cube(Vec3::new(0.0, 0.0, -1.0e5), 1.0)));
+    }
+
+    #[test]
+    fn transformed_bounds() {
+        let b = Bounds::from_points(&[Vec3::zero(), Vec3::new(2.0, 1.0, 1.0)]);
+        let corners = |b: &Bounds| {
+            [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+                Vec3::new(
+                    if i & 1 == 0 { b.min.x } else { b.max.x },
+                    if i & 2 == 0 { b.min.y } else { b.max.y },
+                    if i & 4 == 0 { b.min.z } else { b.max.z },
+                )
+            })
+        };
+        for angle in [0.0, PI / 2.0, PI / 4.0, 1.0] {
+            let trf = Similarity3::new(
+                Vec3::new(10.0, 0.0, -3.0),
+                Rotor3::from_euler_angles(0.3, 0.0, angle),
+                2.0,
+            );
+            let world = b.transformed(&trf);
+            assert!((world.center - trf.transform_vec(b.center)).mag() < 1e-4);
+            assert!((world.radius - 2.0 * b.radius).abs() < 1e-4);
+            // holds every corner of the moved box...
+            let moved = corners(&b).map(|c| trf.transform_vec(c));
+            for c in moved {
+                assert!(c.x >= world.min.x - 1e-4 && c.x <= world.max.x + 1e-4);
+                assert!(c.y >= world.min.y - 1e-4 && c.y <= world.max.y + 1e-4);
+                assert!(c.z >= world.min.z - 1e-4 && c.z <= world.max.z + 1e-4);
+            }
+            // ...and no more than it has to
+            let lo = moved.iter().fold(moved[0], |a, c| a.min_by_component(*c));
+            let hi = moved.iter().fold(moved[0], |a, c| a.max_by_component(*c));
+            assert!((lo - world.min).mag() < 1e-4 && (hi - world.max).mag() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn classify_and_count() {
+        let cam = camera();
+        let sun = Light::Directional {
+            dir: -Vec3::unit_y(),
+            color: Vec3::one(),
+            shadow: true,
+        };
+        let lit = Culler::new(
+            &cam,
+            &Lights {
+                ambient: Vec3::one(),
+                lights: vec![sun],
+            },
+        );
+        let unlit = Culler::new(&cam, &Lights::default());
+        let b = cube(Vec3::zero(), 1.0);
+        let ahead = at(Vec3::new(0.0, 0.0, -10.0));
+        // behind the camera, but close enough to shadow what it sees
+        let behind = at(Vec3::new(0.0, 5.0, 10.0));
+        let far = at(Vec3::new(500.0, 0.0, 10.0));
+        assert_eq!(lit.classify(&b, &ahead), Visibility::Visible);
+        assert_eq!(lit.classify(&b, &behind), Visibility::ShadowOnly);
+        assert_eq!(lit.classify(&b, &far), Visibility::Culled);
+        assert_eq!(unlit.classify(&b, &ahead), Visibility::Visible);
+        assert_eq!(unlit.classify(&b, &behind), Visibility::Culled);
+
+        let mut stats = CullStats::default();
+        for trf in [ahead, behind, far, ahead] {
+            stats.count(lit.classify(&b, &trf));
+        }
+        assert_eq!(
+            stats,
+            CullStats {
+                drawn: 2,
+                shadow_only: 1,
+                culled: 1
+            }
+        );
+        let mut other = CullStats::default();
+        other.count(Visibility::Culled);
+        assert_eq!(
+            stats + other,
+            CullStats {
+                drawn: 2,
+                shadow_only: 1,
+                culled: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! The lights a scene is shaded with.
+//!
+//! Worlds add lights to their `RenderState` every frame, like anything else
+//! they draw.  The flat, textured and skinned renderers all upload the same
+//! `LightData` to set 0, binding 1 and shade with `shaders/lighting.glsl`, so
+//! a mesh looks the same whichever renderer draws it.
+//!
+//! Directional and spot lights can cast shadows.  Before the main pass,
+//! every renderer draws its batches into the caster's shadow map (set 0,
+//! binding 2) from the light's point of view, using `Light::shadow_view`.
+use crate::camera::Camera;
+use crate::types::*;
+use crate::vulkan::SHADOW_MAP_SIZE;
+use bytemuck::{Pod, Zeroable};
+
+// must match MAX_LIGHTS and MAX_SHADOWS in shaders/lighting.glsl
+pub const MAX_LIGHTS: usize = 16;
+pub const MAX_SHADOWS: usize = 4;
+// how far around the camera a directional light's shadows reach
+pub const DIRECTIONAL_SHADOW_RADIUS: f32 = 30.0;
+// near plane of a spot light's shadow map
+const SPOT_SHADOW_NEAR: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Light {
+    /// Light from far away (like the sun) shining along `dir`.
+    Directional {
+        dir: Vec3,
+        color: Vec3,
+        shadow: bool,
+    },
+    /// Light from `pos` that fades out completely `range` units away.
+    Point { pos: Vec3, color: Vec3, range: f32 },
+    /// A point light that only shines within `angle` radians of `dir`,
+    /// softening over the outer fifth of the cone.
+    Spot {
+        pos: Vec3,
+        dir: Vec3,
+        color: Vec3,
+        range: f32,
+        angle: f32,
+        shadow: bool,
+    },
+}
+impl Light {
+    pub fn casts_shadow(&self) -> bool {
+        match *self {
+            Light::Directional { shadow, .. } | Light::Spot { shadow, .. } => shadow,
+            Light::Point { .. } => false,
+        }
+    }
+    /// The light-space view-projection its shadow map is drawn with, if it
+    /// casts shadows.  Directional lights cover the area around `camera`.
+    pub fn shadow_view(&self, camera: &Camera) -> Option<Mat4> {
+        if !self.casts_shadow() {
+            return None;
+        }
+        match *self {
+            Light::Directional { dir, .. } => {
+                let dir = dir.normalized();
+                let r = DIRECTIONAL_SHADOW_RADIUS;
+                let view = Mat4::look_at(Vec3::zero(), dir, up_for(dir));
+                // snap to whole shadow map texels, so shadow edges don't
+                // shimmer as the camera moves
+                let texel = 2.0 * r / SHADOW_MAP_SIZE as f32;
+                let c = view.transform_point3(camera.eye());
+                let (x, y) = ((c.x / texel).floor() * texel, (c.y / texel).floor() * texel);
+                let center = Mat4::from_translation(Vec3::new(-x, -y, 0.0));
+                // reach well back towards the light for anything in between.
+                // (off-center bounds come out upside down, so center first.)
+                let proj = ultraviolet::projection::rh_yup::orthographic_vk(
+                    -r,
+                    r,
+                    -r,
+                    r,
+                    -c.z - 3.0 * r,
+                    -c.z + r,
+                );
+                Some(proj * center * view)
+            }
+            Light::Spot {
+                pos,
+                dir,
+                range,
+                angle,
+                ..
+            } => {
+                let dir = dir.normalized();
+                let view = Mat4::look_at(pos, pos + dir, up_for(dir));
+                let proj = ultraviolet::projection::rh_yup::perspective_vk(
+                    2.0 * angle,
+                    1.0,
+                    SPOT_SHADOW_NEAR,
+                    range,
+                );
+                Some(proj * view)
+            }
+            Light::Point { .. } => None,
+        }
+    }
+}
+// anything not parallel to `dir`
+fn up_for(dir: Vec3) -> Vec3 {
+    if dir.y.abs() > 0.99 {
+        Vec3::unit_z()
+    } else {
+        Vec3::unit_y()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Lights {
+    // added to every surface regardless of the lights
+    pub ambient: Vec3,
+    // only the first MAX_LIGHTS are used, and of those only the first
+    // MAX_SHADOWS shadow casters get shadows
+    pub lights: Vec<Light>,
+}
+impl Default for Lights {
+    /// Full white ambient and no lights, which leaves colors as they are.
+    fn default() -> Self {
+        Self {
+            ambient: Vec3::one(),
+            lights: vec![],
+        }
+    }
+}
+impl Lights {
+    /// The view-projection of each shadow map in use, in shadow map order.
+    pub fn shadow_views(&self, camera: &Camera) -> Vec<Mat4> {
+        self.lights
+            .iter()
+            .take(MAX_LIGHTS)
+            .filter_map(|l| l.shadow_view(camera))
+            .take(MAX_SHADOWS)
+            .collect()
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Default, Pod, Debug)]
+struct GpuLight {
+    // xyz is the direction for a directional light (w = 0), or the position
+    // for a point (w = 1) or spot (w = 2) light
+    pos: [f32; 4],
+    // rgb is the color, a is the range
+    color: [f32; 4],
+    // a spot light's direction, and the cosine of its angle
+    dir: [f32; 4],
+    // which shadow map, or -1 for none
+    shadow: i32,
+    // where a spot light's cone starts to soften
+    cos_inner: f32,
+    _pad: [f32; 2],
+}
+
+/// The `Lights` uniform block from `shaders/lighting.glsl`, laid out std140.
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod, Debug)]
+pub(crate) struct LightData {
+    ambient: [f32; 4],
+    eye: [f32; 4],
+    count: u32,
+    _pad: [u32; 3],
+    lights: [GpuLight; MAX_LIGHTS],
+    shadow_views: [[f32; 16]; MAX_SHADOWS],
+}
+impl LightData {
+    pub(crate) fn new(lights: &Lights, camera: &Camera) -> Self {
+        let mut data = Self {
+            ambient: lights.ambient.into_homogeneous_vector().into(),
+            eye: camera.eye().into_homogeneous_point().into(),
+            count: lights.lights.len().min(MAX_LIGHTS) as u32,
+            _pad: [0; 3],
+            lights: [GpuLight::default(); MAX_LIGHTS],
+            shadow_views: [[0.0; 16]; MAX_SHADOWS],
+        };
+        let mut shadows = 0;
+        for (gl, l) in data.lights.iter_mut().zip(lights.lights.iter()) {
+            *gl = match *l {
+                Light::Directional { dir, color, .. } => GpuLight {
+                    pos: dir.normalized().into_homogeneous_vector().into(),
+                    color: color.into_homogeneous_vector().into(),
+                    ..GpuLight::default()
+                },
+                Light::Point { pos, color, range } => GpuLight {
+                    pos: pos.into_homogeneous_point().into(),
+                    color: [color.x, color.y, color.z, range],
+                    ..GpuLight::default()
+                },
+                Light::Spot {
+                    pos,
+                    dir,
+                    color,
+                    range,
+                    angle,
+                    ..
+                } => {
+                    let dir = dir.normalized();
+                    GpuLight {
+                        pos: [pos.x, pos.y, pos.z, 2.0],
+                        color: [color.x, color.y, color.z, range],
+                        dir: [dir.x, dir.y, dir.z, angle.cos()],
+                        cos_inner: (angle * 0.8).cos(),
+                        ..GpuLight::default()
+                    }
+                }
+            };
+            gl.shadow = -1;
+            if shadows < MAX_SHADOWS {
+                if let Some(view) = l.shadow_view(camera) {
+                    data.shadow_views[shadows] = *view.as_array();
+                    gl.shadow = shadows as i32;
+                    shadows += 1;
+                }
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera::look_at(
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(4.0, 2.0, 8.0),
+            Vec3::unit_y(),
+        )
+    }
+    fn sun(shadow: bool) -> Light {
+        Light::Directional {
+            dir: Vec3::new(1.0, -2.0, 0.5),
+            color: Vec3::one(),
+            shadow,
+        }
+    }
+    fn lamp() -> Light {
+        Light::Point {
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            color: Vec3::new(0.5, 0.25, 0.125),
+            range: 10.0,
+        }
+    }
+    // where `p` lands in a shadow map drawn with `view`
+    fn project(view: Mat4, p: Vec3) -> Vec3 {
+        let clip = view * p.into_homogeneous_point();
+        clip.xyz() / clip.w
+    }
+    fn in_map(p: Vec3) -> bool {
+        p.x.abs() <= 1.0 && p.y.abs() <= 1.0 && (0.0..=1.0).contains(&p.z)
+    }
+
+    #[test]
+    fn packs_like_std140() {
+        assert_eq!(std::mem::size_of::<GpuLight>(), 64);
+        assert_eq!(
+            std::mem::size_of::<LightData>(),
+            16 + 16 + 16 + 64 * MAX_LIGHTS + 64 * MAX_SHADOWS
+        );
+        let lights = Lights {
+            ambient: Vec3::new(0.1, 0.2, 0.3),
+            lights: vec![
+                lamp(),
+                Light::Spot {
+                    pos: Vec3::new(4.0, 5.0, 6.0),
+                    dir: Vec3::new(0.0, -2.0, 0.0),
+                    color: Vec3::one(),
+                    range: 8.0,
+                    angle: 0.5,
+                    shadow: false,
+                },
+            ],
+        };
+        let cam = camera();
+        let data = LightData::new(&lights, &cam);
+        let floats: &[f32] = bytemuck::cast_slice(bytemuck::bytes_of(&data));
+        let ints: &[i32] = bytemuck::cast_slice(bytemuck::bytes_of(&data));
+        assert_eq!(floats[0..4], [0.1, 0.2, 0.3, 0.0]);
+        let eye = cam.eye();
+        assert_eq!(floats[4..8], [eye.x, eye.y, eye.z, 1.0]);
+        assert_eq!(ints[8], 2);
+        // the point light
+        assert_eq!(floats[12..16], [1.0, 2.0, 3.0, 1.0]);
+        assert_eq!(floats[16..20], [0.5, 0.25, 0.125, 10.0]);
+        assert_eq!(ints[24], -1);
+        // the spot light
+        let spot = &floats[28..44];
+        assert_eq!(spot[0..8], [4.0, 5.0, 6.0, 2.0, 1.0, 1.0, 1.0, 8.0]);
+        assert_eq!(spot[8..12], [0.0, -1.0, 0.0, 0.5f32.cos()]);
+        assert_eq!(ints[28 + 12], -1);
+        assert_eq!(spot[13], 0.4f32.cos());
+    }
+
+    #[test]
+    fn only_so_many_lights_and_shadows() {
+        let mut lights = Lights::default();
+        // casters and non-casters mixed, more of each than fit
+        for _ in 0..MAX_LIGHTS {
+            lights.lights.push(lamp());
+            lights.lights.push(sun(true));
+            lights.lights.push(sun(false));
+        }
+        let cam = camera();
+        let data = LightData::new(&lights, &cam);
+        assert_eq!(data.count as usize, MAX_LIGHTS);
+        let slots: Vec<i32> = data.lights.iter().map(|l| l.shadow).collect();
+        let mut expected = vec![];
+        let mut next = 0;
+        for l in lights.lights.iter().take(MAX_LIGHTS) {
+            if l.casts_shadow() && next < MAX_SHADOWS as i32 {
+                expected.push(next);
+                next += 1;
+            } else {
+                expected.push(-1);
+            }
+        }
+        assert_eq!(slots, expected);
+        assert_eq!(next, MAX_SHADOWS as i32);
+        // and shadow_views gives the maps in the same order
+        let views = lights.shadow_views(&cam);
+        assert_eq!(views.len(), MAX_SHADOWS);
+        for (view, slot) in views.iter().zip(data.shadow_views.iter()) {
+            assert_eq!(view.as_array(), slot);
+        }
+    }
+
+    #[test]
+    fn shadow_views() {
+        let cam = camera();
+        assert!(lamp().shadow_view(&cam).is_none());
+        assert!(sun(false).shadow_view(&cam).is_none());
+        let view = sun(true).shadow_view(&cam).unwrap();
+        // the camera's surroundings are in the map...
+        assert!(in_map(project(view, cam.eye())));
+        let off = Vec3::new(20.0, -1.0, -15.0);
+        assert!(in_map(project(view, cam.eye() + off)));
+        // ...including things between them and the sun...
+        let toward_sun = -Vec3::new(1.0, -2.0, 0.5).normalized();
+        let high = project(view, cam.eye() + toward_sun * 50.0);
+        assert!(in_map(high));
+        // ...which come out nearer the light
+        assert!(high.z < project(view, cam.eye()).z);
+        // but not far away
+        assert!(!in_map(project(
+            view,
+            cam.eye() + Vec3::new(100.0, 0.0, 0.0)
+        )));
+        let spot = Light::Spot {
+            pos: Vec3::new(0.0, 5.0, 0.0),
+            dir: -Vec3::unit_y(),
+            color: Vec3::one(),
+            range: 10.0,
+            angle: 0.5,
+            shadow: true,
+        };
+        let view = spot.shadow_view(&cam).unwrap();
+        let below = project(view, Vec3::new(0.0, 0.0, 0.0));
+        assert!(below.x.abs() < 1e-4 && below.y.abs() < 1e-4, "{:?}", below);
+        assert!(in_map(below));
+        assert!(in_map(project(view, Vec3::new(1.0, 1.0, 1.0))));
+        // beyond the range or outside the cone
+        assert!(!in_map(project(view, Vec3::new(0.0, -6.0, 0.0))));
+        assert!(!in_map(project(view, Vec3::new(4.0, 4.0, 0.0))));
+        assert_eq!(Lights::default().shadow_views(&cam), vec![]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Levels of detail: cheaper versions of a model to draw in its place once
+//! it's far away or small on screen.
+//!
+//! Flat and textured models can carry coarser levels after their full
+//! detail meshes (see `flat::Model::with_lod`), either loaded from their own
+//! files or made at import by `decimate`.  Each renderer's `prepare` picks a
+//! level per instance.  So that an instance sitting near a threshold doesn't
+//! keep popping between levels, it only moves to a coarser level once it's
+//! `HYSTERESIS` past that level's threshold, and only moves back once it's
+//! `HYSTERESIS` short of it.
+use super::culling::Bounds;
+use super::RenderKey;
+use crate::camera::Camera;
+use crate::types::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// how far past a threshold, as a fraction of it, an instance has to go
+// before it changes level
+pub const HYSTERESIS: f32 = 0.1;
+
+/// When to switch to a level.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum Threshold {
+    // once the model's center is farther than this from the camera
+    Distance(f32),
+    // once the model's bounding sphere is shorter than this fraction of the
+    // screen's height
+    ScreenSize(f32),
+}
+impl Threshold {
+    // above 1 past the threshold, below 1 short of it
+    fn progress(&self, dist: f32, radius: f32, camera: &Camera) -> f32 {
+        match *self {
+            Threshold::Distance(d) => dist / d,
+            Threshold::ScreenSize(frac) => {
+                let size = radius / (dist.max(camera.near) * (camera.fov / 2.0).tan());
+                frac / size.max(f32::EPSILON)
+            }
+        }
+    }
+}
+
+/// Every instance's level last frame, for hysteresis.
+#[derive(Default)]
+pub(crate) struct Levels {
+    last: HashMap<RenderKey, usize>,
+    current: HashMap<RenderKey, usize>,
+}
+impl Levels {
+    /// Forget instances that weren't drawn last frame.  Call at the start of
+    /// `prepare`.
+    pub(crate) fn start_frame(&mut self) {
+        std::mem::swap(&mut self.last, &mut self.current);
+        self.current.clear();
+    }
+    /// Which level to draw the instance `key` at this frame, 0 being full
+    /// detail and `n` the `n`th of `thresholds`.  `center` and `radius` are
+    /// the world-space bounding sphere of its full detail meshes.
+    pub(crate) fn pick(
+        &mut self,
+        key: RenderKey,
+        thresholds: impl Iterator<Item = Threshold>,
+        center: Vec3,
+        radius: f32,
+        camera: &Camera,
+    ) -> usize {
+        let dist = (center - camera.eye()).mag();
+        let last = self.last.get(&key).copied().unwrap_or(0);
+        let mut level = 0;
+        for (i, t) in thresholds.enumerate() {
+            // already at this level or coarser: stay unless well short of
+            // it; finer: only switch once well past it
+            let needed = if i < last {
+                1.0 - HYSTERESIS
+            } else {
+                1.0 + HYSTERESIS
+            };
+            if t.progress(dist, radius, camera) <= needed {
+                break;
+            }
+            level = i + 1;
+        }
+        self.current.insert(key, level);
+        level
+    }
+}
+
+/// Simplify a triangle mesh by vertex clustering: vertices are snapped to a
+/// grid sized so that about `ratio` of them survive, each cell keeps its
+/// first vertex, and triangles that collapse are dropped.  Cheap rather than
+/// pretty, but fine for meshes seen from far away.  Normals and texture
+/// coordinates aren't blended, so seams can smear.
+pub fn decimate<V: Copy>(
+    verts: &[V],
+    idx: &[u32],
+    ratio: f32,
+    position: impl Fn(&V) -> Vec3,
+) -> (Vec<V>, Vec<u32>) {
+    let points: Vec<Vec3> = verts.iter().map(position).collect();
+    let bounds = Bounds::from_points(&points);
+    let extent = bounds.max - bounds.min;
+    let longest = extent.x.max(extent.y).max(extent.z);
+    if ratio >= 1.0 || longest <= 0.0 {
+        return (verts.to_vec(), idx.to_vec());
+    }
+    // a surface passes through about cells^2 cells of the grid, not cells^3
+    let cells = (verts.len() as f32 * ratio.max(0.0)).sqrt().ceil().max(1.0);
+    let cell_size = longest / cells;
+    let mut clusters: HashMap<(i32, i32, i32), u32> = HashMap::new();
+    let mut out_verts = vec![];
+    let remap: Vec<u32> = points
+        .iter()
+        .zip(verts.iter())
+        .map(|(p, v)| {
+            let c = (*p - bounds.min) / cell_size;
+            *clusters
+                .entry((c.x as i32, c.y as i32, c.z as i32))
+                .or_insert_with(|| {
+                    out_verts.push(*v);
+                    (out_verts.len() - 1) as u32
+                })
+        })
+        .collect();
+    let out_idx = idx
+        .chunks_exact(3)
+        .map(|t| {
+            [
+                remap[t[0] as usize],
+                remap[t[1] as usize],
+                remap[t[2] as usize],
+            ]
+        })
+        .filter(|[a, b, c]| a != b && b != c && a != c)
+        .flatten()
+        .collect();
+    (out_verts, out_idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an n by n grid of vertices in the xz plane, two triangles per square
+    fn grid(n: u32) -> (Vec<Vec3>, Vec<u32>) {
+        let verts = (0..n * n)
+            .map(|i| Vec3::new((i % n) as f32, 0.0, (i / n) as f32))
+            .collect();
+        let mut idx = vec![];
+        for z in 0..n - 1 {
+            for x in 0..n - 1 {
+                let i = z * n + x;
+                idx.extend([i, i + n, i + 1, i + 1, i + n, i + n + 1]);
+            }
+        }
+        (verts, idx)
+    }
+
+    #[test]
+    fn decimate_keeps_full_detail() {
+        let (verts, idx) = grid(8);
+        for ratio in [1.0, 2.0] {
+            let (v, i) = decimate(&verts, &idx, ratio, |v| *v);
+            assert_eq!(v, verts);
+            assert_eq!(i, idx);
+        }
+    }
+
+    #[test]
+    fn decimate_drops_collapsed_triangles() {
+        let (verts, idx) = grid(20);
+        let (v, i) = decimate(&verts, &idx, 0.1, |v| *v);
+        assert!(v.len() < verts.len() / 2, "{} vertices left", v.len());
+        assert!(!i.is_empty() && i.len() < idx.len());
+        assert_eq!(i.len() % 3, 0);
+        assert!(i.iter().all(|&i| (i as usize) < v.len()));
+        for t in i.chunks_exact(3) {
+            assert!(t[0] != t[1] && t[1] != t[2] && t[0] != t[2], "{:?}", t);
+        }
+    }
+
+    // the level of a model `dist` in front of the camera this frame
+    fn pick_at(levels: &mut Levels, dist: f32) -> usize {
+        let camera = Camera::from_rotation(Vec3::zero(), Rotor3::identity());
+        let thresholds = [Threshold::Distance(10.0)].into_iter();
+        let center = Vec3::new(0.0, 0.0, -dist);
+        levels.start_frame();
+        levels.pick(RenderKey(0), thresholds, center, 1.0, &camera)
+    }
+
+    #[test]
+    fn pick_waits_out_hysteresis() {
+        let mut levels = Levels::default();
+        // just past the threshold isn't far enough to switch...
+        assert_eq!(pick_at(&mut levels, 10.5), 0);
+        assert_eq!(pick_at(&mut levels, 11.5), 1);
+        // ...nor is just short of it far enough to switch back
+        assert_eq!(pick_at(&mut levels, 9.5), 1);
+        assert_eq!(pick_at(&mut levels, 8.5), 0);
+        // an instance that wasn't drawn last frame starts from full detail
+        assert_eq!(pick_at(&mut levels, 11.5), 1);
+        levels.start_frame();
+        assert_eq!(pick_at(&mut levels, 10.5), 0);
+    }
+}
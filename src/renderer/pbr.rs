@@ -0,0 +1,247 @@
+//! Metallic-roughness materials, as in glTF, and the environment cubemap
+//! that lights them from every direction.
+//!
+//! `shaders/lighting.glsl` shades every surface with a material (in the flat
+//! and textured renderers) with a GGX specular term.  Ambient light comes from the `Environment` (set 0, binding 3),
+//! tinted by `Lights::ambient`: its blurrier mip levels stand in for rougher
+//! reflections, and the blurriest for diffuse light.
+use crate::image::Image;
+use crate::types::*;
+use crate::vulkan::Vulkan;
+use bytemuck::{Pod, Zeroable};
+use color_eyre::eyre::{ensure, Result};
+use std::sync::Arc;
+use vulkano::image::immutable::ImmutableImage;
+use vulkano::image::view::ImageView;
+use vulkano::sampler::Sampler;
+
+// what materials without any PBR properties get, which looks close to the
+// Blinn-Phong shading of surfaces without a material
+pub const DEFAULT_ROUGHNESS: f32 = 0.5;
+
+/// How a material's surface responds to light.  Each factor scales the
+/// matching texture, if the material has one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Factors {
+    pub base_color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vec3,
+    // drawn after everything opaque, back to front and blended by alpha,
+    // instead of being cut out where alpha is low
+    pub transparent: bool,
+}
+impl Default for Factors {
+    fn default() -> Self {
+        Self {
+            base_color: Vec4::one(),
+            metallic: 0.0,
+            roughness: DEFAULT_ROUGHNESS,
+            emissive: Vec3::zero(),
+            transparent: false,
+        }
+    }
+}
+
+/// The `Material` uniform block in the flat and textured shaders, laid out
+/// std140.
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod, Debug, Default)]
+pub(crate) struct FactorData {
+    base_color: [f32; 4],
+    emissive: [f32; 4],
+    metallic: f32,
+    roughness: f32,
+    _pad: [f32; 2],
+}
+impl From<&Factors> for FactorData {
+    fn from(f: &Factors) -> Self {
+        Self {
+            base_color: f.base_color.into(),
+            emissive: f.emissive.into_homogeneous_vector().into(),
+            metallic: f.metallic,
+            roughness: f.roughness,
+            _pad: [0.0; 2],
+        }
+    }
+}
+
+/// A cubemap with faces in +x, -x, +y, -y, +z, -z order, along with every
+/// mip level down to 1x1.  Mip levels are plain averages rather than
+/// properly convolved, which is close enough for rough reflections.
+pub struct Environment {
+    // levels[0] is the full size faces
+    levels: Vec<Vec<Image>>,
+    // None until `Assets::upload`
+    pub(crate) gpu: Option<(Arc<ImageView<ImmutableImage>>, Arc<Sampler>)>,
+}
+impl Environment {
+    /// `faces` must be six square sRGB images of the same power-of-two size.
+    pub fn new(faces: Vec<Image>) -> Result<Self> {
+        ensure!(
+            faces.len() == 6,
+            "Environment needs 6 faces, got {}",
+            faces.len()
+        );
+        let size = faces[0].sz.x;
+        ensure!(
+            size.is_power_of_two() && faces.iter().all(|f| f.sz.x == size && f.sz.y == size),
+            "Environment faces must all be the same power-of-two square size"
+        );
+        let mut levels = vec![faces];
+        while levels.last().unwrap()[0].sz.x > 1 {
+            let next = levels.last().unwrap().iter().map(downsample).collect();
+            levels.push(next);
+        }
+        Ok(Self { levels, gpu: None })
+    }
+    /// The same color in every direction.
+    pub fn uniform(color: Color) -> Self {
+        let faces = (0..6)
+            .map(|_| {
+                let mut img = Image::new(1, 1);
+                img.as_mut_slice()[0] = color;
+                img
+            })
+            .collect();
+        Self {
+            levels: vec![faces],
+            gpu: None,
+        }
+    }
+    pub(crate) fn upload(&mut self, vulkan: &mut Vulkan) -> Result<()> {
+        use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+        use vulkano::command_buffer::{
+            AutoCommandBufferBuilder, CommandBufferUsage, PrimaryCommandBuffer,
+        };
+        use vulkano::format::Format;
+        use vulkano::image::view::{ImageViewCreateInfo, ImageViewType};
+        use vulkano::image::{
+            ImageCreateFlags, ImageDimensions, ImageLayout, ImageUsage, MipmapsCount,
+        };
+        use vulkano::sampler::{
+            Filter, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, LOD_CLAMP_NONE,
+        };
+        use vulkano::sync::GpuFuture;
+        if self.gpu.is_some() {
+            return Ok(());
+        }
+        let size = self.levels[0][0].sz.x;
+        // ImmutableImage::from_iter can't make cube-compatible images, so
+        // fill each level by hand
+        let (image, init) = ImmutableImage::uninitialized(
+            vulkan.device.clone(),
+            ImageDimensions::Dim2d {
+                width: size,
+                height: size,
+                array_layers: 6,
+            },
+            Format::R8G8B8A8_SRGB,
+            MipmapsCount::Specific(self.levels.len() as u32),
+            ImageUsage {
+                transfer_destination: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+            ImageCreateFlags {
+                cube_compatible: true,
+                ..ImageCreateFlags::none()
+            },
+            ImageLayout::ShaderReadOnlyOptimal,
+            vulkan.device.active_queue_families(),
+        )?;
+        let mut builder = AutoCommandBufferBuilder::primary(
+            vulkan.device.clone(),
+            vulkan.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        for (level, faces) in self.levels.iter().enumerate() {
+            let sz = faces[0].sz.x;
+            let buffer = CpuAccessibleBuffer::from_iter(
+                vulkan.device.clone(),
+                BufferUsage::transfer_source(),
+                false,
+                faces.iter().flat_map(|f| f.as_slice().iter().copied()),
+            )?;
+            builder.copy_buffer_to_image_dimensions(
+                buffer,
+                init.clone(),
+                [0, 0, 0],
+                [sz, sz, 1],
+                0,
+                6,
+                level as u32,
+            )?;
+        }
+        let fut = builder
+            .build()?
+            .execute(vulkan.queue.clone())?
+            .then_signal_fence_and_flush()?;
+        vulkan.wait_for(Box::new(fut));
+        let view = ImageView::new(
+            image.clone(),
+            ImageViewCreateInfo {
+                view_type: ImageViewType::Cube,
+                ..ImageViewCreateInfo::from_image(&image)
+            },
+        )?;
+        let sampler = Sampler::new(
+            vulkan.device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                lod: 0.0..=LOD_CLAMP_NONE,
+                ..Default::default()
+            },
+        )?;
+        self.gpu = Some((view, sampler));
+        Ok(())
+    }
+    /// The cubemap and its sampler, for a lit pipeline.
+    pub(crate) fn descriptor_write(
+        &self,
+        binding: u32,
+    ) -> vulkano::descriptor_set::WriteDescriptorSet {
+        let (view, sampler) = self
+            .gpu
+            .clone()
+            .expect("environment used for drawing before Assets::upload");
+        vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(binding, view, sampler)
+    }
+}
+
+// half the size, averaging each 2x2 block in linear color
+fn downsample(img: &Image) -> Image {
+    let (w, h) = ((img.sz.x / 2).max(1), (img.sz.y / 2).max(1));
+    let mut out = Image::new(w, h);
+    let src = img.as_slice();
+    for y in 0..h {
+        for x in 0..w {
+            let mut sum = [0.0_f32; 4];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let sx = (2 * x + dx).min(img.sz.x - 1);
+                let sy = (2 * y + dy).min(img.sz.y - 1);
+                let Color(r, g, b, a) = src[(sy * img.sz.x + sx) as usize];
+                sum[0] += to_linear(r);
+                sum[1] += to_linear(g);
+                sum[2] += to_linear(b);
+                sum[3] += a as f32 / 255.0;
+            }
+            out.as_mut_slice()[(y * w + x) as usize] = Color(
+                from_linear(sum[0] / 4.0),
+                from_linear(sum[1] / 4.0),
+                from_linear(sum[2] / 4.0),
+                (sum[3] / 4.0 * 255.0).round() as u8,
+            );
+        }
+    }
+    out
+}
+fn to_linear(c: u8) -> f32 {
+    (c as f32 / 255.0).powf(2.2)
+}
+fn from_linear(c: f32) -> u8 {
+    (c.powf(1.0 / 2.2) * 255.0).round() as u8
+}
@@ -0,0 +1,178 @@
+// Lighting shared by every lit renderer: `shade_surface` gives materials
+// metallic-roughness shading with GGX highlights, and `shade` gives anything
+// without a material Lambert diffuse plus Blinn-Phong highlights.  The
+// uniform block matches renderer::lights::LightData, and the environment is
+// renderer::pbr::Environment.
+
+#define MAX_LIGHTS 16
+#define MAX_SHADOWS 4
+
+const float PI = 3.14159265;
+const float SHININESS = 32.0;
+const float SPECULAR = 0.25;
+// how much light non-metals reflect head-on
+const vec3 DIELECTRIC_F0 = vec3(0.04);
+// how far to push a point off its surface before looking it up in a shadow
+// map, and how much nearer than the stored depth it has to be, to keep
+// surfaces from shadowing themselves
+const float SHADOW_NORMAL_OFFSET = 0.05;
+const float SHADOW_BIAS = 0.0005;
+
+struct Light {
+  // direction for a directional light (w = 0), position for a point (w = 1)
+  // or spot (w = 2) light
+  vec4 pos;
+  // rgb is the color, a is the range
+  vec4 color;
+  // a spot light's direction, and the cosine of its angle
+  vec4 dir;
+  // which shadow map, or -1 for none
+  int shadow;
+  // where a spot light's cone starts to soften
+  float cos_inner;
+};
+
+layout(set = 0, binding = 1) uniform Lights {
+  vec4 ambient;
+  vec4 eye;
+  uint light_count;
+  Light lights[MAX_LIGHTS];
+  mat4 shadow_views[MAX_SHADOWS];
+};
+layout(set = 0, binding = 2) uniform sampler2DShadow shadow_maps[MAX_SHADOWS];
+// ambient light from every direction, blurrier at higher mip levels
+layout(set = 0, binding = 3) uniform samplerCube environment;
+
+// how much of a point light reaches `dist` away: falling off with the square
+// of the distance (measured in ranges), and eased down to exactly zero at the
+// range so lights don't reach further than they say
+float attenuate(float dist, float range) {
+  float d = dist / range;
+  float edge = clamp(1.0 - d * d * d * d, 0.0, 1.0);
+  return edge * edge / (1.0 + 4.0 * d * d);
+}
+
+// how lit `pos` is according to shadow map `which`, from 0 to 1, averaging
+// a 3x3 block of depth comparisons (percentage-closer filtering)
+float shadow(int which, vec3 pos, vec3 n) {
+  vec4 clip = shadow_views[which] * vec4(pos + n * SHADOW_NORMAL_OFFSET, 1.0);
+  vec3 ndc = clip.xyz / clip.w;
+  // beyond the far plane
+  if (ndc.z > 1.0) { return 1.0; }
+  vec2 uv = ndc.xy * 0.5 + 0.5;
+  vec2 texel = 1.0 / vec2(textureSize(shadow_maps[which], 0));
+  float lit = 0.0;
+  for (int x = -1; x <= 1; x++) {
+    for (int y = -1; y <= 1; y++) {
+      lit += texture(shadow_maps[which], vec3(uv + vec2(x, y) * texel, ndc.z - SHADOW_BIAS));
+    }
+  }
+  return lit / 9.0;
+}
+
+// the GGX (Trowbridge-Reitz) distribution of microfacet normals
+float distribution(float n_h, float a) {
+  float a2 = a * a;
+  float d = n_h * n_h * (a2 - 1.0) + 1.0;
+  return a2 / (PI * d * d);
+}
+
+// Smith-Schlick masking and shadowing, over 4 n.l n.v to save dividing later
+float visibility(float n_l, float n_v, float roughness) {
+  float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+  return 0.25 / ((n_l * (1.0 - k) + k) * (n_v * (1.0 - k) + k));
+}
+
+vec3 fresnel(float cos_theta, vec3 f0) {
+  return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// the split-sum environment BRDF, fitted analytically (Karis 2014) instead
+// of read from a lookup texture: a scale and bias for f0
+vec2 env_brdf(float roughness, float n_v) {
+  const vec4 c0 = vec4(-1.0, -0.0275, -0.572, 0.022);
+  const vec4 c1 = vec4(1.0, 0.0425, 1.04, -0.04);
+  vec4 r = roughness * c0 + c1;
+  float a004 = min(r.x * r.x, exp2(-9.28 * n_v)) * r.x + r.y;
+  return vec2(-1.04, 1.04) * a004 + r.zw;
+}
+
+// light a surface of color `albedo` at world position `pos` facing `normal`.
+// Rougher surfaces have broader, dimmer highlights; metals have highlights
+// tinted by their color and no diffuse light of their own.  `occlusion`
+// darkens only the ambient light.
+vec3 shade_surface(vec3 albedo, vec3 pos, vec3 normal, float roughness, float metallic,
+                   float occlusion) {
+  vec3 n = normalize(normal);
+  vec3 v = normalize(eye.xyz - pos);
+  float n_v = max(dot(n, v), 0.0001);
+  roughness = clamp(roughness, 0.04, 1.0);
+  float a = roughness * roughness;
+  vec3 f0 = mix(DIELECTRIC_F0, albedo, metallic);
+  vec3 diff_color = albedo * (1.0 - metallic);
+
+  float levels = float(textureQueryLevels(environment) - 1);
+  vec3 irradiance = textureLod(environment, n, levels).rgb;
+  vec3 reflected = textureLod(environment, reflect(-v, n), roughness * levels).rgb;
+  vec2 brdf = env_brdf(roughness, n_v);
+  vec3 lit = (diff_color * irradiance + reflected * (f0 * brdf.x + brdf.y))
+    * ambient.rgb * occlusion;
+
+  for (uint i = 0; i < min(light_count, MAX_LIGHTS); i++) {
+    vec3 l;
+    float amount = 1.0;
+    if (lights[i].pos.w == 0.0) {
+      l = -lights[i].pos.xyz;
+    } else {
+      vec3 to_light = lights[i].pos.xyz - pos;
+      float dist = length(to_light);
+      l = to_light / max(dist, 0.0001);
+      amount = attenuate(dist, lights[i].color.a);
+      if (lights[i].pos.w == 2.0) {
+        amount *= smoothstep(lights[i].dir.w, lights[i].cos_inner, dot(-l, lights[i].dir.xyz));
+      }
+    }
+    float n_l = dot(n, l);
+    if (n_l <= 0.0 || amount <= 0.0) { continue; }
+    if (lights[i].shadow >= 0) {
+      amount *= shadow(lights[i].shadow, pos, n);
+    }
+    vec3 h = normalize(l + v);
+    vec3 f = fresnel(max(dot(h, v), 0.0), f0);
+    vec3 specular = f * distribution(max(dot(n, h), 0.0), a) * visibility(n_l, n_v, roughness);
+    // light colors are how bright a white diffuse surface facing them gets,
+    // hence the factors of pi
+    vec3 diffuse = (1.0 - f) * diff_color;
+    lit += (diffuse + PI * specular) * lights[i].color.rgb * n_l * amount;
+  }
+  return lit;
+}
+
+vec3 shade(vec3 albedo, vec3 pos, vec3 normal) {
+  vec3 n = normalize(normal);
+  vec3 v = normalize(eye.xyz - pos);
+  vec3 lit = ambient.rgb * albedo;
+  for (uint i = 0; i < min(light_count, MAX_LIGHTS); i++) {
+    vec3 l;
+    float amount = 1.0;
+    if (lights[i].pos.w == 0.0) {
+      l = -lights[i].pos.xyz;
+    } else {
+      vec3 to_light = lights[i].pos.xyz - pos;
+      float dist = length(to_light);
+      l = to_light / max(dist, 0.0001);
+      amount = attenuate(dist, lights[i].color.a);
+      if (lights[i].pos.w == 2.0) {
+        amount *= smoothstep(lights[i].dir.w, lights[i].cos_inner, dot(-l, lights[i].dir.xyz));
+      }
+    }
+    float diffuse = max(dot(n, l), 0.0);
+    if (diffuse <= 0.0 || amount <= 0.0) { continue; }
+    if (lights[i].shadow >= 0) {
+      amount *= shadow(lights[i].shadow, pos, n);
+    }
+    float specular = pow(max(dot(n, normalize(l + v)), 0.0), SHININESS) * SPECULAR;
+    lit += (albedo * diffuse + specular) * lights[i].color.rgb * amount;
+  }
+  return lit;
+}
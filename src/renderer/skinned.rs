@@ -1,10 +1,13 @@
+use super::culling::{Bounds, CullStats, Culler, Visibility};
+use super::lights::{LightData, Lights};
+use super::pbr::Environment;
 use super::RenderState;
 use crate::animation;
 use crate::assets;
 use crate::assets::Texture;
 use crate::camera::Camera;
 use crate::types::*;
-use crate::vulkan::Vulkan;
+use crate::vulkan::{ShadowMaps, Vulkan};
 use bytemuck::{Pod, Zeroable};
 use std::collections::HashMap;
 use std::rc::Rc;
@@ -34,18 +37,29 @@ pub struct Vertex {
     // Really, four u8 values
     pub bone_ids: u32,
     pub bone_weights: [f32; 4],
+    pub normal: [f32; 3],
 }
-vulkano::impl_vertex!(Vertex, position, uv, bone_ids, bone_weights);
+vulkano::impl_vertex!(Vertex, position, uv, bone_ids, bone_weights, normal);
 pub struct Mesh {
     pub mesh: russimp::mesh::Mesh,
     pub rig: animation::Rig,
-    pub verts: Arc<ImmutableBuffer<[Vertex]>>,
-    pub idx: Arc<ImmutableBuffer<[u32]>>,
+    pub verts: Vec<Vertex>,
+    pub idx: Vec<u32>,
+    // in the mesh's own space, padded for how far animations might move it
+    // from its bind pose
+    pub bounds: Bounds,
+    // None until `Assets::upload`
+    pub(crate) gpu: Option<assets::MeshBuffers<Vertex>>,
 }
 impl Mesh {
     pub fn bone_count(&self) -> usize {
         self.rig.joints.len()
     }
+    pub(crate) fn gpu(&self) -> &assets::MeshBuffers<Vertex> {
+        self.gpu
+            .as_ref()
+            .expect("mesh used for drawing before Assets::upload")
+    }
 }
 #[derive(Clone)]
 pub struct Model {
@@ -107,6 +121,11 @@ struct BatchData {
     bone_ds: Option<Arc<vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet>>,
     instance_data: Vec<InstanceData>,
     bones: Vec<animation::Bone>,
+    // off screen but maybe casting shadows into it; these go after
+    // instance_data and bones in their buffers, and only the shadow pass
+    // draws them
+    shadow_only: Vec<InstanceData>,
+    shadow_bones: Vec<animation::Bone>,
     instance_buf:
         Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
     bone_count_buf: Arc<CpuBufferPoolSubbuffer<u32, Arc<vulkano::memory::pool::StdMemoryPool>>>,
@@ -116,10 +135,13 @@ struct BatchData {
 
 pub struct Renderer {
     pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    shadow_pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    shadows: ShadowMaps,
     sampler: Arc<Sampler>,
     // we'll use one uniform buffer across all batches.
     // it will be the projection-view transform.
     uniform_buffers: CpuBufferPool<Mat4>,
+    light_buffers: CpuBufferPool<LightData>,
     uniform_pds: SingleLayoutDescSetPool,
     bone_count_buffers: CpuBufferPool<u32>,
     storage_buffers: CpuBufferPool<animation::Bone>,
@@ -127,6 +149,7 @@ pub struct Renderer {
     uniform_binding: Option<Arc<SingleLayoutDescSet>>,
     instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
     batches: HashMap<ModelKey, BatchData>,
+    stats: CullStats,
 }
 
 impl Renderer {
@@ -144,9 +167,12 @@ layout(location = 2) in uint bone_ids;
 layout(location = 3) in vec4 bone_weights;
 // instance data
 layout(location = 4) in mat4 model;
+layout(location = 8) in vec3 normal;
 
 // outputs
 layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_pos;
+layout(location = 2) out vec3 out_normal;
 
 // uniforms
 layout(set=0, binding=0) uniform BatchData { mat4 viewproj; };
@@ -165,6 +191,7 @@ vec3 quat_rot(vec4 q, vec3 v) {
 void main() {
   uint first_bone = gl_InstanceIndex * bone_count;
   vec3 new_vertex = vec3(0,0,0);
+  vec3 new_normal = vec3(0,0,0);
   // accumulate weighted sum (midpoint) from four weights
   for (int idx=0; idx < 4; idx++) {
     // bit operations to find which bone ID to weight
@@ -176,10 +203,15 @@ void main() {
     vec4 rot = bone_dat.rot;
     vec3 disp = bone_dat.pos.xyz;
     new_vertex += (quat_rot(rot, position)*scale + disp)*weight;
+    // normals only turn with the bone
+    new_normal += quat_rot(rot, normal)*weight;
   }
-  gl_Position = viewproj * model * vec4(new_vertex.xyz, 1.0);
+  vec4 world_pos = model * vec4(new_vertex.xyz, 1.0);
+  gl_Position = viewproj * world_pos;
   //gl_Position = viewproj * model * vec4(position.xyz, 1.0);
   out_uv = uv;
+  out_pos = world_pos.xyz;
+  out_normal = mat3(model) * new_normal;
 }
                 "
             }
@@ -188,23 +220,85 @@ void main() {
         mod fs {
             vulkano_shaders::shader! {
                 ty: "fragment",
+                include: ["src/renderer/shaders"],
                 src: "
                 #version 450
+                #include <lighting.glsl>
 
                 layout(set = 2, binding = 0) uniform sampler2D tex;
                 layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 pos;
+                layout(location = 2) in vec3 normal;
                 layout(location = 0) out vec4 f_color;
 
                 void main() {
                     vec4 col = texture(tex, uv);
                     //col = vec4(1.0, 1.0, 0.0, 1.0);
                     if (col.a < 0.1) { discard; }
-                    f_color = col;
+                    f_color = vec4(shade(col.rgb, pos, normal), col.a);
                 }
             "
             }
         }
 
+        mod shadow_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                src: "
+#version 450
+
+// vertex attributes
+layout(location = 0) in vec3 position;
+layout(location = 2) in uint bone_ids;
+layout(location = 3) in vec4 bone_weights;
+// instance data
+layout(location = 4) in mat4 model;
+
+// uniforms
+layout(push_constant) uniform ShadowData { mat4 light_viewproj; };
+struct Bone {
+  vec4 pos;
+  vec4 rot;
+};
+layout(set = 1, binding = 0) uniform BoneCount { uint bone_count; };
+layout(std430, set = 1, binding = 1) buffer Bones { Bone bones[]; };
+
+// rotate a vector by a quaternion.
+vec3 quat_rot(vec4 q, vec3 v) {
+  return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+// the same skinning as the main pass, so shadows move with the animation
+void main() {
+  uint first_bone = gl_InstanceIndex * bone_count;
+  vec3 new_vertex = vec3(0,0,0);
+  for (int idx=0; idx < 4; idx++) {
+    int bone = int(bone_ids >> (8*(3-idx)) & 0x000000FF);
+    float weight = bone_weights[idx];
+    Bone bone_dat = bones[first_bone+bone];
+    float scale = bone_dat.pos.w;
+    vec4 rot = bone_dat.rot;
+    vec3 disp = bone_dat.pos.xyz;
+    new_vertex += (quat_rot(rot, position)*scale + disp)*weight;
+  }
+  gl_Position = light_viewproj * model * vec4(new_vertex.xyz, 1.0);
+}
+                "
+            }
+        }
+
+        // depth only
+        mod shadow_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                void main() {}
+            "
+            }
+        }
+
         let vs = vs::load(vulkan.device.clone()).unwrap();
         let fs = fs::load(vulkan.device.clone()).unwrap();
         use vulkano::sampler::SamplerCreateInfo;
@@ -242,7 +336,29 @@ void main() {
             .build(vulkan.device.clone())
             .unwrap();
 
+        let shadow_vs = shadow_vs::load(vulkan.device.clone()).unwrap();
+        let shadow_fs = shadow_fs::load(vulkan.device.clone()).unwrap();
+        // no culling, so walls cast shadows whichever way they face
+        let shadow_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<Vertex>()
+                    .instance::<InstanceData>(),
+            )
+            .vertex_shader(shadow_vs.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new().topology(
+                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+            ))
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(shadow_fs.entry_point("main").unwrap(), ())
+            .rasterization_state(RasterizationState::new())
+            .depth_stencil_state(DepthStencilState::simple_depth_test())
+            .render_pass(Subpass::from(vulkan.shadows.render_pass.clone(), 0).unwrap())
+            .build(vulkan.device.clone())
+            .unwrap();
+
         let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let light_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
         let uniform_pds =
             SingleLayoutDescSetPool::new(pipeline.layout().set_layouts().get(0).unwrap().clone());
         let bone_count_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
@@ -263,7 +379,10 @@ void main() {
         Self {
             sampler,
             pipeline,
+            shadow_pipeline,
+            shadows: vulkan.shadows.clone(),
             uniform_buffers,
+            light_buffers,
             uniform_pds,
             bone_count_buffers,
             storage_buffers,
@@ -271,8 +390,10 @@ void main() {
             instance_pool,
             batches: HashMap::new(),
             uniform_binding: None,
+            stats: CullStats::default(),
         }
     }
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn push_model(
         &mut self,
         key: ModelKey,
@@ -281,6 +402,7 @@ void main() {
         trf: Similarity3,
         anim: &animation::Animation,
         state: &animation::AnimationState,
+        vis: Visibility,
     ) {
         use std::collections::hash_map::Entry;
         let inst = InstanceData {
@@ -296,10 +418,10 @@ void main() {
                     texture,
                     mesh.bone_count(),
                 );
-                b.push_instance(inst, mesh, anim, state);
+                b.push_instance(inst, mesh, anim, state, vis);
                 v.insert(b);
             }
-            Entry::Occupied(v) => v.into_mut().push_instance(inst, mesh, anim, state),
+            Entry::Occupied(v) => v.into_mut().push_instance(inst, mesh, anim, state, vis),
         }
     }
     fn create_batch(
@@ -312,10 +434,12 @@ void main() {
     ) -> BatchData {
         BatchData {
             bone_count_buf: bone_count_buffers.next(bone_count as u32).unwrap(),
-            verts: mesh.verts.clone(),
-            idxs: mesh.idx.clone(),
+            verts: mesh.gpu().verts.clone(),
+            idxs: mesh.gpu().idx.clone(),
             instance_data: vec![],
             bones: vec![],
+            shadow_only: vec![],
+            shadow_bones: vec![],
             instance_buf: None,
             bone_ds: None,
             bone_data_buf: None,
@@ -324,7 +448,7 @@ void main() {
                 [
                     vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
                         0,
-                        vulkano::image::view::ImageView::new_default(texture.texture.clone())
+                        vulkano::image::view::ImageView::new_default(texture.gpu_image())
                             .unwrap(),
                         sampler,
                     ),
@@ -334,9 +458,16 @@ void main() {
         }
     }
     pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        let culler = Culler::new(camera, &rs.lights);
+        self.stats = CullStats::default();
         for v in rs.skinned.values() {
             for (meshr, texr) in v.model.meshes.iter().zip(v.model.textures.iter()) {
                 let mesh = assets.skinned_mesh(*meshr);
+                let vis = culler.classify(&mesh.bounds, &v.transform);
+                self.stats.count(vis);
+                if vis == Visibility::Culled {
+                    continue;
+                }
                 let tex = assets.texture(*texr);
                 let anim = assets.animation(v.animation);
                 self.push_model(
@@ -346,18 +477,26 @@ void main() {
                     v.transform,
                     anim,
                     &v.state,
+                    vis,
                 );
             }
         }
-        self.prepare_draw(camera);
+        self.prepare_draw(camera, &rs.lights, assets.environment());
     }
-    fn prepare_draw(&mut self, camera: &Camera) {
+    fn prepare_draw(&mut self, camera: &Camera, lights: &Lights, environment: &Environment) {
         let buf = self.uniform_buffers.next(camera.as_matrix()).unwrap();
+        let light_buf = self
+            .light_buffers
+            .next(LightData::new(lights, camera))
+            .unwrap();
         let uds = self
             .uniform_pds
-            .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
-                0, buf,
-            )])
+            .next(vec![
+                vulkano::descriptor_set::WriteDescriptorSet::buffer(0, buf),
+                vulkano::descriptor_set::WriteDescriptorSet::buffer(1, light_buf),
+                self.shadows.descriptor_write(2),
+                environment.descriptor_write(3),
+            ])
             .unwrap();
         self.uniform_binding = Some(uds);
         for (_k, b) in self.batches.iter_mut() {
@@ -373,11 +512,38 @@ void main() {
 
         builder.bind_pipeline_graphics(self.pipeline.clone());
 
-        for (_b, dat) in self.batches.iter() {
+        for (_b, dat) in self
+            .batches
+            .iter()
+            .filter(|(_b, dat)| !dat.instance_data.is_empty())
+        {
             dat.draw(self.pipeline.clone(), uds.clone(), builder);
         }
         self.clear_frame();
     }
+    /// How many instances last frame's `prepare` drew, kept for shadows
+    /// only, or skipped.
+    pub fn cull_stats(&self) -> CullStats {
+        self.stats
+    }
+    /// Draw every batch into the shadow map being rendered, as seen with
+    /// `light_viewproj`.  Call between `prepare` and `draw`.
+    pub fn draw_shadows<P, L>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+        light_viewproj: Mat4,
+    ) {
+        builder
+            .bind_pipeline_graphics(self.shadow_pipeline.clone())
+            .push_constants(
+                self.shadow_pipeline.layout().clone(),
+                0,
+                *light_viewproj.as_array(),
+            );
+        for (_b, dat) in self.batches.iter() {
+            dat.draw_shadow(self.shadow_pipeline.clone(), builder);
+        }
+    }
     fn clear_frame(&mut self) {
         // delete batch data for objects that didn't get rendered this frame.
         // TODO: something more sophisticated!
@@ -394,7 +560,11 @@ impl BatchData {
         bone_pds: &mut SingleLayoutDescSetPool,
         instance_pool: &CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
     ) {
-        self.bone_data_buf = Some(storage_buffers.chunk(self.bones.iter().copied()).unwrap());
+        self.bone_data_buf = Some(
+            storage_buffers
+                .chunk(self.bones.iter().chain(self.shadow_bones.iter()).copied())
+                .unwrap(),
+        );
         self.bone_ds = Some(
             bone_pds
                 .next([
@@ -411,7 +581,12 @@ impl BatchData {
         );
         self.instance_buf = Some(
             instance_pool
-                .chunk(self.instance_data.iter().copied())
+                .chunk(
+                    self.instance_data
+                        .iter()
+                        .chain(self.shadow_only.iter())
+                        .copied(),
+                )
                 .unwrap(),
         );
     }
@@ -452,12 +627,38 @@ impl BatchData {
             )
             .unwrap();
     }
+    fn draw_shadow<P, L>(
+        &self,
+        pipeline: Arc<GraphicsPipeline>,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+    ) {
+        builder
+            .bind_vertex_buffers(0, [self.verts.clone()])
+            .bind_vertex_buffers(1, [self.instance_buf.clone().unwrap()])
+            .bind_index_buffer(self.idxs.clone())
+            .bind_descriptor_sets(
+                vulkano::pipeline::PipelineBindPoint::Graphics,
+                (*pipeline).layout().clone(),
+                1,
+                self.bone_ds.clone().unwrap(),
+            )
+            .draw_indexed(
+                self.idxs.len() as u32,
+                (self.instance_data.len() + self.shadow_only.len()) as u32,
+                0,
+                0,
+                0,
+            )
+            .unwrap();
+    }
     fn clear_frame(&mut self) {
         self.instance_data.clear();
         self.bones.clear();
+        self.shadow_only.clear();
+        self.shadow_bones.clear();
     }
     fn is_empty(&self) -> bool {
-        self.instance_data.is_empty()
+        self.instance_data.is_empty() && self.shadow_only.is_empty()
     }
     fn push_instance(
         &mut self,
@@ -465,9 +666,15 @@ impl BatchData {
         mesh: &Mesh,
         anim: &animation::Animation,
         state: &animation::AnimationState,
+        vis: Visibility,
     ) {
-        self.instance_data.push(inst);
+        let (instances, bones) = if vis == Visibility::ShadowOnly {
+            (&mut self.shadow_only, &mut self.shadow_bones)
+        } else {
+            (&mut self.instance_data, &mut self.bones)
+        };
+        instances.push(inst);
         // animation sampling here
-        mesh.rig.write_bones(&mut self.bones, anim, state);
+        mesh.rig.write_bones(bones, anim, state);
     }
 }
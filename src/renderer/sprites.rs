@@ -1,3 +1,4 @@
+use super::culling::{Bounds, CullStats, Culler, Visibility};
 use super::RenderState;
 use crate::assets;
 use crate::assets::Texture;
@@ -13,6 +14,7 @@ use vulkano::command_buffer::AutoCommandBufferBuilder;
 use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
 use vulkano::descriptor_set::PersistentDescriptorSet;
 use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::pipeline::graphics::color_blend::ColorBlendState;
 use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
 use vulkano::pipeline::graphics::rasterization::RasterizationState;
 use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
@@ -27,6 +29,7 @@ pub struct SingleRenderState {
     region: Rect,
     transform: Isometry3,
     size: Vec2,
+    transparent: bool,
 }
 impl SingleRenderState {
     pub(crate) fn new(
@@ -34,12 +37,14 @@ impl SingleRenderState {
         region: Rect,
         transform: Isometry3,
         size: Vec2,
+        transparent: bool,
     ) -> Self {
         Self {
             texture,
             region,
             transform,
             size,
+            transparent,
         }
     }
     pub fn interpolate(&self, other: &Self, r: f32) -> Self {
@@ -48,9 +53,13 @@ impl SingleRenderState {
             transform: self.transform.lerp(&other.transform, r),
             size: self.size.lerp(other.size, r),
             region: self.region.lerp(&other.region, r),
+            transparent: other.transparent,
         }
     }
 }
+// sprites of the same texture are batched separately depending on whether
+// they're transparent
+type BatchKey = (assets::TextureRef, bool);
 
 #[repr(C)]
 #[derive(Clone, Copy, Zeroable, Default, Pod, Debug, PartialEq)]
@@ -60,6 +69,8 @@ struct InstanceData {
 }
 vulkano::impl_vertex!(InstanceData, model, size_uv);
 struct BatchData {
+    // drawn one instance at a time in the transparent pass
+    transparent: bool,
     material_pds: Arc<vulkano::descriptor_set::PersistentDescriptorSet>,
     instance_data: Vec<InstanceData>,
     instance_buf:
@@ -69,6 +80,7 @@ struct BatchData {
 
 pub struct Renderer {
     pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    transparent_pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
     sampler: Arc<Sampler>,
     // we'll use one uniform buffer across all batches.
     // it will be the projection-view transform.
@@ -77,7 +89,11 @@ pub struct Renderer {
     uniform_binding: Option<Arc<SingleLayoutDescSet>>,
     index_buf: Arc<ImmutableBuffer<[u16]>>,
     instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
-    batches: HashMap<assets::TextureRef, BatchData>,
+    batches: HashMap<BatchKey, BatchData>,
+    // this frame's transparent instances: squared distance from the camera,
+    // batch, and instance within the batch
+    transparent: Vec<(f32, BatchKey, u32)>,
+    stats: CullStats,
 }
 
 impl Renderer {
@@ -123,6 +139,7 @@ void main() {
                 src: "
                 #version 450
 
+                layout(constant_id = 0) const bool TRANSPARENT = false;
                 layout(set = 1, binding = 0) uniform sampler2D tex;
                 layout(location = 0) in vec2 uv;
                 layout(location = 0) out vec4 f_color;
@@ -130,7 +147,7 @@ void main() {
                 void main() {
                     vec4 col = texture(tex, uv);
                     //col = vec4(1.0, 1.0, 0.0, 1.0);
-                    if (col.a < 0.1) { discard; }
+                    if (!TRANSPARENT && col.a < 0.1) { discard; }
                     f_color = col;
                 }
             "
@@ -142,33 +159,49 @@ void main() {
         use vulkano::sampler::SamplerCreateInfo;
         let sampler = Sampler::new(vulkan.device.clone(), SamplerCreateInfo::default()).unwrap();
         use vulkano::pipeline::graphics::depth_stencil::*;
-        let pipeline = GraphicsPipeline::start()
-            .vertex_input_state(BuffersDefinition::new().instance::<InstanceData>())
-            .vertex_shader(vs.entry_point("main").unwrap(), ())
-            .input_assembly_state(InputAssemblyState::new().topology(
-                vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
-            ))
-            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
-            .fragment_shader(fs.entry_point("main").unwrap(), ())
-            .rasterization_state(
-                RasterizationState::new()
-                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
-                    .front_face(
-                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
-                    ),
-            )
-            .depth_stencil_state(DepthStencilState {
-                depth: Some(DepthState {
-                    compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
-                    enable_dynamic: false,
-                    write_enable: vulkano::pipeline::StateMode::Fixed(true),
-                }),
-                depth_bounds: None,
-                stencil: None,
-            })
-            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
-            .build(vulkan.device.clone())
-            .unwrap();
+        // opaque sprites cut out low alpha and write depth; transparent ones
+        // blend over everything opaque and leave depth alone
+        let build_pipeline = |transparent: bool| {
+            GraphicsPipeline::start()
+                .vertex_input_state(BuffersDefinition::new().instance::<InstanceData>())
+                .vertex_shader(vs.entry_point("main").unwrap(), ())
+                .input_assembly_state(InputAssemblyState::new().topology(
+                    vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+                ))
+                .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+                .fragment_shader(
+                    fs.entry_point("main").unwrap(),
+                    fs::SpecializationConstants {
+                        TRANSPARENT: transparent as u32,
+                    },
+                )
+                .rasterization_state(
+                    RasterizationState::new()
+                        .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
+                        .front_face(
+                            vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                        ),
+                )
+                .depth_stencil_state(DepthStencilState {
+                    depth: Some(DepthState {
+                        compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
+                        enable_dynamic: false,
+                        write_enable: vulkano::pipeline::StateMode::Fixed(!transparent),
+                    }),
+                    depth_bounds: None,
+                    stencil: None,
+                })
+                .color_blend_state(if transparent {
+                    super::premultiplied_blend()
+                } else {
+                    ColorBlendState::new(1)
+                })
+                .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+                .build(vulkan.device.clone())
+                .unwrap()
+        };
+        let pipeline = build_pipeline(false);
+        let transparent_pipeline = build_pipeline(true);
 
         let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
         let uniform_pds =
@@ -186,14 +219,18 @@ void main() {
         Self {
             sampler,
             pipeline,
+            transparent_pipeline,
             uniform_buffers,
             uniform_pds,
             index_buf,
             instance_pool,
             batches: HashMap::new(),
+            transparent: vec![],
             uniform_binding: None,
+            stats: CullStats::default(),
         }
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn push_model(
         &mut self,
         tr: assets::TextureRef,
@@ -201,6 +238,8 @@ void main() {
         region: Rect,
         trf: Isometry3,
         size: Vec2,
+        transparent: bool,
+        eye: Vec3,
     ) {
         use std::collections::hash_map::Entry;
         let inst = InstanceData {
@@ -209,27 +248,35 @@ void main() {
             .as_array(),
             size_uv: [region.sz.x, region.sz.y, region.pos.x, region.pos.y],
         };
-        match self.batches.entry(tr) {
-            Entry::Vacant(v) => {
-                let mut b = Self::create_batch(
-                    self.pipeline.clone(),
-                    self.sampler.clone(),
-                    texture,
-                    self.index_buf.clone(),
-                );
-                b.push_instance(inst);
-                v.insert(b);
-            }
-            Entry::Occupied(v) => v.into_mut().push_instance(inst),
+        let key = (tr, transparent);
+        let b = match self.batches.entry(key) {
+            Entry::Vacant(v) => v.insert(Self::create_batch(
+                self.pipeline.clone(),
+                self.sampler.clone(),
+                texture,
+                self.index_buf.clone(),
+                transparent,
+            )),
+            Entry::Occupied(v) => v.into_mut(),
+        };
+        if transparent {
+            // sprite quads are centered on their origin, so this is the
+            // middle of the sprite, like the meshes' bounds centers
+            let dist = (trf.translation - eye).mag_sq();
+            self.transparent
+                .push((dist, key, b.instance_data.len() as u32));
         }
+        b.push_instance(inst);
     }
     fn create_batch(
         pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
         sampler: Arc<Sampler>,
         texture: &Texture,
         index_buf: Arc<ImmutableBuffer<[u16]>>,
+        transparent: bool,
     ) -> BatchData {
         BatchData {
+            transparent,
             instance_data: vec![],
             instance_buf: None,
             index_buf,
@@ -238,7 +285,7 @@ void main() {
                 [
                     vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
                         0,
-                        vulkano::image::view::ImageView::new_default(texture.texture.clone())
+                        vulkano::image::view::ImageView::new_default(texture.gpu_image())
                             .unwrap(),
                         sampler,
                     ),
@@ -248,9 +295,32 @@ void main() {
         }
     }
     pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
+        let eye = camera.eye();
+        let culler = Culler::new(camera, &rs.lights);
+        self.stats = CullStats::default();
         for v in rs.sprites.values() {
+            let half = Vec3::new(v.size.x / 2.0, v.size.y / 2.0, 0.0);
+            let bounds = Bounds::from_points(&[-half, half]);
+            let trf = Similarity3::new(v.transform.translation, v.transform.rotation, 1.0);
+            // sprites don't cast shadows, so they're either on screen or culled
+            let vis = match culler.classify(&bounds, &trf) {
+                Visibility::Visible => Visibility::Visible,
+                _ => Visibility::Culled,
+            };
+            self.stats.count(vis);
+            if vis == Visibility::Culled {
+                continue;
+            }
             let tex = assets.texture(v.texture);
-            self.push_model(v.texture, tex, v.region, v.transform, v.size);
+            self.push_model(
+                v.texture,
+                tex,
+                v.region,
+                v.transform,
+                v.size,
+                v.transparent,
+                eye,
+            );
         }
         self.prepare_draw(camera);
     }
@@ -267,14 +337,48 @@ void main() {
             b.prepare_draw(&self.instance_pool);
         }
     }
-    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+    /// How many sprites last frame's `prepare` drew or skipped.
+    pub fn cull_stats(&self) -> CullStats {
+        self.stats
+    }
+    /// Draw every opaque batch.
+    pub fn draw<P, L>(&self, builder: &mut AutoCommandBufferBuilder<P, L>) {
         let uds = self.uniform_binding.clone().unwrap();
 
         builder.bind_pipeline_graphics(self.pipeline.clone());
 
-        for (_b, dat) in self.batches.iter() {
+        for (_b, dat) in self.batches.iter().filter(|(_b, dat)| !dat.transparent) {
             dat.draw(self.pipeline.clone(), uds.clone(), builder);
         }
+    }
+    /// This frame's transparent instances, as their squared distance from
+    /// the camera and which one to pass to `draw_transparent`.
+    pub(crate) fn transparent_draws(&self) -> impl Iterator<Item = (f32, usize)> + '_ {
+        self.transparent
+            .iter()
+            .enumerate()
+            .map(|(i, (dist, _, _))| (*dist, i))
+    }
+    /// Blend one transparent sprite over what's been drawn so far.
+    pub(crate) fn draw_transparent<P, L>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+        which: usize,
+    ) {
+        let (_, key, instance) = self.transparent[which];
+        let uds = self.uniform_binding.clone().unwrap();
+        builder.bind_pipeline_graphics(self.transparent_pipeline.clone());
+        self.batches[&key].draw_instances(
+            self.transparent_pipeline.clone(),
+            uds,
+            builder,
+            instance,
+            1,
+        );
+    }
+    /// Forget this frame's instances, once both passes have drawn them.
+    pub fn finish_frame(&mut self) {
+        self.transparent.clear();
         self.clear_frame();
     }
     fn clear_frame(&mut self) {
@@ -302,6 +406,16 @@ impl BatchData {
         pipeline: Arc<GraphicsPipeline>,
         unis: Arc<vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet>,
         builder: &mut AutoCommandBufferBuilder<P, L>,
+    ) {
+        self.draw_instances(pipeline, unis, builder, 0, self.instance_data.len() as u32);
+    }
+    fn draw_instances<P, L>(
+        &self,
+        pipeline: Arc<GraphicsPipeline>,
+        unis: Arc<vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet>,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+        first: u32,
+        count: u32,
     ) {
         builder
             .bind_vertex_buffers(0, [self.instance_buf.clone().unwrap()])
@@ -318,7 +432,7 @@ impl BatchData {
                 1,
                 self.material_pds.clone(),
             )
-            .draw_indexed(6, self.instance_data.len() as u32, 0, 0, 0)
+            .draw_indexed(6, count, 0, 0, first)
             .unwrap();
     }
     fn clear_frame(&mut self) {
@@ -1,9 +1,12 @@
+use super::culling::{Bounds, CullStats, Culler, Visibility};
+use super::lights::{LightData, Lights};
+use super::lod::{Levels, Threshold};
+use super::pbr::{Environment, FactorData, Factors};
 use super::RenderState;
-use crate::assets;
-use crate::assets::Texture;
+use crate::assets::{self, MaterialRef, TextureRef};
 use crate::camera::Camera;
 use crate::types::*;
-use crate::vulkan::Vulkan;
+use crate::vulkan::{ShadowMaps, Vulkan};
 use bytemuck::{Pod, Zeroable};
 use std::collections::HashMap;
 use std::rc::Rc;
@@ -16,6 +19,7 @@ use vulkano::command_buffer::AutoCommandBufferBuilder;
 use vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet;
 use vulkano::descriptor_set::PersistentDescriptorSet;
 use vulkano::descriptor_set::SingleLayoutDescSetPool;
+use vulkano::pipeline::graphics::color_blend::ColorBlendState;
 use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
 use vulkano::pipeline::graphics::rasterization::RasterizationState;
 use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
@@ -30,29 +34,123 @@ use vulkano::sampler::Sampler;
 pub struct Vertex {
     pub position: [f32; 3],
     pub uv: [f32; 2],
+    pub normal: [f32; 3],
+    // w is 1 or -1, for whether the bitangent is normal x tangent or its opposite
+    pub tangent: [f32; 4],
 }
-vulkano::impl_vertex!(Vertex, position, uv);
+vulkano::impl_vertex!(Vertex, position, uv, normal, tangent);
 pub struct Mesh {
     pub mesh: russimp::mesh::Mesh,
-    pub verts: Arc<ImmutableBuffer<[Vertex]>>,
-    pub idx: Arc<ImmutableBuffer<[u32]>>,
+    pub verts: Vec<Vertex>,
+    pub idx: Vec<u32>,
+    // in the mesh's own space
+    pub bounds: Bounds,
+    // None until `Assets::upload`
+    pub(crate) gpu: Option<assets::MeshBuffers<Vertex>>,
 }
-impl Mesh {}
+impl Mesh {
+    pub(crate) fn gpu(&self) -> &assets::MeshBuffers<Vertex> {
+        self.gpu
+            .as_ref()
+            .expect("mesh used for drawing before Assets::upload")
+    }
+}
+/// The textures a mesh is drawn with, each scaled by the matching factor.
+/// Slots a model file doesn't fill are given `Assets`' blank textures,
+/// which leave the surface as it would be without them.
+#[derive(Clone)]
+pub struct Material {
+    pub name: String,
+    // sRGB base color
+    pub diffuse: TextureRef,
+    // tangent space, linear
+    pub normal: TextureRef,
+    // linear, with roughness in green and metalness in blue as in glTF
+    pub metallic_roughness: TextureRef,
+    // sRGB, added on top of the lighting
+    pub emissive: TextureRef,
+    // linear, in red; darkens ambient light in creases
+    pub occlusion: TextureRef,
+    pub factors: Factors,
+    // None until `Assets::upload`
+    pub(crate) buffer: Option<Arc<ImmutableBuffer<FactorData>>>,
+}
+
 #[derive(Clone)]
 pub struct Model {
     meshes: Vec<assets::MeshRef<Mesh>>,
-    textures: Vec<assets::TextureRef>,
+    materials: Vec<MaterialRef<Material>>,
+    // coarser and coarser versions, each drawn past its threshold
+    lods: Vec<(Threshold, Model)>,
+    // the hierarchy of the file it was loaded from, if any
+    nodes: Vec<assets::ModelNode>,
 }
 impl Model {
-    pub(crate) fn new(
-        meshes: Vec<assets::MeshRef<Mesh>>,
-        textures: Vec<assets::TextureRef>,
-    ) -> Self {
-        Self { meshes, textures }
+    pub fn new(meshes: Vec<assets::MeshRef<Mesh>>, materials: Vec<MaterialRef<Material>>) -> Self {
+        assert_eq!(meshes.len(), materials.len());
+        Self {
+            meshes,
+            materials,
+            lods: vec![],
+            nodes: vec![],
+        }
+    }
+    /// Draw `lod` instead of this model once an instance is past
+    /// `threshold`.  Add levels from finest to coarsest; `lod`'s own levels
+    /// are ignored.
+    pub fn with_lod(mut self, threshold: Threshold, lod: Model) -> Self {
+        self.lods.push((threshold, lod));
+        self
+    }
+    pub fn meshes(&self) -> &[assets::MeshRef<Mesh>] {
+        &self.meshes
+    }
+    pub fn materials(&self) -> &[MaterialRef<Material>] {
+        &self.materials
+    }
+    pub fn lods(&self) -> &[(Threshold, Model)] {
+        &self.lods
+    }
+    /// Remember the node hierarchy this model's meshes came from, so that
+    /// `part` can pick pieces out of it.
+    pub fn with_nodes(mut self, nodes: Vec<assets::ModelNode>) -> Self {
+        self.nodes = nodes;
+        self
+    }
+    pub fn nodes(&self) -> &[assets::ModelNode] {
+        &self.nodes
+    }
+    pub fn node(&self, name: &str) -> Option<&assets::ModelNode> {
+        self.nodes.iter().find(|n| n.name == name)
+    }
+    /// Just the meshes of the node called `name` and everything under it,
+    /// with the same materials and still in this model's space, or `None`
+    /// if there's no such node.  Levels of detail come along as far as
+    /// they have a node called `name` too.
+    pub fn part(&self, name: &str) -> Option<Model> {
+        let meshes = assets::node_meshes(&self.nodes, name)?;
+        let mut part = Model::new(
+            meshes.iter().map(|&m| self.meshes[m]).collect(),
+            meshes.iter().map(|&m| self.materials[m]).collect(),
+        );
+        part.lods = self
+            .lods
+            .iter()
+            .map_while(|(t, lod)| Some((*t, lod.part(name)?)))
+            .collect();
+        Some(part)
+    }
+    // 0 is this model
+    fn level(&self, level: usize) -> &Model {
+        if level == 0 {
+            self
+        } else {
+            &self.lods[level - 1].1
+        }
     }
 }
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct ModelKey(assets::MeshRef<Mesh>, assets::TextureRef);
+pub(crate) struct ModelKey(assets::MeshRef<Mesh>, MaterialRef<Material>);
 
 pub struct SingleRenderState {
     model: Rc<Model>,
@@ -78,24 +176,38 @@ struct InstanceData {
 vulkano::impl_vertex!(InstanceData, model);
 
 struct BatchData {
+    // drawn one instance at a time in the transparent pass
+    transparent: bool,
     verts: Arc<ImmutableBuffer<[Vertex]>>,
     idxs: Arc<ImmutableBuffer<[u32]>>,
     material_pds: Arc<vulkano::descriptor_set::PersistentDescriptorSet>,
     instance_data: Vec<InstanceData>,
+    // off screen but maybe casting shadows into it; these go after
+    // instance_data in instance_buf, and only the shadow pass draws them
+    shadow_only: Vec<InstanceData>,
     instance_buf:
         Option<Arc<CpuBufferPoolChunk<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>>>,
 }
 
 pub struct Renderer {
     pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    transparent_pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    shadow_pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
+    shadows: ShadowMaps,
     sampler: Arc<Sampler>,
     // we'll use one uniform buffer across all batches.
     // it will be the projection-view transform.
     uniform_buffers: CpuBufferPool<Mat4>,
+    light_buffers: CpuBufferPool<LightData>,
     uniform_pds: SingleLayoutDescSetPool,
     uniform_binding: Option<Arc<SingleLayoutDescSet>>,
     instance_pool: CpuBufferPool<InstanceData, Arc<vulkano::memory::pool::StdMemoryPool>>,
     batches: HashMap<ModelKey, BatchData>,
+    // this frame's transparent instances: squared distance from the camera,
+    // batch, and instance within the batch
+    transparent: Vec<(f32, ModelKey, u32)>,
+    stats: CullStats,
+    levels: Levels,
 }
 
 impl Renderer {
@@ -109,18 +221,28 @@ impl Renderer {
 // vertex attributes
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 uv;
+layout(location = 2) in vec3 normal;
+layout(location = 3) in vec4 tangent;
 // instance data
 layout(location = 4) in mat4 model;
 
 // outputs
 layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec3 out_pos;
+layout(location = 2) out vec3 out_normal;
+layout(location = 3) out vec4 out_tangent;
 
 // uniforms
 layout(set=0, binding=0) uniform BatchData { mat4 viewproj; };
 
 void main() {
-  gl_Position = viewproj * model * vec4(position.xyz, 1.0);
+  vec4 world_pos = model * vec4(position.xyz, 1.0);
+  gl_Position = viewproj * world_pos;
   out_uv = uv;
+  out_pos = world_pos.xyz;
+  // models are only ever scaled uniformly, so this keeps normals normal
+  out_normal = mat3(model) * normal;
+  out_tangent = vec4(mat3(model) * tangent.xyz, tangent.w);
 }
                 "
             }
@@ -129,61 +251,158 @@ void main() {
         mod fs {
             vulkano_shaders::shader! {
                 ty: "fragment",
+                include: ["src/renderer/shaders"],
                 src: "
                 #version 450
+                #include <lighting.glsl>
 
+                layout(constant_id = 0) const bool TRANSPARENT = false;
                 layout(set = 1, binding = 0) uniform sampler2D tex;
+                layout(set = 1, binding = 1) uniform sampler2D normal_map;
+                layout(set = 1, binding = 2) uniform sampler2D metallic_roughness_map;
+                layout(set = 1, binding = 3) uniform sampler2D emissive_map;
+                layout(set = 1, binding = 4) uniform sampler2D occlusion_map;
+                layout(set = 1, binding = 5) uniform Material {
+                    vec4 base_color;
+                    vec4 emissive;
+                    float metallic;
+                    float roughness;
+                };
                 layout(location = 0) in vec2 uv;
+                layout(location = 1) in vec3 pos;
+                layout(location = 2) in vec3 normal;
+                layout(location = 3) in vec4 tangent;
                 layout(location = 0) out vec4 f_color;
 
                 void main() {
-                    vec4 col = texture(tex, uv);
+                    // textures are premultiplied, so premultiply the factor too
+                    vec4 col = texture(tex, uv) * vec4(base_color.rgb * base_color.a, base_color.a);
                     //col = vec4(1.0, 1.0, 0.0, 1.0);
-                    if (col.a < 0.1) { discard; }
-                    f_color = col;
+                    if (!TRANSPARENT && col.a < 0.1) { discard; }
+                    // from tangent space to the world
+                    vec3 n = normalize(normal);
+                    vec3 t = normalize(tangent.xyz - n * dot(n, tangent.xyz));
+                    vec3 b = cross(n, t) * tangent.w;
+                    vec3 mapped = texture(normal_map, uv).xyz * 2.0 - 1.0;
+                    n = normalize(mat3(t, b, n) * mapped);
+                    vec2 mr = texture(metallic_roughness_map, uv).bg * vec2(metallic, roughness);
+                    float occlusion = texture(occlusion_map, uv).r;
+                    vec3 lit = shade_surface(col.rgb, pos, n, mr.y, mr.x, occlusion);
+                    f_color = vec4(lit + texture(emissive_map, uv).rgb * emissive.rgb, col.a);
                 }
             "
             }
         }
 
+        mod shadow_vs {
+            vulkano_shaders::shader! {
+                ty: "vertex",
+                src: "
+#version 450
+
+// vertex attributes
+layout(location = 0) in vec3 position;
+// instance data
+layout(location = 1) in mat4 model;
+
+// uniforms
+layout(push_constant) uniform ShadowData { mat4 light_viewproj; };
+
+void main() {
+  gl_Position = light_viewproj * model * vec4(position.xyz, 1.0);
+}
+                "
+            }
+        }
+
+        // depth only
+        mod shadow_fs {
+            vulkano_shaders::shader! {
+                ty: "fragment",
+                src: "
+                #version 450
+
+                void main() {}
+            "
+            }
+        }
+
         let vs = vs::load(vulkan.device.clone()).unwrap();
         let fs = fs::load(vulkan.device.clone()).unwrap();
         use vulkano::sampler::SamplerCreateInfo;
         let sampler = Sampler::new(vulkan.device.clone(), SamplerCreateInfo::default()).unwrap();
         use vulkano::pipeline::graphics::depth_stencil::*;
-        let pipeline = GraphicsPipeline::start()
+        // opaque batches cut out low alpha and write depth; transparent ones
+        // blend over them and leave depth alone
+        let build_pipeline = |transparent: bool| {
+            GraphicsPipeline::start()
+                .vertex_input_state(
+                    BuffersDefinition::new()
+                        .vertex::<Vertex>()
+                        .instance::<InstanceData>(),
+                )
+                .vertex_shader(vs.entry_point("main").unwrap(), ())
+                .input_assembly_state(InputAssemblyState::new().topology(
+                    vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
+                ))
+                .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+                .fragment_shader(
+                    fs.entry_point("main").unwrap(),
+                    fs::SpecializationConstants {
+                        TRANSPARENT: transparent as u32,
+                    },
+                )
+                .rasterization_state(
+                    RasterizationState::new()
+                        .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
+                        .front_face(
+                            vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
+                        ),
+                )
+                .depth_stencil_state(DepthStencilState {
+                    depth: Some(DepthState {
+                        compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
+                        enable_dynamic: false,
+                        write_enable: vulkano::pipeline::StateMode::Fixed(!transparent),
+                    }),
+                    depth_bounds: None,
+                    stencil: None,
+                })
+                .color_blend_state(if transparent {
+                    super::premultiplied_blend()
+                } else {
+                    ColorBlendState::new(1)
+                })
+                .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+                .build(vulkan.device.clone())
+                .unwrap()
+        };
+        let pipeline = build_pipeline(false);
+        let transparent_pipeline = build_pipeline(true);
+
+        let shadow_vs = shadow_vs::load(vulkan.device.clone()).unwrap();
+        let shadow_fs = shadow_fs::load(vulkan.device.clone()).unwrap();
+        // no culling, so walls cast shadows whichever way they face
+        let shadow_pipeline = GraphicsPipeline::start()
             .vertex_input_state(
                 BuffersDefinition::new()
                     .vertex::<Vertex>()
                     .instance::<InstanceData>(),
             )
-            .vertex_shader(vs.entry_point("main").unwrap(), ())
+            .vertex_shader(shadow_vs.entry_point("main").unwrap(), ())
             .input_assembly_state(InputAssemblyState::new().topology(
                 vulkano::pipeline::graphics::input_assembly::PrimitiveTopology::TriangleList,
             ))
             .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
-            .fragment_shader(fs.entry_point("main").unwrap(), ())
-            .rasterization_state(
-                RasterizationState::new()
-                    .cull_mode(vulkano::pipeline::graphics::rasterization::CullMode::Back)
-                    .front_face(
-                        vulkano::pipeline::graphics::rasterization::FrontFace::CounterClockwise,
-                    ),
-            )
-            .depth_stencil_state(DepthStencilState {
-                depth: Some(DepthState {
-                    compare_op: vulkano::pipeline::StateMode::Fixed(CompareOp::Greater),
-                    enable_dynamic: false,
-                    write_enable: vulkano::pipeline::StateMode::Fixed(true),
-                }),
-                depth_bounds: None,
-                stencil: None,
-            })
-            .render_pass(Subpass::from(vulkan.render_pass.clone(), 0).unwrap())
+            .fragment_shader(shadow_fs.entry_point("main").unwrap(), ())
+            .rasterization_state(RasterizationState::new())
+            .depth_stencil_state(DepthStencilState::simple_depth_test())
+            .render_pass(Subpass::from(vulkan.shadows.render_pass.clone(), 0).unwrap())
             .build(vulkan.device.clone())
             .unwrap();
 
         let uniform_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
+        let light_buffers = CpuBufferPool::uniform_buffer(vulkan.device.clone());
         let uniform_pds =
             SingleLayoutDescSetPool::new(pipeline.layout().set_layouts().get(0).unwrap().clone());
         let instance_pool = CpuBufferPool::vertex_buffer(vulkan.device.clone());
@@ -191,53 +410,98 @@ void main() {
         Self {
             sampler,
             pipeline,
+            transparent_pipeline,
+            shadow_pipeline,
+            shadows: vulkan.shadows.clone(),
             uniform_buffers,
+            light_buffers,
             uniform_pds,
             instance_pool,
             batches: HashMap::new(),
+            transparent: vec![],
             uniform_binding: None,
+            stats: CullStats::default(),
+            levels: Levels::default(),
         }
     }
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn push_model(
         &mut self,
         key: ModelKey,
         mesh: &Mesh,
-        texture: &Texture,
+        material: &Material,
+        assets: &assets::Assets,
         trf: Similarity3,
+        eye: Vec3,
+        vis: Visibility,
     ) {
         use std::collections::hash_map::Entry;
+        if vis == Visibility::ShadowOnly && material.factors.transparent {
+            // transparent batches don't cast shadows anyway
+            return;
+        }
         let inst = InstanceData {
             model: *trf.into_homogeneous_matrix().as_array(),
         };
-        match self.batches.entry(key) {
-            Entry::Vacant(v) => {
-                let mut b =
-                    Self::create_batch(self.pipeline.clone(), self.sampler.clone(), mesh, texture);
-                b.push_instance(inst);
-                v.insert(b);
-            }
-            Entry::Occupied(v) => v.into_mut().push_instance(inst),
+        let b = match self.batches.entry(key) {
+            Entry::Vacant(v) => v.insert(Self::create_batch(
+                self.pipeline.clone(),
+                self.sampler.clone(),
+                mesh,
+                material,
+                assets,
+            )),
+            Entry::Occupied(v) => v.into_mut(),
+        };
+        if vis == Visibility::ShadowOnly {
+            b.shadow_only.push(inst);
+            return;
+        }
+        if b.transparent {
+            // sort by the middle of the mesh, which for a model made of
+            // several meshes needn't be anywhere near its origin
+            let dist = (mesh.bounds.transformed(&trf).center - eye).mag_sq();
+            self.transparent
+                .push((dist, key, b.instance_data.len() as u32));
         }
+        b.push_instance(inst);
     }
     fn create_batch(
         pipeline: Arc<vulkano::pipeline::GraphicsPipeline>,
         sampler: Arc<Sampler>,
         mesh: &Mesh,
-        texture: &Texture,
+        material: &Material,
+        assets: &assets::Assets,
     ) -> BatchData {
+        let slot = |binding, tex: TextureRef| {
+            vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
+                binding,
+                vulkano::image::view::ImageView::new_default(assets.texture(tex).gpu_image())
+                    .unwrap(),
+                sampler.clone(),
+            )
+        };
         BatchData {
-            verts: mesh.verts.clone(),
-            idxs: mesh.idx.clone(),
+            transparent: material.factors.transparent,
+            verts: mesh.gpu().verts.clone(),
+            idxs: mesh.gpu().idx.clone(),
             instance_data: vec![],
+            shadow_only: vec![],
             instance_buf: None,
             material_pds: PersistentDescriptorSet::new(
                 pipeline.layout().set_layouts().get(1).unwrap().clone(),
                 [
-                    vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler(
-                        0,
-                        vulkano::image::view::ImageView::new_default(texture.texture.clone())
-                            .unwrap(),
-                        sampler,
+                    slot(0, material.diffuse),
+                    slot(1, material.normal),
+                    slot(2, material.metallic_roughness),
+                    slot(3, material.emissive),
+                    slot(4, material.occlusion),
+                    vulkano::descriptor_set::WriteDescriptorSet::buffer(
+                        5,
+                        material
+                            .buffer
+                            .clone()
+                            .expect("material used for drawing before Assets::upload"),
                     ),
                 ],
             )
@@ -245,38 +509,140 @@ void main() {
         }
     }
     pub fn prepare(&mut self, rs: &RenderState, assets: &assets::Assets, camera: &Camera) {
-        for v in rs.textured.values() {
-            for (meshr, texr) in v.model.meshes.iter().zip(v.model.textures.iter()) {
+        let eye = camera.eye();
+        let culler = Culler::new(camera, &rs.lights);
+        self.stats = CullStats::default();
+        self.levels.start_frame();
+        for (k, v) in rs.textured.iter() {
+            let model = if v.model.lods.is_empty() {
+                &*v.model
+            } else {
+                let radius = v
+                    .model
+                    .meshes
+                    .iter()
+                    .map(|m| {
+                        let b = &assets.textured_mesh(*m).bounds;
+                        b.center.mag() + b.radius
+                    })
+                    .fold(0.0, f32::max);
+                let level = self.levels.pick(
+                    *k,
+                    v.model.lods.iter().map(|(t, _)| *t),
+                    v.transform.translation,
+                    radius * v.transform.scale,
+                    camera,
+                );
+                v.model.level(level)
+            };
+            for (meshr, matr) in model.meshes.iter().zip(model.materials.iter()) {
                 let mesh = assets.textured_mesh(*meshr);
-                let tex = assets.texture(*texr);
-                self.push_model(ModelKey(*meshr, *texr), mesh, tex, v.transform);
+                let vis = culler.classify(&mesh.bounds, &v.transform);
+                self.stats.count(vis);
+                if vis == Visibility::Culled {
+                    continue;
+                }
+                let mat = assets.textured_material(*matr);
+                self.push_model(
+                    ModelKey(*meshr, *matr),
+                    mesh,
+                    mat,
+                    assets,
+                    v.transform,
+                    eye,
+                    vis,
+                );
             }
         }
-        self.prepare_draw(camera);
+        self.prepare_draw(camera, &rs.lights, assets.environment());
     }
-    fn prepare_draw(&mut self, camera: &Camera) {
+    fn prepare_draw(&mut self, camera: &Camera, lights: &Lights, environment: &Environment) {
         let buf = self.uniform_buffers.next(camera.as_matrix()).unwrap();
+        let light_buf = self
+            .light_buffers
+            .next(LightData::new(lights, camera))
+            .unwrap();
         let uds = self
             .uniform_pds
-            .next(vec![vulkano::descriptor_set::WriteDescriptorSet::buffer(
-                0, buf,
-            )])
+            .next(vec![
+                vulkano::descriptor_set::WriteDescriptorSet::buffer(0, buf),
+                vulkano::descriptor_set::WriteDescriptorSet::buffer(1, light_buf),
+                self.shadows.descriptor_write(2),
+                environment.descriptor_write(3),
+            ])
             .unwrap();
         self.uniform_binding = Some(uds);
         for (_k, b) in self.batches.iter_mut() {
             b.prepare_draw(&self.instance_pool);
         }
     }
-    pub fn draw<P, L>(&mut self, builder: &mut AutoCommandBufferBuilder<P, L>) {
+    /// Draw every opaque batch.
+    pub fn draw<P, L>(&self, builder: &mut AutoCommandBufferBuilder<P, L>) {
         let uds = self.uniform_binding.clone().unwrap();
 
         builder.bind_pipeline_graphics(self.pipeline.clone());
 
-        for (_b, dat) in self.batches.iter() {
+        for (_b, dat) in self
+            .batches
+            .iter()
+            .filter(|(_b, dat)| !dat.transparent && !dat.instance_data.is_empty())
+        {
             dat.draw(self.pipeline.clone(), uds.clone(), builder);
         }
+    }
+    /// This frame's transparent instances, as their squared distance from
+    /// the camera and which one to pass to `draw_transparent`.
+    pub(crate) fn transparent_draws(&self) -> impl Iterator<Item = (f32, usize)> + '_ {
+        self.transparent
+            .iter()
+            .enumerate()
+            .map(|(i, (dist, _, _))| (*dist, i))
+    }
+    /// Blend one transparent instance over what's been drawn so far.
+    pub(crate) fn draw_transparent<P, L>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+        which: usize,
+    ) {
+        let (_, key, instance) = self.transparent[which];
+        let uds = self.uniform_binding.clone().unwrap();
+        builder.bind_pipeline_graphics(self.transparent_pipeline.clone());
+        self.batches[&key].draw_instances(
+            self.transparent_pipeline.clone(),
+            uds,
+            builder,
+            instance,
+            1,
+        );
+    }
+    /// How many instances last frame's `prepare` drew, kept for shadows
+    /// only, or skipped.
+    pub fn cull_stats(&self) -> CullStats {
+        self.stats
+    }
+    /// Forget this frame's instances, once both passes have drawn them.
+    pub fn finish_frame(&mut self) {
+        self.transparent.clear();
         self.clear_frame();
     }
+    /// Draw every batch into the shadow map being rendered, as seen with
+    /// `light_viewproj`.  Call between `prepare` and `draw`.
+    pub fn draw_shadows<P, L>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+        light_viewproj: Mat4,
+    ) {
+        builder
+            .bind_pipeline_graphics(self.shadow_pipeline.clone())
+            .push_constants(
+                self.shadow_pipeline.layout().clone(),
+                0,
+                *light_viewproj.as_array(),
+            );
+        for (_b, dat) in self.batches.iter().filter(|(_b, dat)| !dat.transparent) {
+            dat.draw_shadow(self.shadow_pipeline.clone(), builder);
+        }
+    }
     fn clear_frame(&mut self) {
         // delete batch data for objects that didn't get rendered this frame.
         // TODO: something more sophisticated!
@@ -293,7 +659,12 @@ impl BatchData {
     ) {
         self.instance_buf = Some(
             instance_pool
-                .chunk(self.instance_data.iter().copied())
+                .chunk(
+                    self.instance_data
+                        .iter()
+                        .chain(self.shadow_only.iter())
+                        .copied(),
+                )
                 .unwrap(),
         );
     }
@@ -302,6 +673,16 @@ impl BatchData {
         pipeline: Arc<GraphicsPipeline>,
         unis: Arc<vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet>,
         builder: &mut AutoCommandBufferBuilder<P, L>,
+    ) {
+        self.draw_instances(pipeline, unis, builder, 0, self.instance_data.len() as u32);
+    }
+    fn draw_instances<P, L>(
+        &self,
+        pipeline: Arc<GraphicsPipeline>,
+        unis: Arc<vulkano::descriptor_set::single_layout_pool::SingleLayoutDescSet>,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+        first: u32,
+        count: u32,
     ) {
         builder
             .bind_vertex_buffers(0, [self.verts.clone()])
@@ -319,9 +700,21 @@ impl BatchData {
                 1,
                 self.material_pds.clone(),
             )
+            .draw_indexed(self.idxs.len() as u32, count, 0, 0, first)
+            .unwrap();
+    }
+    fn draw_shadow<P, L>(
+        &self,
+        pipeline: Arc<GraphicsPipeline>,
+        builder: &mut AutoCommandBufferBuilder<P, L>,
+    ) {
+        builder
+            .bind_vertex_buffers(0, [self.verts.clone()])
+            .bind_vertex_buffers(1, [self.instance_buf.clone().unwrap()])
+            .bind_index_buffer(self.idxs.clone())
             .draw_indexed(
                 self.idxs.len() as u32,
-                self.instance_data.len() as u32,
+                (self.instance_data.len() + self.shadow_only.len()) as u32,
                 0,
                 0,
                 0,
@@ -330,9 +723,10 @@ impl BatchData {
     }
     fn clear_frame(&mut self) {
         self.instance_data.clear();
+        self.shadow_only.clear();
     }
     fn is_empty(&self) -> bool {
-        self.instance_data.is_empty()
+        self.instance_data.is_empty() && self.shadow_only.is_empty()
     }
     fn push_instance(&mut self, inst: InstanceData) {
         self.instance_data.push(inst);
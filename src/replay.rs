@@ -0,0 +1,191 @@
+//! Recording the input a game sees each tick, and playing it back.
+//!
+//! Worlds only change in `update`, once per fixed timestep, so feeding a
+//! world the same `Input` for every tick reproduces a session exactly.
+//! `Recorder` wraps a world and writes down the input it gets; `replay` (or
+//! `Simulation::replay`, which needs no GPU) feeds a recording back to a
+//! world, for reproducing bug reports or checking where a scripted
+//! playthrough ends up.
+use crate::assets::Assets;
+use crate::bindings::Bindings;
+use crate::engine::World;
+use crate::input::Input;
+use crate::renderer::RenderState;
+use color_eyre::eyre::{ensure, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const RECORDING_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Recording {
+    pub version: u32,
+    // the timestep the recording was made with; replays need the same one
+    pub dt: f64,
+    // the player's bindings, since the same input means nothing without them
+    #[serde(default)]
+    pub bindings: Option<Bindings>,
+    // one snapshot per update, in order
+    pub frames: Vec<Input>,
+}
+
+impl Recording {
+    pub fn new(dt: f64) -> Self {
+        Self {
+            version: RECORDING_VERSION,
+            dt,
+            bindings: None,
+            frames: vec![],
+        }
+    }
+    pub fn push(&mut self, input: &Input) {
+        self.frames.push(input.clone());
+    }
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // one frame per line keeps big recordings readable and diffable
+        let text = ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::new().depth_limit(2),
+        )?;
+        std::fs::write(path, text).wrap_err_with(|| format!("Couldn't write recording {:?}", path))
+    }
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read recording {:?}", path))?;
+        let rec: Recording = ron::from_str(&text)
+            .wrap_err_with(|| format!("Couldn't parse recording {:?}", path))?;
+        ensure!(
+            rec.version == RECORDING_VERSION,
+            "Recording {:?} is version {}, but only version {} can be replayed",
+            path,
+            rec.version,
+            RECORDING_VERSION
+        );
+        Ok(rec)
+    }
+}
+
+/// Feed every frame of `rec` to `world`, as `Engine::play_world` would have.
+pub fn replay(rec: &Recording, world: &mut impl World, assets: &mut Assets) {
+    for input in rec.frames.iter() {
+        world.update(input, assets);
+    }
+}
+
+/// A world that records the input it's given before passing it on, and saves
+/// the recording to `path` when the game quits or the recorder is dropped.
+pub struct Recorder<W: World> {
+    world: W,
+    recording: Recording,
+    path: PathBuf,
+    // how many frames were in the recording when it was last saved
+    saved: Option<usize>,
+}
+impl<W: World> Recorder<W> {
+    pub fn new(world: W, recording: Recording, path: PathBuf) -> Self {
+        Self {
+            world,
+            recording,
+            path,
+            saved: None,
+        }
+    }
+    pub fn world(&self) -> &W {
+        &self.world
+    }
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+    pub fn save(&self) -> Result<()> {
+        self.recording.save(&self.path)
+    }
+    /// Save the recording, unless nothing's been recorded since the last
+    /// time, and report how it went.
+    pub fn flush(&mut self) {
+        if self.saved == Some(self.recording.len()) {
+            return;
+        }
+        match self.save() {
+            Ok(()) => {
+                self.saved = Some(self.recording.len());
+                println!(
+                    "Saved {} frames of input to {:?}",
+                    self.recording.len(),
+                    self.path
+                );
+            }
+            Err(e) => println!("Couldn't save recording: {:?}", e),
+        }
+    }
+}
+impl<W: World> World for Recorder<W> {
+    fn update(&mut self, inp: &Input, assets: &mut Assets) {
+        self.recording.push(inp);
+        self.world.update(inp, assets);
+    }
+    fn render(&mut self, assets: &mut Assets, render_state: &mut RenderState) {
+        self.world.render(assets, render_state);
+    }
+    fn quit(&mut self) {
+        self.world.quit();
+        self.flush();
+    }
+}
+impl<W: World> Drop for Recorder<W> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Simulation;
+    use winit::event::VirtualKeyCode as K;
+
+    fn hold(sim: &mut Simulation<impl World>, key: K, ticks: usize) {
+        sim.input_mut().set_key(key, true);
+        sim.step_n(ticks);
+        sim.input_mut().set_key(key, false);
+    }
+
+    #[test]
+    fn replays_end_where_recordings_did() {
+        let path = std::env::temp_dir().join(format!("replay-test-{}.ron", std::process::id()));
+        let mut game = crate::tests::new_game();
+        game.keep_saves_in_memory();
+        let recorder = Recorder::new(game, Recording::new(crate::DT), path.clone());
+        let mut sim = Simulation::new(recorder, Assets::new(), crate::DT);
+        hold(&mut sim, K::W, 30);
+        hold(&mut sim, K::F5, 1);
+        let saved = sim.world().world().save_data();
+        hold(&mut sim, K::D, 20);
+        assert_ne!(sim.world().world().save_data().player_pos, saved.player_pos);
+        // quickloading reads back the save made in memory
+        hold(&mut sim, K::F9, 1);
+        sim.step_n(5);
+        let end = sim.world().world().save_data();
+        assert_eq!(end.player_pos, saved.player_pos);
+        let ticks = sim.ticks();
+        // dropping the recorder saves the recording
+        drop(sim.into_world());
+
+        let rec = Recording::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rec.len(), ticks);
+        let mut game = crate::tests::new_game();
+        game.keep_saves_in_memory();
+        let mut sim = Simulation::new(game, Assets::new(), rec.dt);
+        sim.replay(&rec);
+        assert_eq!(sim.world().save_data(), end);
+    }
+}
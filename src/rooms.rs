@@ -0,0 +1,209 @@
+//! Keeping track of which room everything is in.
+//!
+//! An entity is in a room when the middle of its volume is.  Rooms can sit
+//! inside other rooms (via their `Container`), so an entity in a cupboard is
+//! also in the kitchen the cupboard is in; an entity's `Container` holds the
+//! innermost one.  Moving from one room to another emits `Exit` events for
+//! every room left, innermost first, then `Enter` events for every room
+//! entered, outermost first.
+use crate::ecs::{Container, Entity, World};
+use crate::types::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomEvent {
+    Enter { object: Entity, room: Entity },
+    Exit { object: Entity, room: Entity },
+}
+
+type Subscriber = Box<dyn FnMut(&RoomEvent)>;
+
+#[derive(Default)]
+pub struct RoomTracker {
+    subscribers: Vec<Subscriber>,
+}
+impl RoomTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Call `f` with every event from now on.
+    pub fn subscribe(&mut self, f: impl FnMut(&RoomEvent) + 'static) {
+        self.subscribers.push(Box::new(f));
+    }
+
+    /// The smallest room containing the middle of `vol`.
+    pub fn innermost(world: &World, vol: RPrism) -> Option<Entity> {
+        let p = vol.center();
+        world
+            .rooms
+            .iter()
+            .filter_map(|(e, _)| Some((e, world.volume(e)?)))
+            .filter(|(_, r)| r.contains_point(p))
+            .map(|(e, r)| (e, r.sz.x * r.sz.y * r.sz.z))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(e, _)| e)
+    }
+
+    /// `room` followed by the room it's in, and the room that's in, and so on.
+    pub fn chain(world: &World, room: Option<Entity>) -> Vec<Entity> {
+        let mut out = vec![];
+        let mut next = room;
+        // a bad container loop can't make us spin forever
+        while let Some(e) = next {
+            if out.contains(&e) {
+                break;
+            }
+            out.push(e);
+            next = world.container(e);
+        }
+        out
+    }
+
+    /// Move every entity with a volume (other than rooms) into whichever
+    /// room it's in now, mark the rooms the player is in as occupied, and
+    /// report (and publish) what changed.
+    pub fn update(&mut self, world: &mut World) -> Vec<RoomEvent> {
+        let mut events = vec![];
+        let mut occupied = vec![];
+        let movers: Vec<Entity> = world
+            .volumes
+            .iter()
+            .map(|(e, _)| e)
+            .filter(|e| !world.rooms.contains(*e))
+            .collect();
+        for e in movers {
+            let old = Self::chain(world, world.container(e));
+            let now = world.volume(e).and_then(|v| Self::innermost(world, v));
+            let new = Self::chain(world, now);
+            if world.players.contains(e) {
+                occupied.extend(new.iter().copied());
+            }
+            if old == new {
+                continue;
+            }
+            events.extend(
+                old.iter()
+                    .filter(|r| !new.contains(r))
+                    .map(|&room| RoomEvent::Exit { object: e, room }),
+            );
+            events.extend(
+                new.iter()
+                    .rev()
+                    .filter(|r| !old.contains(r))
+                    .map(|&room| RoomEvent::Enter { object: e, room }),
+            );
+            world.containers.insert(e, Container(now));
+        }
+        for (e, r) in world.rooms.iter_mut() {
+            r.occupied = occupied.contains(&e);
+        }
+        for e in events.iter() {
+            for s in self.subscribers.iter_mut() {
+                s(e);
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::{Player, Room, Transform, Volume};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use RoomEvent::*;
+
+    fn boxed(world: &mut World, pos: Vec3, sz: Vec3) -> Entity {
+        let e = world.spawn();
+        world.transforms.insert(e, Transform { pos });
+        world.volumes.insert(
+            e,
+            Volume(RPrism {
+                pos: Vec3::zero(),
+                sz,
+            }),
+        );
+        e
+    }
+    fn room(world: &mut World, pos: Vec3, sz: f32, container: Option<Entity>) -> Entity {
+        let e = boxed(world, pos, Vec3::new(sz, sz, sz));
+        world.rooms.insert(e, Room::default());
+        world.containers.insert(e, Container(container));
+        e
+    }
+    fn move_to(world: &mut World, e: Entity, pos: Vec3) {
+        world.transforms.insert(e, Transform { pos });
+    }
+
+    #[test]
+    fn nested_rooms() {
+        let mut world = World::new();
+        let house = room(&mut world, Vec3::zero(), 20.0, None);
+        let kitchen = room(&mut world, Vec3::zero(), 10.0, Some(house));
+        let cupboard = room(&mut world, Vec3::zero(), 2.0, Some(kitchen));
+        let hall = room(&mut world, Vec3::new(10.0, 0.0, 10.0), 10.0, Some(house));
+        let player = boxed(&mut world, Vec3::new(30.0, 0.0, 0.0), Vec3::one());
+        world.players.insert(player, Player);
+        let heard = Rc::new(RefCell::new(vec![]));
+        let mut tracker = RoomTracker::new();
+        let h = heard.clone();
+        tracker.subscribe(move |e| h.borrow_mut().push(*e));
+
+        assert_eq!(tracker.update(&mut world), vec![]);
+        assert_eq!(world.container(player), None);
+
+        // into the cupboard from outside: outermost first
+        move_to(&mut world, player, Vec3::new(0.5, 0.5, 0.5));
+        let events = tracker.update(&mut world);
+        let enter = |room| Enter {
+            object: player,
+            room,
+        };
+        let exit = |room| Exit {
+            object: player,
+            room,
+        };
+        assert_eq!(events, vec![enter(house), enter(kitchen), enter(cupboard)]);
+        assert_eq!(world.container(player), Some(cupboard));
+        assert!(world.rooms.get(kitchen).unwrap().occupied);
+        assert!(!world.rooms.get(hall).unwrap().occupied);
+        // nothing changes while we stay put
+        assert_eq!(tracker.update(&mut world), vec![]);
+
+        // out of the kitchen into the hall: innermost first, and the house
+        // we never left isn't mentioned
+        move_to(&mut world, player, Vec3::new(15.0, 0.5, 15.0));
+        let events = tracker.update(&mut world);
+        assert_eq!(events, vec![exit(cupboard), exit(kitchen), enter(hall)]);
+        assert_eq!(world.container(player), Some(hall));
+        assert!(world.rooms.get(house).unwrap().occupied);
+        assert!(!world.rooms.get(kitchen).unwrap().occupied);
+        assert!(world.rooms.get(hall).unwrap().occupied);
+
+        // and out of the house altogether
+        move_to(&mut world, player, Vec3::new(30.0, 0.0, 0.0));
+        let events = tracker.update(&mut world);
+        assert_eq!(events, vec![exit(hall), exit(house)]);
+        assert!(world.rooms.iter().all(|(_, r)| !r.occupied));
+
+        assert_eq!(heard.borrow().len(), 8);
+    }
+
+    #[test]
+    fn other_entities_move_too() {
+        let mut world = World::new();
+        let house = room(&mut world, Vec3::zero(), 20.0, None);
+        let clue = boxed(&mut world, Vec3::new(1.0, 1.0, 1.0), Vec3::one());
+        let events = RoomTracker::new().update(&mut world);
+        assert_eq!(
+            events,
+            vec![Enter {
+                object: clue,
+                room: house
+            }]
+        );
+        assert_eq!(world.container(clue), Some(house));
+        // only the player occupies rooms
+        assert!(!world.rooms.get(house).unwrap().occupied);
+    }
+}
@@ -0,0 +1,134 @@
+//! Saved game progress, stored as RON in `saves/slot<N>.ron`.
+//!
+//! Every file records the `version` it was written with.  When a field is
+//! added, give it a `#[serde(default)]`, bump `SAVE_VERSION`, and teach
+//! `migrate` how to fill it in for older files.  Saves from a newer version
+//! than this one are refused rather than half-read.
+use color_eyre::eyre::{ensure, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const SAVE_VERSION: u32 = 1;
+pub const SAVE_DIR: &str = "saves";
+pub const SLOT_COUNT: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SaveData {
+    pub version: u32,
+    pub current_room: usize,
+    pub player_pos: (f32, f32, f32),
+    pub perspective_deg: (f32, f32),
+    pub film_used: usize,
+    // in the order they were photographed
+    pub clues_found: Vec<usize>,
+    // ids of every clue marked found
+    pub found: Vec<usize>,
+}
+
+impl SaveData {
+    pub fn from_ron(text: &str) -> Result<Self> {
+        let data: SaveData = ron::from_str(text)?;
+        data.migrate()
+    }
+    pub fn to_ron(&self) -> Result<String> {
+        Ok(ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::new(),
+        )?)
+    }
+    /// Bring a save from an older version up to `SAVE_VERSION`, one version
+    /// at a time.  Version 1 is the first, so there's nothing to do yet; the
+    /// next version adds a loop here matching on `self.version`, with an arm
+    /// for each version that shipped.
+    pub fn migrate(self) -> Result<Self> {
+        ensure!(
+            self.version >= 1,
+            "Save has version {}, but versions start at 1",
+            self.version
+        );
+        ensure!(
+            self.version <= SAVE_VERSION,
+            "Save is from a newer version ({}) than this game supports ({})",
+            self.version,
+            SAVE_VERSION
+        );
+        Ok(self)
+    }
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_ron()?)
+            .wrap_err_with(|| format!("Couldn't write save {:?}", path))
+    }
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read save {:?}", path))?;
+        Self::from_ron(&text).wrap_err_with(|| format!("Couldn't load save {:?}", path))
+    }
+}
+
+/// Where `slot` is saved, or an error if there's no such slot.
+pub fn slot_path(slot: usize) -> Result<PathBuf> {
+    ensure!(
+        slot < SLOT_COUNT,
+        "No save slot {}; slots go from 0 to {}",
+        slot,
+        SLOT_COUNT - 1
+    );
+    Ok(Path::new(SAVE_DIR).join(format!("slot{}.ron", slot)))
+}
+
+/// The slots that have something saved in them.
+pub fn used_slots() -> Vec<usize> {
+    (0..SLOT_COUNT)
+        .filter(|s| slot_path(*s).map_or(false, |p| p.exists()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> SaveData {
+        SaveData {
+            version: SAVE_VERSION,
+            current_room: 2,
+            player_pos: (1.5, 0.0, -3.25),
+            perspective_deg: (90.0, -10.0),
+            film_used: 3,
+            clues_found: vec![12, 10],
+            found: vec![10, 12],
+        }
+    }
+
+    #[test]
+    fn ron_round_trip() {
+        let d = data();
+        assert_eq!(SaveData::from_ron(&d.to_ron().unwrap()).unwrap(), d);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let newer = SaveData {
+            version: SAVE_VERSION + 1,
+            ..data()
+        };
+        assert!(SaveData::from_ron(&newer.to_ron().unwrap()).is_err());
+        let unversioned = SaveData {
+            version: 0,
+            ..data()
+        };
+        assert!(SaveData::from_ron(&unversioned.to_ron().unwrap()).is_err());
+        // and a missing version isn't read as any version at all
+        let text = data().to_ron().unwrap().replace("version: 1,", "");
+        assert!(SaveData::from_ron(&text).is_err());
+    }
+
+    #[test]
+    fn slots_are_bounded() {
+        assert_eq!(slot_path(0).unwrap(), Path::new(SAVE_DIR).join("slot0.ron"));
+        assert!(slot_path(SLOT_COUNT - 1).is_ok());
+        assert!(slot_path(SLOT_COUNT).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! A scene graph of flat and textured models.  Each node has a transform
+//! relative to its parent, and caches its transform in the world, which is
+//! brought up to date whenever it or anything above it moves or is
+//! reparented.  Removing a node removes everything under it too.
+use crate::renderer::{self, RenderState};
+use crate::types::*;
+use std::rc::Rc;
+use thunderdome::{Arena, Index};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeRef(Index);
+
+/// What a node draws.
+#[derive(Clone)]
+pub enum Drawable {
+    // nothing, just a transform to hang children from
+    Empty,
+    Flat(Rc<renderer::flat::Model>),
+    Textured(Rc<renderer::textured::Model>),
+}
+
+pub struct Node {
+    pub drawable: Drawable,
+    local: Similarity3,
+    // parent's world * local
+    world: Similarity3,
+    parent: Option<NodeRef>,
+    children: Vec<NodeRef>,
+}
+
+#[derive(Default)]
+pub struct Scene {
+    nodes: Arena<Node>,
+    roots: Vec<NodeRef>,
+}
+impl Scene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a node at `local`, relative to `parent` if it has one.
+    pub fn add(
+        &mut self,
+        drawable: Drawable,
+        local: Similarity3,
+        parent: Option<NodeRef>,
+    ) -> NodeRef {
+        let world = match parent {
+            Some(p) => self.nodes[p.0].world * local,
+            None => local,
+        };
+        let node = NodeRef(self.nodes.insert(Node {
+            drawable,
+            local,
+            world,
+            parent,
+            children: vec![],
+        }));
+        self.siblings_mut(parent).push(node);
+        node
+    }
+    /// Remove `node` and everything under it.
+    pub fn remove(&mut self, node: NodeRef) {
+        let parent = self.nodes[node.0].parent;
+        self.siblings_mut(parent).retain(|n| *n != node);
+        let mut doomed = vec![node];
+        while let Some(n) = doomed.pop() {
+            if let Some(removed) = self.nodes.remove(n.0) {
+                doomed.extend(removed.children);
+            }
+        }
+    }
+    pub fn contains(&self, node: NodeRef) -> bool {
+        self.nodes.contains(node.0)
+    }
+    pub fn get(&self, node: NodeRef) -> &Node {
+        &self.nodes[node.0]
+    }
+    pub fn get_mut(&mut self, node: NodeRef) -> &mut Node {
+        &mut self.nodes[node.0]
+    }
+    pub fn roots(&self) -> &[NodeRef] {
+        &self.roots
+    }
+    pub fn local(&self, node: NodeRef) -> Similarity3 {
+        self.nodes[node.0].local
+    }
+    pub fn world(&self, node: NodeRef) -> Similarity3 {
+        self.nodes[node.0].world
+    }
+    pub fn parent(&self, node: NodeRef) -> Option<NodeRef> {
+        self.nodes[node.0].parent
+    }
+    pub fn children(&self, node: NodeRef) -> &[NodeRef] {
+        &self.nodes[node.0].children
+    }
+    /// Move `node` relative to its parent, taking its children along.
+    pub fn set_local(&mut self, node: NodeRef, local: Similarity3) {
+        self.nodes[node.0].local = local;
+        self.update_world(node);
+    }
+    /// Move `node` to `world`, wherever its parent is.
+    pub fn set_world(&mut self, node: NodeRef, world: Similarity3) {
+        let local = match self.nodes[node.0].parent {
+            Some(p) => self.nodes[p.0].world.inversed() * world,
+            None => world,
+        };
+        self.set_local(node, local);
+    }
+    /// Hang `node` from `parent`, or make it a root, without moving it in
+    /// the world.  Panics if `parent` is `node` or under it.
+    pub fn set_parent(&mut self, node: NodeRef, parent: Option<NodeRef>) {
+        let mut above = parent;
+        while let Some(a) = above {
+            assert!(a != node, "Can't parent a scene node to its own descendant");
+            above = self.nodes[a.0].parent;
+        }
+        let old = self.nodes[node.0].parent;
+        self.siblings_mut(old).retain(|n| *n != node);
+        self.siblings_mut(parent).push(node);
+        self.nodes[node.0].parent = parent;
+        let world = self.nodes[node.0].world;
+        self.set_world(node, world);
+    }
+    /// Draw every node, walking down from the roots.  Nodes are keyed by
+    /// their index in the arena, generation and all, so a node keeps its key
+    /// for as long as it exists and one added in a removed node's place
+    /// doesn't take over what the renderer remembered about it.
+    pub fn render(&self, rs: &mut RenderState) {
+        let mut stack: Vec<NodeRef> = self.roots.clone();
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n.0];
+            let key = n.0.to_bits() as usize;
+            match &node.drawable {
+                Drawable::Empty => {}
+                Drawable::Flat(model) => rs.render_flat(model.clone(), node.world, key),
+                Drawable::Textured(model) => rs.render_textured(model.clone(), node.world, key),
+            }
+            stack.extend(node.children.iter().copied());
+        }
+    }
+    // recompute the world transforms of `node` and everything under it
+    fn update_world(&mut self, node: NodeRef) {
+        let mut stack = vec![node];
+        while let Some(n) = stack.pop() {
+            let parent_world = self.nodes[n.0].parent.map(|p| self.nodes[p.0].world);
+            let node = &mut self.nodes[n.0];
+            node.world = match parent_world {
+                Some(pw) => pw * node.local,
+                None => node.local,
+            };
+            stack.extend(node.children.iter().copied());
+        }
+    }
+    fn siblings_mut(&mut self, parent: Option<NodeRef>) -> &mut Vec<NodeRef> {
+        match parent {
+            Some(p) => &mut self.nodes[p.0].children,
+            None => &mut self.roots,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, y: f32, z: f32) -> Similarity3 {
+        Similarity3::new(Vec3::new(x, y, z), Rotor3::identity(), 1.0)
+    }
+    fn assert_at(trf: Similarity3, x: f32, y: f32, z: f32) {
+        let d = trf.translation - Vec3::new(x, y, z);
+        assert!(d.mag() < 1e-5, "{:?}", trf.translation);
+    }
+
+    #[test]
+    fn children_follow_their_parents() {
+        let mut scene = Scene::new();
+        let a = scene.add(Drawable::Empty, at(1.0, 0.0, 0.0), None);
+        let b = scene.add(Drawable::Empty, at(0.0, 2.0, 0.0), Some(a));
+        let c = scene.add(Drawable::Empty, at(0.0, 0.0, 3.0), Some(b));
+        assert_at(scene.world(c), 1.0, 2.0, 3.0);
+        let mut moved = at(5.0, 0.0, 0.0);
+        moved.scale = 2.0;
+        scene.set_local(a, moved);
+        assert_at(scene.world(b), 5.0, 4.0, 0.0);
+        assert_at(scene.world(c), 5.0, 4.0, 6.0);
+        assert_at(scene.local(c), 0.0, 0.0, 3.0);
+        // moving a child leaves its parent alone
+        scene.set_world(b, at(0.0, 0.0, 0.0));
+        assert_at(scene.world(a), 5.0, 0.0, 0.0);
+        assert_at(scene.local(b), -2.5, 0.0, 0.0);
+        assert_eq!(scene.world(b).scale, 1.0);
+        assert_at(scene.world(c), 0.0, 0.0, 3.0);
+    }
+
+    #[test]
+    fn reparenting_stays_put() {
+        let mut scene = Scene::new();
+        let a = scene.add(Drawable::Empty, at(1.0, 0.0, 0.0), None);
+        let b = scene.add(Drawable::Empty, at(0.0, 0.0, 3.0), None);
+        scene.set_parent(b, Some(a));
+        assert_at(scene.world(b), 0.0, 0.0, 3.0);
+        assert_at(scene.local(b), -1.0, 0.0, 3.0);
+        assert_eq!(scene.roots(), &[a]);
+        assert_eq!(scene.children(a), &[b]);
+        assert_eq!(scene.parent(b), Some(a));
+        // and now moves with its new parent
+        scene.set_local(a, at(2.0, 0.0, 0.0));
+        assert_at(scene.world(b), 1.0, 0.0, 3.0);
+        scene.set_parent(b, None);
+        assert_at(scene.world(b), 1.0, 0.0, 3.0);
+        assert_eq!(scene.roots(), &[a, b]);
+        assert!(scene.children(a).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn no_parenting_to_descendants() {
+        let mut scene = Scene::new();
+        let a = scene.add(Drawable::Empty, at(0.0, 0.0, 0.0), None);
+        let b = scene.add(Drawable::Empty, at(0.0, 0.0, 0.0), Some(a));
+        scene.set_parent(a, Some(b));
+    }
+
+    #[test]
+    fn remove_takes_the_subtree() {
+        let mut scene = Scene::new();
+        let a = scene.add(Drawable::Empty, at(0.0, 0.0, 0.0), None);
+        let b = scene.add(Drawable::Empty, at(0.0, 0.0, 0.0), Some(a));
+        let c = scene.add(Drawable::Empty, at(0.0, 0.0, 0.0), Some(b));
+        let d = scene.add(Drawable::Empty, at(0.0, 0.0, 0.0), Some(a));
+        scene.remove(b);
+        assert!(!scene.contains(b) && !scene.contains(c));
+        assert!(scene.contains(a) && scene.contains(d));
+        assert_eq!(scene.children(a), &[d]);
+        // a node reusing b's slot is a different node
+        let e = scene.add(Drawable::Empty, at(0.0, 0.0, 0.0), None);
+        assert_ne!(e, b);
+        assert!(!scene.contains(b));
+        scene.remove(a);
+        assert_eq!(scene.roots(), &[e]);
+        assert!(!scene.contains(d));
+    }
+}
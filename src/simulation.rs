@@ -0,0 +1,184 @@
+//! Running a `World` without a window or a GPU.
+//!
+//! `World::update` only needs `Input` and `Assets`, and assets load into
+//! plain CPU-side data until an engine uploads them, so a `Simulation` can
+//! step a world tick by tick anywhere: checking physics, picking and game
+//! rules, replaying recordings, or scripting a playthrough.  Nothing is ever
+//! rendered.
+use crate::assets::Assets;
+use crate::engine::World;
+use crate::gamepad::PadDevice;
+use crate::input::Input;
+use crate::replay::{self, Recording};
+
+pub struct Simulation<W: World> {
+    world: W,
+    assets: Assets,
+    input: Input,
+    pad_device: Option<Box<dyn PadDevice>>,
+    dt: f64,
+    ticks: usize,
+}
+
+impl<W: World> Simulation<W> {
+    /// Simulate `world` using `assets`, which should be whatever the world was
+    /// built from (e.g. the `Assets` given to `Level::build`).
+    pub fn new(world: W, assets: Assets, dt: f64) -> Self {
+        Self {
+            world,
+            assets,
+            input: Input::new(),
+            pad_device: None,
+            dt,
+            ticks: 0,
+        }
+    }
+    /// Read gamepads from `device` before every step.
+    pub fn set_pad_device(&mut self, device: Box<dyn PadDevice>) {
+        self.pad_device = Some(device);
+    }
+    /// The input the next step will see.  Keys and buttons set here stay held
+    /// until they're let go, just like real ones.
+    pub fn input_mut(&mut self) -> &mut Input {
+        &mut self.input
+    }
+    pub fn input(&self) -> &Input {
+        &self.input
+    }
+
+    /// Run one fixed-timestep update with the current input.
+    pub fn step(&mut self) {
+        if let Some(device) = self.pad_device.as_mut() {
+            self.input.handle_pads(device.poll());
+        }
+        self.world.update(&self.input, &mut self.assets);
+        self.input.next_frame();
+        self.ticks += 1;
+    }
+    pub fn step_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.step();
+        }
+    }
+    /// Run one update with exactly `input`, e.g. a frame of a recording.
+    pub fn step_with(&mut self, input: &Input) {
+        self.world.update(input, &mut self.assets);
+        self.ticks += 1;
+    }
+    /// Run every frame of `rec`, as `Engine::play_world` would have.
+    pub fn replay(&mut self, rec: &Recording) {
+        replay::replay(rec, &mut self.world, &mut self.assets);
+        self.ticks += rec.len();
+    }
+
+    /// How many updates have run so far.
+    pub fn ticks(&self) -> usize {
+        self.ticks
+    }
+    /// How much game time has passed, in seconds.
+    pub fn time(&self) -> f64 {
+        self.ticks as f64 * self.dt
+    }
+    pub fn dt(&self) -> f64 {
+        self.dt
+    }
+    pub fn world(&self) -> &W {
+        &self.world
+    }
+    pub fn world_mut(&mut self) -> &mut W {
+        &mut self.world
+    }
+    pub fn assets(&self) -> &Assets {
+        &self.assets
+    }
+    pub fn assets_mut(&mut self) -> &mut Assets {
+        &mut self.assets
+    }
+    pub fn into_world(self) -> W {
+        self.world
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use crate::GameState;
+    use winit::event::{MouseButton, VirtualKeyCode};
+
+    fn sim() -> Simulation<GameState> {
+        Simulation::new(crate::tests::new_game(), Assets::new(), crate::DT)
+    }
+    fn found(game: &GameState) -> Vec<usize> {
+        game.save_data().found
+    }
+
+    #[test]
+    fn falls_onto_the_floor() {
+        let mut sim = sim();
+        sim.world_mut().player.set_pos(Vec3::new(5.0, 3.0, 5.0));
+        sim.step_n(10);
+        let (y, vel_y) = (sim.world().player.get_pos().y, sim.world().player.vel_y);
+        assert!(!sim.world().player.grounded);
+        assert!(y < 3.0 && vel_y < 0.0);
+        // gravity speeds the fall up
+        sim.step_n(10);
+        assert!(sim.world().player.vel_y < vel_y);
+        assert!(3.0 - sim.world().player.get_pos().y > 2.0 * (3.0 - y));
+        sim.step_n(60);
+        let player = &sim.world().player;
+        assert!(player.grounded);
+        assert_eq!(player.vel_y, 0.0);
+        assert!(player.get_pos().y.abs() < 1e-3);
+        assert_eq!((player.get_pos().x, player.get_pos().z), (5.0, 5.0));
+    }
+
+    #[test]
+    fn walks_where_it_faces() {
+        let mut sim = sim();
+        sim.step_n(5);
+        assert!(sim.world().player.grounded);
+        // yaw 0 faces +z
+        sim.world_mut().player.set_deg((0.0, 0.0));
+        sim.input_mut().set_key(VirtualKeyCode::W, true);
+        sim.step_n(30);
+        sim.input_mut().set_key(VirtualKeyCode::W, false);
+        let pos = sim.world().player.get_pos();
+        assert!((pos.x - 5.0).abs() < 1e-3);
+        let walked = pos.z - 5.0;
+        let top_speed = sim.world().player.move_spd * sim.time() as f32;
+        assert!(walked > 0.5 * top_speed && walked < top_speed);
+        // and stops soon after letting go
+        sim.step_n(30);
+        let player = &sim.world().player;
+        assert_eq!(player.vel_xz, Vec2::zero());
+        assert!(player.get_pos().y.abs() < 1e-3);
+        // the player's entity keeps up with them
+        let world = &sim.world().stuff.world;
+        let e = world.player().unwrap();
+        assert_eq!(world.transforms.get(e).unwrap().pos, player.get_pos());
+    }
+
+    #[test]
+    fn photographs_find_clues_in_view() {
+        let mut sim = sim();
+        // clue 3 is straight ahead, clue 2 is behind
+        sim.world_mut().player.set_pos(Vec3::new(8.5, 0.0, 2.0));
+        sim.world_mut().player.set_deg((0.0, 0.0));
+        sim.step();
+        assert_eq!(found(sim.world()), Vec::<usize>::new());
+        sim.input_mut().set_mouse_button(MouseButton::Left, true);
+        sim.step();
+        sim.input_mut().set_mouse_button(MouseButton::Left, false);
+        assert_eq!(found(sim.world()), vec![3]);
+        assert_eq!(sim.world().clues_found, vec![3]);
+        assert_eq!(sim.world().film_used, 1);
+        // another photo uses more film but finds nothing new, and holding
+        // the button down doesn't take any more
+        sim.step();
+        sim.input_mut().set_mouse_button(MouseButton::Left, true);
+        sim.step_n(3);
+        assert_eq!(sim.world().film_used, 2);
+        assert_eq!(sim.world().clues_found, vec![3]);
+    }
+}
@@ -1,6 +1,6 @@
 use bytemuck::{Pod, Zeroable};
 pub use std::f32::consts::PI;
-pub use ultraviolet::mat::Mat4;
+pub use ultraviolet::mat::{Mat3, Mat4};
 pub use ultraviolet::rotor::Rotor3;
 pub use ultraviolet::transform::{Isometry3, Similarity3};
 pub use ultraviolet::vec::{Vec2, Vec3, Vec4};
@@ -47,6 +47,96 @@ impl RPrism {
             && obr.y <= br.y
             && obr.z <= br.z
     }
+    pub fn max(&self) -> Vec3 {
+        self.pos + self.sz
+    }
+    pub fn center(&self) -> Vec3 {
+        self.pos + self.sz / 2.0
+    }
+    pub fn contains_point(&self, p: Vec3) -> bool {
+        let br = self.max();
+        self.pos.x <= p.x
+            && self.pos.y <= p.y
+            && self.pos.z <= p.z
+            && p.x <= br.x
+            && p.y <= br.y
+            && p.z <= br.z
+    }
+    /// True if the two prisms share some volume; touching faces don't count.
+    pub fn overlaps(&self, other: RPrism) -> bool {
+        let br = self.max();
+        let obr = other.max();
+        self.pos.x < obr.x
+            && other.pos.x < br.x
+            && self.pos.y < obr.y
+            && other.pos.y < br.y
+            && self.pos.z < obr.z
+            && other.pos.z < br.z
+    }
+    /// The smallest prism containing both prisms.
+    pub fn union(&self, other: RPrism) -> RPrism {
+        let pos = self.pos.min_by_component(other.pos);
+        let br = self.max().max_by_component(other.max());
+        RPrism { pos, sz: br - pos }
+    }
+    /// The smallest prism containing all the points, or None if there are none.
+    pub fn from_points(mut pts: impl Iterator<Item = Vec3>) -> Option<RPrism> {
+        let first = pts.next()?;
+        let (lo, hi) = pts.fold((first, first), |(lo, hi), p| {
+            (lo.min_by_component(p), hi.max_by_component(p))
+        });
+        Some(RPrism { pos: lo, sz: hi - lo })
+    }
+    /// Move this prism by `vel` and find the first moment it touches `other`.
+    /// Returns the fraction of `vel` travelled before contact (in 0..=1) and
+    /// the normal of the face of `other` that was hit.  Prisms which already
+    /// overlap report a time of 0 and a zero normal.
+    pub fn sweep(&self, vel: Vec3, other: RPrism) -> Option<(f32, Vec3)> {
+        if self.overlaps(other) {
+            return Some((0.0, Vec3::zero()));
+        }
+        let br = self.max();
+        let obr = other.max();
+        let mut t_enter = f32::NEG_INFINITY;
+        let mut t_exit = 1.0_f32;
+        let mut normal = Vec3::zero();
+        for axis in 0..3 {
+            let (lo, hi, olo, ohi, v) = (
+                self.pos[axis],
+                br[axis],
+                other.pos[axis],
+                obr[axis],
+                vel[axis],
+            );
+            if v == 0.0 {
+                // never moves along this axis, so it has to overlap already
+                if hi <= olo || ohi <= lo {
+                    return None;
+                }
+                continue;
+            }
+            let (enter, exit, n) = if v > 0.0 {
+                ((olo - hi) / v, (ohi - lo) / v, -1.0)
+            } else {
+                ((ohi - lo) / v, (olo - hi) / v, 1.0)
+            };
+            if enter > t_enter {
+                t_enter = enter;
+                normal = Vec3::zero();
+                normal[axis] = n;
+            }
+            t_exit = t_exit.min(exit);
+            if t_enter >= t_exit {
+                return None;
+            }
+        }
+        // a negative entry time means the prisms are only touching and
+        // moving apart
+        if t_enter < 0.0 {
+            return None;
+        }
+        Some((t_enter, normal))
+    }
 }
 
 #[repr(C)]
@@ -82,3 +172,60 @@ impl LerpF for Rect {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_at(x: f32, y: f32, z: f32) -> RPrism {
+        RPrism {
+            pos: Vec3::new(x, y, z),
+            sz: Vec3::one(),
+        }
+    }
+
+    #[test]
+    fn overlaps_needs_shared_volume() {
+        let a = unit_at(0.0, 0.0, 0.0);
+        assert!(a.overlaps(unit_at(0.5, 0.5, 0.5)));
+        // sharing a face isn't overlapping
+        assert!(!a.overlaps(unit_at(1.0, 0.0, 0.0)));
+        assert!(!a.overlaps(unit_at(0.0, 2.0, 0.0)));
+    }
+
+    #[test]
+    fn sweep_finds_first_contact() {
+        let a = unit_at(0.0, 0.0, 0.0);
+        let wall = unit_at(3.0, 0.0, 0.0);
+        let (t, n) = a.sweep(Vec3::new(4.0, 0.0, 0.0), wall).unwrap();
+        assert_eq!(t, 0.5);
+        assert_eq!(n, Vec3::new(-1.0, 0.0, 0.0));
+        // coming the other way hits the other face
+        let (t, n) = unit_at(6.0, 0.0, 0.0)
+            .sweep(Vec3::new(-4.0, 0.0, 0.0), wall)
+            .unwrap();
+        assert_eq!(t, 0.5);
+        assert_eq!(n, Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn sweep_misses() {
+        let a = unit_at(0.0, 0.0, 0.0);
+        let wall = unit_at(3.0, 0.0, 0.0);
+        // too short
+        assert!(a.sweep(Vec3::new(1.0, 0.0, 0.0), wall).is_none());
+        // wrong way
+        assert!(a.sweep(Vec3::new(-4.0, 0.0, 0.0), wall).is_none());
+        // passes over the top
+        let above = unit_at(3.0, 2.0, 0.0);
+        assert!(a.sweep(Vec3::new(4.0, 0.0, 0.0), above).is_none());
+    }
+
+    #[test]
+    fn sweep_starting_inside() {
+        let a = unit_at(0.0, 0.0, 0.0);
+        let (t, n) = a.sweep(Vec3::new(1.0, 0.0, 0.0), unit_at(0.5, 0.0, 0.0)).unwrap();
+        assert_eq!(t, 0.0);
+        assert_eq!(n, Vec3::zero());
+    }
+}
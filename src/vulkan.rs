@@ -1,11 +1,9 @@
 use vulkano::command_buffer::PrimaryAutoCommandBuffer;
 use vulkano::image::ImageAccess;
-use winit::window::Window;
 use vulkano::image::AttachmentImage;
 use vulkano::image::view::ImageView;
 use vulkano::render_pass::Framebuffer;
 use vulkano::render_pass::RenderPass;
-use vulkano::image::SwapchainImage;
 use vulkano::image::ImageUsage;
 use vulkano::device::Device;
 use vulkano::instance::Instance;
@@ -17,15 +15,128 @@ use std::sync::Arc;
 use vulkano::sync::{self, GpuFuture};
 use winit::event_loop::EventLoop;
 use winit::window::WindowBuilder;
+use vulkano::format::Format;
+use color_eyre::eyre::{eyre, Result};
+use crate::image::Image;
+use crate::renderer::lights::MAX_SHADOWS;
+use vulkano::sampler::Sampler;
+
+// sRGB like a typical swapchain, so read-back pixels match what's on screen
+const OFFSCREEN_FORMAT: Format = Format::R8G8B8A8_SRGB;
+// width and height of every shadow map
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+const SHADOW_FORMAT: Format = Format::D32_SFLOAT;
+
+/// One depth map per shadow-casting light, drawn in a depth-only pass from
+/// the light's point of view before the main pass samples them.
+#[derive(Clone)]
+pub struct ShadowMaps {
+    pub render_pass: Arc<RenderPass>,
+    pub views: Vec<Arc<ImageView<AttachmentImage>>>,
+    pub framebuffers: Vec<Arc<Framebuffer>>,
+    // compares against the stored depth, so it's 1.0 where a point is lit
+    pub sampler: Arc<Sampler>,
+}
+impl ShadowMaps {
+    fn new(device: Arc<Device>) -> Self {
+        use vulkano::sampler::{BorderColor, Filter, SamplerAddressMode, SamplerCreateInfo};
+        let render_pass = vulkano::single_pass_renderpass!(
+            device.clone(),
+            attachments: {
+                depth: {
+                    load: Clear,
+                    store: Store,
+                    format: SHADOW_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [],
+                depth_stencil: {depth}
+            }
+        )
+        .unwrap();
+        let views: Vec<_> = (0..MAX_SHADOWS)
+            .map(|_| {
+                ImageView::new_default(
+                    AttachmentImage::with_usage(
+                        device.clone(),
+                        [SHADOW_MAP_SIZE, SHADOW_MAP_SIZE],
+                        SHADOW_FORMAT,
+                        ImageUsage {
+                            depth_stencil_attachment: true,
+                            sampled: true,
+                            ..ImageUsage::none()
+                        },
+                    ).unwrap(),
+                ).unwrap()
+            })
+            .collect();
+        let framebuffers = views
+            .iter()
+            .map(|view| {
+                Framebuffer::new(render_pass.clone(),
+                                 vulkano::render_pass::FramebufferCreateInfo {
+                                     attachments:vec![view.clone()],
+                                     ..Default::default()
+                                 }
+                ).unwrap()
+            })
+            .collect();
+        let sampler = Sampler::new(
+            device,
+            SamplerCreateInfo {
+                mag_filter: Filter::Nearest,
+                min_filter: Filter::Nearest,
+                // anything outside the map is lit
+                address_mode: [SamplerAddressMode::ClampToBorder; 3],
+                border_color: BorderColor::FloatOpaqueWhite,
+                compare: Some(vulkano::pipeline::graphics::depth_stencil::CompareOp::LessOrEqual),
+                ..Default::default()
+            },
+        )
+            .unwrap();
+        Self {
+            render_pass,
+            views,
+            framebuffers,
+            sampler,
+        }
+    }
+    pub fn viewport() -> Viewport {
+        Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [SHADOW_MAP_SIZE as f32, SHADOW_MAP_SIZE as f32],
+            depth_range: 0.0..1.0,
+        }
+    }
+    /// Every shadow map as an array of samplers, for a lit pipeline.
+    pub fn descriptor_write(&self, binding: u32) -> vulkano::descriptor_set::WriteDescriptorSet {
+        vulkano::descriptor_set::WriteDescriptorSet::image_view_sampler_array(
+            binding,
+            0,
+            self.views.iter().map(|v| {
+                (
+                    v.clone() as Arc<dyn vulkano::image::view::ImageViewAbstract>,
+                    self.sampler.clone(),
+                )
+            }),
+        )
+    }
+}
 
 pub struct Vulkan {
-    pub surface: Arc<vulkano::swapchain::Surface<winit::window::Window>>,
+    // surface and swapchain are None when rendering offscreen
+    pub surface: Option<Arc<vulkano::swapchain::Surface<winit::window::Window>>>,
     pub device: Arc<vulkano::device::Device>,
     pub present_mode:vulkano::swapchain::PresentMode,
     pub min_image_count:u32,
     pub queue: Arc<vulkano::device::Queue>,
     pub render_pass: Arc<vulkano::render_pass::RenderPass>,
-    pub swapchain: Arc<Swapchain<winit::window::Window>>,
+    pub shadows: ShadowMaps,
+    pub swapchain: Option<Arc<Swapchain<winit::window::Window>>>,
+    // color target for headless rendering; read back with `read_back`
+    pub offscreen: Option<Arc<AttachmentImage>>,
     pub viewport: Viewport,
     pub framebuffers: Vec<Arc<vulkano::render_pass::Framebuffer>>,
     pub recreate_swapchain: bool,
@@ -35,30 +146,23 @@ pub struct Vulkan {
 impl Vulkan {
     pub fn new(wb: WindowBuilder, event_loop: &EventLoop<()>) -> Self {
         dbg!(vulkano::Version::HEADER_VERSION);
-        let required_extensions = vulkano::instance::InstanceExtensions {
-            ext_debug_report: true,
-            ..vulkano_win::required_extensions()
-        };
-        let instance = Instance::new(
-            vulkano::instance::InstanceCreateInfo {
-                enabled_extensions:required_extensions,
-                enabled_layers:vec!["VK_LAYER_KHRONOS_validation".to_string()],
-                max_api_version:Some(vulkano::Version::V1_2),
-                ..Default::default()
-            }
-        )
-            .unwrap();
+        let instance = Self::create_instance(vulkano_win::required_extensions()).unwrap();
 
         use vulkano::instance::debug::{DebugCallback, MessageSeverity, MessageType};
-        let _callback = DebugCallback::new(
-            &instance,
-            MessageSeverity::all(),
-            MessageType::all(),
-            |msg| {
-                println!("Debug callback: {:?}", msg.description);
-            },
-        )
-            .ok();
+        // only there when the validation layers are
+        let _callback = if instance.enabled_extensions().ext_debug_report {
+            DebugCallback::new(
+                &instance,
+                MessageSeverity::all(),
+                MessageType::all(),
+                |msg| {
+                    println!("Debug callback: {:?}", msg.description);
+                },
+            )
+                .ok()
+        } else {
+            None
+        };
 
         use vulkano_win::VkSurfaceBuild;
         let surface = wb.build_vk_surface(event_loop, instance.clone()).unwrap();
@@ -73,13 +177,7 @@ impl Vulkan {
                     .find(|&q| q.supports_graphics() && q.supports_surface(&surface).unwrap_or(false))
                     .map(|q| (p, q))
             })
-            .min_by_key(|(p, _)| match p.properties().device_type {
-                PhysicalDeviceType::DiscreteGpu => 0,
-                PhysicalDeviceType::IntegratedGpu => 1,
-                PhysicalDeviceType::VirtualGpu => 2,
-                PhysicalDeviceType::Cpu => 3,
-                PhysicalDeviceType::Other => 4,
-            })
+            .min_by_key(|(p, _)| Self::device_preference(p))
             .unwrap();
         let (device, mut queues) = Device::new(
             physical_device,
@@ -110,29 +208,8 @@ impl Vulkan {
         };
         surface.window().set_cursor_visible(false);
 
-        
-        let render_pass = vulkano::single_pass_renderpass!(
-            device.clone(),
-            attachments: {
-                color: {
-                    load: Clear,
-                    store: Store,
-                    format: swapchain.image_format(),
-                    samples: 1,
-                },
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: vulkano::format::Format::D32_SFLOAT,
-                    samples: 1,
-                }
-            },
-            pass: {
-                color: [color],
-                depth_stencil: {depth}
-            }
-        )
-        .unwrap();
+        let render_pass = Self::create_render_pass(device.clone(), swapchain.image_format());
+        let shadows = ShadowMaps::new(device.clone());
 
         let mut viewport = Viewport {
             origin: [0.0, 0.0],
@@ -146,25 +223,160 @@ impl Vulkan {
         let previous_frame_end = Some(sync::now(device.clone()).boxed());
 
         Self {
-            surface,
+            surface: Some(surface),
             device,
             present_mode,
             min_image_count,
             render_pass,
+            shadows,
             queue,
-            swapchain,
+            swapchain: Some(swapchain),
+            offscreen: None,
             viewport,
             framebuffers,
             recreate_swapchain,
             previous_frame_end,
         }
     }
-    fn window_size_dependent_setup(
+    /// Set up Vulkan without a window: frames are drawn into a `w` by `h`
+    /// offscreen color image which can be read back with `read_back`.
+    /// This works with software drivers like lavapipe/swiftshader too, and
+    /// fails rather than panicking when there's no usable device.
+    pub fn headless(w: u32, h: u32) -> Result<Self> {
+        let instance = Self::create_instance(vulkano::instance::InstanceExtensions::none())?;
+        let (physical_device, queue_family) = PhysicalDevice::enumerate(&instance)
+            .filter_map(|p| {
+                p.queue_families()
+                    .find(|&q| q.supports_graphics())
+                    .map(|q| (p, q))
+            })
+            .min_by_key(|(p, _)| Self::device_preference(p))
+            .ok_or_else(|| eyre!("No Vulkan device with a graphics queue"))?;
+        let (device, mut queues) = Device::new(
+            physical_device,
+            vulkano::device::DeviceCreateInfo {
+                enabled_extensions:physical_device
+                .required_extensions()
+                    .union(&DeviceExtensions::none()),
+                queue_create_infos:vec![vulkano::device::QueueCreateInfo::family(queue_family)],
+                ..Default::default()
+            }
+        )?;
+        let queue = queues.next().ok_or_else(|| eyre!("Vulkan device has no queue"))?;
+
+        let render_pass = Self::create_render_pass(device.clone(), OFFSCREEN_FORMAT);
+        let shadows = ShadowMaps::new(device.clone());
+        let offscreen = AttachmentImage::with_usage(
+            device.clone(),
+            [w, h],
+            OFFSCREEN_FORMAT,
+            ImageUsage {
+                color_attachment: true,
+                transfer_source: true,
+                ..ImageUsage::none()
+            },
+        )?;
+        let mut viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [0.0, 0.0],
+            depth_range: 0.0..1.0,
+        };
+        let framebuffers = Self::window_size_dependent_setup(
+            device.clone(),
+            &[offscreen.clone()],
+            render_pass.clone(),
+            &mut viewport,
+        );
+        let previous_frame_end = Some(sync::now(device.clone()).boxed());
+
+        Ok(Self {
+            surface: None,
+            device,
+            present_mode: vulkano::swapchain::PresentMode::Fifo,
+            min_image_count: 1,
+            render_pass,
+            shadows,
+            queue,
+            swapchain: None,
+            offscreen: Some(offscreen),
+            viewport,
+            framebuffers,
+            recreate_swapchain: false,
+            previous_frame_end,
+        })
+    }
+    pub fn is_headless(&self) -> bool {
+        self.swapchain.is_none()
+    }
+    // Debug builds also turn on validation and debug reports, but only if
+    // they're installed: CI machines and software drivers often lack them.
+    fn create_instance(extensions: vulkano::instance::InstanceExtensions) -> Result<Arc<Instance>> {
+        const VALIDATION: &str = "VK_LAYER_KHRONOS_validation";
+        let mut extensions = extensions;
+        let mut layers = vec![];
+        if cfg!(debug_assertions) {
+            let has_validation = vulkano::instance::layers_list()
+                .map(|mut ls| ls.any(|l| l.name() == VALIDATION))
+                .unwrap_or(false);
+            let has_debug_report = vulkano::instance::InstanceExtensions::supported_by_core()
+                .map(|e| e.ext_debug_report)
+                .unwrap_or(false);
+            if has_validation && has_debug_report {
+                layers.push(VALIDATION.to_string());
+                extensions.ext_debug_report = true;
+            }
+        }
+        Ok(Instance::new(
+            vulkano::instance::InstanceCreateInfo {
+                enabled_extensions:extensions,
+                enabled_layers:layers,
+                max_api_version:Some(vulkano::Version::V1_2),
+                ..Default::default()
+            }
+        )?)
+    }
+    fn device_preference(p: &PhysicalDevice) -> u32 {
+        match p.properties().device_type {
+            PhysicalDeviceType::DiscreteGpu => 0,
+            PhysicalDeviceType::IntegratedGpu => 1,
+            PhysicalDeviceType::VirtualGpu => 2,
+            PhysicalDeviceType::Cpu => 3,
+            PhysicalDeviceType::Other => 4,
+        }
+    }
+    fn create_render_pass(device: Arc<Device>, format: Format) -> Arc<RenderPass> {
+        vulkano::single_pass_renderpass!(
+            device,
+            attachments: {
+                color: {
+                    load: Clear,
+                    store: Store,
+                    format: format,
+                    samples: 1,
+                },
+                depth: {
+                    load: Clear,
+                    store: DontCare,
+                    format: vulkano::format::Format::D32_SFLOAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {depth}
+            }
+        )
+        .unwrap()
+    }
+    fn window_size_dependent_setup<I>(
         device:Arc<Device>,
-        images: &[Arc<SwapchainImage<Window>>],
+        images: &[Arc<I>],
         render_pass: Arc<RenderPass>,
         viewport: &mut Viewport,
-    ) -> Vec<Arc<Framebuffer>> {
+    ) -> Vec<Arc<Framebuffer>>
+    where
+        I: ImageAccess + 'static,
+    {
         let dimensions = images[0].dimensions().width_height();
         viewport.dimensions = [dimensions[0] as f32, dimensions[1] as f32];
         images
@@ -203,10 +415,14 @@ impl Vulkan {
                 // Dropping the future will block until it's done.
             }
         }
+        let (surface, swapchain) = match (&self.surface, &self.swapchain) {
+            (Some(surface), Some(swapchain)) => (surface, swapchain),
+            // the offscreen target never changes size
+            _ => return,
+        };
         if self.recreate_swapchain {
-            let dimensions: [u32; 2] = self.surface.window().inner_size().into();
-            let (new_swapchain, new_images) = match self
-                .swapchain
+            let dimensions: [u32; 2] = surface.window().inner_size().into();
+            let (new_swapchain, new_images) = match swapchain
                 .recreate(vulkano::swapchain::SwapchainCreateInfo {
                     image_extent:dimensions,
                     image_usage:ImageUsage::color_attachment(),
@@ -220,7 +436,7 @@ impl Vulkan {
                 Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
             };
 
-            self.swapchain = new_swapchain;
+            self.swapchain = Some(new_swapchain);
             self.framebuffers = Self::window_size_dependent_setup(
                 self.device.clone(),
                 &new_images,
@@ -239,8 +455,13 @@ impl Vulkan {
     }
 
     pub fn get_next_image(&mut self) -> Option<usize> {
+        let swapchain = match &self.swapchain {
+            Some(swapchain) => swapchain.clone(),
+            // headless: there's only the one offscreen framebuffer
+            None => return Some(0),
+        };
         let (image_num, suboptimal, acquire_future) =
-            match swapchain::acquire_next_image(self.swapchain.clone(), None) {
+            match swapchain::acquire_next_image(swapchain, None) {
                 Ok(r) => r,
                 Err(AcquireError::OutOfDate) => {
                     self.recreate_swapchain = true;
@@ -263,17 +484,22 @@ impl Vulkan {
         let future = old_fut
             .unwrap_or_else(|| vulkano::sync::now(self.device.clone()).boxed())
             .then_execute(self.queue.clone(), command_buffer)
-            .unwrap()
-            .then_swapchain_present(
-                self.queue.clone(),
-                self.swapchain.clone(),
-                image_num,
-            )
-            .then_signal_fence_and_flush();
+            .unwrap();
+        let future = match &self.swapchain {
+            Some(swapchain) => future
+                .then_swapchain_present(
+                    self.queue.clone(),
+                    swapchain.clone(),
+                    image_num,
+                )
+                .then_signal_fence_and_flush()
+                .map(|f| f.boxed()),
+            None => future.then_signal_fence_and_flush().map(|f| f.boxed()),
+        };
 
         match future {
             Ok(future) => {
-                self.previous_frame_end = Some(future.boxed());
+                self.previous_frame_end = Some(future);
             }
             Err(vulkano::sync::FlushError::OutOfDate) => {
                 self.recreate_swapchain = true;
@@ -287,6 +513,39 @@ impl Vulkan {
             }
         }
     }
+    /// Copy the offscreen color buffer into an `Image`, blocking until
+    /// all submitted rendering has finished.
+    pub fn read_back(&mut self) -> Result<Image> {
+        use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, PrimaryCommandBuffer};
+        let offscreen = self
+            .offscreen
+            .clone()
+            .ok_or_else(|| eyre!("read_back is only available on a headless Vulkan"))?;
+        let [w, h] = offscreen.dimensions().width_height();
+        let buf = CpuAccessibleBuffer::from_iter(
+            self.device.clone(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..w * h * 4).map(|_| 0_u8),
+        )?;
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        builder.copy_image_to_buffer(offscreen, buf.clone())?;
+        let command_buffer = builder.build()?;
+        let old_fut = self.previous_frame_end.take();
+        old_fut
+            .unwrap_or_else(|| vulkano::sync::now(self.device.clone()).boxed())
+            .then_execute(self.queue.clone(), command_buffer)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+        self.previous_frame_end = Some(vulkano::sync::now(self.device.clone()).boxed());
+        let pixels = buf.read()?;
+        Ok(Image::from_rgba8(w, h, &pixels))
+    }
     pub fn wait_for(&mut self, f: Box<dyn GpuFuture>) {
         let old_fut = self.previous_frame_end.take();
         self.previous_frame_end = match old_fut {